@@ -0,0 +1,706 @@
+use std::cmp::Ordering;
+use std::num::FpCategory;
+use std::ops::{Add, Div, Mul, Neg, Rem, Sub};
+
+use num_traits::{Float, Num, NumCast, One, ToPrimitive, Zero};
+
+use crate::{Rhs, SolverError};
+
+/// Number with its derivative in one direction, for
+/// forward-mode automatic differentiation
+///
+/// Evaluating a model with [`Dual`] values whose derivatives
+/// are one for a single input and zero otherwise gives the
+/// derivatives of every output by that input, exact up to
+/// rounding. Comparisons only look at the value.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Dual {
+    pub value: f64,
+    pub derivative: f64,
+}
+
+impl Dual {
+    /// Value not depending on the input
+    pub fn constant(value: f64) -> Self {
+        Dual {
+            value,
+            derivative: 0.0,
+        }
+    }
+
+    /// The input the derivatives are taken by
+    pub fn variable(value: f64) -> Self {
+        Dual {
+            value,
+            derivative: 1.0,
+        }
+    }
+
+    /// `f(self)` from `value = f(x)` and `slope = f'(x)`
+    fn chain(self, value: f64, slope: f64) -> Self {
+        Dual {
+            value,
+            derivative: slope * self.derivative,
+        }
+    }
+}
+
+impl PartialEq for Dual {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl PartialOrd for Dual {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.value.partial_cmp(&other.value)
+    }
+}
+
+impl Neg for Dual {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Dual {
+            value: -self.value,
+            derivative: -self.derivative,
+        }
+    }
+}
+
+impl Add for Dual {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Dual {
+            value: self.value + other.value,
+            derivative: self.derivative + other.derivative,
+        }
+    }
+}
+
+impl Sub for Dual {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Dual {
+            value: self.value - other.value,
+            derivative: self.derivative - other.derivative,
+        }
+    }
+}
+
+impl Mul for Dual {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Dual {
+            value: self.value * other.value,
+            derivative: self.derivative * other.value
+                + self.value * other.derivative,
+        }
+    }
+}
+
+impl Div for Dual {
+    type Output = Self;
+
+    fn div(self, other: Self) -> Self {
+        let value = self.value / other.value;
+        Dual {
+            value,
+            derivative: (self.derivative
+                - value * other.derivative)
+                / other.value,
+        }
+    }
+}
+
+impl Rem for Dual {
+    type Output = Self;
+
+    fn rem(self, other: Self) -> Self {
+        let quotient = (self.value / other.value).trunc();
+        Dual {
+            value: self.value % other.value,
+            derivative: self.derivative
+                - quotient * other.derivative,
+        }
+    }
+}
+
+impl Zero for Dual {
+    fn zero() -> Self {
+        Dual::constant(0.0)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.value == 0.0
+    }
+}
+
+impl One for Dual {
+    fn one() -> Self {
+        Dual::constant(1.0)
+    }
+}
+
+impl Num for Dual {
+    type FromStrRadixErr = <f64 as Num>::FromStrRadixErr;
+
+    fn from_str_radix(
+        text: &str,
+        radix: u32,
+    ) -> Result<Self, Self::FromStrRadixErr> {
+        <f64 as Num>::from_str_radix(text, radix)
+            .map(Dual::constant)
+    }
+}
+
+impl ToPrimitive for Dual {
+    fn to_i64(&self) -> Option<i64> {
+        self.value.to_i64()
+    }
+
+    fn to_u64(&self) -> Option<u64> {
+        self.value.to_u64()
+    }
+
+    fn to_f64(&self) -> Option<f64> {
+        Some(self.value)
+    }
+}
+
+impl NumCast for Dual {
+    fn from<T: ToPrimitive>(n: T) -> Option<Self> {
+        n.to_f64().map(Dual::constant)
+    }
+}
+
+impl Float for Dual {
+    fn nan() -> Self {
+        Dual::constant(f64::NAN)
+    }
+
+    fn infinity() -> Self {
+        Dual::constant(f64::INFINITY)
+    }
+
+    fn neg_infinity() -> Self {
+        Dual::constant(f64::NEG_INFINITY)
+    }
+
+    fn neg_zero() -> Self {
+        Dual::constant(-0.0)
+    }
+
+    fn min_value() -> Self {
+        Dual::constant(f64::MIN)
+    }
+
+    fn min_positive_value() -> Self {
+        Dual::constant(f64::MIN_POSITIVE)
+    }
+
+    fn epsilon() -> Self {
+        Dual::constant(f64::EPSILON)
+    }
+
+    fn max_value() -> Self {
+        Dual::constant(f64::MAX)
+    }
+
+    fn is_nan(self) -> bool {
+        self.value.is_nan()
+    }
+
+    fn is_infinite(self) -> bool {
+        self.value.is_infinite()
+    }
+
+    fn is_finite(self) -> bool {
+        self.value.is_finite()
+    }
+
+    fn is_normal(self) -> bool {
+        self.value.is_normal()
+    }
+
+    fn classify(self) -> FpCategory {
+        self.value.classify()
+    }
+
+    // Rounding is piecewise constant
+
+    fn floor(self) -> Self {
+        Dual::constant(self.value.floor())
+    }
+
+    fn ceil(self) -> Self {
+        Dual::constant(self.value.ceil())
+    }
+
+    fn round(self) -> Self {
+        Dual::constant(self.value.round())
+    }
+
+    fn trunc(self) -> Self {
+        Dual::constant(self.value.trunc())
+    }
+
+    fn fract(self) -> Self {
+        self.chain(self.value.fract(), 1.0)
+    }
+
+    fn abs(self) -> Self {
+        self.chain(self.value.abs(), self.value.signum())
+    }
+
+    fn signum(self) -> Self {
+        Dual::constant(self.value.signum())
+    }
+
+    fn is_sign_positive(self) -> bool {
+        self.value.is_sign_positive()
+    }
+
+    fn is_sign_negative(self) -> bool {
+        self.value.is_sign_negative()
+    }
+
+    fn mul_add(self, a: Self, b: Self) -> Self {
+        self * a + b
+    }
+
+    fn recip(self) -> Self {
+        let value = self.value.recip();
+        self.chain(value, -value * value)
+    }
+
+    fn powi(self, n: i32) -> Self {
+        self.chain(
+            self.value.powi(n),
+            n as f64 * self.value.powi(n - 1),
+        )
+    }
+
+    fn powf(self, n: Self) -> Self {
+        let value = self.value.powf(n.value);
+        let mut result = self
+            .chain(value, n.value * self.value.powf(n.value - 1.0));
+        // Constant exponents avoid the logarithm of the base,
+        // which fails for bases <= 0
+        if n.derivative != 0.0 {
+            result.derivative +=
+                value * self.value.ln() * n.derivative;
+        }
+        result
+    }
+
+    fn sqrt(self) -> Self {
+        let value = self.value.sqrt();
+        self.chain(value, 0.5 / value)
+    }
+
+    fn exp(self) -> Self {
+        let value = self.value.exp();
+        self.chain(value, value)
+    }
+
+    fn exp2(self) -> Self {
+        let value = self.value.exp2();
+        self.chain(value, value * std::f64::consts::LN_2)
+    }
+
+    fn ln(self) -> Self {
+        self.chain(self.value.ln(), self.value.recip())
+    }
+
+    fn log(self, base: Self) -> Self {
+        self.ln() / base.ln()
+    }
+
+    fn log2(self) -> Self {
+        self.chain(
+            self.value.log2(),
+            (self.value * std::f64::consts::LN_2).recip(),
+        )
+    }
+
+    fn log10(self) -> Self {
+        self.chain(
+            self.value.log10(),
+            (self.value * std::f64::consts::LN_10).recip(),
+        )
+    }
+
+    fn max(self, other: Self) -> Self {
+        if self.value >= other.value || other.value.is_nan() {
+            self
+        } else {
+            other
+        }
+    }
+
+    fn min(self, other: Self) -> Self {
+        if self.value <= other.value || other.value.is_nan() {
+            self
+        } else {
+            other
+        }
+    }
+
+    fn abs_sub(self, other: Self) -> Self {
+        if self.value > other.value {
+            self - other
+        } else {
+            Dual::constant(0.0)
+        }
+    }
+
+    fn cbrt(self) -> Self {
+        let value = self.value.cbrt();
+        self.chain(value, (3.0 * value * value).recip())
+    }
+
+    fn hypot(self, other: Self) -> Self {
+        let value = self.value.hypot(other.value);
+        Dual {
+            value,
+            derivative: (self.value * self.derivative
+                + other.value * other.derivative)
+                / value,
+        }
+    }
+
+    fn sin(self) -> Self {
+        self.chain(self.value.sin(), self.value.cos())
+    }
+
+    fn cos(self) -> Self {
+        self.chain(self.value.cos(), -self.value.sin())
+    }
+
+    fn tan(self) -> Self {
+        let value = self.value.tan();
+        self.chain(value, 1.0 + value * value)
+    }
+
+    fn asin(self) -> Self {
+        self.chain(
+            self.value.asin(),
+            (1.0 - self.value * self.value).sqrt().recip(),
+        )
+    }
+
+    fn acos(self) -> Self {
+        self.chain(
+            self.value.acos(),
+            -(1.0 - self.value * self.value).sqrt().recip(),
+        )
+    }
+
+    fn atan(self) -> Self {
+        self.chain(
+            self.value.atan(),
+            (1.0 + self.value * self.value).recip(),
+        )
+    }
+
+    fn atan2(self, other: Self) -> Self {
+        let (y, x) = (self.value, other.value);
+        Dual {
+            value: y.atan2(x),
+            derivative: (x * self.derivative
+                - y * other.derivative)
+                / (x * x + y * y),
+        }
+    }
+
+    fn sin_cos(self) -> (Self, Self) {
+        (self.sin(), self.cos())
+    }
+
+    fn exp_m1(self) -> Self {
+        self.chain(self.value.exp_m1(), self.value.exp())
+    }
+
+    fn ln_1p(self) -> Self {
+        self.chain(self.value.ln_1p(), (1.0 + self.value).recip())
+    }
+
+    fn sinh(self) -> Self {
+        self.chain(self.value.sinh(), self.value.cosh())
+    }
+
+    fn cosh(self) -> Self {
+        self.chain(self.value.cosh(), self.value.sinh())
+    }
+
+    fn tanh(self) -> Self {
+        let value = self.value.tanh();
+        self.chain(value, 1.0 - value * value)
+    }
+
+    fn asinh(self) -> Self {
+        self.chain(
+            self.value.asinh(),
+            (self.value * self.value + 1.0).sqrt().recip(),
+        )
+    }
+
+    fn acosh(self) -> Self {
+        self.chain(
+            self.value.acosh(),
+            (self.value * self.value - 1.0).sqrt().recip(),
+        )
+    }
+
+    fn atanh(self) -> Self {
+        self.chain(
+            self.value.atanh(),
+            (1.0 - self.value * self.value).recip(),
+        )
+    }
+
+    fn integer_decode(self) -> (u64, i16, i8) {
+        Float::integer_decode(self.value)
+    }
+}
+
+/// Model written for any float type, e.g.
+///
+/// ```ignore
+/// impl GenericRhs for Decay {
+///     fn eval_generic<T: Float>(
+///         &self,
+///         _time: T,
+///         values: &[T],
+///         pars: &[T],
+///     ) -> Result<Vec<T>, SolverError> {
+///         Ok(vec![-pars[0] * values[0]])
+///     }
+/// }
+/// ```
+///
+/// Wrapped in [`AutoDiff`] it is a [`Rhs`] with an exact
+/// Jacobian.
+pub trait GenericRhs {
+    fn eval_generic<T: Float>(
+        &self,
+        time: T,
+        values: &[T],
+        pars: &[T],
+    ) -> Result<Vec<T>, SolverError>;
+}
+
+/// Model whose Jacobian is computed by forward-mode automatic
+/// differentiation, one evaluation with [`Dual`] numbers for
+/// every variable
+pub struct AutoDiff<M>(pub M);
+
+impl<M: GenericRhs> AutoDiff<M> {
+    /// `J[i][j]`, the derivative of output `i` by input `j` of
+    /// `seeded`, which makes input `j` the variable
+    fn derivatives(
+        &self,
+        time: f64,
+        n_inputs: usize,
+        seeded: impl Fn(usize) -> (Vec<Dual>, Vec<Dual>),
+    ) -> Result<Vec<Vec<f64>>, SolverError> {
+        let mut jac: Vec<Vec<f64>> = vec![];
+        for j in 0..n_inputs {
+            let (values, pars) = seeded(j);
+            let column = self.0.eval_generic(
+                Dual::constant(time),
+                &values,
+                &pars,
+            )?;
+            if jac.is_empty() {
+                jac = vec![vec![0.0; n_inputs]; column.len()];
+            }
+            for (row, x) in jac.iter_mut().zip(&column) {
+                row[j] = x.derivative;
+            }
+        }
+        Ok(jac)
+    }
+
+    /// `∂f_i/∂p_j`, how the derivatives change with the
+    /// parameters
+    pub fn parameter_jacobian(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<Vec<Vec<f64>>, SolverError> {
+        let values: Vec<Dual> =
+            values.iter().map(|&y| Dual::constant(y)).collect();
+        self.derivatives(time, pars.len(), |j| {
+            let pars = seed(pars, j);
+            (values.clone(), pars)
+        })
+    }
+}
+
+/// `values` as constants except for the variable at `j`
+fn seed(values: &[f64], j: usize) -> Vec<Dual> {
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, &x)| {
+            if i == j {
+                Dual::variable(x)
+            } else {
+                Dual::constant(x)
+            }
+        })
+        .collect()
+}
+
+impl<M: GenericRhs> Rhs for AutoDiff<M> {
+    fn eval(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        self.0.eval_generic(time, values, pars)
+    }
+
+    fn jacobian(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Option<Result<Vec<Vec<f64>>, SolverError>> {
+        let pars: Vec<Dual> =
+            pars.iter().map(|&p| Dual::constant(p)).collect();
+        Some(self.derivatives(time, values.len(), |j| {
+            (seed(values, j), pars.clone())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::implicit::{Kvaerno45Options, kvaerno45};
+    use crate::models::{LotkaVolterra, lotka_volterra};
+
+    #[test]
+    fn differentiates_elementary_functions() {
+        let x = Dual::variable(0.7);
+        let two = Dual::constant(2.0);
+        type Case = (fn(Dual) -> Dual, fn(f64) -> f64);
+        let cases: [Case; 8] = [
+            (
+                |x| x * x.sin() / (Dual::one() + x),
+                |x| x * x.sin() / (1.0 + x),
+            ),
+            (
+                |x| x.exp().ln_1p() - x.sqrt().cbrt(),
+                |x| x.exp().ln_1p() - x.sqrt().cbrt(),
+            ),
+            (|x| x.powf(x) + x.powi(3), |x| x.powf(x) + x.powi(3)),
+            (
+                |x| x.atan2(Dual::one() - x).tanh(),
+                |x| x.atan2(1.0 - x).tanh(),
+            ),
+            (
+                |x| x.log10() * x.asin() + x.acos().cosh(),
+                |x| x.log10() * x.asin() + x.acos().cosh(),
+            ),
+            (
+                |x| x.hypot(x.recip()) % Dual::constant(0.9),
+                |x| x.hypot(x.recip()) % 0.9,
+            ),
+            (
+                |x| x.max(x * x).abs() - x.min(-x).tan(),
+                |x| x.max(x * x).abs() - x.min(-x).tan(),
+            ),
+            (
+                |x| {
+                    x.asinh()
+                        + (x + Dual::one()).acosh()
+                        + x.atanh()
+                },
+                |x| x.asinh() + (x + 1.0).acosh() + x.atanh(),
+            ),
+        ];
+        for (i, (dual, float)) in cases.iter().enumerate() {
+            let h = 1e-6;
+            let expected = (float(x.value + h)
+                - float(x.value - h))
+                / (2.0 * h);
+            let found = dual(x);
+            assert_eq!(found.value, float(x.value));
+            assert!(
+                (found.derivative - expected).abs() < 1e-7,
+                "case {i}: {} != {expected}",
+                found.derivative
+            );
+        }
+        assert_eq!(
+            two.powf(Dual::variable(3.0)).derivative,
+            8.0 * 2f64.ln()
+        );
+    }
+
+    #[test]
+    fn computes_exact_jacobians() {
+        let model = AutoDiff(LotkaVolterra);
+        let (y, pars) = ([3.0, 5.0], [0.1, 0.02, 0.4, 0.02]);
+        assert_eq!(
+            model.eval(0.0, &y, &pars).unwrap(),
+            lotka_volterra(0.0, &y, &pars).unwrap()
+        );
+        let [alpha, beta, gamma, delta] = pars;
+        assert_eq!(
+            model.jacobian(0.0, &y, &pars).unwrap().unwrap(),
+            vec![
+                vec![alpha - beta * y[1], -beta * y[0]],
+                vec![delta * y[1], delta * y[0] - gamma],
+            ]
+        );
+        let interaction = y[0] * y[1];
+        assert_eq!(
+            model.parameter_jacobian(0.0, &y, &pars).unwrap(),
+            vec![
+                vec![y[0], -interaction, 0.0, 0.0],
+                vec![0.0, 0.0, -y[1], interaction],
+            ]
+        );
+    }
+
+    #[test]
+    fn replaces_finite_differences_in_implicit_solvers() {
+        let run = |model: &dyn Rhs| {
+            kvaerno45(
+                model,
+                vec![10.0, 10.0],
+                vec![0.1, 0.02, 0.4, 0.02],
+                0.0,
+                10.0,
+                Kvaerno45Options {
+                    rtol: 1e-4,
+                    atol: 1e-6.into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let exact = run(&AutoDiff(LotkaVolterra));
+        let approx = run(&lotka_volterra);
+        // Only the finite differences evaluate the model for the
+        // Jacobian
+        assert!(
+            exact.stats.rhs_evaluations
+                < approx.stats.rhs_evaluations
+        );
+        let (a, b) = (exact.values.last(), approx.values.last());
+        for (a, b) in a.unwrap().iter().zip(b.unwrap()) {
+            assert!((a - b).abs() < 1e-3 * a.abs(), "{a} != {b}");
+        }
+    }
+}
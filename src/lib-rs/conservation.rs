@@ -0,0 +1,452 @@
+use crate::network::ReactionNetwork;
+use crate::{Integration, Rhs, SolverError};
+
+/// Entries below this, relative to the largest entry of the
+/// stoichiometric matrix, count as zero
+const TOLERANCE: f64 = 1e-10;
+
+/// Weighted sum of species that no reaction changes, e.g. free
+/// plus bound enzyme
+///
+/// Weights can be negative, so a law is a linear invariant
+/// rather than a conserved moiety in the chemical sense, e.g.
+/// `s - e + p`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConservationLaw {
+    /// Weight of every species
+    pub coefficients: Vec<f64>,
+    /// Species with weight one that only this law contains,
+    /// computed from the others when the system is reduced
+    pub dependent: usize,
+}
+
+impl ConservationLaw {
+    /// Conserved total of `values`
+    pub fn total(&self, values: &[f64]) -> f64 {
+        self.coefficients
+            .iter()
+            .zip(values)
+            .map(|(c, y)| c * y)
+            .sum()
+    }
+
+    /// The conserved sum as text, e.g. `e + 2 es`
+    pub fn expression(&self, names: &[&str]) -> String {
+        let mut text = String::new();
+        for (name, &c) in names.iter().zip(&self.coefficients) {
+            if c == 0.0 {
+                continue;
+            }
+            let sign = if c < 0.0 { "-" } else { "+" };
+            match (text.is_empty(), c < 0.0) {
+                (true, false) => {}
+                (true, true) => text.push('-'),
+                (false, _) => text.push_str(&format!(" {sign} ")),
+            }
+            if c.abs() != 1.0 {
+                text.push_str(&format!("{} ", c.abs()));
+            }
+            text.push_str(name);
+        }
+        text
+    }
+}
+
+/// Basis of the left null space of `matrix`, one law for every
+/// vector `c` with `cᵀ · N = 0`
+///
+/// The species are eliminated in order, so the laws make the
+/// species that come last dependent. The basis is the reduced
+/// row echelon form, not the non-negative moieties: with
+/// `e + es` and `s - e + p`, the moiety `s + es + p` is the sum
+/// of both laws.
+pub fn conservation_laws(
+    matrix: &[Vec<f64>],
+) -> Vec<ConservationLaw> {
+    let n_species = matrix.len();
+    let n_reactions = matrix.first().map_or(0, Vec::len);
+    let scale = matrix
+        .iter()
+        .flatten()
+        .fold(0.0_f64, |max, x| max.max(x.abs()));
+    let tolerance = TOLERANCE * scale.max(1.0);
+
+    // Reduced row echelon form of the transpose, whose null
+    // space is the left null space of `matrix`
+    let mut rows: Vec<Vec<f64>> = (0..n_reactions)
+        .map(|j| matrix.iter().map(|row| row[j]).collect())
+        .collect();
+    let mut pivots = vec![];
+    for column in 0..n_species {
+        let row = pivots.len();
+        let Some(best) = (row..rows.len()).max_by(|&a, &b| {
+            rows[a][column].abs().total_cmp(&rows[b][column].abs())
+        }) else {
+            break;
+        };
+        if rows[best][column].abs() <= tolerance {
+            continue;
+        }
+        rows.swap(row, best);
+        let pivot = rows[row][column];
+        for x in &mut rows[row] {
+            *x /= pivot;
+        }
+        let pivot_row = rows[row].clone();
+        for (other, values) in rows.iter_mut().enumerate() {
+            let factor = values[column];
+            if other == row || factor == 0.0 {
+                continue;
+            }
+            for (x, p) in values.iter_mut().zip(&pivot_row) {
+                *x -= factor * p;
+            }
+        }
+        pivots.push(column);
+    }
+
+    (0..n_species)
+        .filter(|column| !pivots.contains(column))
+        .map(|dependent| {
+            let mut coefficients = vec![0.0; n_species];
+            coefficients[dependent] = 1.0;
+            for (row, &pivot) in pivots.iter().enumerate() {
+                let c = -rows[row][dependent];
+                if c.abs() > tolerance {
+                    coefficients[pivot] = c;
+                }
+            }
+            ConservationLaw {
+                coefficients,
+                dependent,
+            }
+        })
+        .collect()
+}
+
+impl ReactionNetwork {
+    /// Linear invariants of the network, see
+    /// [`conservation_laws`]
+    pub fn conservation_laws(&self) -> Vec<ConservationLaw> {
+        conservation_laws(&self.stoichiometric_matrix())
+    }
+}
+
+/// Network without its dependent species, which follow from
+/// the conserved totals of the initial state
+///
+/// Integrating the independent species only removes the
+/// singular directions of the Jacobian of the full system.
+/// [`ReducedNetwork::recover`] adds the dependent species back
+/// to a result.
+pub struct ReducedNetwork<'a> {
+    network: &'a ReactionNetwork,
+    laws: Vec<ConservationLaw>,
+    totals: Vec<f64>,
+    /// Indices of the species that are integrated
+    independent: Vec<usize>,
+}
+
+impl<'a> ReducedNetwork<'a> {
+    /// Reduce `network` with the totals of the full state `y0`
+    pub fn new(
+        network: &'a ReactionNetwork,
+        y0: &[f64],
+    ) -> Result<Self, SolverError> {
+        let n = network.species_names().len();
+        if y0.len() != n {
+            return Err(SolverError::DimensionMismatch {
+                t: 0.0,
+                quantity: "initial values",
+                expected: n,
+                found: y0.len(),
+            });
+        }
+        let laws = network.conservation_laws();
+        let totals = laws.iter().map(|law| law.total(y0)).collect();
+        let independent = (0..y0.len())
+            .filter(|&i| laws.iter().all(|law| law.dependent != i))
+            .collect();
+        Ok(ReducedNetwork {
+            network,
+            laws,
+            totals,
+            independent,
+        })
+    }
+
+    pub fn laws(&self) -> &[ConservationLaw] {
+        &self.laws
+    }
+
+    pub fn independent(&self) -> &[usize] {
+        &self.independent
+    }
+
+    /// Independent species of a full state
+    pub fn reduce(&self, values: &[f64]) -> Vec<f64> {
+        self.independent.iter().map(|&i| values[i]).collect()
+    }
+
+    /// Full state from the independent species
+    pub fn expand(&self, reduced: &[f64]) -> Vec<f64> {
+        let n = self.independent.len() + self.laws.len();
+        let mut values = vec![0.0; n];
+        for (&i, &y) in self.independent.iter().zip(reduced) {
+            values[i] = y;
+        }
+        // Laws only contain independent species besides their
+        // own dependent one
+        for (law, total) in self.laws.iter().zip(&self.totals) {
+            values[law.dependent] = total - law.total(&values);
+        }
+        values
+    }
+
+    /// Result of integrating the reduced system with every
+    /// species, in the order of the network
+    pub fn recover(
+        &self,
+        mut integration: Integration,
+    ) -> Integration {
+        for values in &mut integration.values {
+            *values = self.expand(values);
+        }
+        if let Some(steps) = &mut integration.steps {
+            for values in &mut steps.values {
+                *values = self.expand(values);
+            }
+        }
+        integration
+    }
+}
+
+impl Rhs for ReducedNetwork<'_> {
+    fn eval(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        if values.len() != self.independent.len() {
+            return Err(SolverError::DimensionMismatch {
+                t: time,
+                quantity: "variables",
+                expected: self.independent.len(),
+                found: values.len(),
+            });
+        }
+        let derivatives =
+            self.network.eval(time, &self.expand(values), pars)?;
+        Ok(self.reduce(&derivatives))
+    }
+
+    /// Jacobian of the network projected onto the independent
+    /// species, `P · J · E`
+    ///
+    /// `P` picks the rows of the independent species and `E`
+    /// is the derivative of [`ReducedNetwork::expand`], so every
+    /// dependent species contributes through its law.
+    fn jacobian(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Option<Result<Vec<Vec<f64>>, SolverError>> {
+        if values.len() != self.independent.len() {
+            return Some(Err(SolverError::DimensionMismatch {
+                t: time,
+                quantity: "variables",
+                expected: self.independent.len(),
+                found: values.len(),
+            }));
+        }
+        let full = match self.network.jacobian(
+            time,
+            &self.expand(values),
+            pars,
+        )? {
+            Ok(full) => full,
+            Err(error) => return Some(Err(error)),
+        };
+        let jac = self
+            .independent
+            .iter()
+            .map(|&row| {
+                self.independent
+                    .iter()
+                    .map(|&column| {
+                        // The dependent species of a law falls by
+                        // the weight of `column` in it
+                        full[row][column]
+                            - self
+                                .laws
+                                .iter()
+                                .map(|law| {
+                                    full[row][law.dependent]
+                                        * law.coefficients[column]
+                                })
+                                .sum::<f64>()
+                    })
+                    .collect()
+            })
+            .collect();
+        Some(Ok(jac))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explicit;
+    use crate::expr::ModelError;
+
+    /// `e + s <-> es -> e + p`
+    fn enzyme() -> Result<ReactionNetwork, ModelError> {
+        ReactionNetwork::new()
+            .species("s", 10.0)?
+            .species("e", 1.0)?
+            .species("es", 0.0)?
+            .species("p", 0.0)?
+            .parameter("k_on", 1.0)?
+            .parameter("k_off", 0.5)?
+            .parameter("k_cat", 2.0)?
+            .reaction(
+                "binding",
+                &[("s", -1.0), ("e", -1.0), ("es", 1.0)],
+                |_, y, p| p[0] * y[0] * y[1] - p[1] * y[2],
+            )
+            .and_then(|n| {
+                n.reaction(
+                    "catalysis",
+                    &[("es", -1.0), ("e", 1.0), ("p", 1.0)],
+                    |_, y, p| p[2] * y[2],
+                )
+            })
+    }
+
+    #[test]
+    fn finds_conserved_moieties() {
+        let network = enzyme().unwrap();
+        let laws = network.conservation_laws();
+        let names = network.species_names();
+        let expressions: Vec<String> =
+            laws.iter().map(|law| law.expression(&names)).collect();
+        // A basis of the conserved sums, `s + es + p` is the sum
+        // of both
+        assert_eq!(expressions, vec!["e + es", "s - e + p"]);
+        let matrix = network.stoichiometric_matrix();
+        for law in &laws {
+            // No reaction changes the total
+            for j in 0..2 {
+                let column: Vec<f64> =
+                    matrix.iter().map(|row| row[j]).collect();
+                assert_eq!(law.total(&column), 0.0);
+            }
+        }
+        assert_eq!(conservation_laws(&[vec![], vec![]]).len(), 2);
+    }
+
+    #[test]
+    fn integrates_the_reduced_system() {
+        let network = enzyme().unwrap();
+        let y0 = network.initial_values();
+        let pars = network.default_parameters();
+        let reduced = ReducedNetwork::new(&network, &y0).unwrap();
+        assert_eq!(reduced.independent(), &[0, 1]);
+        for y0 in [&y0[..3], &[1.0; 5]] {
+            assert!(matches!(
+                ReducedNetwork::new(&network, y0),
+                Err(SolverError::DimensionMismatch {
+                    expected: 4,
+                    ..
+                })
+            ));
+        }
+
+        let full = explicit::euler(
+            &network,
+            y0.clone(),
+            pars.clone(),
+            0.01,
+            0.0,
+            1.0,
+            &[],
+        )
+        .unwrap();
+        let recovered = reduced.recover(
+            explicit::euler(
+                &reduced,
+                reduced.reduce(&y0),
+                pars,
+                0.01,
+                0.0,
+                1.0,
+                &[],
+            )
+            .unwrap(),
+        );
+        let (a, b) = (full.values.last(), recovered.values.last());
+        for (a, b) in a.unwrap().iter().zip(b.unwrap()) {
+            assert!((a - b).abs() < 1e-12, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn projects_the_jacobian() {
+        // `enzyme` with rate laws, whose Jacobian is known
+        let network = ReactionNetwork::new()
+            .species("s", 10.0)
+            .and_then(|n| n.species("e", 1.0))
+            .and_then(|n| n.species("es", 0.0))
+            .and_then(|n| n.species("p", 0.0))
+            .and_then(|n| n.parameter("k_on", 1.0))
+            .and_then(|n| n.parameter("k_off", 0.5))
+            .and_then(|n| n.parameter("k_cat", 2.0))
+            .and_then(|n| {
+                n.kinetic_reaction(
+                    "binding",
+                    &[("s", -1.0), ("e", -1.0), ("es", 1.0)],
+                    "reversible_mass_action_2_1",
+                    &["s", "e", "es", "k_on", "k_off"],
+                )
+            })
+            .and_then(|n| {
+                n.kinetic_reaction(
+                    "catalysis",
+                    &[("es", -1.0), ("e", 1.0), ("p", 1.0)],
+                    "mass_action_1",
+                    &["es", "k_cat"],
+                )
+            })
+            .unwrap();
+        let pars = network.default_parameters();
+        let reduced =
+            ReducedNetwork::new(&network, &[10.0, 1.0, 0.5, 2.0])
+                .unwrap();
+        let y = [6.0, 0.7];
+        let exact =
+            reduced.jacobian(0.0, &y, &pars).unwrap().unwrap();
+        for j in 0..y.len() {
+            let (mut up, mut down) = (y.to_vec(), y.to_vec());
+            up[j] += 1e-6;
+            down[j] -= 1e-6;
+            let up = reduced.eval(0.0, &up, &pars).unwrap();
+            let down = reduced.eval(0.0, &down, &pars).unwrap();
+            for i in 0..y.len() {
+                let approx = (up[i] - down[i]) / 2e-6;
+                assert!(
+                    (exact[i][j] - approx).abs() < 1e-6,
+                    "J[{i}][{j}] = {} != {approx}",
+                    exact[i][j]
+                );
+            }
+        }
+        assert!(
+            ReducedNetwork::new(&enzyme().unwrap(), &[1.0; 4])
+                .unwrap()
+                .jacobian(0.0, &y, &pars)
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+
+use crate::SolverError;
+
+/// Time points at which an integrator reports the solution
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Default, PartialEq,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputTimes {
+    /// Every accepted step
+    #[default]
+    Steps,
+    /// The given time points, filled by the method's interpolant
+    Times(Vec<f64>),
+    /// `n` evenly spaced points from start to end (inclusive)
+    Uniform(usize),
+}
+
+impl OutputTimes {
+    /// Requested time points between `t_start` and `t_end`,
+    /// sorted in the direction of integration, or `None` if
+    /// every step should be reported
+    ///
+    /// Given times outside the time span are an error rather
+    /// than dropped, so that every requested time gets a row.
+    pub fn resolve(
+        &self,
+        t_start: f64,
+        t_end: f64,
+    ) -> Result<Option<Vec<f64>>, SolverError> {
+        let lower = t_start.min(t_end);
+        let upper = t_start.max(t_end);
+        let mut times = match self {
+            OutputTimes::Steps => return Ok(None),
+            OutputTimes::Times(times) => {
+                if let Some(t) = times
+                    .iter()
+                    .find(|t| !(lower..=upper).contains(*t))
+                {
+                    return Err(SolverError::InvalidOption {
+                        t: t_start,
+                        option: "output times",
+                        message: format!(
+                            "{t} is outside of {t_start} to {t_end}"
+                        ),
+                    });
+                }
+                times.clone()
+            }
+            OutputTimes::Uniform(0) => vec![],
+            OutputTimes::Uniform(1) => vec![t_end],
+            OutputTimes::Uniform(n) => {
+                let dt = (t_end - t_start) / (*n - 1) as f64;
+                // Rounding could move the end point past `t_end`,
+                // where it would never be reached
+                (0..*n)
+                    .map(|i| {
+                        if i == *n - 1 {
+                            t_end
+                        } else {
+                            (t_start + i as f64 * dt)
+                                .clamp(lower, upper)
+                        }
+                    })
+                    .collect()
+            }
+        };
+        times.sort_by(f64::total_cmp);
+        if t_end < t_start {
+            times.reverse();
+        }
+        Ok(Some(times))
+    }
+}
+
+/// Cubic Hermite interpolation between two accepted steps
+pub fn hermite(
+    (t0, y0, f0): (f64, &[f64], &[f64]),
+    (t1, y1, f1): (f64, &[f64], &[f64]),
+    t: f64,
+) -> Vec<f64> {
+    let h = t1 - t0;
+    let theta = (t - t0) / h;
+    let theta2 = theta * theta;
+    let theta3 = theta2 * theta;
+    let h00 = 2.0 * theta3 - 3.0 * theta2 + 1.0;
+    let h10 = theta3 - 2.0 * theta2 + theta;
+    let h01 = -2.0 * theta3 + 3.0 * theta2;
+    let h11 = theta3 - theta2;
+
+    (0..y0.len())
+        .map(|i| {
+            h00 * y0[i]
+                + h10 * h * f0[i]
+                + h01 * y1[i]
+                + h11 * h * f1[i]
+        })
+        .collect()
+}
+
+/// Collects the solution at requested time points while an
+/// integrator advances step by step
+pub struct DenseOutput {
+    times: Vec<f64>,
+    next: usize,
+    /// `1.0` when integrating forward, `-1.0` when backward
+    direction: f64,
+    pub time: Vec<f64>,
+    pub values: Vec<Vec<f64>>,
+}
+
+impl DenseOutput {
+    pub fn new(times: Vec<f64>, direction: f64) -> Self {
+        DenseOutput {
+            next: 0,
+            direction,
+            time: Vec::with_capacity(times.len()),
+            values: Vec::with_capacity(times.len()),
+            times,
+        }
+    }
+
+    /// Record all requested points that coincide with the start
+    pub fn start(&mut self, t0: f64, y0: &[f64]) {
+        while self.next < self.times.len() && self.reached(t0) {
+            self.time.push(self.times[self.next]);
+            self.values.push(y0.to_vec());
+            self.next += 1;
+        }
+    }
+
+    /// Record all requested points between `t0` (exclusive) and
+    /// `t1` (inclusive) using `interpolate`
+    pub fn step(
+        &mut self,
+        t1: f64,
+        interpolate: impl Fn(f64) -> Vec<f64>,
+    ) {
+        while self.next < self.times.len() && self.reached(t1) {
+            let t = self.times[self.next];
+            self.time.push(t);
+            self.values.push(interpolate(t));
+            self.next += 1;
+        }
+    }
+
+    /// `true` if the next requested point is not beyond `t`
+    fn reached(&self, t: f64) -> bool {
+        (t - self.times[self.next]) * self.direction >= 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uniform_ends_exactly_at_t_end() {
+        for n in [2, 3, 148, 501, 1000] {
+            for (t_start, t_end) in
+                [(0.0, 10.0), (0.1, 0.7), (10.0, 0.0)]
+            {
+                let times = OutputTimes::Uniform(n)
+                    .resolve(t_start, t_end)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(times.len(), n);
+                assert_eq!(times[0], t_start);
+                assert_eq!(times[n - 1], t_end);
+            }
+        }
+    }
+
+    #[test]
+    fn times_are_checked_and_sorted() {
+        let times = OutputTimes::Times(vec![0.0, 1.0, 2.5, 2.0])
+            .resolve(2.5, 0.0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(times, vec![2.5, 2.0, 1.0, 0.0]);
+        for t in [3.0, -1.0, f64::NAN] {
+            assert!(matches!(
+                OutputTimes::Times(vec![1.0, t]).resolve(2.5, 0.0),
+                Err(SolverError::InvalidOption {
+                    option: "output times",
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn hermite_matches_cubic() {
+        // y = t³ with y' = 3t²
+        let y = |t: f64| vec![t * t * t];
+        let f = |t: f64| vec![3.0 * t * t];
+        let (t0, t1) = (0.5, 2.0);
+        for t in [0.5, 0.8, 1.3, 2.0] {
+            let value = hermite(
+                (t0, &y(t0), &f(t0)),
+                (t1, &y(t1), &f(t1)),
+                t,
+            );
+            assert!((value[0] - y(t)[0]).abs() < 1e-12);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+
+use serde::Serialize;
+use wasm_bindgen::JsValue;
+
+/// Reasons an integration can fail
+///
+/// Every variant carries the time `t` at which the problem
+/// was detected. Running out of steps or below `h_min` is not
+/// an error, the integration then ends early with a
+/// [`ReturnCode`](crate::ReturnCode) and the results so far.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum SolverError {
+    /// The Newton iteration matrix could not be inverted
+    SingularJacobian { t: f64 },
+    /// The Newton iteration did not converge at the smallest
+    /// allowed step size
+    #[serde(rename_all = "camelCase")]
+    NewtonFailure { t: f64, iterations: i64 },
+    /// An option passed to the solver cannot be used, e.g. a
+    /// non-positive fixed step size
+    InvalidOption {
+        t: f64,
+        option: &'static str,
+        message: String,
+    },
+    /// A NaN or infinite value showed up in the state or the
+    /// right hand side
+    NanDetected { t: f64 },
+    /// A vector did not have the length the model expects
+    DimensionMismatch {
+        t: f64,
+        quantity: &'static str,
+        expected: usize,
+        found: usize,
+    },
+    /// The model itself reported an error, e.g. an exception
+    /// thrown by a JavaScript model
+    ModelFailure { t: f64, message: String },
+}
+
+impl SolverError {
+    /// Time at which the error occurred
+    pub fn time(&self) -> f64 {
+        match *self {
+            SolverError::SingularJacobian { t }
+            | SolverError::NewtonFailure { t, .. }
+            | SolverError::InvalidOption { t, .. }
+            | SolverError::NanDetected { t }
+            | SolverError::DimensionMismatch { t, .. }
+            | SolverError::ModelFailure { t, .. } => t,
+        }
+    }
+}
+
+impl fmt::Display for SolverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolverError::SingularJacobian { t } => {
+                write!(f, "singular Jacobian at t = {t}")
+            }
+            SolverError::NewtonFailure { t, iterations } => write!(
+                f,
+                "Newton iteration did not converge within \
+                 {iterations} iterations at t = {t}"
+            ),
+            SolverError::InvalidOption { t, option, message } => {
+                write!(f, "invalid {option} at t = {t}: {message}")
+            }
+            SolverError::NanDetected { t } => {
+                write!(f, "NaN detected at t = {t}")
+            }
+            SolverError::DimensionMismatch {
+                t,
+                quantity,
+                expected,
+                found,
+            } => write!(
+                f,
+                "expected {expected} {quantity}, got {found} \
+                 at t = {t}"
+            ),
+            SolverError::ModelFailure { t, message } => {
+                write!(f, "model failed at t = {t}: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SolverError {}
+
+#[derive(Serialize)]
+struct JsSolverError<'a> {
+    #[serde(flatten)]
+    error: &'a SolverError,
+    message: String,
+}
+
+impl From<SolverError> for JsValue {
+    fn from(error: SolverError) -> Self {
+        let object = JsSolverError {
+            error: &error,
+            message: error.to_string(),
+        };
+        serde_wasm_bindgen::to_value(&object)
+            .unwrap_or_else(|_| JsValue::from_str(&object.message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_failure_and_its_time() {
+        let error = SolverError::DimensionMismatch {
+            t: 1.5,
+            quantity: "derivatives",
+            expected: 2,
+            found: 3,
+        };
+        assert_eq!(error.time(), 1.5);
+        assert_eq!(
+            error.to_string(),
+            "expected 2 derivatives, got 3 at t = 1.5"
+        );
+    }
+
+    #[test]
+    fn serialises_with_kind_tag() {
+        let error = SolverError::NewtonFailure {
+            t: 2.0,
+            iterations: 10,
+        };
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({
+                "kind": "newtonFailure",
+                "t": 2.0,
+                "iterations": 10,
+            })
+        );
+    }
+}
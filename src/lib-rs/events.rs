@@ -0,0 +1,275 @@
+use std::fmt;
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+
+/// Event function `g(t, y, p)`, the event fires where it
+/// changes sign
+///
+/// Shared closures, so events can carry data such as the
+/// expressions of a model defined at runtime.
+pub type EventFunction = Rc<dyn Fn(f64, &[f64], &[f64]) -> f64>;
+
+/// Changes state and parameters in place when an event fires
+pub type EventModifier = Rc<dyn Fn(f64, &mut [f64], &mut [f64])>;
+
+/// Which sign changes of an event function trigger the event,
+/// seen in the direction of integration
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Crossing {
+    #[default]
+    Any,
+    /// From negative to zero or positive
+    Rising,
+    /// From positive to zero or negative
+    Falling,
+}
+
+impl Crossing {
+    fn triggers(self, g0: f64, g1: f64) -> bool {
+        let rising = g0 < 0.0 && g1 >= 0.0;
+        let falling = g0 > 0.0 && g1 <= 0.0;
+        match self {
+            Crossing::Any => rising || falling,
+            Crossing::Rising => rising,
+            Crossing::Falling => falling,
+        }
+    }
+}
+
+/// What the integrator does once an event is located
+#[derive(Clone)]
+pub enum EventAction {
+    /// Only record time and state
+    Record,
+    /// Record and stop the integration
+    Terminate,
+    /// Record, apply the modifier and restart from the event
+    Modify(EventModifier),
+}
+
+#[derive(Clone)]
+pub struct Event {
+    pub condition: EventFunction,
+    pub crossing: Crossing,
+    pub action: EventAction,
+}
+
+impl fmt::Debug for EventAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventAction::Record => write!(f, "Record"),
+            EventAction::Terminate => write!(f, "Terminate"),
+            EventAction::Modify(_) => write!(f, "Modify(..)"),
+        }
+    }
+}
+
+impl fmt::Debug for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Event")
+            .field("crossing", &self.crossing)
+            .field("action", &self.action)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An event located during integration, `values` is the state
+/// before any modification
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EventRecord {
+    /// Position of the event in the list passed to the solver
+    pub index: usize,
+    pub time: f64,
+    pub values: Vec<f64>,
+}
+
+/// Event that interrupts the current step
+pub struct Interrupt {
+    pub action: EventAction,
+    pub time: f64,
+    pub values: Vec<f64>,
+}
+
+/// Watches the event functions for sign changes while an
+/// integrator advances step by step
+pub struct EventMonitor<'a> {
+    events: &'a [Event],
+    previous: Vec<f64>,
+    pub records: Vec<EventRecord>,
+}
+
+impl<'a> EventMonitor<'a> {
+    pub fn new(
+        events: &'a [Event],
+        t: f64,
+        y: &[f64],
+        pars: &[f64],
+    ) -> Self {
+        let mut monitor = EventMonitor {
+            events,
+            previous: vec![],
+            records: vec![],
+        };
+        monitor.restart(t, y, pars);
+        monitor
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Re-evaluate the event functions, e.g. after the state
+    /// was modified
+    pub fn restart(&mut self, t: f64, y: &[f64], pars: &[f64]) {
+        self.previous = self
+            .events
+            .iter()
+            .map(|event| (event.condition)(t, y, pars))
+            .collect();
+    }
+
+    /// Check the step from `t0` to `t1` for events and locate
+    /// them on `interpolate`
+    ///
+    /// Events are handled in the order they occur. Recording
+    /// events are stored, the first terminating or modifying
+    /// one interrupts the step and is returned.
+    pub fn step(
+        &mut self,
+        t0: f64,
+        (t1, y1): (f64, &[f64]),
+        pars: &[f64],
+        interpolate: impl Fn(f64) -> Vec<f64>,
+    ) -> Option<Interrupt> {
+        if self.is_empty() {
+            return None;
+        }
+        let current: Vec<f64> = self
+            .events
+            .iter()
+            .map(|event| (event.condition)(t1, y1, pars))
+            .collect();
+
+        let mut hits: Vec<(usize, f64)> = self
+            .events
+            .iter()
+            .enumerate()
+            .filter(|(i, event)| {
+                event
+                    .crossing
+                    .triggers(self.previous[*i], current[*i])
+            })
+            .map(|(i, event)| {
+                let g = |t: f64| {
+                    (event.condition)(t, &interpolate(t), pars)
+                };
+                let root = find_root(
+                    g,
+                    (t0, self.previous[i]),
+                    (t1, current[i]),
+                );
+                (i, root)
+            })
+            .collect();
+        hits.sort_by(|(_, a), (_, b)| {
+            (a - t0).abs().total_cmp(&(b - t0).abs())
+        });
+        self.previous = current;
+
+        for (index, time) in hits {
+            let values = if time == t1 {
+                y1.to_vec()
+            } else {
+                interpolate(time)
+            };
+            self.records.push(EventRecord {
+                index,
+                time,
+                values: values.clone(),
+            });
+            match &self.events[index].action {
+                EventAction::Record => {}
+                action => {
+                    return Some(Interrupt {
+                        action: action.clone(),
+                        time,
+                        values,
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Locate a sign change of `g` between `a` and `b` with the
+/// Illinois variant of regula falsi
+///
+/// Returns a point on the side of `b`, i.e. just after the
+/// crossing, so the event does not fire again on restart.
+pub fn find_root(
+    g: impl Fn(f64) -> f64,
+    (mut a, mut ga): (f64, f64),
+    (mut b, mut gb): (f64, f64),
+) -> f64 {
+    const MAX_ITER: usize = 100;
+    let tol = 4.0 * f64::EPSILON * a.abs().max(b.abs()).max(1.0);
+    // Which end was replaced last, to detect a stuck side
+    let mut side = 0;
+
+    for _ in 0..MAX_ITER {
+        if gb == 0.0 || (b - a).abs() <= tol {
+            break;
+        }
+        let c = (a * gb - b * ga) / (gb - ga);
+        let gc = g(c);
+        if !gc.is_finite() {
+            break;
+        }
+        if gc == 0.0 || gc.signum() == gb.signum() {
+            b = c;
+            gb = gc;
+            if side == 1 {
+                ga *= 0.5;
+            }
+            side = 1;
+        } else {
+            a = c;
+            ga = gc;
+            if side == -1 {
+                gb *= 0.5;
+            }
+            side = -1;
+        }
+    }
+    b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_root_after_the_crossing() {
+        let g = |t: f64| t * t - 2.0;
+        let root = find_root(g, (0.0, g(0.0)), (2.0, g(2.0)));
+        assert!((root - 2f64.sqrt()).abs() < 1e-14);
+        assert!(g(root) >= 0.0);
+
+        let root = find_root(g, (2.0, g(2.0)), (0.0, g(0.0)));
+        assert!((root - 2f64.sqrt()).abs() < 1e-14);
+        assert!(g(root) <= 0.0);
+    }
+
+    #[test]
+    fn crossing_direction_filters_sign_changes() {
+        assert!(Crossing::Any.triggers(-1.0, 1.0));
+        assert!(Crossing::Any.triggers(1.0, 0.0));
+        assert!(Crossing::Rising.triggers(-1.0, 0.0));
+        assert!(!Crossing::Rising.triggers(1.0, -1.0));
+        assert!(Crossing::Falling.triggers(1.0, -1.0));
+        assert!(!Crossing::Falling.triggers(0.0, -1.0));
+    }
+}
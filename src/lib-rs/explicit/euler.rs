@@ -1,37 +1,316 @@
-use crate::{Integration, Model};
+use crate::events::{Event, EventAction, EventMonitor, Interrupt};
+use crate::{
+    Integration, ReturnCode, Rhs, SolverError, Statistics,
+};
+
+/// Output points reserved up front, more are appended as
+/// needed
+const MAX_PREALLOCATED: usize = 100_000;
 
 /// Euler integration method
+///
+/// Integrates from `t_start` to `t_end`, backwards in time if
+/// `t_end < t_start`. `step_size` is the step length and must
+/// be positive in both directions. The last step is shortened
+/// to end exactly on `t_end`.
 pub fn euler(
-    rhs: Model,
+    rhs: &dyn Rhs,
     y0: Vec<f64>,
-    pars: Vec<f64>,
+    mut pars: Vec<f64>,
     step_size: f64,
+    t_start: f64,
     t_end: f64,
-) -> Integration {
-    let t_start = 0.0;
-    let n_steps = ((t_end - t_start) / step_size).ceil() as usize;
-
-    let mut time = Vec::with_capacity(n_steps + 1);
-    let mut values = Vec::with_capacity(n_steps + 1);
+    events: &[Event],
+) -> Result<Integration, SolverError> {
+    if step_size <= 0.0 || step_size.is_nan() {
+        return Err(SolverError::InvalidOption {
+            t: t_start,
+            option: "step size",
+            message: format!("{step_size} is not positive"),
+        });
+    }
+    if !t_start.is_finite() || !t_end.is_finite() {
+        return Err(SolverError::InvalidOption {
+            t: t_start,
+            option: "time span",
+            message: format!("{t_start} to {t_end} is not finite"),
+        });
+    }
+    let n_steps_from =
+        |t: f64| ((t_end - t).abs() / step_size).ceil() as usize;
+    let step = if t_end < t_start {
+        -step_size
+    } else {
+        step_size
+    };
 
-    time.push(t_start);
-    values.push(y0);
+    let mut n_steps = n_steps_from(t_start);
+    // Tiny step sizes saturate `n_steps`, so only reserve what
+    // a reasonable output needs
+    let capacity = n_steps.saturating_add(1).min(MAX_PREALLOCATED);
+    let mut time = Vec::with_capacity(capacity);
+    let mut values = Vec::with_capacity(capacity);
+    let mut stats = Statistics::default();
+    let mut status = ReturnCode::Success;
+    let mut monitor =
+        EventMonitor::new(events, t_start, &y0, &pars);
 
-    for i in 0..n_steps {
-        let current_time = time[i];
-        let current_values = &values[i];
+    let mut t = t_start;
+    let mut y = y0;
+    time.push(t);
+    values.push(y.clone());
 
-        let derivatives = rhs(current_time, current_values, &pars);
-        let next_values: Vec<f64> = current_values
+    // Grid points are counted from the last restart so that
+    // rounding errors do not accumulate
+    let mut segment_start = t_start;
+    let mut i = 0;
+    while i < n_steps {
+        stats.rhs_evaluations += 1;
+        let derivatives = rhs.eval(t, &y, &pars)?;
+        if derivatives.len() != y.len() {
+            return Err(SolverError::DimensionMismatch {
+                t,
+                quantity: "derivatives",
+                expected: y.len(),
+                found: derivatives.len(),
+            });
+        }
+        i += 1;
+        let next_time = if i == n_steps {
+            t_end
+        } else {
+            segment_start + i as f64 * step
+        };
+        let dt = next_time - t;
+        let next_values: Vec<f64> = y
             .iter()
             .zip(derivatives.iter())
-            .map(|(y, dydt)| y + dydt * step_size)
+            .map(|(y, dydt)| y + dydt * dt)
             .collect();
+        if next_values.iter().any(|x| !x.is_finite()) {
+            return Err(SolverError::NanDetected { t });
+        }
+        stats.accepted_steps += 1;
+
+        // Euler's interpolant is the straight line between steps
+        let interrupt = monitor.step(
+            t,
+            (next_time, &next_values),
+            &pars,
+            |ti| {
+                let theta = (ti - t) / dt;
+                y.iter()
+                    .zip(&next_values)
+                    .map(|(y0, y1)| y0 + theta * (y1 - y0))
+                    .collect()
+            },
+        );
+        if let Some(Interrupt {
+            action,
+            time: t_event,
+            values: mut y_event,
+        }) = interrupt
+        {
+            time.push(t_event);
+            values.push(y_event.clone());
+            let EventAction::Modify(modify) = action else {
+                status = ReturnCode::Terminated;
+                break;
+            };
+            modify(t_event, &mut y_event, &mut pars);
+            monitor.restart(t_event, &y_event, &pars);
+            time.push(t_event);
+            values.push(y_event.clone());
+            t = t_event;
+            y = y_event;
+            segment_start = t;
+            n_steps = n_steps_from(t);
+            i = 0;
+            continue;
+        }
+
+        t = next_time;
+        y = next_values;
+        time.push(t);
+        values.push(y.clone());
+    }
+
+    Ok(Integration {
+        time,
+        values,
+        status,
+        stats,
+        steps: None,
+        events: monitor.records,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::events::Crossing;
+
+    fn decay(
+        _t: f64,
+        y: &[f64],
+        _p: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        Ok(vec![-y[0]])
+    }
+
+    #[test]
+    fn counts_steps_and_evaluations() {
+        let result =
+            euler(&decay, vec![1.0], vec![], 0.125, 0.0, 1.0, &[])
+                .unwrap();
+        assert_eq!(result.status, ReturnCode::Success);
+        assert_eq!(result.stats.accepted_steps, 8);
+        assert_eq!(result.stats.rhs_evaluations, 8);
+        assert_eq!(result.time.len(), 9);
+        assert_eq!(*result.time.last().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn integrates_backwards_from_t_start() {
+        let result =
+            euler(&decay, vec![1.0], vec![], 0.125, 3.0, 2.0, &[])
+                .unwrap();
+        assert_eq!(result.time.len(), 9);
+        assert_eq!(result.time[0], 3.0);
+        assert_eq!(*result.time.last().unwrap(), 2.0);
+        assert_eq!(result.values[1], vec![1.125]);
+    }
+
+    #[test]
+    fn rejects_invalid_step_size() {
+        for step_size in [0.0, -0.1, f64::NAN] {
+            let error = euler(
+                &decay,
+                vec![1.0],
+                vec![],
+                step_size,
+                0.0,
+                1.0,
+                &[],
+            )
+            .err();
+            assert!(matches!(
+                error,
+                Some(SolverError::InvalidOption {
+                    option: "step size",
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_infinite_time_spans() {
+        for (t_start, t_end) in
+            [(0.0, f64::INFINITY), (f64::NAN, 1.0)]
+        {
+            let error = euler(
+                &decay,
+                vec![1.0],
+                vec![],
+                0.1,
+                t_start,
+                t_end,
+                &[],
+            )
+            .err();
+            assert!(matches!(
+                error,
+                Some(SolverError::InvalidOption {
+                    option: "time span",
+                    ..
+                })
+            ));
+        }
+        // Saturates the step count, which must not be allocated
+        let short = |_t: f64, _y: &[f64], _p: &[f64]| Ok(vec![]);
+        let error =
+            euler(&short, vec![1.0], vec![], 1e-300, 0.0, 1.0, &[]);
+        assert!(matches!(
+            error,
+            Err(SolverError::DimensionMismatch { .. })
+        ));
+    }
 
-        let next_time = current_time + step_size;
-        time.push(next_time);
-        values.push(next_values);
+    #[test]
+    fn reports_model_failures_as_errors() {
+        let short = |_t: f64, _y: &[f64], _p: &[f64]| Ok(vec![]);
+        let error =
+            euler(&short, vec![1.0], vec![], 0.125, 0.0, 1.0, &[]);
+        assert!(matches!(
+            error.err(),
+            Some(SolverError::DimensionMismatch { t: 0.0, .. })
+        ));
+        let blowup =
+            |_t: f64, _y: &[f64], _p: &[f64]| Ok(vec![f64::NAN]);
+        let error =
+            euler(&blowup, vec![1.0], vec![], 0.125, 0.0, 1.0, &[]);
+        assert!(matches!(
+            error.err(),
+            Some(SolverError::NanDetected { t: 0.0 })
+        ));
     }
 
-    Integration { time, values }
+    #[test]
+    fn terminates_at_event() {
+        // Stop once the decaying value falls below one half
+        let event = Event {
+            condition: Rc::new(|_t, y: &[f64], _p: &[f64]| {
+                y[0] - 0.5
+            }),
+            crossing: Crossing::Falling,
+            action: EventAction::Terminate,
+        };
+        let result = euler(
+            &decay,
+            vec![1.0],
+            vec![],
+            0.125,
+            0.0,
+            2.0,
+            &[event],
+        )
+        .unwrap();
+        assert_eq!(result.status, ReturnCode::Terminated);
+        assert_eq!(result.events.len(), 1);
+        let record = &result.events[0];
+        assert!((record.values[0] - 0.5).abs() < 1e-12);
+        assert_eq!(*result.time.last().unwrap(), record.time);
+        assert!(record.time > 0.625 && record.time < 0.75);
+    }
+
+    #[test]
+    fn restarts_after_modifying_event() {
+        // Refill to one whenever the value falls below one half
+        let event = Event {
+            condition: Rc::new(|_t, y: &[f64], _p: &[f64]| {
+                y[0] - 0.5
+            }),
+            crossing: Crossing::Falling,
+            action: EventAction::Modify(Rc::new(
+                |_t, y: &mut [f64], _p: &mut [f64]| y[0] = 1.0,
+            )),
+        };
+        let result = euler(
+            &decay,
+            vec![1.0],
+            vec![],
+            0.125,
+            0.0,
+            2.0,
+            &[event],
+        )
+        .unwrap();
+        assert_eq!(result.status, ReturnCode::Success);
+        assert_eq!(result.events.len(), 3);
+        assert_eq!(*result.time.last().unwrap(), 2.0);
+        assert!(result.values.iter().all(|y| y[0] >= 0.5 - 1e-12));
+    }
 }
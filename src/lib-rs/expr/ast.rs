@@ -0,0 +1,277 @@
+use std::fmt;
+
+use serde::{Serialize, Serializer};
+
+/// Arithmetic operators
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+impl BinaryOp {
+    pub fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            BinaryOp::Add => a + b,
+            BinaryOp::Sub => a - b,
+            BinaryOp::Mul => a * b,
+            BinaryOp::Div => a / b,
+            BinaryOp::Pow => a.powf(b),
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Pow => "^",
+        }
+    }
+
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Add | BinaryOp::Sub => 1,
+            BinaryOp::Mul | BinaryOp::Div => 2,
+            BinaryOp::Pow => 4,
+        }
+    }
+}
+
+/// Functions that can be called in expressions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Function {
+    Exp,
+    Ln,
+    Log10,
+    Sqrt,
+    Abs,
+    Sin,
+    Cos,
+    Tan,
+    Min,
+    Max,
+}
+
+impl Function {
+    pub const ALL: &[Function] = &[
+        Function::Exp,
+        Function::Ln,
+        Function::Log10,
+        Function::Sqrt,
+        Function::Abs,
+        Function::Sin,
+        Function::Cos,
+        Function::Tan,
+        Function::Min,
+        Function::Max,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Function::Exp => "exp",
+            Function::Ln => "ln",
+            Function::Log10 => "log10",
+            Function::Sqrt => "sqrt",
+            Function::Abs => "abs",
+            Function::Sin => "sin",
+            Function::Cos => "cos",
+            Function::Tan => "tan",
+            Function::Min => "min",
+            Function::Max => "max",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|f| f.name() == name)
+    }
+
+    /// Number of arguments
+    pub fn arity(self) -> usize {
+        match self {
+            Function::Min | Function::Max => 2,
+            _ => 1,
+        }
+    }
+
+    /// Evaluate on exactly [`arity`](Self::arity) arguments
+    pub fn apply(self, args: &[f64]) -> f64 {
+        match (self, args) {
+            (Function::Exp, [x]) => x.exp(),
+            (Function::Ln, [x]) => x.ln(),
+            (Function::Log10, [x]) => x.log10(),
+            (Function::Sqrt, [x]) => x.sqrt(),
+            (Function::Abs, [x]) => x.abs(),
+            (Function::Sin, [x]) => x.sin(),
+            (Function::Cos, [x]) => x.cos(),
+            (Function::Tan, [x]) => x.tan(),
+            (Function::Min, [a, b]) => a.min(*b),
+            (Function::Max, [a, b]) => a.max(*b),
+            _ => f64::NAN,
+        }
+    }
+}
+
+/// Names with a fixed meaning in every expression
+pub const TIME: &str = "time";
+pub const PI: &str = "pi";
+
+/// Parsed arithmetic expression
+///
+/// Displays as text that parses back to the same expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    /// State, parameter, derived value, [`TIME`] or [`PI`]
+    Name(String),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+impl Expr {
+    /// Every name the expression refers to, in order of
+    /// appearance and with repetitions
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = vec![];
+        self.collect_names(&mut names);
+        names
+    }
+
+    fn collect_names<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Name(name) => names.push(name),
+            Expr::Neg(x) => x.collect_names(names),
+            Expr::Binary(_, a, b) => {
+                a.collect_names(names);
+                b.collect_names(names);
+            }
+            Expr::Call(_, args) => {
+                for arg in args {
+                    arg.collect_names(names);
+                }
+            }
+        }
+    }
+
+    /// Copy of the expression with names replaced wherever
+    /// `replace` returns an expression
+    pub fn substitute(
+        &self,
+        replace: &impl Fn(&str) -> Option<Expr>,
+    ) -> Expr {
+        match self {
+            Expr::Number(value) => Expr::Number(*value),
+            Expr::Name(name) => {
+                replace(name).unwrap_or_else(|| self.clone())
+            }
+            Expr::Neg(x) => {
+                Expr::Neg(Box::new(x.substitute(replace)))
+            }
+            Expr::Binary(op, a, b) => Expr::Binary(
+                *op,
+                Box::new(a.substitute(replace)),
+                Box::new(b.substitute(replace)),
+            ),
+            Expr::Call(function, args) => Expr::Call(
+                *function,
+                args.iter()
+                    .map(|arg| arg.substitute(replace))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Value of an expression without names other than [`PI`]
+    pub fn constant(&self) -> Option<f64> {
+        match self {
+            Expr::Number(value) => Some(*value),
+            Expr::Name(name) if name == PI => {
+                Some(std::f64::consts::PI)
+            }
+            Expr::Name(_) => None,
+            Expr::Neg(x) => x.constant().map(|x| -x),
+            Expr::Binary(op, a, b) => {
+                Some(op.apply(a.constant()?, b.constant()?))
+            }
+            Expr::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(Expr::constant)
+                    .collect::<Option<Vec<_>>>()?;
+                Some(function.apply(&args))
+            }
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Number(value) if *value < 0.0 => 3,
+            Expr::Neg(_) => 3,
+            Expr::Binary(op, _, _) => op.precedence(),
+            Expr::Number(_) | Expr::Name(_) | Expr::Call(..) => 5,
+        }
+    }
+}
+
+/// Write `expr`, in parentheses if it binds weaker than `min`
+fn write_operand(
+    f: &mut fmt::Formatter<'_>,
+    expr: &Expr,
+    min: u8,
+) -> fmt::Result {
+    if expr.precedence() < min {
+        write!(f, "({expr})")
+    } else {
+        write!(f, "{expr}")
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(value) => write!(f, "{value}"),
+            Expr::Name(name) => write!(f, "{name}"),
+            Expr::Neg(x) => {
+                write!(f, "-")?;
+                write_operand(f, x, 3)
+            }
+            Expr::Binary(op, a, b) => {
+                let precedence = op.precedence();
+                // `^` is right associative, the others group to
+                // the left
+                let (left, right) = match op {
+                    BinaryOp::Pow => (precedence + 1, precedence),
+                    _ => (precedence, precedence + 1),
+                };
+                write_operand(f, a, left)?;
+                write!(f, " {} ", op.symbol())?;
+                write_operand(f, b, right)
+            }
+            Expr::Call(function, args) => {
+                write!(f, "{}(", function.name())?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{arg}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl Serialize for Expr {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
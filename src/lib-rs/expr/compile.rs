@@ -0,0 +1,801 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::rc::Rc;
+
+use wasm_bindgen::prelude::*;
+
+use super::{
+    BinaryOp, Expr, ExprModel, Function, ModelError, PI, TIME,
+};
+use crate::events::{Crossing, Event, EventAction, EventFunction};
+use crate::{Rhs, SolverError, simulate};
+
+/// Instruction of the stack machine evaluating a model
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Const(f64),
+    Load(usize),
+    Neg,
+    Binary(BinaryOp),
+    Call(Function),
+    /// Pop the top of the stack into a slot
+    Store(usize),
+}
+
+/// Instructions with the stack size they need
+#[derive(Debug, Default)]
+pub(crate) struct Tape {
+    ops: Vec<Op>,
+    depth: usize,
+    stack_size: usize,
+}
+
+impl Tape {
+    fn push(&mut self, op: Op) {
+        match op {
+            Op::Const(_) | Op::Load(_) => self.depth += 1,
+            Op::Neg => {}
+            Op::Binary(_) | Op::Store(_) => self.depth -= 1,
+            Op::Call(function) => {
+                self.depth -= function.arity() - 1
+            }
+        }
+        self.stack_size = self.stack_size.max(self.depth);
+        self.ops.push(op);
+    }
+
+    /// Append the instructions for `expr`, which leave its
+    /// value on the stack
+    pub(crate) fn expr(
+        &mut self,
+        expr: &Expr,
+        slots: &HashMap<&str, usize>,
+    ) {
+        match expr {
+            Expr::Number(value) => self.push(Op::Const(*value)),
+            Expr::Name(name) if name == PI => {
+                self.push(Op::Const(std::f64::consts::PI))
+            }
+            Expr::Name(name) => self.push(Op::Load(slots[&**name])),
+            Expr::Neg(x) => {
+                self.expr(x, slots);
+                self.push(Op::Neg);
+            }
+            Expr::Binary(op, a, b) => {
+                self.expr(a, slots);
+                self.expr(b, slots);
+                self.push(Op::Binary(*op));
+            }
+            Expr::Call(function, args) => {
+                for arg in args {
+                    self.expr(arg, slots);
+                }
+                self.push(Op::Call(*function));
+            }
+        }
+    }
+
+    /// Run the instructions on `slots` and return the values
+    /// left on the stack
+    pub(crate) fn run(&self, slots: &mut [f64]) -> Vec<f64> {
+        let mut stack = Vec::with_capacity(self.stack_size);
+        let pop = |stack: &mut Vec<f64>| {
+            stack.pop().expect("compiled tape is balanced")
+        };
+        for op in &self.ops {
+            match *op {
+                Op::Const(value) => stack.push(value),
+                Op::Load(slot) => stack.push(slots[slot]),
+                Op::Neg => {
+                    let x = pop(&mut stack);
+                    stack.push(-x);
+                }
+                Op::Binary(op) => {
+                    let b = pop(&mut stack);
+                    let a = pop(&mut stack);
+                    stack.push(op.apply(a, b));
+                }
+                Op::Call(function) => {
+                    let at = stack.len() - function.arity();
+                    let value = function.apply(&stack[at..]);
+                    stack.truncate(at);
+                    stack.push(value);
+                }
+                Op::Store(slot) => slots[slot] = pop(&mut stack),
+            }
+        }
+        stack
+    }
+}
+
+/// Value changed by an event
+#[derive(Clone, Copy, Debug)]
+enum Target {
+    State(usize),
+    Parameter(usize),
+}
+
+#[derive(Debug)]
+struct CompiledEvent {
+    /// Leaves the trigger value on the stack
+    trigger: Tape,
+    crossing: Crossing,
+    /// Leaves the new value of every target on the stack
+    assignments: Tape,
+    targets: Vec<Target>,
+}
+
+/// Everything needed to evaluate a model, shared with the
+/// closures of its events
+///
+/// All values live in one array of slots: time, states,
+/// parameters, derived values, reaction rates and rate rules,
+/// each in the order of the model. One pass over the tape
+/// fills the derived values in dependency order and then the
+/// rates.
+#[derive(Debug)]
+struct Program {
+    n_states: usize,
+    n_parameters: usize,
+    n_slots: usize,
+    tape: Tape,
+    derived: Range<usize>,
+    rates: Range<usize>,
+    /// `(state, coefficient)` pairs of every reaction
+    stoichiometry: Vec<Vec<(usize, f64)>>,
+    /// Slot of the compartment size of every state
+    compartments: Vec<Option<usize>>,
+    /// State and slot of every rate rule
+    rate_rules: Vec<(usize, usize)>,
+    events: Vec<CompiledEvent>,
+}
+
+impl Program {
+    /// Run the tape and return all slots
+    fn evaluate(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        if values.len() != self.n_states {
+            return Err(SolverError::DimensionMismatch {
+                t: time,
+                quantity: "variables",
+                expected: self.n_states,
+                found: values.len(),
+            });
+        }
+        if pars.len() != self.n_parameters {
+            return Err(SolverError::DimensionMismatch {
+                t: time,
+                quantity: "parameters",
+                expected: self.n_parameters,
+                found: pars.len(),
+            });
+        }
+        let mut slots = Vec::with_capacity(self.n_slots);
+        slots.push(time);
+        slots.extend_from_slice(values);
+        slots.extend_from_slice(pars);
+        slots.resize(self.n_slots, 0.0);
+        self.tape.run(&mut slots);
+        Ok(slots)
+    }
+
+    fn derivatives(&self, slots: &[f64]) -> Vec<f64> {
+        let mut derivatives = vec![0.0; self.n_states];
+        for (columns, rate) in self
+            .stoichiometry
+            .iter()
+            .zip(&slots[self.rates.clone()])
+        {
+            for &(state, coefficient) in columns {
+                derivatives[state] += coefficient * rate;
+            }
+        }
+        for (derivative, compartment) in
+            derivatives.iter_mut().zip(&self.compartments)
+        {
+            if let Some(slot) = compartment {
+                *derivative /= slots[*slot];
+            }
+        }
+        for &(state, slot) in &self.rate_rules {
+            derivatives[state] = slots[slot];
+        }
+        derivatives
+    }
+}
+
+/// Model compiled into flat lists of instructions
+#[wasm_bindgen]
+pub struct CompiledModel {
+    model: ExprModel,
+    program: Rc<Program>,
+}
+
+/// Reserved names that cannot be defined by a model
+fn is_reserved(name: &str) -> bool {
+    name == TIME
+        || name == PI
+        || Function::from_name(name).is_some()
+}
+
+impl CompiledModel {
+    /// Check the names used in `model` and compile it
+    ///
+    /// Names have to be unique among states, parameters and
+    /// derived values and must not shadow `time`, `pi` or a
+    /// function. Derived values must not depend on themselves.
+    /// Compartments have to be parameters, and a state with a
+    /// rate rule cannot take part in reactions.
+    pub fn new(model: ExprModel) -> Result<Self, ModelError> {
+        let mut slots: HashMap<&str, usize> = HashMap::new();
+        slots.insert(TIME, 0);
+        let definitions = model
+            .states
+            .iter()
+            .map(|s| &s.name)
+            .chain(model.parameters.iter().map(|p| &p.name))
+            .chain(model.derived.iter().map(|d| &d.name));
+        for (i, name) in definitions.enumerate() {
+            if is_reserved(name) {
+                return Err(ModelError::new(format!(
+                    "`{name}` is reserved"
+                )));
+            }
+            if slots.insert(name, i + 1).is_some() {
+                return Err(ModelError::new(format!(
+                    "`{name}` is defined twice"
+                )));
+            }
+        }
+        let n_states = model.states.len();
+        let n_parameters = model.parameters.len();
+        let state = |name: &str| {
+            slots
+                .get(name)
+                .filter(|&&slot| (1..=n_states).contains(&slot))
+                .map(|slot| slot - 1)
+        };
+        let parameter = |name: &str| {
+            slots
+                .get(name)
+                .map(|slot| slot.wrapping_sub(1 + n_states))
+                .filter(|&i| i < n_parameters)
+        };
+
+        let check = |expr: &Expr, context: &str| match expr
+            .names()
+            .into_iter()
+            .find(|name| *name != PI && !slots.contains_key(name))
+        {
+            Some(name) => Err(ModelError::new(format!(
+                "unknown name `{name}` in {context}"
+            ))),
+            None => Ok(()),
+        };
+        for derived in &model.derived {
+            check(
+                &derived.expression,
+                &format!("derived value `{}`", derived.name),
+            )?;
+        }
+        let compartments = model
+            .states
+            .iter()
+            .map(|s| match &s.compartment {
+                None => Ok(None),
+                Some(compartment) => match parameter(compartment) {
+                    Some(i) => Ok(Some(1 + n_states + i)),
+                    None => Err(ModelError::new(format!(
+                        "compartment `{compartment}` of `{}` has to \
+                         be a parameter",
+                        s.name
+                    ))),
+                },
+            })
+            .collect::<Result<_, _>>()?;
+
+        let mut stoichiometry =
+            Vec::with_capacity(model.reactions.len());
+        for (i, reaction) in model.reactions.iter().enumerate() {
+            if model.reactions[..i]
+                .iter()
+                .any(|other| other.name == reaction.name)
+            {
+                return Err(ModelError::new(format!(
+                    "reaction `{}` is defined twice",
+                    reaction.name
+                )));
+            }
+            check(
+                &reaction.rate,
+                &format!("rate of reaction `{}`", reaction.name),
+            )?;
+            let columns = reaction
+                .stoichiometry
+                .iter()
+                .map(|(name, coefficient)| match state(name) {
+                    Some(i) => Ok((i, *coefficient)),
+                    None => Err(ModelError::new(format!(
+                        "reaction `{}` changes `{name}`, which is \
+                         not a state",
+                        reaction.name
+                    ))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            stoichiometry.push(columns);
+        }
+
+        let derived_start = 1 + n_states + n_parameters;
+        let rate_start = derived_start + model.derived.len();
+        let rule_start = rate_start + model.reactions.len();
+        let mut rate_rules =
+            Vec::with_capacity(model.rate_rules.len());
+        for (i, rule) in model.rate_rules.iter().enumerate() {
+            let name = &rule.name;
+            let Some(target) = state(name) else {
+                return Err(ModelError::new(format!(
+                    "rate rule for `{name}`, which is not a state"
+                )));
+            };
+            if rate_rules.iter().any(|&(other, _)| other == target)
+            {
+                return Err(ModelError::new(format!(
+                    "`{name}` has two rate rules"
+                )));
+            }
+            if let Some(reaction) =
+                model.reactions.iter().find(|r| {
+                    r.stoichiometry
+                        .iter()
+                        .any(|(species, _)| species == name)
+                })
+            {
+                return Err(ModelError::new(format!(
+                    "`{name}` has a rate rule and takes part in \
+                     reaction `{}`",
+                    reaction.name
+                )));
+            }
+            check(
+                &rule.expression,
+                &format!("rate rule of `{name}`"),
+            )?;
+            rate_rules.push((target, rule_start + i));
+        }
+
+        let mut tape = Tape::default();
+        for i in derived_order(&model, &slots, derived_start)? {
+            tape.expr(&model.derived[i].expression, &slots);
+            tape.push(Op::Store(derived_start + i));
+        }
+        for (i, reaction) in model.reactions.iter().enumerate() {
+            tape.expr(&reaction.rate, &slots);
+            tape.push(Op::Store(rate_start + i));
+        }
+        for (i, rule) in model.rate_rules.iter().enumerate() {
+            tape.expr(&rule.expression, &slots);
+            tape.push(Op::Store(rule_start + i));
+        }
+
+        let mut events = Vec::with_capacity(model.events.len());
+        for event in &model.events {
+            let context = format!("event `{}`", event.name);
+            check(&event.trigger, &context)?;
+            let mut trigger = Tape::default();
+            trigger.expr(&event.trigger, &slots);
+            let mut assignments = Tape::default();
+            let mut targets =
+                Vec::with_capacity(event.assignments.len());
+            for assignment in &event.assignments {
+                let name = &assignment.name;
+                let target = match (state(name), parameter(name)) {
+                    (Some(i), _) => Target::State(i),
+                    (_, Some(i)) => Target::Parameter(i),
+                    _ => {
+                        return Err(ModelError::new(format!(
+                            "{context} assigns to `{name}`, which is \
+                             neither a state nor a parameter"
+                        )));
+                    }
+                };
+                check(&assignment.expression, &context)?;
+                assignments.expr(&assignment.expression, &slots);
+                targets.push(target);
+            }
+            events.push(CompiledEvent {
+                trigger,
+                crossing: event.crossing,
+                assignments,
+                targets,
+            });
+        }
+
+        let program = Program {
+            n_states,
+            n_parameters,
+            n_slots: rule_start + model.rate_rules.len(),
+            tape,
+            derived: derived_start..rate_start,
+            rates: rate_start..rule_start,
+            stoichiometry,
+            compartments,
+            rate_rules,
+            events,
+        };
+        Ok(CompiledModel {
+            model,
+            program: Rc::new(program),
+        })
+    }
+
+    pub fn model(&self) -> &ExprModel {
+        &self.model
+    }
+
+    /// Initial values of the states as given in the model
+    pub fn initial_values(&self) -> Vec<f64> {
+        self.model.states.iter().map(|s| s.value).collect()
+    }
+
+    /// Default values of the parameters
+    pub fn default_parameters(&self) -> Vec<f64> {
+        self.model.parameters.iter().map(|p| p.value).collect()
+    }
+
+    /// Derived values in the order of the model
+    pub fn derived_values(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        let slots = self.program.evaluate(time, values, pars)?;
+        Ok(slots[self.program.derived.clone()].to_vec())
+    }
+
+    /// Rate of every reaction
+    pub fn rates(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        let slots = self.program.evaluate(time, values, pars)?;
+        Ok(slots[self.program.rates.clone()].to_vec())
+    }
+
+    /// Events of the model for the solvers
+    ///
+    /// Events without assignments are only recorded.
+    pub fn events(&self) -> Vec<Event> {
+        (0..self.program.events.len())
+            .map(|i| {
+                let program = Rc::clone(&self.program);
+                let condition: EventFunction =
+                    Rc::new(move |t, y, p| {
+                        match program.evaluate(t, y, p) {
+                            Ok(mut slots) => program.events[i]
+                                .trigger
+                                .run(&mut slots)[0],
+                            Err(_) => f64::NAN,
+                        }
+                    });
+                let event = &self.program.events[i];
+                let action = if event.targets.is_empty() {
+                    EventAction::Record
+                } else {
+                    let program = Rc::clone(&self.program);
+                    EventAction::Modify(Rc::new(move |t, y, p| {
+                        let event = &program.events[i];
+                        let Ok(mut slots) =
+                            program.evaluate(t, y, p)
+                        else {
+                            return;
+                        };
+                        let values =
+                            event.assignments.run(&mut slots);
+                        for (target, value) in
+                            event.targets.iter().zip(values)
+                        {
+                            match *target {
+                                Target::State(j) => y[j] = value,
+                                Target::Parameter(j) => {
+                                    p[j] = value
+                                }
+                            }
+                        }
+                    }))
+                };
+                Event {
+                    condition,
+                    crossing: event.crossing,
+                    action,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Rhs for CompiledModel {
+    fn eval(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        let slots = self.program.evaluate(time, values, pars)?;
+        Ok(self.program.derivatives(&slots))
+    }
+}
+
+/// Indices of the derived values so that each comes after the
+/// ones it uses
+fn derived_order(
+    model: &ExprModel,
+    slots: &HashMap<&str, usize>,
+    derived_start: usize,
+) -> Result<Vec<usize>, ModelError> {
+    let n = model.derived.len();
+    let uses: Vec<Vec<usize>> = model
+        .derived
+        .iter()
+        .map(|derived| {
+            derived
+                .expression
+                .names()
+                .into_iter()
+                .filter_map(|name| slots.get(name))
+                .filter(|&&slot| slot >= derived_start)
+                .map(|slot| slot - derived_start)
+                .collect()
+        })
+        .collect();
+
+    // Depth first search, `visiting` marks the current path
+    let mut order = Vec::with_capacity(n);
+    let mut done = vec![false; n];
+    let mut visiting = vec![false; n];
+    fn visit(
+        i: usize,
+        uses: &[Vec<usize>],
+        done: &mut [bool],
+        visiting: &mut [bool],
+        order: &mut Vec<usize>,
+    ) -> Result<(), usize> {
+        if done[i] {
+            return Ok(());
+        }
+        if visiting[i] {
+            return Err(i);
+        }
+        visiting[i] = true;
+        for &j in &uses[i] {
+            visit(j, uses, done, visiting, order)?;
+        }
+        visiting[i] = false;
+        done[i] = true;
+        order.push(i);
+        Ok(())
+    }
+    for i in 0..n {
+        visit(i, &uses, &mut done, &mut visiting, &mut order)
+            .map_err(|j| {
+                ModelError::new(format!(
+                    "derived value `{}` depends on itself",
+                    model.derived[j].name
+                ))
+            })?;
+    }
+    Ok(order)
+}
+
+#[wasm_bindgen]
+impl CompiledModel {
+    /// States, parameters, derived values and reactions with
+    /// the expressions as text
+    #[wasm_bindgen(js_name = info)]
+    pub fn js_info(&self) -> Result<JsValue, JsValue> {
+        serde_wasm_bindgen::to_value(&self.model).map_err(|e| {
+            JsValue::from_str(&format!(
+                "Serialization error: {}",
+                e
+            ))
+        })
+    }
+
+    /// The model as an SBML document with the model id `id`,
+    /// see [`crate::sbml::export_sbml`]
+    #[wasm_bindgen(js_name = toSbml)]
+    pub fn js_to_sbml(&self, id: &str) -> Result<String, JsValue> {
+        Ok(crate::sbml::export_sbml(&self.model, id)?)
+    }
+
+    /// Integrate the model with `options` as in `wa_simulate`
+    ///
+    /// Empty `y0` or `pars` use the values given in the model.
+    #[wasm_bindgen(js_name = simulate)]
+    pub fn js_simulate(
+        &self,
+        y0: Vec<f64>,
+        pars: Vec<f64>,
+        options: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let options: simulate::SimulateOptions =
+            serde_wasm_bindgen::from_value(options).map_err(
+                |e| {
+                    JsValue::from_str(&format!(
+                        "Invalid options: {}",
+                        e
+                    ))
+                },
+            )?;
+        let y0 = if y0.is_empty() {
+            self.initial_values()
+        } else {
+            y0
+        };
+        let pars = if pars.is_empty() {
+            self.default_parameters()
+        } else {
+            pars
+        };
+        let integration = simulate::simulate_rhs(
+            self,
+            y0,
+            pars,
+            options,
+            self.events(),
+        )?;
+
+        serde_wasm_bindgen::to_value(&integration).map_err(|e| {
+            JsValue::from_str(&format!(
+                "Serialization error: {}",
+                e
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::parse_model;
+    use crate::models::lotka_volterra;
+
+    const LOTKA_VOLTERRA: &str = "\
+        state prey = 10\n\
+        state predator = 10\n\
+        parameter alpha = 0.1\n\
+        parameter beta = 0.02\n\
+        parameter gamma = 0.4\n\
+        parameter delta = 0.02\n\
+        derived eaten = prey * predator\n\
+        reaction growth: -> prey; alpha * prey\n\
+        reaction predation: prey -> ; beta * eaten\n\
+        reaction reproduction: -> predator; delta * eaten\n\
+        reaction death: predator -> ; gamma * predator\n";
+
+    fn compile(text: &str) -> Result<CompiledModel, ModelError> {
+        CompiledModel::new(parse_model(text).unwrap())
+    }
+
+    #[test]
+    fn matches_the_built_in_model() {
+        let model = compile(LOTKA_VOLTERRA).unwrap();
+        let pars = model.default_parameters();
+        for y in [[10.0, 10.0], [3.0, 7.5], [0.0, 1.0]] {
+            assert_eq!(
+                model.eval(0.0, &y, &pars).unwrap(),
+                lotka_volterra(0.0, &y, &pars).unwrap()
+            );
+        }
+        assert_eq!(
+            model.derived_values(0.0, &[3.0, 7.5], &pars).unwrap(),
+            vec![22.5]
+        );
+    }
+
+    #[test]
+    fn orders_derived_values_by_dependency() {
+        let model = compile(
+            "state x = 2\n\
+             derived b = a * 3\n\
+             derived a = x + time\n\
+             reaction r: x -> ; b\n",
+        )
+        .unwrap();
+        assert_eq!(
+            model.derived_values(1.0, &[2.0], &[]).unwrap(),
+            vec![9.0, 3.0]
+        );
+        assert_eq!(
+            model.eval(1.0, &[2.0], &[]).unwrap(),
+            vec![-9.0]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        let message = |text| compile(text).err().unwrap().message;
+        assert_eq!(
+            message("state x = 1\nderived y = x + z"),
+            "unknown name `z` in derived value `y`"
+        );
+        assert_eq!(
+            message("state x = 1\nparameter x = 2"),
+            "`x` is defined twice"
+        );
+        assert_eq!(
+            message("parameter exp = 1"),
+            "`exp` is reserved"
+        );
+        assert_eq!(
+            message("parameter k = 1\nreaction r: k -> ; 1"),
+            "reaction `r` changes `k`, which is not a state"
+        );
+        assert_eq!(
+            message("derived a = b\nderived b = 2 * a"),
+            "derived value `a` depends on itself"
+        );
+    }
+
+    #[test]
+    fn checks_vector_lengths() {
+        let model = compile(LOTKA_VOLTERRA).unwrap();
+        assert!(matches!(
+            model.eval(0.0, &[1.0], &model.default_parameters()),
+            Err(SolverError::DimensionMismatch {
+                quantity: "variables",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn applies_compartments_rules_and_events() {
+        let model = compile(
+            "state a = 1 in cell\n\
+             state v = 0\n\
+             parameter cell = 2\n\
+             parameter k = 1\n\
+             reaction decay: a -> ; k * a * cell\n\
+             rate v = 3\n\
+             event refill: a < 0.5; a = 1, k = k / 2\n",
+        )
+        .unwrap();
+        let pars = model.default_parameters();
+        assert_eq!(
+            model.eval(0.0, &[1.0, 0.0], &pars).unwrap(),
+            vec![-1.0, 3.0]
+        );
+
+        let events = model.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            (events[0].condition)(0.0, &[0.25, 0.0], &pars),
+            -0.25
+        );
+        let EventAction::Modify(modify) = &events[0].action else {
+            panic!("expected a modifying event");
+        };
+        let (mut y, mut p) = (vec![0.5, 0.0], pars.clone());
+        modify(1.0, &mut y, &mut p);
+        assert_eq!((y, p), (vec![1.0, 0.0], vec![2.0, 0.5]));
+
+        let message = |text| compile(text).err().unwrap().message;
+        assert_eq!(
+            message("state a = 1 in b\nstate b = 1"),
+            "compartment `b` of `a` has to be a parameter"
+        );
+        assert_eq!(
+            message(
+                "state a = 1\nreaction r: a -> ; 1\nrate a = 1"
+            ),
+            "`a` has a rate rule and takes part in reaction `r`"
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use super::{BinaryOp, Expr, Function};
+
+// Constructors folding the zeros and ones that differentiation
+// produces, so derivatives stay readable
+
+fn number(expr: &Expr) -> Option<f64> {
+    match expr {
+        Expr::Number(value) => Some(*value),
+        _ => None,
+    }
+}
+
+fn binary(op: BinaryOp, a: Expr, b: Expr) -> Expr {
+    Expr::Binary(op, Box::new(a), Box::new(b))
+}
+
+fn add(a: Expr, b: Expr) -> Expr {
+    match (number(&a), number(&b)) {
+        (Some(x), Some(y)) => Expr::Number(x + y),
+        (Some(0.0), _) => b,
+        (_, Some(0.0)) => a,
+        _ => binary(BinaryOp::Add, a, b),
+    }
+}
+
+fn sub(a: Expr, b: Expr) -> Expr {
+    match (number(&a), number(&b)) {
+        (Some(x), Some(y)) => Expr::Number(x - y),
+        (Some(0.0), _) => neg(b),
+        (_, Some(0.0)) => a,
+        _ => binary(BinaryOp::Sub, a, b),
+    }
+}
+
+fn mul(a: Expr, b: Expr) -> Expr {
+    match (number(&a), number(&b)) {
+        (Some(x), Some(y)) => Expr::Number(x * y),
+        (Some(0.0), _) | (_, Some(0.0)) => Expr::Number(0.0),
+        (Some(1.0), _) => b,
+        (_, Some(1.0)) => a,
+        _ => binary(BinaryOp::Mul, a, b),
+    }
+}
+
+fn div(a: Expr, b: Expr) -> Expr {
+    match (number(&a), number(&b)) {
+        (Some(0.0), _) => Expr::Number(0.0),
+        (_, Some(1.0)) => a,
+        _ => binary(BinaryOp::Div, a, b),
+    }
+}
+
+fn neg(x: Expr) -> Expr {
+    match x {
+        Expr::Number(value) => Expr::Number(-value),
+        Expr::Neg(inner) => *inner,
+        x => Expr::Neg(Box::new(x)),
+    }
+}
+
+fn call(function: Function, args: Vec<Expr>) -> Expr {
+    Expr::Call(function, args)
+}
+
+impl Expr {
+    /// Partial derivative with respect to `name`
+    ///
+    /// `min` and `max` are differentiated through the sign of
+    /// the difference of their arguments, which is undefined
+    /// where both are equal.
+    pub fn derivative(&self, name: &str) -> Expr {
+        match self {
+            Expr::Number(_) => Expr::Number(0.0),
+            Expr::Name(other) => {
+                Expr::Number(if other == name { 1.0 } else { 0.0 })
+            }
+            Expr::Neg(x) => neg(x.derivative(name)),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.as_ref(), b.as_ref());
+                let (da, db) =
+                    (a.derivative(name), b.derivative(name));
+                match op {
+                    BinaryOp::Add => add(da, db),
+                    BinaryOp::Sub => sub(da, db),
+                    BinaryOp::Mul => {
+                        add(mul(da, b.clone()), mul(a.clone(), db))
+                    }
+                    BinaryOp::Div => sub(
+                        div(da, b.clone()),
+                        div(
+                            mul(a.clone(), db),
+                            binary(
+                                BinaryOp::Pow,
+                                b.clone(),
+                                Expr::Number(2.0),
+                            ),
+                        ),
+                    ),
+                    // Exponents without `name` avoid the logarithm
+                    // of the base, which fails for bases <= 0
+                    BinaryOp::Pow if !b.names().contains(&name) => {
+                        let power = binary(
+                            BinaryOp::Pow,
+                            a.clone(),
+                            sub(b.clone(), Expr::Number(1.0)),
+                        );
+                        mul(mul(b.clone(), power), da)
+                    }
+                    BinaryOp::Pow => mul(
+                        self.clone(),
+                        add(
+                            mul(
+                                db,
+                                call(Function::Ln, vec![a.clone()]),
+                            ),
+                            div(mul(b.clone(), da), a.clone()),
+                        ),
+                    ),
+                }
+            }
+            Expr::Call(function, args) => {
+                let x = &args[0];
+                let dx = x.derivative(name);
+                let outer = match function {
+                    Function::Exp => self.clone(),
+                    Function::Ln => {
+                        div(Expr::Number(1.0), x.clone())
+                    }
+                    Function::Log10 => div(
+                        Expr::Number(1.0),
+                        mul(
+                            x.clone(),
+                            call(
+                                Function::Ln,
+                                vec![Expr::Number(10.0)],
+                            ),
+                        ),
+                    ),
+                    Function::Sqrt => {
+                        div(Expr::Number(0.5), self.clone())
+                    }
+                    Function::Abs => div(x.clone(), self.clone()),
+                    Function::Sin => {
+                        call(Function::Cos, vec![x.clone()])
+                    }
+                    Function::Cos => {
+                        neg(call(Function::Sin, vec![x.clone()]))
+                    }
+                    Function::Tan => div(
+                        Expr::Number(1.0),
+                        binary(
+                            BinaryOp::Pow,
+                            call(Function::Cos, vec![x.clone()]),
+                            Expr::Number(2.0),
+                        ),
+                    ),
+                    Function::Min | Function::Max => {
+                        let y = &args[1];
+                        let dy = y.derivative(name);
+                        let difference = sub(x.clone(), y.clone());
+                        let sign = div(
+                            difference.clone(),
+                            call(Function::Abs, vec![difference]),
+                        );
+                        let mean = div(
+                            add(dx.clone(), dy.clone()),
+                            Expr::Number(2.0),
+                        );
+                        let spread = mul(
+                            sign,
+                            div(sub(dx, dy), Expr::Number(2.0)),
+                        );
+                        return if *function == Function::Max {
+                            add(mean, spread)
+                        } else {
+                            sub(mean, spread)
+                        };
+                    }
+                };
+                mul(outer, dx)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parse_expr;
+
+    #[test]
+    fn matches_finite_differences() {
+        let at = |text: &str, x: f64| {
+            parse_expr(text)
+                .unwrap()
+                .substitute(&|name| {
+                    (name == "x").then_some(super::Expr::Number(x))
+                })
+                .constant()
+                .unwrap()
+        };
+        for text in [
+            "3 * x^2 - x / (1 + x)",
+            "exp(-x) * ln(x) + log10(x) - sqrt(x)",
+            "x^x + abs(sin(x)) + cos(x) * tan(x)",
+            "max(x, 1) + min(2 * x, 1)",
+            "-x^2.5 / 7",
+        ] {
+            let derivative = parse_expr(text)
+                .unwrap()
+                .derivative("x")
+                .to_string();
+            for x in [0.3, 0.7, 1.9] {
+                let h = 1e-6;
+                let expected =
+                    (at(text, x + h) - at(text, x - h)) / (2.0 * h);
+                let found = at(&derivative, x);
+                assert!(
+                    (expected - found).abs()
+                        < 1e-6 * expected.abs().max(1.0),
+                    "{text} at {x}: {found} != {expected}"
+                );
+            }
+        }
+        assert_eq!(
+            parse_expr("k * s")
+                .unwrap()
+                .derivative("s")
+                .to_string(),
+            "k"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+mod ast;
+mod compile;
+mod derivative;
+mod model;
+mod parser;
+
+use std::fmt;
+
+use serde::Serialize;
+use wasm_bindgen::JsValue;
+
+pub use ast::{BinaryOp, Expr, Function, PI, TIME};
+pub use compile::CompiledModel;
+pub(crate) use compile::Tape;
+pub use model::{
+    Assignment, ExprModel, ModelEvent, Quantity, Reaction, State,
+};
+pub use parser::{parse_expr, parse_model};
+
+/// Why a model could not be parsed or compiled
+///
+/// Syntax errors carry the 1-based position in the text,
+/// errors found while compiling do not.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelError {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl ModelError {
+    pub fn new(message: String) -> Self {
+        ModelError {
+            line: None,
+            column: None,
+            message,
+        }
+    }
+
+    pub fn at(line: usize, column: usize, message: String) -> Self {
+        ModelError {
+            line: Some(line),
+            column: Some(column),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(line), Some(column)) = (self.line, self.column)
+        {
+            write!(f, "line {line}, column {column}: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+impl From<ModelError> for JsValue {
+    fn from(error: ModelError) -> Self {
+        serde_wasm_bindgen::to_value(&error).unwrap_or_else(|_| {
+            JsValue::from_str(&error.to_string())
+        })
+    }
+}
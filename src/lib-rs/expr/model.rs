@@ -0,0 +1,171 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use super::Expr;
+use crate::events::Crossing;
+
+/// Named number, the default of a parameter
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Quantity {
+    pub name: String,
+    pub value: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+}
+
+/// State variable with its initial value
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct State {
+    pub name: String,
+    pub value: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// Parameter holding the size of the compartment, the
+    /// reactions then change the amount and the state is the
+    /// concentration
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compartment: Option<String>,
+}
+
+/// Derived value computed from an expression
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Assignment {
+    pub name: String,
+    pub expression: Expr,
+}
+
+/// Reaction changing the states by `stoichiometry * rate`
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Reaction {
+    pub name: String,
+    /// `(state, coefficient)` pairs, negative for substrates
+    pub stoichiometry: Vec<(String, f64)>,
+    pub rate: Expr,
+}
+
+/// Event changing states or parameters once `trigger`
+/// crosses zero
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ModelEvent {
+    pub name: String,
+    pub trigger: Expr,
+    /// `Rising` for `trigger > 0`, `Falling` for `trigger < 0`
+    pub crossing: Crossing,
+    /// Evaluated with the values at the event, then applied
+    /// all at once
+    pub assignments: Vec<Assignment>,
+}
+
+/// Model defined at runtime, e.g. parsed from text with
+/// [`parse_model`](super::parse_model)
+///
+/// Displays in the text format, one definition per line.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ExprModel {
+    pub states: Vec<State>,
+    pub parameters: Vec<Quantity>,
+    /// Evaluated in dependency order, not necessarily the
+    /// order given here
+    pub derived: Vec<Assignment>,
+    pub reactions: Vec<Reaction>,
+    /// Time derivatives set directly, for states that take no
+    /// part in reactions
+    pub rate_rules: Vec<Assignment>,
+    pub events: Vec<ModelEvent>,
+}
+
+/// Terms of one side of a reaction, each with a leading space
+fn write_side<'a>(
+    f: &mut fmt::Formatter<'_>,
+    terms: impl Iterator<Item = (&'a str, f64)>,
+) -> fmt::Result {
+    for (i, (name, coefficient)) in terms.enumerate() {
+        write!(f, "{}", if i > 0 { " + " } else { " " })?;
+        if coefficient != 1.0 {
+            write!(f, "{coefficient} ")?;
+        }
+        write!(f, "{name}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for ExprModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for state in &self.states {
+            write!(f, "state {} = {}", state.name, state.value)?;
+            if let Some(unit) = &state.unit {
+                write!(f, " [{unit}]")?;
+            }
+            match &state.compartment {
+                Some(compartment) => {
+                    writeln!(f, " in {compartment}")?
+                }
+                None => writeln!(f)?,
+            }
+        }
+        for parameter in &self.parameters {
+            write!(
+                f,
+                "parameter {} = {}",
+                parameter.name, parameter.value
+            )?;
+            match &parameter.unit {
+                Some(unit) => writeln!(f, " [{unit}]")?,
+                None => writeln!(f)?,
+            }
+        }
+        for derived in &self.derived {
+            writeln!(
+                f,
+                "derived {} = {}",
+                derived.name, derived.expression
+            )?;
+        }
+        for reaction in &self.reactions {
+            let terms = |sign: f64| {
+                reaction
+                    .stoichiometry
+                    .iter()
+                    .filter(move |(_, c)| c * sign > 0.0)
+                    .map(move |(name, c)| (name.as_str(), c * sign))
+            };
+            write!(f, "reaction {}:", reaction.name)?;
+            write_side(f, terms(-1.0))?;
+            write!(f, " ->")?;
+            write_side(f, terms(1.0))?;
+            writeln!(f, "; {}", reaction.rate)?;
+        }
+        for rule in &self.rate_rules {
+            writeln!(
+                f,
+                "rate {} = {}",
+                rule.name, rule.expression
+            )?;
+        }
+        for event in &self.events {
+            let comparison = match event.crossing {
+                Crossing::Rising => ">",
+                Crossing::Falling => "<",
+                Crossing::Any => "!=",
+            };
+            write!(
+                f,
+                "event {}: {} {comparison} 0;",
+                event.name, event.trigger
+            )?;
+            for (i, assignment) in
+                event.assignments.iter().enumerate()
+            {
+                let separator = if i > 0 { "," } else { "" };
+                write!(
+                    f,
+                    "{separator} {} = {}",
+                    assignment.name, assignment.expression
+                )?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
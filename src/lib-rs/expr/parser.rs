@@ -0,0 +1,636 @@
+use super::{
+    Assignment, BinaryOp, Expr, ExprModel, Function, ModelError,
+    ModelEvent, Quantity, Reaction, State,
+};
+use crate::events::Crossing;
+use crate::kinetics::kinetic_law;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Symbol(&'static str),
+    /// Text between `[` and `]`
+    Unit(String),
+}
+
+// Longer symbols first, so `->` is not read as `-`
+const SYMBOLS: &[&str] = &[
+    "->", "!=", ">=", "<=", "+", "-", "*", "/", "^", "(", ")", ",",
+    "=", ":", ";", ">", "<",
+];
+
+/// Split one line into tokens with their 1-based columns
+fn tokenize(
+    text: &str,
+    line: usize,
+) -> Result<Vec<(Token, usize)>, ModelError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_ascii_digit() || chars[i] == '.')
+            {
+                i += 1;
+            }
+            // Exponent only if digits follow, `2e` is not a number
+            if i < chars.len() && matches!(chars[i], 'e' | 'E') {
+                let sign =
+                    matches!(chars.get(i + 1), Some('+' | '-'));
+                let digit = i + 1 + usize::from(sign);
+                if chars
+                    .get(digit)
+                    .is_some_and(char::is_ascii_digit)
+                {
+                    i = digit;
+                    while i < chars.len()
+                        && chars[i].is_ascii_digit()
+                    {
+                        i += 1;
+                    }
+                }
+            }
+            let literal: String = chars[start..i].iter().collect();
+            let value = literal.parse().map_err(|_| {
+                ModelError::at(
+                    line,
+                    column,
+                    format!("invalid number `{literal}`"),
+                )
+            })?;
+            tokens.push((Token::Number(value), column));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_')
+            {
+                i += 1;
+            }
+            let name = chars[start..i].iter().collect();
+            tokens.push((Token::Name(name), column));
+        } else if c == '[' {
+            let end = chars[i..]
+                .iter()
+                .position(|&c| c == ']')
+                .ok_or_else(|| {
+                    ModelError::at(
+                        line,
+                        column,
+                        "unit without closing `]`".to_string(),
+                    )
+                })?;
+            let unit: String =
+                chars[i + 1..i + end].iter().collect();
+            tokens.push((
+                Token::Unit(unit.trim().to_string()),
+                column,
+            ));
+            i += end + 1;
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| {
+                    symbol
+                        .chars()
+                        .enumerate()
+                        .all(|(k, s)| chars.get(i + k) == Some(&s))
+                })
+                .ok_or_else(|| {
+                    ModelError::at(
+                        line,
+                        column,
+                        format!("unexpected character `{c}`"),
+                    )
+                })?;
+            i += symbol.len();
+            tokens.push((Token::Symbol(symbol), column));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    line: usize,
+    /// Column right after the last character, for errors at
+    /// the end of the line
+    end: usize,
+}
+
+impl Parser {
+    fn new(text: &str, line: usize) -> Result<Self, ModelError> {
+        Ok(Parser {
+            tokens: tokenize(text, line)?,
+            position: 0,
+            line,
+            end: text.chars().count() + 1,
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn column(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.end, |&(_, column)| column)
+    }
+
+    fn error(&self, message: String) -> ModelError {
+        ModelError::at(self.line, self.column(), message)
+    }
+
+    /// Error for the current token not being `expected`
+    fn unexpected(&self, expected: &str) -> ModelError {
+        let found = match self.peek() {
+            None => "end of line".to_string(),
+            Some(Token::Number(value)) => format!("`{value}`"),
+            Some(Token::Name(name)) => format!("`{name}`"),
+            Some(Token::Symbol(symbol)) => format!("`{symbol}`"),
+            Some(Token::Unit(unit)) => format!("`[{unit}]`"),
+        };
+        self.error(format!("expected {expected}, found {found}"))
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol)
+        {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), ModelError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{symbol}`")))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, ModelError> {
+        match self.peek() {
+            Some(Token::Name(name)) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected("a name")),
+        }
+    }
+
+    fn finish(&self) -> Result<(), ModelError> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.unexpected("end of line")),
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, ModelError> {
+        let mut lhs = self.product()?;
+        loop {
+            let op = if self.eat("+") {
+                BinaryOp::Add
+            } else if self.eat("-") {
+                BinaryOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.product()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, ModelError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat("*") {
+                BinaryOp::Mul
+            } else if self.eat("/") {
+                BinaryOp::Div
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.unary()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    /// Unary minus binds weaker than `^`, so `-a^2` is `-(a^2)`
+    fn unary(&mut self) -> Result<Expr, ModelError> {
+        if self.eat("-") {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else if self.eat("+") {
+            self.unary()
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<Expr, ModelError> {
+        let base = self.primary()?;
+        if self.eat("^") {
+            let exponent = self.unary()?;
+            Ok(Expr::Binary(
+                BinaryOp::Pow,
+                Box::new(base),
+                Box::new(exponent),
+            ))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, ModelError> {
+        match self.peek().cloned() {
+            Some(Token::Number(value)) => {
+                self.position += 1;
+                Ok(Expr::Number(value))
+            }
+            Some(Token::Name(name)) => {
+                let column = self.column();
+                self.position += 1;
+                if !self.eat("(") {
+                    return Ok(Expr::Name(name));
+                }
+                let mut args = vec![self.expression()?];
+                while self.eat(",") {
+                    args.push(self.expression()?);
+                }
+                self.expect(")")?;
+                let function = Function::from_name(&name);
+                let law = kinetic_law(&name);
+                let arity = match (function, law) {
+                    (Some(function), _) => function.arity(),
+                    (None, Some(law)) => law.arguments.len(),
+                    (None, None) => {
+                        return Err(ModelError::at(
+                            self.line,
+                            column,
+                            format!("unknown function `{name}`"),
+                        ));
+                    }
+                };
+                if args.len() != arity {
+                    return Err(ModelError::at(
+                        self.line,
+                        column,
+                        format!(
+                            "`{name}` takes {arity} argument(s), got {}",
+                            args.len()
+                        ),
+                    ));
+                }
+                Ok(match (function, law) {
+                    (Some(function), _) => {
+                        Expr::Call(function, args)
+                    }
+                    // Rate laws of the library are inlined
+                    (None, law) => {
+                        law.expect("checked above").call(&args)
+                    }
+                })
+            }
+            Some(Token::Symbol("(")) => {
+                self.position += 1;
+                let inner = self.expression()?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            _ => Err(self.unexpected("an expression")),
+        }
+    }
+
+    /// `name = constant expression [unit]`
+    fn quantity(&mut self) -> Result<Quantity, ModelError> {
+        let name = self.name()?;
+        self.expect("=")?;
+        let column = self.column();
+        let value =
+            self.expression()?.constant().ok_or_else(|| {
+                ModelError::at(
+                    self.line,
+                    column,
+                    format!(
+                        "value of `{name}` has to be a constant"
+                    ),
+                )
+            })?;
+        let unit = match self.peek() {
+            Some(Token::Unit(unit)) => {
+                let unit = unit.clone();
+                self.position += 1;
+                Some(unit)
+            }
+            _ => None,
+        };
+        Ok(Quantity { name, value, unit })
+    }
+
+    /// `name = constant expression [unit] [in compartment]`
+    fn state(&mut self) -> Result<State, ModelError> {
+        let Quantity { name, value, unit } = self.quantity()?;
+        let compartment = if matches!(self.peek(), Some(Token::Name(n)) if n == "in")
+        {
+            self.position += 1;
+            Some(self.name()?)
+        } else {
+            None
+        };
+        Ok(State {
+            name,
+            value,
+            unit,
+            compartment,
+        })
+    }
+
+    /// `name = expression`
+    fn assignment(&mut self) -> Result<Assignment, ModelError> {
+        let name = self.name()?;
+        self.expect("=")?;
+        let expression = self.expression()?;
+        Ok(Assignment { name, expression })
+    }
+
+    /// `name: lhs > rhs; target = expression, ...`
+    fn event(&mut self) -> Result<ModelEvent, ModelError> {
+        let name = self.name()?;
+        self.expect(":")?;
+        let lhs = self.expression()?;
+        let crossing = if self.eat(">") || self.eat(">=") {
+            Crossing::Rising
+        } else if self.eat("<") || self.eat("<=") {
+            Crossing::Falling
+        } else if self.eat("!=") {
+            Crossing::Any
+        } else {
+            return Err(self.unexpected("`>`, `<` or `!=`"));
+        };
+        let rhs = self.expression()?;
+        let trigger = match rhs.constant() {
+            Some(0.0) => lhs,
+            _ => Expr::Binary(
+                BinaryOp::Sub,
+                Box::new(lhs),
+                Box::new(rhs),
+            ),
+        };
+        self.expect(";")?;
+        let mut assignments = vec![];
+        if self.peek().is_some() {
+            assignments.push(self.assignment()?);
+            while self.eat(",") {
+                assignments.push(self.assignment()?);
+            }
+        }
+        Ok(ModelEvent {
+            name,
+            trigger,
+            crossing,
+            assignments,
+        })
+    }
+
+    /// `[coefficient] name + ...` up to `->` or `;`, with the
+    /// coefficients multiplied by `sign`
+    fn side(
+        &mut self,
+        sign: f64,
+        stoichiometry: &mut Vec<(String, f64)>,
+    ) -> Result<(), ModelError> {
+        if matches!(self.peek(), Some(Token::Symbol("->" | ";"))) {
+            return Ok(());
+        }
+        loop {
+            let coefficient = match self.peek() {
+                Some(&Token::Number(value)) => {
+                    self.position += 1;
+                    value
+                }
+                _ => 1.0,
+            };
+            stoichiometry.push((self.name()?, sign * coefficient));
+            if !self.eat("+") {
+                return Ok(());
+            }
+        }
+    }
+
+    /// `name: substrates -> products; rate`
+    fn reaction(&mut self) -> Result<Reaction, ModelError> {
+        let name = self.name()?;
+        self.expect(":")?;
+        let mut stoichiometry = vec![];
+        self.side(-1.0, &mut stoichiometry)?;
+        self.expect("->")?;
+        self.side(1.0, &mut stoichiometry)?;
+        self.expect(";")?;
+        let rate = self.expression()?;
+        Ok(Reaction {
+            name,
+            stoichiometry,
+            rate,
+        })
+    }
+}
+
+/// Parse a single expression, e.g. `vmax * s / (km + s)`
+pub fn parse_expr(text: &str) -> Result<Expr, ModelError> {
+    let mut parser = Parser::new(text, 1)?;
+    let expr = parser.expression()?;
+    parser.finish()?;
+    Ok(expr)
+}
+
+/// Parse a model from text with one definition per line
+///
+/// ```text
+/// # Lotka-Volterra
+/// state prey = 10
+/// state predator = 10
+/// parameter alpha = 0.1
+/// derived eaten = beta * prey * predator
+/// reaction growth: -> prey; alpha * prey
+/// reaction predation: prey -> ; eaten
+/// ```
+///
+/// States and parameters take constant values. Derived values
+/// and reaction rates may use states, parameters, other
+/// derived values, `time` and `pi`. Substrates and products of
+/// a reaction can have a coefficient, e.g. `2 a + b -> c`.
+/// A state `in` a compartment is a concentration, see
+/// [`State`]. A unit in brackets may follow the value of a
+/// state or parameter, e.g. `parameter k = 0.1 [1/s]`.
+/// `rate x = expression` sets the derivative of a
+/// state directly, and `event refill: x < 0.5; x = 1, k = 2 * k`
+/// changes values whenever the comparison becomes true.
+/// Rate laws of [`crate::kinetics`] are called like functions,
+/// e.g. `michaelis_menten(s, vmax, km)`, and inlined.
+/// Everything after `#` is a comment. Names are only checked
+/// once the model is compiled.
+pub fn parse_model(text: &str) -> Result<ExprModel, ModelError> {
+    let mut model = ExprModel::default();
+    for (i, line) in text.lines().enumerate() {
+        let content = line.split('#').next().unwrap_or_default();
+        let mut parser = Parser::new(content, i + 1)?;
+        if parser.peek().is_none() {
+            continue;
+        }
+        let keyword_error = parser.unexpected(
+            "`state`, `parameter`, `derived`, `reaction`, `rate` or \
+             `event`",
+        );
+        match parser.name().as_deref() {
+            Ok("state") => model.states.push(parser.state()?),
+            Ok("parameter") => {
+                model.parameters.push(parser.quantity()?)
+            }
+            Ok("derived") => {
+                model.derived.push(parser.assignment()?)
+            }
+            Ok("reaction") => {
+                model.reactions.push(parser.reaction()?)
+            }
+            Ok("rate") => {
+                model.rate_rules.push(parser.assignment()?)
+            }
+            Ok("event") => model.events.push(parser.event()?),
+            _ => return Err(keyword_error),
+        }
+        parser.finish()?;
+    }
+    Ok(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn respects_precedence_and_round_trips() {
+        for (text, display) in [
+            ("1 + 2 * 3", "1 + 2 * 3"),
+            ("(1 + 2) * 3", "(1 + 2) * 3"),
+            ("a - (b - c)", "a - (b - c)"),
+            ("a / b / c", "a / b / c"),
+            ("-a^2", "-a ^ 2"),
+            ("(-a)^2", "(-a) ^ 2"),
+            ("2^3^2", "2 ^ 3 ^ 2"),
+            ("(2^3)^2", "(2 ^ 3) ^ 2"),
+            (
+                "max(x, 1e-3) * exp(-k*time)",
+                "max(x, 0.001) * exp(-k * time)",
+            ),
+        ] {
+            let expr = parse_expr(text).unwrap();
+            assert_eq!(expr.to_string(), display);
+            assert_eq!(parse_expr(display).unwrap(), expr);
+        }
+        assert_eq!(
+            parse_expr("-2^2").unwrap().constant(),
+            Some(-4.0)
+        );
+        assert_eq!(
+            parse_expr("2^3^2").unwrap().constant(),
+            Some(512.0)
+        );
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        let error = parse_expr("a * (b + ").unwrap_err();
+        assert_eq!((error.line, error.column), (Some(1), Some(10)));
+        assert_eq!(
+            error.message,
+            "expected an expression, found end of line"
+        );
+
+        let error = parse_model("state a = 1\n\nstate b = a + 1\n")
+            .unwrap_err();
+        assert_eq!((error.line, error.column), (Some(3), Some(11)));
+
+        let error = parse_model("derived x = foo(1)").unwrap_err();
+        assert_eq!(error.message, "unknown function `foo`");
+
+        let error =
+            parse_model("derived x = hill(1, 2)").unwrap_err();
+        assert_eq!(
+            error.message,
+            "`hill` takes 4 argument(s), got 2"
+        );
+
+        let error =
+            parse_model("parameter k = 1 [1/s").unwrap_err();
+        assert_eq!(error.message, "unit without closing `]`");
+
+        let error = parse_model("species a = 1").unwrap_err();
+        assert_eq!((error.line, error.column), (Some(1), Some(1)));
+    }
+
+    #[test]
+    fn parses_reactions() {
+        let model = parse_model(
+            "# comment\n\
+             state a = 1 [mmol] # initial\n\
+             parameter k = 2 [1/(mmol s)]\n\
+             reaction dimerise: 2 a -> b; k * a^2\n\
+             reaction inflow: -> a; 1\n\
+             reaction uptake: a -> ; michaelis_menten(a, k, 0.5)\n",
+        )
+        .unwrap();
+        assert_eq!(model.states[0].value, 1.0);
+        assert_eq!(model.states[0].unit.as_deref(), Some("mmol"));
+        assert_eq!(
+            model.parameters[0].unit.as_deref(),
+            Some("1/(mmol s)")
+        );
+        let dimerise = &model.reactions[0];
+        assert_eq!(
+            dimerise.stoichiometry,
+            vec![("a".to_string(), -2.0), ("b".to_string(), 1.0)]
+        );
+        assert_eq!(model.reactions[1].stoichiometry.len(), 1);
+        // Rate laws of the library are inlined
+        assert_eq!(
+            model.reactions[2].rate.to_string(),
+            "k * a / (0.5 + a)"
+        );
+        assert_eq!(parse_model(&model.to_string()).unwrap(), model);
+    }
+
+    #[test]
+    fn parses_rules_and_events() {
+        let model = parse_model(
+            "state x = 1 in cell\n\
+             state v = 0\n\
+             parameter cell = 2\n\
+             rate v = -x\n\
+             event refill: x <= 0.5; x = 1, cell = 2 * cell\n\
+             event late: time > 10;\n",
+        )
+        .unwrap();
+        assert_eq!(
+            model.states[0].compartment.as_deref(),
+            Some("cell")
+        );
+        assert_eq!(model.rate_rules[0].name, "v");
+        let refill = &model.events[0];
+        assert_eq!(refill.crossing, Crossing::Falling);
+        assert_eq!(refill.trigger.to_string(), "x - 0.5");
+        assert_eq!(refill.assignments.len(), 2);
+        assert!(model.events[1].assignments.is_empty());
+        assert_eq!(parse_model(&model.to_string()).unwrap(), model);
+    }
+}
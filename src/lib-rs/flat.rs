@@ -0,0 +1,204 @@
+use js_sys::Float64Array;
+use wasm_bindgen::prelude::*;
+
+use crate::Integration;
+
+/// Memory order of the flat `values` array
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// All variables of the first time point, then the second…
+    RowMajor,
+    /// The whole trajectory of the first variable, then the
+    /// second…
+    ColumnMajor,
+}
+
+/// Integration result kept in WASM memory as flat vectors
+///
+/// The `*_view` methods return `Float64Array`s pointing
+/// directly into WASM memory. They are only valid until the
+/// next allocation in WASM, which may grow the memory, so copy
+/// them or use them right away. The other accessors copy.
+#[wasm_bindgen]
+pub struct FlatIntegration {
+    time: Vec<f64>,
+    values: Vec<f64>,
+    n_variables: usize,
+    layout: Layout,
+    integration: Integration,
+}
+
+impl FlatIntegration {
+    pub fn new(integration: Integration, layout: Layout) -> Self {
+        let n_times = integration.time.len();
+        let n_variables =
+            integration.values.first().map_or(0, Vec::len);
+        let mut values = Vec::with_capacity(n_times * n_variables);
+        match layout {
+            Layout::RowMajor => {
+                for row in &integration.values {
+                    values.extend_from_slice(row);
+                }
+            }
+            Layout::ColumnMajor => {
+                for j in 0..n_variables {
+                    values.extend(
+                        integration.values.iter().map(|row| row[j]),
+                    );
+                }
+            }
+        }
+        let Integration {
+            time,
+            status,
+            stats,
+            steps,
+            events,
+            ..
+        } = integration;
+        FlatIntegration {
+            time,
+            values,
+            n_variables,
+            layout,
+            // The nested values are not needed anymore
+            integration: Integration {
+                time: vec![],
+                values: vec![],
+                status,
+                stats,
+                steps,
+                events,
+            },
+        }
+    }
+
+    /// Trajectory of variable `j`
+    pub fn variable(&self, j: usize) -> Vec<f64> {
+        let n_times = self.time.len();
+        match self.layout {
+            Layout::RowMajor => self
+                .values
+                .iter()
+                .skip(j)
+                .step_by(self.n_variables)
+                .copied()
+                .collect(),
+            Layout::ColumnMajor => {
+                self.values[j * n_times..(j + 1) * n_times].to_vec()
+            }
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl FlatIntegration {
+    #[wasm_bindgen(getter, js_name = nTimes)]
+    pub fn n_times(&self) -> usize {
+        self.time.len()
+    }
+
+    #[wasm_bindgen(getter, js_name = nVariables)]
+    pub fn n_variables(&self) -> usize {
+        self.n_variables
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Copy of the time points
+    pub fn time(&self) -> Vec<f64> {
+        self.time.clone()
+    }
+
+    /// Copy of all values in `layout` order
+    pub fn values(&self) -> Vec<f64> {
+        self.values.clone()
+    }
+
+    /// Copy of the trajectory of variable `j`
+    #[wasm_bindgen(js_name = column)]
+    pub fn js_column(&self, j: usize) -> Result<Vec<f64>, JsValue> {
+        if j >= self.n_variables {
+            return Err(JsValue::from_str(&format!(
+                "Variable {j} out of range"
+            )));
+        }
+        Ok(self.variable(j))
+    }
+
+    /// View of the time points without copying
+    #[wasm_bindgen(js_name = timeView)]
+    pub fn time_view(&self) -> Float64Array {
+        // SAFETY: the view is handed to JS right away, see the
+        // type documentation for how long it stays valid
+        unsafe { Float64Array::view(&self.time) }
+    }
+
+    /// View of all values in `layout` order without copying
+    #[wasm_bindgen(js_name = valuesView)]
+    pub fn values_view(&self) -> Float64Array {
+        // SAFETY: as in `time_view`
+        unsafe { Float64Array::view(&self.values) }
+    }
+
+    /// Return code, statistics, raw steps and events, i.e.
+    /// everything of the integration except `time` and `values`
+    pub fn info(&self) -> Result<JsValue, JsValue> {
+        serde_wasm_bindgen::to_value(&self.integration).map_err(
+            |e| {
+                JsValue::from_str(&format!(
+                    "Serialization error: {}",
+                    e
+                ))
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReturnCode;
+
+    fn integration() -> Integration {
+        Integration {
+            time: vec![0.0, 1.0, 2.0],
+            values: vec![
+                vec![1.0, 10.0],
+                vec![2.0, 20.0],
+                vec![3.0, 30.0],
+            ],
+            status: ReturnCode::Success,
+            stats: Default::default(),
+            steps: None,
+            events: vec![],
+        }
+    }
+
+    #[test]
+    fn flattens_in_both_layouts() {
+        let rows =
+            FlatIntegration::new(integration(), Layout::RowMajor);
+        assert_eq!(
+            rows.values,
+            vec![1.0, 10.0, 2.0, 20.0, 3.0, 30.0]
+        );
+        let columns = FlatIntegration::new(
+            integration(),
+            Layout::ColumnMajor,
+        );
+        assert_eq!(
+            columns.values,
+            vec![1.0, 2.0, 3.0, 10.0, 20.0, 30.0]
+        );
+        for flat in [rows, columns] {
+            assert_eq!(flat.n_times(), 3);
+            assert_eq!(flat.n_variables(), 2);
+            assert_eq!(flat.variable(1), vec![10.0, 20.0, 30.0]);
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{Rhs, SolverError, Statistics};
+
+/// Pivots below this count as zero, as in `utils::Lu`
+const SINGULAR: f64 = 1e-12;
+
+/// Square matrix whose entries vanish more than `lower` below
+/// and `upper` above the diagonal, e.g. the Jacobian of a
+/// chain of compartments
+#[derive(Clone, Debug, PartialEq)]
+pub struct BandedMatrix {
+    lower: usize,
+    upper: usize,
+    /// Entries from column `i - lower` to `i + upper` of every
+    /// row `i`, zero outside the matrix
+    rows: Vec<Vec<f64>>,
+}
+
+impl BandedMatrix {
+    /// Zero `n × n` matrix with the given bandwidths
+    pub fn zeros(n: usize, lower: usize, upper: usize) -> Self {
+        BandedMatrix {
+            lower,
+            upper,
+            rows: vec![vec![0.0; lower + upper + 1]; n],
+        }
+    }
+
+    /// Band of `dense`, entries outside of it are dropped
+    pub fn from_dense(
+        dense: &[Vec<f64>],
+        lower: usize,
+        upper: usize,
+    ) -> Self {
+        let mut matrix = Self::zeros(dense.len(), lower, upper);
+        for (i, row) in dense.iter().enumerate() {
+            for j in matrix.columns(i) {
+                matrix.rows[i][j + lower - i] = row[j];
+            }
+        }
+        matrix
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Columns of row `i` inside the band
+    fn columns(&self, i: usize) -> std::ops::Range<usize> {
+        i.saturating_sub(self.lower)
+            ..(i + self.upper + 1).min(self.len())
+    }
+
+    /// Entry `(i, j)`, zero outside the band
+    pub fn get(&self, i: usize, j: usize) -> f64 {
+        (j + self.lower)
+            .checked_sub(i)
+            .and_then(|offset| self.rows[i].get(offset))
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    /// `I + scale · self`, the Newton matrix of a stage
+    pub fn shifted(&self, scale: f64) -> Self {
+        let mut matrix = self.clone();
+        for row in &mut matrix.rows {
+            for x in row.iter_mut() {
+                *x *= scale;
+            }
+            row[self.lower] += 1.0;
+        }
+        matrix
+    }
+
+    /// LU factorisation with partial pivoting, `None` if the
+    /// matrix is singular
+    ///
+    /// Row exchanges widen the upper band of `U` to
+    /// `lower + upper`, `L` keeps the lower band.
+    pub fn lu(&self) -> Option<BandedLu> {
+        let n = self.len();
+        let mut upper: Vec<Segment> = (0..n)
+            .map(|i| {
+                let columns = self.columns(i);
+                Segment {
+                    start: columns.start,
+                    values: columns
+                        .map(|j| self.get(i, j))
+                        .collect(),
+                }
+            })
+            .collect();
+        let mut lower: Vec<Vec<(usize, f64)>> = vec![vec![]; n];
+        let mut permutation: Vec<usize> = (0..n).collect();
+        for k in 0..n {
+            let last = (k + self.lower).min(n - 1);
+            let pivot_row = (k..=last).max_by(|&a, &b| {
+                upper[a]
+                    .get(k)
+                    .abs()
+                    .total_cmp(&upper[b].get(k).abs())
+            })?;
+            let pivot = upper[pivot_row].get(k);
+            if pivot.abs() < SINGULAR || pivot.is_nan() {
+                return None;
+            }
+            upper.swap(k, pivot_row);
+            lower.swap(k, pivot_row);
+            permutation.swap(k, pivot_row);
+            let (done, rest) = upper.split_at_mut(k + 1);
+            let pivot_segment = &done[k];
+            for (r, row) in rest[..last - k].iter_mut().enumerate()
+            {
+                let factor = row.get(k) / pivot;
+                if factor == 0.0 {
+                    continue;
+                }
+                lower[k + 1 + r].push((k, factor));
+                row.extend_to(pivot_segment.end());
+                for j in k..pivot_segment.end() {
+                    *row.get_mut(j) -=
+                        factor * pivot_segment.get(j);
+                }
+            }
+        }
+        Some(BandedLu {
+            lower,
+            upper,
+            permutation,
+        })
+    }
+}
+
+/// Entries of a row from column `start` on
+#[derive(Clone, Debug)]
+struct Segment {
+    start: usize,
+    values: Vec<f64>,
+}
+
+impl Segment {
+    fn end(&self) -> usize {
+        self.start + self.values.len()
+    }
+
+    fn get(&self, j: usize) -> f64 {
+        j.checked_sub(self.start)
+            .and_then(|offset| self.values.get(offset))
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    fn get_mut(&mut self, j: usize) -> &mut f64 {
+        &mut self.values[j - self.start]
+    }
+
+    fn extend_to(&mut self, end: usize) {
+        if end > self.end() {
+            self.values.resize(end - self.start, 0.0);
+        }
+    }
+}
+
+/// `P·A = L·U` of a [`BandedMatrix`], reusable for several
+/// right-hand sides
+#[derive(Clone, Debug)]
+pub struct BandedLu {
+    /// Entries below the unit diagonal of `L`, by row
+    lower: Vec<Vec<(usize, f64)>>,
+    /// Rows of `U`, zero left of the diagonal
+    upper: Vec<Segment>,
+    /// Row of `A` that ended up in each row
+    permutation: Vec<usize>,
+}
+
+impl BandedLu {
+    /// Solution of `A·x = b`
+    pub fn solve(&self, b: &[f64]) -> Vec<f64> {
+        let mut x: Vec<f64> =
+            self.permutation.iter().map(|&i| b[i]).collect();
+        for i in 0..x.len() {
+            let sum: f64 =
+                self.lower[i].iter().map(|&(k, l)| l * x[k]).sum();
+            x[i] -= sum;
+        }
+        for i in (0..x.len()).rev() {
+            let row = &self.upper[i];
+            let sum: f64 =
+                (i + 1..row.end()).map(|j| row.get(j) * x[j]).sum();
+            x[i] = (x[i] - sum) / row.get(i);
+        }
+        x
+    }
+}
+
+/// Finite difference Jacobian of a banded model with
+/// `lower + upper + 1` evaluations
+///
+/// Columns `lower + upper + 1` apart share no row of the band,
+/// so they are perturbed together.
+pub fn banded_jacobian(
+    model: &dyn Rhs,
+    t: f64,
+    y: &[f64],
+    pars: &[f64],
+    (lower, upper): (usize, usize),
+    eps: f64,
+    stats: &mut Statistics,
+) -> Result<BandedMatrix, SolverError> {
+    let n = y.len();
+    let width = (lower + upper + 1).min(n);
+    stats.jacobian_evaluations += 1;
+    stats.rhs_evaluations += width + 1;
+    let f0 = model.eval(t, y, pars)?;
+    let steps: Vec<f64> =
+        y.iter().map(|yj| eps * yj.abs().max(1.0)).collect();
+    let perturbed: Vec<Vec<f64>> = (0..width)
+        .map(|group| {
+            let mut y_perturbed = y.to_vec();
+            for j in (group..n).step_by(width) {
+                y_perturbed[j] += steps[j];
+            }
+            y_perturbed
+        })
+        .collect();
+    let f1 = model.eval_batch(t, &perturbed, pars)?;
+    if f1.len() != width {
+        return Err(SolverError::DimensionMismatch {
+            t,
+            quantity: "batch results",
+            expected: width,
+            found: f1.len(),
+        });
+    }
+
+    let mut jac = BandedMatrix::zeros(n, lower, upper);
+    for (i, row) in jac.rows.iter_mut().enumerate() {
+        for j in i.saturating_sub(lower)..(i + upper + 1).min(n) {
+            let f1 = &f1[j % width];
+            row[j + lower - i] = (f1[i] - f0[i]) / steps[j];
+        }
+    }
+    Ok(jac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::implicit::utils::{Lu, approx_jacobian};
+
+    /// Chain of compartments exchanging by diffusion, with an
+    /// inflow into the first one from the second next
+    fn chain(
+        _t: f64,
+        y: &[f64],
+        _p: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        let n = y.len();
+        Ok((0..n)
+            .map(|i| {
+                let left =
+                    if i > 0 { y[i - 1] - y[i] } else { 0.0 };
+                let right =
+                    if i + 1 < n { y[i + 1] - y[i] } else { 0.0 };
+                let skip =
+                    if i + 2 < n { y[i + 2] * y[i] } else { 0.0 };
+                left + 2.0 * right + skip
+            })
+            .collect())
+    }
+
+    #[test]
+    fn matches_the_dense_jacobian() {
+        let y: Vec<f64> =
+            (0..13).map(|i| 1.0 + 0.1 * i as f64).collect();
+        let mut stats = Statistics::default();
+        let banded = banded_jacobian(
+            &chain,
+            0.0,
+            &y,
+            &[],
+            (1, 2),
+            1e-8,
+            &mut stats,
+        )
+        .unwrap();
+        assert_eq!(stats.rhs_evaluations, 5);
+        let dense =
+            approx_jacobian(&chain, 0.0, &y, &[], 1e-8, &mut stats)
+                .unwrap();
+        for (i, row) in dense.iter().enumerate() {
+            for (j, x) in row.iter().enumerate() {
+                assert!(
+                    (banded.get(i, j) - x).abs() < 1e-6,
+                    "J[{i}][{j}] = {} != {x}",
+                    banded.get(i, j)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn solves_with_the_banded_factorisation() {
+        // Small diagonal, so that the rows need to be exchanged
+        let dense: Vec<Vec<f64>> = (0..7)
+            .map(|i| {
+                (0..7)
+                    .map(|j| match j as i64 - i as i64 {
+                        0 => 0.01 * (i + 1) as f64,
+                        -2 => 3.0 + i as f64,
+                        -1 | 1 => 1.0 - 0.2 * j as f64,
+                        _ => 0.0,
+                    })
+                    .collect()
+            })
+            .collect();
+        let b: Vec<f64> = (0..7).map(|i| i as f64 - 2.5).collect();
+        let banded = BandedMatrix::from_dense(&dense, 2, 1);
+        let found = banded.lu().unwrap().solve(&b);
+        let expected = Lu::factor(&dense).unwrap().solve(&b);
+        for (x, e) in found.iter().zip(&expected) {
+            assert!((x - e).abs() < 1e-10, "{x} != {e}");
+        }
+        assert!(
+            (banded.shifted(2.0).get(3, 3) - 1.08).abs() < 1e-15
+        );
+        assert_eq!(banded.get(0, 6), 0.0);
+        assert!(BandedMatrix::zeros(3, 1, 1).lu().is_none());
+    }
+}
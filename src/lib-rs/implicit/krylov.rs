@@ -0,0 +1,251 @@
+use std::rc::Rc;
+
+use serde::Deserialize;
+
+use crate::{Rhs, SolverError, Statistics};
+
+/// Approximate solution `z` of `(I - scale · J) · z = r` from
+/// time, state, parameters, `scale` and `r`, which speeds up
+/// the convergence of GMRES
+pub type Preconditioner =
+    Rc<dyn Fn(f64, &[f64], &[f64], f64, &[f64]) -> Vec<f64>>;
+
+/// Settings of the restarted GMRES iteration
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct KrylovOptions {
+    /// Size of the Krylov subspace before a restart
+    pub max_dimension: usize,
+    pub max_restarts: usize,
+    /// Residual norm relative to the right-hand side at which
+    /// the linear iteration stops
+    pub tolerance: f64,
+}
+
+impl Default for KrylovOptions {
+    fn default() -> Self {
+        KrylovOptions {
+            max_dimension: 30,
+            max_restarts: 2,
+            tolerance: 1e-4,
+        }
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn norm(a: &[f64]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+/// Restarted GMRES with right preconditioning for `A·x = b`
+///
+/// `apply` multiplies with `A` and `precondition` applies an
+/// approximate inverse of it. Returns the best solution found,
+/// also if the tolerance was not reached, as an inexact Newton
+/// iteration can still use it.
+pub fn gmres(
+    mut apply: impl FnMut(&[f64]) -> Result<Vec<f64>, SolverError>,
+    precondition: impl Fn(&[f64]) -> Vec<f64>,
+    b: &[f64],
+    options: &KrylovOptions,
+    stats: &mut Statistics,
+) -> Result<Vec<f64>, SolverError> {
+    let n = b.len();
+    let m = options.max_dimension.clamp(1, n.max(1));
+    let target = options.tolerance * norm(b);
+    let mut x = vec![0.0; n];
+    let mut r = b.to_vec();
+
+    for restart in 0..=options.max_restarts {
+        if restart > 0 {
+            let ax = apply(&x)?;
+            r = b.iter().zip(&ax).map(|(b, ax)| b - ax).collect();
+        }
+        let beta = norm(&r);
+        if beta <= target || beta == 0.0 {
+            break;
+        }
+
+        // Arnoldi basis, Hessenberg matrix by column, the
+        // Givens rotations triangularising it and the rotated
+        // right-hand side `beta · e1`
+        let mut basis: Vec<Vec<f64>> =
+            vec![r.iter().map(|x| x / beta).collect()];
+        let mut h: Vec<Vec<f64>> = vec![];
+        let mut rotations: Vec<(f64, f64)> = vec![];
+        let mut g = vec![beta];
+        for j in 0..m {
+            stats.linear_iterations += 1;
+            let mut w = apply(&precondition(&basis[j]))?;
+            let mut column = vec![0.0; j + 2];
+            // Modified Gram-Schmidt
+            for (i, v) in basis.iter().enumerate() {
+                column[i] = dot(&w, v);
+                for (w, v) in w.iter_mut().zip(v) {
+                    *w -= column[i] * v;
+                }
+            }
+            column[j + 1] = norm(&w);
+            let breakdown = column[j + 1] <= f64::EPSILON * beta;
+            if !breakdown {
+                basis.push(
+                    w.iter().map(|w| w / column[j + 1]).collect(),
+                );
+            }
+
+            for (i, &(c, s)) in rotations.iter().enumerate() {
+                let (a, b) = (column[i], column[i + 1]);
+                column[i] = c * a + s * b;
+                column[i + 1] = -s * a + c * b;
+            }
+            let (a, b) = (column[j], column[j + 1]);
+            let radius = a.hypot(b);
+            let (c, s) = if radius == 0.0 {
+                (1.0, 0.0)
+            } else {
+                (a / radius, b / radius)
+            };
+            column[j] = radius;
+            column[j + 1] = 0.0;
+            rotations.push((c, s));
+            g.push(-s * g[j]);
+            g[j] *= c;
+            h.push(column);
+
+            if g[j + 1].abs() <= target || breakdown {
+                break;
+            }
+        }
+
+        // Back substitution in the triangular system
+        let k = h.len();
+        let mut coefficients = vec![0.0; k];
+        for i in (0..k).rev() {
+            let sum: f64 =
+                (i + 1..k).map(|l| h[l][i] * coefficients[l]).sum();
+            coefficients[i] = (g[i] - sum) / h[i][i];
+        }
+        let mut update = vec![0.0; n];
+        for (v, c) in basis.iter().zip(&coefficients) {
+            for (u, v) in update.iter_mut().zip(v) {
+                *u += c * v;
+            }
+        }
+        for (x, u) in x.iter_mut().zip(precondition(&update)) {
+            *x += u;
+        }
+        if g[k].abs() <= target {
+            break;
+        }
+    }
+    Ok(x)
+}
+
+/// Solution `dk` of `(I - scale · J) · dk = residual` without
+/// forming `J`, whose products with vectors are directional
+/// finite differences of the model
+#[allow(clippy::too_many_arguments)]
+pub fn krylov_step(
+    model: &dyn Rhs,
+    t: f64,
+    y: &[f64],
+    pars: &[f64],
+    scale: f64,
+    residual: &[f64],
+    options: &KrylovOptions,
+    preconditioner: Option<&Preconditioner>,
+    stats: &mut Statistics,
+) -> Result<Vec<f64>, SolverError> {
+    stats.rhs_evaluations += 1;
+    let f0 = model.eval(t, y, pars)?;
+    let y_norm = norm(y).max(1.0);
+    let mut evaluations = 0;
+    let apply = |v: &[f64]| {
+        let v_norm = norm(v);
+        if v_norm == 0.0 {
+            return Ok(vec![0.0; v.len()]);
+        }
+        let sigma = f64::EPSILON.sqrt() * y_norm / v_norm;
+        let shifted: Vec<f64> =
+            y.iter().zip(v).map(|(y, v)| y + sigma * v).collect();
+        evaluations += 1;
+        let f1 = model.eval(t, &shifted, pars)?;
+        Ok(v.iter()
+            .zip(f1.iter().zip(&f0))
+            .map(|(v, (f1, f0))| v - scale * (f1 - f0) / sigma)
+            .collect())
+    };
+    let precondition = |r: &[f64]| match preconditioner {
+        Some(preconditioner) => {
+            preconditioner(t, y, pars, scale, r)
+        }
+        None => r.to_vec(),
+    };
+    let dk = gmres(apply, precondition, residual, options, stats);
+    stats.rhs_evaluations += evaluations;
+    dk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::implicit::utils::Lu;
+
+    #[test]
+    fn solves_nonsymmetric_systems() {
+        let n = 12;
+        let a: Vec<Vec<f64>> = (0..n)
+            .map(|i| {
+                (0..n)
+                    .map(|j| match j as i64 - i as i64 {
+                        0 => 4.0 + i as f64,
+                        1 => -1.0,
+                        -1 => 2.0,
+                        3 => 0.5,
+                        _ => 0.0,
+                    })
+                    .collect()
+            })
+            .collect();
+        let b: Vec<f64> =
+            (0..n).map(|i| (i as f64).sin()).collect();
+        let expected = Lu::factor(&a).unwrap().solve(&b);
+        let apply = |x: &[f64]| {
+            Ok(a.iter()
+                .map(|row| dot(row, x))
+                .collect::<Vec<f64>>())
+        };
+        let options = KrylovOptions {
+            max_dimension: 5,
+            max_restarts: 20,
+            tolerance: 1e-12,
+        };
+
+        let mut plain = Statistics::default();
+        let x =
+            gmres(apply, |r| r.to_vec(), &b, &options, &mut plain)
+                .unwrap();
+        for (x, e) in x.iter().zip(&expected) {
+            assert!((x - e).abs() < 1e-10, "{x} != {e}");
+        }
+
+        // The diagonal already captures most of the matrix
+        let jacobi = |r: &[f64]| {
+            r.iter().enumerate().map(|(i, r)| r / a[i][i]).collect()
+        };
+        let mut preconditioned = Statistics::default();
+        let x =
+            gmres(apply, jacobi, &b, &options, &mut preconditioned)
+                .unwrap();
+        for (x, e) in x.iter().zip(&expected) {
+            assert!((x - e).abs() < 1e-10, "{x} != {e}");
+        }
+        assert!(
+            preconditioned.linear_iterations
+                < plain.linear_iterations
+        );
+    }
+}
@@ -1,37 +1,152 @@
+use serde::Deserialize;
+
+use super::krylov::Preconditioner;
+use super::linear::{JacobianOption, LinearSolver};
+use super::newton::Newton;
 use super::utils::{scale_vec, solve_stages, sub_vec};
-use crate::{Integration, Model};
+use crate::dense::{DenseOutput, OutputTimes, hermite};
+use crate::events::{Event, EventAction, EventMonitor, Interrupt};
+use crate::tolerance::{ErrorControl, ErrorNorm, Tolerance};
+use crate::{
+    Integration, ReturnCode, Rhs, SolverError, Statistics, Steps,
+};
 
+#[derive(Deserialize, Clone)]
+#[serde(default, rename_all = "camelCase")]
 pub struct Kvaerno45Options {
     pub rtol: f64,
-    pub atol: f64,
+    /// One absolute tolerance for all components or one each
+    pub atol: Tolerance,
+    /// How the local errors of the components are combined
+    pub error_norm: ErrorNorm,
+    /// Components left out of the step size control, e.g.
+    /// counters that only accumulate
+    pub excluded: Vec<usize>,
     pub h_min: f64,
     pub h_max: f64,
     pub h_init: f64,
     pub max_steps: i64,
     pub max_iter: i64,
+    /// Time points to report, filled by cubic Hermite
+    /// interpolation between steps
+    pub output: OutputTimes,
+    /// Also return the raw steps when `output` is not
+    /// `OutputTimes::Steps`
+    pub keep_steps: bool,
+    /// Dense, sparse, banded or matrix-free Jacobians for the
+    /// Newton iteration
+    pub jacobian: JacobianOption,
+    /// Steps a Jacobian and its factorisations are reused for
+    /// by the simplified Newton iteration, `0` computes a new
+    /// Jacobian for every Newton update
+    pub max_jacobian_age: usize,
+    /// Preconditioner of the Krylov solver, ignored by the
+    /// others
+    #[serde(skip)]
+    pub preconditioner: Option<Preconditioner>,
+    /// Event functions monitored for sign changes, located on
+    /// the same interpolant as `output`
+    #[serde(skip)]
+    pub events: Vec<Event>,
 }
 
 impl Default for Kvaerno45Options {
     fn default() -> Self {
         Kvaerno45Options {
             rtol: 1e-6,
-            atol: 1e-8,
+            atol: 1e-8.into(),
+            error_norm: ErrorNorm::Rms,
+            excluded: vec![],
             h_min: 1e-8,
             h_max: 1.0,
             h_init: 0.1,
             max_steps: 10_000,
             max_iter: 10,
+            output: OutputTimes::Steps,
+            keep_steps: false,
+            jacobian: JacobianOption::Dense,
+            max_jacobian_age: 20,
+            preconditioner: None,
+            events: vec![],
+        }
+    }
+}
+
+impl Kvaerno45Options {
+    /// Reject step size settings the step size control cannot
+    /// work with, reported at time `t`
+    pub fn validate(&self, t: f64) -> Result<(), SolverError> {
+        let invalid = |option, message: String| {
+            Err(SolverError::InvalidOption { t, option, message })
+        };
+        for (option, h) in [
+            ("h_min", self.h_min),
+            ("h_max", self.h_max),
+            ("h_init", self.h_init),
+        ] {
+            if !h.is_finite() {
+                return invalid(
+                    option,
+                    format!("{h} is not finite"),
+                );
+            }
+        }
+        if self.h_min < 0.0 {
+            return invalid(
+                "h_min",
+                format!("{} is negative", self.h_min),
+            );
+        }
+        if self.h_max <= 0.0 {
+            return invalid(
+                "h_max",
+                format!("{} is not positive", self.h_max),
+            );
+        }
+        if self.h_init <= 0.0 {
+            return invalid(
+                "h_init",
+                format!("{} is not positive", self.h_init),
+            );
+        }
+        if self.h_min > self.h_max {
+            return invalid(
+                "h_min",
+                format!(
+                    "{} is larger than h_max = {}",
+                    self.h_min, self.h_max
+                ),
+            );
+        }
+        if self.max_steps < 0 {
+            return invalid(
+                "max_steps",
+                format!("{} is negative", self.max_steps),
+            );
+        }
+        if self.max_iter < 1 {
+            return invalid(
+                "max_iter",
+                format!("{} is not positive", self.max_iter),
+            );
         }
+        Ok(())
     }
 }
 
+/// Adaptive fifth order ESDIRK method by Kværnø
+///
+/// Integrates from `t_start` to `t_end`, backwards in time if
+/// `t_end < t_start`. Step sizes in the options are step
+/// lengths and always positive.
 pub fn kvaerno45(
-    rhs: Model,
+    rhs: &dyn Rhs,
     y0: Vec<f64>,
-    pars: Vec<f64>,
+    mut pars: Vec<f64>,
+    t_start: f64,
     t_end: f64,
     options: Kvaerno45Options,
-) -> Integration {
+) -> Result<Integration, SolverError> {
     let a = vec![
         vec![0.24169426078821, 0.0, 0.0, 0.0, 0.0, 0.0],
         vec![
@@ -62,72 +177,798 @@ pub fn kvaerno45(
         vec![0.04606, -0.044, 0.122, -0.101, 0.239, 0.23794],
     ];
     let b = a[5].clone();
-    let b_hat = vec![0.04, -0.06, 0.13, -0.09, 0.31, 0.25];
+    let b_hat = [0.04, -0.06, 0.13, -0.09, 0.31, 0.25];
     let c: Vec<f64> =
         a.iter().map(|row| row.iter().sum()).collect();
 
     let s = a.len();
     let n = y0.len();
-    let mut t = 0.0;
+    let mut t = t_start;
     let mut y = y0.clone();
+    let direction = if t_end < t_start { -1.0 } else { 1.0 };
+
+    options.validate(t)?;
+    if !t_start.is_finite() || !t_end.is_finite() {
+        return Err(SolverError::InvalidOption {
+            t,
+            option: "time span",
+            message: format!("{t_start} to {t_end} is not finite"),
+        });
+    }
 
     let mut t_out = vec![t];
     let mut y_out = vec![y.clone()];
+    let mut dense = options
+        .output
+        .resolve(t, t_end)?
+        .map(|times| DenseOutput::new(times, direction));
+    let keep_steps = dense.is_none() || options.keep_steps;
 
     let mut h = options.h_init;
     let max_steps = options.max_steps;
     let rtol = options.rtol;
-    let atol = options.atol;
     let h_min = options.h_min;
     let h_max = options.h_max;
     let max_iter = options.max_iter;
+    let mut stats = Statistics::default();
+    let mut status = ReturnCode::Success;
+    let mut monitor =
+        EventMonitor::new(&options.events, t, &y, &pars);
+
+    if y0.iter().any(|x| !x.is_finite()) {
+        return Err(SolverError::NanDetected { t });
+    }
+    let control = ErrorControl::new(
+        rtol,
+        &options.atol,
+        options.error_norm,
+        &options.excluded,
+        n,
+        t,
+    )?;
+    let linear = LinearSolver::new(
+        &options.jacobian,
+        options.preconditioner.clone(),
+        rhs,
+        t,
+        &y,
+        &pars,
+        &mut stats,
+    )?;
+    let mut newton = Newton::new(linear, options.max_jacobian_age);
+
+    // Derivative at the start of the current step, only
+    // needed for the interpolant
+    let interpolated = dense.is_some() || !monitor.is_empty();
+    if let Some(dense) = dense.as_mut() {
+        dense.start(t, &y);
+    }
+    let mut f = if interpolated {
+        stats.rhs_evaluations += 1;
+        rhs.eval(t, &y, &pars)?
+    } else {
+        vec![]
+    };
 
     for _step in 0..max_steps {
-        if t >= t_end {
+        let remaining = (t_end - t) * direction;
+        if remaining <= 0.0 {
             break;
         }
-        if t + h > t_end {
-            h = t_end - t;
+        let last = h >= remaining;
+        if last {
+            h = remaining;
         }
+        // Signed step, negative when integrating backwards
+        let dt = direction * h;
+        // Land exactly on `t_end` instead of a rounded sum
+        let t_next = if last { t_end } else { t + dt };
 
-        let k = solve_stages(
-            &rhs, &y, t, &pars, h, &a, &c, s, rtol, max_iter,
-        );
+        let k = match solve_stages(
+            rhs,
+            &y,
+            t,
+            &pars,
+            dt,
+            &a,
+            &c,
+            s,
+            rtol,
+            max_iter,
+            &mut newton,
+            &mut stats,
+        ) {
+            Ok(k) => k,
+            Err(
+                e @ (SolverError::NewtonFailure { .. }
+                | SolverError::SingularJacobian { .. }
+                | SolverError::NanDetected { .. }),
+            ) => {
+                // Retry with a new Jacobian, then with a smaller
+                // step unless we are already at the minimal step
+                // size
+                if newton.refresh() {
+                    stats.newton_failures += 1;
+                    continue;
+                }
+                if h <= h_min {
+                    return Err(e);
+                }
+                stats.newton_failures += 1;
+                stats.rejected_steps += 1;
+                h = f64::max(h_min, 0.25 * h);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
 
         let mut y5 = y.clone();
         let mut y4 = y.clone();
         for i in 0..s {
-            let scaled_k = scale_vec(&k[i], h);
+            let scaled_k = scale_vec(&k[i], dt);
             for j in 0..n {
                 y5[j] += b[i] * scaled_k[j];
                 y4[j] += b_hat[i] * scaled_k[j];
             }
         }
 
-        let err_vec = sub_vec(&y5, &y4);
-        let err = (0..n)
-            .map(|i| {
-                let sc =
-                    atol + rtol * f64::max(y[i].abs(), y5[i].abs());
-                (err_vec[i] / sc).powi(2)
-            })
-            .sum::<f64>();
-        let err = (err / n as f64).sqrt();
+        let err = control.error(&sub_vec(&y5, &y4), &y, &y5);
+
+        if !err.is_finite() {
+            if h <= h_min {
+                return Err(SolverError::NanDetected { t });
+            }
+            stats.rejected_steps += 1;
+            h = f64::max(h_min, 0.25 * h);
+            continue;
+        }
 
         if err <= 1.0 {
-            t += h;
-            y = y5.clone();
-            t_out.push(t);
-            y_out.push(y.clone());
+            stats.accepted_steps += 1;
+            let f5 = if interpolated {
+                stats.rhs_evaluations += 1;
+                rhs.eval(t_next, &y5, &pars)?
+            } else {
+                vec![]
+            };
+            let interpolate =
+                |ti| hermite((t, &y, &f), (t_next, &y5, &f5), ti);
+            let interrupt =
+                monitor.step(t, (t_next, &y5), &pars, interpolate);
+            // Requested points after an event belong to the
+            // trajectory that follows it
+            let t_reached =
+                interrupt.as_ref().map_or(t_next, |i| i.time);
+            if let Some(dense) = dense.as_mut() {
+                dense.step(t_reached, interpolate);
+            }
+
+            if let Some(Interrupt {
+                action,
+                time: t_event,
+                values: mut y_event,
+            }) = interrupt
+            {
+                if keep_steps {
+                    t_out.push(t_event);
+                    y_out.push(y_event.clone());
+                }
+                let EventAction::Modify(modify) = action else {
+                    t = t_event;
+                    status = ReturnCode::Terminated;
+                    break;
+                };
+                modify(t_event, &mut y_event, &mut pars);
+                monitor.restart(t_event, &y_event, &pars);
+                stats.rhs_evaluations += 1;
+                f = rhs.eval(t_event, &y_event, &pars)?;
+                t = t_event;
+                y = y_event;
+                if keep_steps {
+                    t_out.push(t);
+                    y_out.push(y.clone());
+                }
+            } else {
+                f = f5;
+                t = t_next;
+                y = y5;
+                if keep_steps {
+                    t_out.push(t);
+                    y_out.push(y.clone());
+                }
+            }
+        } else if h <= h_min {
+            status = ReturnCode::StepSizeUnderflow;
+            break;
+        } else {
+            stats.rejected_steps += 1;
         }
 
         let fac = 0.9 * (1.0 / (err + 1e-10)).powf(1.0 / 5.0);
-        h *= f64::min(5.0, f64::max(0.2, fac));
+        h *= fac.clamp(0.2, 5.0);
         h = f64::max(h_min, f64::min(h, h_max));
     }
 
-    Integration {
-        time: t_out,
-        values: y_out,
+    if status == ReturnCode::Success
+        && (t_end - t) * direction > 0.0
+    {
+        status = ReturnCode::MaxStepsReached;
+    }
+
+    Ok(match dense {
+        Some(dense) => Integration {
+            time: dense.time,
+            values: dense.values,
+            status,
+            stats,
+            steps: keep_steps.then_some(Steps {
+                time: t_out,
+                values: y_out,
+            }),
+            events: monitor.records,
+        },
+        None => Integration {
+            time: t_out,
+            values: y_out,
+            status,
+            stats,
+            steps: None,
+            events: monitor.records,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::events::Crossing;
+    use crate::implicit::banded::BandedMatrix;
+
+    fn decay(
+        _t: f64,
+        y: &[f64],
+        _p: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        Ok(vec![-y[0]])
+    }
+
+    fn options() -> Kvaerno45Options {
+        Kvaerno45Options {
+            rtol: 1e-3,
+            atol: 1e-6.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reports_requested_times() {
+        let times = vec![0.0, 0.25, 0.5, 1.0, 2.0];
+        let steps = kvaerno45(
+            &decay,
+            vec![1.0],
+            vec![],
+            0.0,
+            2.0,
+            options(),
+        )
+        .unwrap();
+        let result = kvaerno45(
+            &decay,
+            vec![1.0],
+            vec![],
+            0.0,
+            2.0,
+            Kvaerno45Options {
+                output: OutputTimes::Times(times.clone()),
+                ..options()
+            },
+        )
+        .unwrap();
+        assert_eq!(result.status, ReturnCode::Success);
+        assert_eq!(result.time, times);
+        assert_eq!(result.values[0], vec![1.0]);
+        assert_eq!(result.values.last(), steps.values.last());
+    }
+
+    #[test]
+    fn integrates_backwards_from_t_start() {
+        // Decay backwards in time mirrors growth forwards
+        let growth =
+            |_t: f64, y: &[f64], _p: &[f64]| Ok(vec![y[0]]);
+        let forward = kvaerno45(
+            &growth,
+            vec![1.0],
+            vec![],
+            1.0,
+            3.0,
+            options(),
+        )
+        .unwrap();
+        let backward = kvaerno45(
+            &decay,
+            vec![1.0],
+            vec![],
+            3.0,
+            1.0,
+            options(),
+        )
+        .unwrap();
+        assert_eq!(backward.status, ReturnCode::Success);
+        assert_eq!(backward.time[0], 3.0);
+        assert_eq!(*backward.time.last().unwrap(), 1.0);
+        assert_eq!(backward.time.len(), forward.time.len());
+        for i in 0..forward.time.len() {
+            assert!(
+                (4.0 - backward.time[i] - forward.time[i]).abs()
+                    < 1e-12
+            );
+            assert!(
+                (backward.values[i][0] - forward.values[i][0])
+                    .abs()
+                    < 1e-12
+            );
+        }
+
+        let dense = kvaerno45(
+            &decay,
+            vec![1.0],
+            vec![],
+            3.0,
+            1.0,
+            Kvaerno45Options {
+                output: OutputTimes::Uniform(3),
+                ..options()
+            },
+        )
+        .unwrap();
+        assert_eq!(dense.time, vec![3.0, 2.0, 1.0]);
+        assert_eq!(dense.values.last(), backward.values.last());
+    }
+
+    #[test]
+    fn handles_events_between_steps() {
+        // Halve the decay rate once y drops below one half and
+        // note when it passes a quarter
+        let events = vec![
+            Event {
+                condition: Rc::new(|_t, y: &[f64], _p: &[f64]| {
+                    y[0] - 0.5
+                }),
+                crossing: Crossing::Falling,
+                action: EventAction::Modify(Rc::new(
+                    |_t, _y: &mut [f64], p: &mut [f64]| p[0] = 0.5,
+                )),
+            },
+            Event {
+                condition: Rc::new(|_t, y: &[f64], _p: &[f64]| {
+                    y[0] - 0.25
+                }),
+                crossing: Crossing::Any,
+                action: EventAction::Record,
+            },
+        ];
+        let rhs =
+            |_t: f64, y: &[f64], p: &[f64]| Ok(vec![-p[0] * y[0]]);
+        let result = kvaerno45(
+            &rhs,
+            vec![1.0],
+            vec![1.0],
+            0.0,
+            10.0,
+            Kvaerno45Options {
+                output: OutputTimes::Uniform(11),
+                events,
+                ..options()
+            },
+        )
+        .unwrap();
+        assert_eq!(result.status, ReturnCode::Success);
+        assert_eq!(result.time.len(), 11);
+        let indices: Vec<_> =
+            result.events.iter().map(|e| e.index).collect();
+        assert_eq!(indices, vec![0, 1]);
+        for (record, level) in result.events.iter().zip([0.5, 0.25])
+        {
+            assert!((record.values[0] - level).abs() < 1e-10);
+        }
+        // The slower decay takes twice as long for the next halving
+        let (first, second) =
+            (&result.events[0], &result.events[1]);
+        let ratio = (second.time - first.time) / first.time;
+        assert!((ratio - 2.0).abs() < 0.1);
+    }
+
+    /// Counts batched calls, like a model behind a costly boundary
+    struct Counted {
+        batches: std::cell::Cell<usize>,
+    }
+
+    impl Rhs for Counted {
+        fn eval(
+            &self,
+            _t: f64,
+            y: &[f64],
+            _p: &[f64],
+        ) -> Result<Vec<f64>, SolverError> {
+            Ok(vec![-y[0], -2.0 * y[1]])
+        }
+
+        fn eval_batch(
+            &self,
+            t: f64,
+            ys: &[Vec<f64>],
+            p: &[f64],
+        ) -> Result<Vec<Vec<f64>>, SolverError> {
+            self.batches.set(self.batches.get() + 1);
+            ys.iter().map(|y| self.eval(t, y, p)).collect()
+        }
+    }
+
+    #[test]
+    fn evaluates_jacobian_columns_in_one_batch() {
+        let model = Counted {
+            batches: Default::default(),
+        };
+        let result = kvaerno45(
+            &model,
+            vec![1.0, 1.0],
+            vec![],
+            0.0,
+            1.0,
+            options(),
+        )
+        .unwrap();
+        assert_eq!(result.status, ReturnCode::Success);
+        assert_eq!(
+            model.batches.get(),
+            result.stats.jacobian_evaluations
+        );
+        assert!(model.batches.get() > 0);
+    }
+
+    /// Chain of compartments exchanging by diffusion with a
+    /// stiff sink in the last one
+    fn diffusion_chain(
+        _t: f64,
+        y: &[f64],
+        _p: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        let n = y.len();
+        Ok((0..n)
+            .map(|i| {
+                let left =
+                    if i > 0 { y[i - 1] - y[i] } else { 0.0 };
+                let right =
+                    if i + 1 < n { y[i + 1] - y[i] } else { 0.0 };
+                let sink =
+                    if i + 1 == n { 1e3 * y[i] } else { 0.0 };
+                10.0 * (left + right) - sink
+            })
+            .collect())
+    }
+
+    #[test]
+    fn solves_with_sparse_jacobians() {
+        let mut y0 = vec![0.0; 20];
+        y0[0] = 1.0;
+        let run = |jacobian| {
+            kvaerno45(
+                &diffusion_chain,
+                y0.clone(),
+                vec![],
+                0.0,
+                0.02,
+                Kvaerno45Options {
+                    jacobian,
+                    // A new Jacobian for every update, so that
+                    // they dominate the evaluations
+                    max_jacobian_age: 0,
+                    ..options()
+                },
+            )
+            .unwrap()
+        };
+        let dense = run(JacobianOption::Dense);
+        let sparse = run(JacobianOption::Sparse { entries: None });
+        let (a, b) = (dense.values.last(), sparse.values.last());
+        for (a, b) in a.unwrap().iter().zip(b.unwrap()) {
+            assert!((a - b).abs() < 1e-6, "{a} != {b}");
+        }
+        // Three colours instead of twenty columns
+        assert!(
+            3 * sparse.stats.rhs_evaluations
+                < dense.stats.rhs_evaluations
+        );
+    }
+
+    #[test]
+    fn solves_with_banded_jacobians() {
+        let mut y0 = vec![0.0; 20];
+        y0[0] = 1.0;
+        let run = |jacobian| {
+            kvaerno45(
+                &diffusion_chain,
+                y0.clone(),
+                vec![],
+                0.0,
+                0.02,
+                Kvaerno45Options {
+                    jacobian,
+                    max_jacobian_age: 0,
+                    ..options()
+                },
+            )
+            .unwrap()
+        };
+        let dense = run(JacobianOption::Dense);
+        let banded =
+            run(JacobianOption::Banded { lower: 1, upper: 1 });
+        let (a, b) = (dense.values.last(), banded.values.last());
+        for (a, b) in a.unwrap().iter().zip(b.unwrap()) {
+            assert!((a - b).abs() < 1e-6, "{a} != {b}");
+        }
+        // Three evaluations per Jacobian instead of twenty
+        assert!(
+            3 * banded.stats.rhs_evaluations
+                < dense.stats.rhs_evaluations
+        );
+    }
+
+    #[test]
+    fn reuses_jacobians_and_factorisations() {
+        let mut y0 = vec![0.0; 10];
+        y0[0] = 1.0;
+        let run = |max_jacobian_age| {
+            kvaerno45(
+                &diffusion_chain,
+                y0.clone(),
+                vec![],
+                0.0,
+                0.05,
+                Kvaerno45Options {
+                    max_jacobian_age,
+                    ..options()
+                },
+            )
+            .unwrap()
+        };
+        let full = run(0);
+        let simplified = run(20);
+        assert_eq!(simplified.status, ReturnCode::Success);
+        let (a, b) = (full.values.last(), simplified.values.last());
+        for (a, b) in a.unwrap().iter().zip(b.unwrap()) {
+            assert!((a - b).abs() < 1e-4, "{a} != {b}");
+        }
+        let (full, simplified) = (full.stats, simplified.stats);
+        assert!(
+            10 * simplified.jacobian_evaluations
+                < full.jacobian_evaluations
+        );
+        assert!(
+            simplified.lu_factorizations < full.lu_factorizations
+        );
+        assert!(simplified.rhs_evaluations < full.rhs_evaluations);
+    }
+
+    #[test]
+    fn solves_with_krylov_iterations() {
+        let mut y0 = vec![0.0; 20];
+        y0[0] = 1.0;
+        let run = |jacobian, preconditioner| {
+            kvaerno45(
+                &diffusion_chain,
+                y0.clone(),
+                vec![],
+                0.0,
+                0.05,
+                Kvaerno45Options {
+                    jacobian,
+                    preconditioner,
+                    ..options()
+                },
+            )
+            .unwrap()
+        };
+        // Exact solution with the tridiagonal Jacobian
+        let tridiagonal: Preconditioner =
+            Rc::new(|_t, y: &[f64], _p, scale, r: &[f64]| {
+                let n = y.len();
+                let mut jac = vec![vec![0.0; n]; n];
+                for i in 0..n {
+                    if i > 0 {
+                        jac[i][i - 1] = 10.0;
+                        jac[i][i] -= 10.0;
+                    }
+                    if i + 1 < n {
+                        jac[i][i + 1] = 10.0;
+                        jac[i][i] -= 10.0;
+                    }
+                }
+                jac[n - 1][n - 1] -= 1e3;
+                BandedMatrix::from_dense(&jac, 1, 1)
+                    .shifted(-scale)
+                    .lu()
+                    .unwrap()
+                    .solve(r)
+            });
+        let dense = run(JacobianOption::Dense, None);
+        let krylov = JacobianOption::Krylov(Default::default());
+        let plain = run(krylov.clone(), None);
+        let preconditioned = run(krylov, Some(tridiagonal));
+        for result in [&plain, &preconditioned] {
+            assert_eq!(result.status, ReturnCode::Success);
+            assert_eq!(result.stats.jacobian_evaluations, 0);
+            assert_eq!(result.stats.lu_factorizations, 0);
+            let (a, b) =
+                (dense.values.last(), result.values.last());
+            for (a, b) in a.unwrap().iter().zip(b.unwrap()) {
+                assert!((a - b).abs() < 1e-4, "{a} != {b}");
+            }
+        }
+        assert!(
+            preconditioned.stats.linear_iterations
+                < plain.stats.linear_iterations
+        );
+    }
+
+    #[test]
+    fn controls_errors_per_component() {
+        // A small decaying state next to a large oscillating one
+        let model = |t: f64, y: &[f64], _p: &[f64]| {
+            Ok(vec![-y[0], 1e3 * (20.0 * t).cos()])
+        };
+        let run = |atol: Tolerance, error_norm, excluded| {
+            kvaerno45(
+                &model,
+                vec![1e-3, 1e3],
+                vec![],
+                0.0,
+                1.0,
+                Kvaerno45Options {
+                    rtol: 1e-3,
+                    atol,
+                    error_norm,
+                    excluded,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .stats
+            .accepted_steps
+        };
+        let shared = run(1e-2.into(), ErrorNorm::Rms, vec![]);
+        // The shared tolerance exceeds the small state, which
+        // then only limits the steps with its own one
+        let separate =
+            run(vec![1e-9, 1e-2].into(), ErrorNorm::Rms, vec![]);
+        assert!(separate > shared);
+        let strict =
+            run(vec![1e-9, 1e-2].into(), ErrorNorm::Max, vec![]);
+        assert!(strict >= separate);
+        // Without the oscillation the steps follow the decay
+        let excluded = run(1e-2.into(), ErrorNorm::Rms, vec![1]);
+        assert!(2 * excluded < shared);
+
+        assert!(matches!(
+            kvaerno45(
+                &model,
+                vec![1e-3, 1e3],
+                vec![],
+                0.0,
+                1.0,
+                Kvaerno45Options {
+                    atol: vec![1e-9].into(),
+                    ..Default::default()
+                },
+            ),
+            Err(SolverError::DimensionMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_step_size_options() {
+        let cases = [
+            (
+                "h_min",
+                Kvaerno45Options {
+                    h_min: -1e-8,
+                    ..options()
+                },
+            ),
+            (
+                "h_min",
+                Kvaerno45Options {
+                    h_min: f64::NAN,
+                    ..options()
+                },
+            ),
+            (
+                "h_max",
+                Kvaerno45Options {
+                    h_max: 0.0,
+                    ..options()
+                },
+            ),
+            (
+                "h_max",
+                Kvaerno45Options {
+                    h_max: f64::INFINITY,
+                    ..options()
+                },
+            ),
+            (
+                "h_init",
+                Kvaerno45Options {
+                    h_init: -0.1,
+                    ..options()
+                },
+            ),
+            (
+                "h_init",
+                Kvaerno45Options {
+                    h_init: f64::NAN,
+                    ..options()
+                },
+            ),
+            (
+                "h_min",
+                Kvaerno45Options {
+                    h_min: 1.0,
+                    h_max: 0.1,
+                    ..options()
+                },
+            ),
+            (
+                "max_steps",
+                Kvaerno45Options {
+                    max_steps: -1,
+                    ..options()
+                },
+            ),
+            (
+                "max_iter",
+                Kvaerno45Options {
+                    max_iter: 0,
+                    ..options()
+                },
+            ),
+        ];
+        for (expected, options) in cases {
+            let error = kvaerno45(
+                &decay,
+                vec![1.0],
+                vec![],
+                0.0,
+                1.0,
+                options,
+            )
+            .err();
+            assert!(
+                matches!(
+                    error,
+                    Some(SolverError::InvalidOption { option, .. })
+                        if option == expected
+                ),
+                "{expected}: {error:?}"
+            );
+        }
+        let error = kvaerno45(
+            &decay,
+            vec![1.0],
+            vec![],
+            0.0,
+            f64::INFINITY,
+            options(),
+        )
+        .err();
+        assert!(matches!(
+            error,
+            Some(SolverError::InvalidOption {
+                option: "time span",
+                ..
+            })
+        ));
     }
 }
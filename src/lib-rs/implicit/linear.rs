@@ -0,0 +1,244 @@
+use serde::Deserialize;
+
+use super::banded::{BandedLu, BandedMatrix, banded_jacobian};
+use super::krylov::{KrylovOptions, Preconditioner, krylov_step};
+use super::sparse::{
+    SparseLu, SparseMatrix, SparsityPattern, sparse_jacobian,
+};
+use super::utils::{Lu, jacobian};
+use crate::{Rhs, SolverError, Statistics};
+
+/// How the implicit solvers get the Jacobian and solve the
+/// linear systems of the Newton iteration
+///
+/// `"dense"` by default, `{ "sparse": {} }` for a sparse
+/// Jacobian with the pattern detected at the start, or
+/// `{ "sparse": { "entries": [[row, column], ...] } }` with a
+/// known pattern. `{ "banded": { "lower": 1, "upper": 1 } }`
+/// suits chains of compartments with exchange between
+/// neighbours. `{ "krylov": {} }` never forms the Jacobian,
+/// for large models whose Jacobian is too expensive to store
+/// or factorise.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum JacobianOption {
+    /// Exact Jacobian of the model or one finite difference
+    /// per variable, solved by Gauss elimination
+    #[default]
+    Dense,
+    /// Finite differences grouped by column colouring, solved
+    /// by sparse LU factorisation
+    Sparse {
+        #[serde(default)]
+        entries: Option<Vec<(usize, usize)>>,
+    },
+    /// Entries at most `lower` below and `upper` above the
+    /// diagonal, `lower + upper + 1` finite differences solved
+    /// by banded LU factorisation
+    Banded { lower: usize, upper: usize },
+    /// Jacobian-vector products by directional differences,
+    /// solved by GMRES with an optional preconditioner
+    Krylov(KrylovOptions),
+}
+
+/// [`JacobianOption`] prepared for a model
+pub enum LinearSolver {
+    Dense,
+    Sparse {
+        pattern: SparsityPattern,
+        colouring: Vec<Vec<usize>>,
+    },
+    Banded {
+        lower: usize,
+        upper: usize,
+    },
+    Krylov {
+        options: KrylovOptions,
+        preconditioner: Option<Preconditioner>,
+    },
+}
+
+impl LinearSolver {
+    /// `preconditioner` is only used by the Krylov solver
+    pub fn new(
+        option: &JacobianOption,
+        preconditioner: Option<Preconditioner>,
+        model: &dyn Rhs,
+        t: f64,
+        y: &[f64],
+        pars: &[f64],
+        stats: &mut Statistics,
+    ) -> Result<Self, SolverError> {
+        Ok(match option {
+            JacobianOption::Dense => LinearSolver::Dense,
+            JacobianOption::Sparse { entries } => {
+                let pattern = match entries {
+                    Some(entries) => {
+                        SparsityPattern::new(y.len(), entries)
+                    }
+                    None => SparsityPattern::detect(
+                        model, t, y, pars, stats,
+                    )?,
+                };
+                let colouring = pattern.colouring();
+                LinearSolver::Sparse { pattern, colouring }
+            }
+            &JacobianOption::Banded { lower, upper } => {
+                LinearSolver::Banded { lower, upper }
+            }
+            JacobianOption::Krylov(options) => {
+                LinearSolver::Krylov {
+                    options: options.clone(),
+                    preconditioner,
+                }
+            }
+        })
+    }
+
+    /// Whether the solver works without a Jacobian matrix
+    pub fn is_matrix_free(&self) -> bool {
+        matches!(self, LinearSolver::Krylov { .. })
+    }
+
+    /// Jacobian at `(t, y)` in the storage of this solver,
+    /// dense for the matrix-free solver
+    pub fn jacobian(
+        &self,
+        model: &dyn Rhs,
+        t: f64,
+        y: &[f64],
+        pars: &[f64],
+        stats: &mut Statistics,
+    ) -> Result<Jacobian, SolverError> {
+        Ok(match self {
+            LinearSolver::Dense | LinearSolver::Krylov { .. } => {
+                Jacobian::Dense(jacobian(model, t, y, pars, stats)?)
+            }
+            LinearSolver::Sparse { pattern, colouring } => {
+                Jacobian::Sparse(match model.jacobian(t, y, pars) {
+                    Some(jac) => {
+                        stats.jacobian_evaluations += 1;
+                        SparseMatrix::from_dense(&jac?)
+                    }
+                    None => sparse_jacobian(
+                        model, t, y, pars, pattern, colouring,
+                        1e-8, stats,
+                    )?,
+                })
+            }
+            &LinearSolver::Banded { lower, upper } => {
+                Jacobian::Banded(match model.jacobian(t, y, pars) {
+                    Some(jac) => {
+                        stats.jacobian_evaluations += 1;
+                        BandedMatrix::from_dense(
+                            &jac?, lower, upper,
+                        )
+                    }
+                    None => banded_jacobian(
+                        model,
+                        t,
+                        y,
+                        pars,
+                        (lower, upper),
+                        1e-8,
+                        stats,
+                    )?,
+                })
+            }
+        })
+    }
+
+    /// Solution `dk` of `(I - scale · J) · dk = residual` with
+    /// a new Jacobian `J` at `(t, y)`, or only its products with
+    /// vectors for the Krylov solver
+    #[allow(clippy::too_many_arguments)]
+    pub fn newton_step(
+        &self,
+        model: &dyn Rhs,
+        t: f64,
+        y: &[f64],
+        pars: &[f64],
+        scale: f64,
+        residual: &[f64],
+        stats: &mut Statistics,
+    ) -> Result<Vec<f64>, SolverError> {
+        if let LinearSolver::Krylov {
+            options,
+            preconditioner,
+        } = self
+        {
+            return krylov_step(
+                model,
+                t,
+                y,
+                pars,
+                scale,
+                residual,
+                options,
+                preconditioner.as_ref(),
+                stats,
+            );
+        }
+        let jac = self.jacobian(model, t, y, pars, stats)?;
+        let lu = jac.factor(scale, t, stats)?;
+        Ok(lu.solve(residual))
+    }
+}
+
+pub enum Jacobian {
+    Dense(Vec<Vec<f64>>),
+    Sparse(SparseMatrix),
+    Banded(BandedMatrix),
+}
+
+impl Jacobian {
+    /// Factorisation of `I - scale · J`
+    pub fn factor(
+        &self,
+        scale: f64,
+        t: f64,
+        stats: &mut Statistics,
+    ) -> Result<Factorisation, SolverError> {
+        stats.lu_factorizations += 1;
+        let lu = match self {
+            Jacobian::Dense(jac) => {
+                let matrix: Vec<Vec<f64>> = jac
+                    .iter()
+                    .enumerate()
+                    .map(|(r, row)| {
+                        let mut row: Vec<f64> = row
+                            .iter()
+                            .map(|x| -scale * x)
+                            .collect();
+                        row[r] += 1.0;
+                        row
+                    })
+                    .collect();
+                Lu::factor(&matrix).map(Factorisation::Dense)
+            }
+            Jacobian::Sparse(jac) => {
+                jac.shifted(-scale).lu().map(Factorisation::Sparse)
+            }
+            Jacobian::Banded(jac) => {
+                jac.shifted(-scale).lu().map(Factorisation::Banded)
+            }
+        };
+        lu.ok_or(SolverError::SingularJacobian { t })
+    }
+}
+
+pub enum Factorisation {
+    Dense(Lu),
+    Sparse(SparseLu),
+    Banded(BandedLu),
+}
+
+impl Factorisation {
+    pub fn solve(&self, b: &[f64]) -> Vec<f64> {
+        match self {
+            Factorisation::Dense(lu) => lu.solve(b),
+            Factorisation::Sparse(lu) => lu.solve(b),
+            Factorisation::Banded(lu) => lu.solve(b),
+        }
+    }
+}
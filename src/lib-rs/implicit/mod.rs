@@ -1,6 +1,12 @@
 mod backward_euler;
+pub mod banded;
+pub mod krylov;
 mod kvaerno45;
+mod linear;
+mod newton;
+pub mod sparse;
 mod utils;
 
 pub use backward_euler::backward_euler;
 pub use kvaerno45::{Kvaerno45Options, kvaerno45};
+pub use linear::JacobianOption;
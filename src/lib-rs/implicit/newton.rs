@@ -0,0 +1,128 @@
+use super::linear::{Factorisation, Jacobian, LinearSolver};
+use crate::{Rhs, SolverError, Statistics};
+
+/// Contraction rate of the Newton iteration above which the
+/// Jacobian is recomputed for the next step
+const SLOW_CONTRACTION: f64 = 0.3;
+
+/// Jacobian and factorisations kept between Newton iterations
+///
+/// The simplified Newton iteration solves with the Jacobian of
+/// an earlier point, as in Hairer and Wanner's codes. A new
+/// Jacobian is computed once it has been used for `max_age`
+/// steps, after a step converged slowly, and before a step is
+/// retried because the iteration failed with an old one.
+/// Factorisations of `I - h·a·J` are kept for every diagonal
+/// coefficient `a` as long as the step size does not change.
+pub struct Newton {
+    linear: LinearSolver,
+    /// `0` for the full Newton iteration, which computes a new
+    /// Jacobian for every update
+    max_age: usize,
+    jacobian: Option<Jacobian>,
+    /// Steps the Jacobian was used for
+    age: usize,
+    /// The Jacobian was computed during the current step
+    fresh: bool,
+    h: f64,
+    factorisations: Vec<(f64, Factorisation)>,
+    /// Largest contraction rate of the current step
+    theta: f64,
+}
+
+impl Newton {
+    pub fn new(linear: LinearSolver, max_age: usize) -> Self {
+        // Nothing to reuse without a Jacobian
+        let max_age =
+            if linear.is_matrix_free() { 0 } else { max_age };
+        Newton {
+            linear,
+            max_age,
+            jacobian: None,
+            age: 0,
+            fresh: false,
+            h: f64::NAN,
+            factorisations: vec![],
+            theta: 0.0,
+        }
+    }
+
+    /// Prepare the iteration of a step of size `h`
+    pub fn start(&mut self, h: f64) {
+        self.fresh = false;
+        self.theta = 0.0;
+        if h != self.h {
+            self.h = h;
+            self.factorisations.clear();
+        }
+    }
+
+    /// Solution `dk` of `(I - scale · J) · dk = residual`
+    #[allow(clippy::too_many_arguments)]
+    pub fn solve(
+        &mut self,
+        model: &dyn Rhs,
+        t: f64,
+        y: &[f64],
+        pars: &[f64],
+        scale: f64,
+        residual: &[f64],
+        stats: &mut Statistics,
+    ) -> Result<Vec<f64>, SolverError> {
+        if self.max_age == 0 {
+            return self.linear.newton_step(
+                model, t, y, pars, scale, residual, stats,
+            );
+        }
+        let jacobian = match &mut self.jacobian {
+            Some(jacobian) => jacobian,
+            jacobian @ None => {
+                self.age = 0;
+                self.fresh = true;
+                self.factorisations.clear();
+                jacobian.insert(
+                    self.linear
+                        .jacobian(model, t, y, pars, stats)?,
+                )
+            }
+        };
+        let index = match self
+            .factorisations
+            .iter()
+            .position(|&(s, _)| s == scale)
+        {
+            Some(index) => index,
+            None => {
+                let lu = jacobian.factor(scale, t, stats)?;
+                self.factorisations.push((scale, lu));
+                self.factorisations.len() - 1
+            }
+        };
+        Ok(self.factorisations[index].1.solve(residual))
+    }
+
+    /// Whether the iteration diverges with the contraction rate
+    /// `theta`, the ratio of two successive update norms
+    pub fn diverges(&mut self, theta: f64) -> bool {
+        self.theta = self.theta.max(theta);
+        self.max_age > 0 && (theta >= 1.0 || theta.is_nan())
+    }
+
+    /// The iteration of the step converged
+    pub fn converged(&mut self) {
+        self.age += 1;
+        if self.age >= self.max_age || self.theta > SLOW_CONTRACTION
+        {
+            self.jacobian = None;
+        }
+    }
+
+    /// Drop a Jacobian from an earlier step after the iteration
+    /// failed, `true` if the step should be retried with a new
+    /// one before reducing the step size
+    pub fn refresh(&mut self) -> bool {
+        let stale = self.jacobian.is_some() && !self.fresh;
+        self.jacobian = None;
+        stale
+    }
+}
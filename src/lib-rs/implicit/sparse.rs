@@ -0,0 +1,406 @@
+use std::collections::BTreeMap;
+
+use crate::{Rhs, SolverError, Statistics};
+
+/// Pivots below this count as zero, as in `utils::Lu`
+const SINGULAR: f64 = 1e-12;
+
+/// Positions of the entries of a Jacobian that can be nonzero
+///
+/// The diagonal is always part of the pattern, the Newton
+/// matrices `I - h·a·J` of implicit methods need it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SparsityPattern {
+    /// Rows with an entry, for every column
+    columns: Vec<Vec<usize>>,
+}
+
+impl SparsityPattern {
+    /// Pattern of an `n × n` matrix from `(row, column)` pairs
+    pub fn new(n: usize, entries: &[(usize, usize)]) -> Self {
+        let mut columns: Vec<Vec<usize>> =
+            (0..n).map(|j| vec![j]).collect();
+        for &(i, j) in entries {
+            if i < n && j < n && !columns[j].contains(&i) {
+                columns[j].push(i);
+            }
+        }
+        for rows in &mut columns {
+            rows.sort_unstable();
+        }
+        SparsityPattern { columns }
+    }
+
+    /// Pattern found by perturbing every variable of `model`
+    /// once
+    ///
+    /// The model is evaluated near `values` with every
+    /// component shifted, so that zero values, e.g. of a
+    /// substrate in a mass action rate, do not hide entries.
+    pub fn detect(
+        model: &dyn Rhs,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+        stats: &mut Statistics,
+    ) -> Result<Self, SolverError> {
+        let n = values.len();
+        let base: Vec<f64> = values
+            .iter()
+            .enumerate()
+            .map(|(i, y)| {
+                y + 0.1
+                    * y.abs().max(1.0)
+                    * (1.0 + i as f64 / n as f64)
+            })
+            .collect();
+        stats.rhs_evaluations += n + 1;
+        let f0 = model.eval(time, &base, pars)?;
+        let perturbed: Vec<Vec<f64>> = (0..n)
+            .map(|j| {
+                let mut y = base.clone();
+                y[j] += 1e-4 * y[j].abs().max(1.0);
+                y
+            })
+            .collect();
+        let f1 = model.eval_batch(time, &perturbed, pars)?;
+        let entries: Vec<(usize, usize)> = f1
+            .iter()
+            .enumerate()
+            .flat_map(|(j, f1)| {
+                f1.iter()
+                    .zip(&f0)
+                    .enumerate()
+                    .filter(|(_, (a, b))| a != b)
+                    .map(move |(i, _)| (i, j))
+            })
+            .collect();
+        Ok(Self::new(n, &entries))
+    }
+
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// Number of entries
+    pub fn entries(&self) -> usize {
+        self.columns.iter().map(Vec::len).sum()
+    }
+
+    /// Groups of columns without a common row, found greedily
+    /// as by Curtis, Powell and Reid
+    ///
+    /// The columns of a group can be perturbed together, so a
+    /// finite difference Jacobian needs one evaluation per
+    /// group instead of one per column.
+    pub fn colouring(&self) -> Vec<Vec<usize>> {
+        let n = self.columns.len();
+        let mut groups: Vec<Vec<usize>> = vec![];
+        // Rows taken by every group
+        let mut taken: Vec<Vec<bool>> = vec![];
+        for (j, rows) in self.columns.iter().enumerate() {
+            let group = taken
+                .iter()
+                .position(|taken| rows.iter().all(|&i| !taken[i]))
+                .unwrap_or_else(|| {
+                    groups.push(vec![]);
+                    taken.push(vec![false; n]);
+                    groups.len() - 1
+                });
+            groups[group].push(j);
+            for &i in rows {
+                taken[group][i] = true;
+            }
+        }
+        groups
+    }
+}
+
+/// Square matrix storing the nonzero entries of every row,
+/// sorted by column
+#[derive(Clone, Debug, PartialEq)]
+pub struct SparseMatrix {
+    rows: Vec<Vec<(usize, f64)>>,
+}
+
+impl SparseMatrix {
+    /// Matrix with the nonzero entries of `dense`
+    pub fn from_dense(dense: &[Vec<f64>]) -> Self {
+        SparseMatrix {
+            rows: dense
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .copied()
+                        .enumerate()
+                        .filter(|&(_, x)| x != 0.0)
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    pub fn rows(&self) -> &[Vec<(usize, f64)>] {
+        &self.rows
+    }
+
+    /// `I + scale · self`, the Newton matrix of a stage
+    pub fn shifted(&self, scale: f64) -> Self {
+        let rows = self
+            .rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| {
+                let mut row: BTreeMap<usize, f64> = row
+                    .iter()
+                    .map(|&(j, x)| (j, scale * x))
+                    .collect();
+                *row.entry(i).or_insert(0.0) += 1.0;
+                row.into_iter().collect()
+            })
+            .collect();
+        SparseMatrix { rows }
+    }
+
+    /// LU factorisation with partial pivoting, `None` if the
+    /// matrix is singular
+    pub fn lu(&self) -> Option<SparseLu> {
+        let n = self.rows.len();
+        let mut upper: Vec<BTreeMap<usize, f64>> = self
+            .rows
+            .iter()
+            .map(|row| row.iter().copied().collect())
+            .collect();
+        let mut lower: Vec<Vec<(usize, f64)>> = vec![vec![]; n];
+        let mut permutation: Vec<usize> = (0..n).collect();
+        for k in 0..n {
+            let pivot_row = (k..n).max_by(|&a, &b| {
+                let entry = |r: usize| {
+                    upper[r].get(&k).map_or(0.0, |x| x.abs())
+                };
+                entry(a).total_cmp(&entry(b))
+            })?;
+            let pivot =
+                upper[pivot_row].get(&k).copied().unwrap_or(0.0);
+            if pivot.abs() < SINGULAR || pivot.is_nan() {
+                return None;
+            }
+            upper.swap(k, pivot_row);
+            lower.swap(k, pivot_row);
+            permutation.swap(k, pivot_row);
+            let (done, rest) = upper.split_at_mut(k + 1);
+            let pivot_entries = &done[k];
+            for (r, row) in rest.iter_mut().enumerate() {
+                let Some(x) = row.remove(&k) else {
+                    continue;
+                };
+                let factor = x / pivot;
+                lower[k + 1 + r].push((k, factor));
+                for (&j, &u) in pivot_entries.range(k + 1..) {
+                    *row.entry(j).or_insert(0.0) -= factor * u;
+                }
+            }
+        }
+        Some(SparseLu {
+            lower,
+            upper: upper
+                .into_iter()
+                .map(|row| row.into_iter().collect())
+                .collect(),
+            permutation,
+        })
+    }
+}
+
+/// `P·A = L·U` of a [`SparseMatrix`], reusable for several
+/// right-hand sides
+#[derive(Clone, Debug)]
+pub struct SparseLu {
+    /// Entries below the unit diagonal of `L`, by row
+    lower: Vec<Vec<(usize, f64)>>,
+    /// Entries of `U` by row, starting with the diagonal
+    upper: Vec<Vec<(usize, f64)>>,
+    /// Row of `A` that ended up in each row
+    permutation: Vec<usize>,
+}
+
+impl SparseLu {
+    /// Solution of `A·x = b`
+    pub fn solve(&self, b: &[f64]) -> Vec<f64> {
+        let mut x: Vec<f64> =
+            self.permutation.iter().map(|&i| b[i]).collect();
+        for i in 0..x.len() {
+            let sum: f64 =
+                self.lower[i].iter().map(|&(k, l)| l * x[k]).sum();
+            x[i] -= sum;
+        }
+        for i in (0..x.len()).rev() {
+            let (_, diagonal) = self.upper[i][0];
+            let sum: f64 = self.upper[i][1..]
+                .iter()
+                .map(|&(j, u)| u * x[j])
+                .sum();
+            x[i] = (x[i] - sum) / diagonal;
+        }
+        x
+    }
+}
+
+/// Finite difference Jacobian with one evaluation per group of
+/// [`SparsityPattern::colouring`]
+#[allow(clippy::too_many_arguments)]
+pub fn sparse_jacobian(
+    model: &dyn Rhs,
+    t: f64,
+    y: &[f64],
+    pars: &[f64],
+    pattern: &SparsityPattern,
+    colouring: &[Vec<usize>],
+    eps: f64,
+    stats: &mut Statistics,
+) -> Result<SparseMatrix, SolverError> {
+    let n = y.len();
+    stats.jacobian_evaluations += 1;
+    stats.rhs_evaluations += colouring.len() + 1;
+    let f0 = model.eval(t, y, pars)?;
+    let steps: Vec<f64> =
+        y.iter().map(|yj| eps * yj.abs().max(1.0)).collect();
+    let perturbed: Vec<Vec<f64>> = colouring
+        .iter()
+        .map(|group| {
+            let mut y_perturbed = y.to_vec();
+            for &j in group {
+                y_perturbed[j] += steps[j];
+            }
+            y_perturbed
+        })
+        .collect();
+    let f1 = model.eval_batch(t, &perturbed, pars)?;
+    if f1.len() != colouring.len() {
+        return Err(SolverError::DimensionMismatch {
+            t,
+            quantity: "batch results",
+            expected: colouring.len(),
+            found: f1.len(),
+        });
+    }
+
+    let mut rows: Vec<Vec<(usize, f64)>> = vec![vec![]; n];
+    for (group, f1) in colouring.iter().zip(&f1) {
+        for &j in group {
+            for &i in &pattern.columns[j] {
+                rows[i].push((j, (f1[i] - f0[i]) / steps[j]));
+            }
+        }
+    }
+    for row in &mut rows {
+        row.sort_unstable_by_key(|&(j, _)| j);
+    }
+    Ok(SparseMatrix { rows })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::implicit::utils::{Lu, approx_jacobian};
+
+    /// Chain of compartments exchanging by diffusion
+    fn diffusion(
+        _t: f64,
+        y: &[f64],
+        p: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        let n = y.len();
+        Ok((0..n)
+            .map(|i| {
+                let left =
+                    if i > 0 { y[i - 1] - y[i] } else { 0.0 };
+                let right =
+                    if i + 1 < n { y[i + 1] - y[i] } else { 0.0 };
+                p[0] * (left + right) - y[i] * y[i]
+            })
+            .collect())
+    }
+
+    #[test]
+    fn colours_a_tridiagonal_pattern() {
+        let y: Vec<f64> = (0..50).map(|i| i as f64 * 0.1).collect();
+        let mut stats = Statistics::default();
+        let pattern = SparsityPattern::detect(
+            &diffusion,
+            0.0,
+            &y,
+            &[2.0],
+            &mut stats,
+        )
+        .unwrap();
+        assert_eq!(pattern.entries(), 50 + 2 * 49);
+        assert_eq!(pattern, {
+            let entries: Vec<(usize, usize)> = (1..50)
+                .flat_map(|i| [(i - 1, i), (i, i - 1)])
+                .collect();
+            SparsityPattern::new(50, &entries)
+        });
+        let colouring = pattern.colouring();
+        assert_eq!(colouring.len(), 3);
+
+        let sparse = sparse_jacobian(
+            &diffusion,
+            0.0,
+            &y,
+            &[2.0],
+            &pattern,
+            &colouring,
+            1e-8,
+            &mut stats,
+        )
+        .unwrap();
+        let dense = approx_jacobian(
+            &diffusion,
+            0.0,
+            &y,
+            &[2.0],
+            1e-8,
+            &mut stats,
+        )
+        .unwrap();
+        for (row, dense) in sparse.rows().iter().zip(&dense) {
+            for &(j, x) in row {
+                assert!((x - dense[j]).abs() < 1e-6);
+            }
+        }
+        assert_eq!(
+            SparseMatrix::from_dense(&dense).rows().len(),
+            50
+        );
+    }
+
+    #[test]
+    fn solves_with_the_sparse_factorisation() {
+        let dense = vec![
+            vec![0.0, 2.0, 0.0, 1.0],
+            vec![1.0, 0.0, 0.0, 0.0],
+            vec![0.0, 3.0, 4.0, 0.0],
+            vec![5.0, 0.0, 1.0, 2.0],
+        ];
+        let b = [1.0, 2.0, 3.0, 4.0];
+        let lu = SparseMatrix::from_dense(&dense).lu().unwrap();
+        let expected = Lu::factor(&dense).unwrap().solve(&b);
+        for (x, e) in lu.solve(&b).iter().zip(&expected) {
+            assert!((x - e).abs() < 1e-12, "{x} != {e}");
+        }
+        let shifted =
+            SparseMatrix::from_dense(&dense).shifted(-1.0);
+        assert_eq!(
+            shifted.rows()[0],
+            vec![(0, 1.0), (1, -2.0), (3, -1.0)]
+        );
+
+        let singular = vec![vec![1.0, 2.0], vec![2.0, 4.0]];
+        assert!(SparseMatrix::from_dense(&singular).lu().is_none());
+    }
+}
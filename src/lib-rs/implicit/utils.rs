@@ -1,115 +1,180 @@
-use crate::Model;
+use super::newton::Newton;
+use crate::{Rhs, SolverError, Statistics};
 use ordered_float::NotNan;
 
-pub fn scale_vec(v: &Vec<f64>, h: f64) -> Vec<f64> {
+pub fn scale_vec(v: &[f64], h: f64) -> Vec<f64> {
     v.iter().map(|&x| x * h).collect()
 }
 
-pub fn sub_vec(a: &Vec<f64>, b: &Vec<f64>) -> Vec<f64> {
+pub fn sub_vec(a: &[f64], b: &[f64]) -> Vec<f64> {
     a.iter().zip(b.iter()).map(|(&x, &y)| x - y).collect()
 }
 
-pub fn max_norm(v: &Vec<f64>) -> f64 {
-    *v.iter()
-        .max_by_key(|x| NotNan::new(x.abs()).unwrap())
-        .unwrap()
+/// Largest absolute entry, `None` if any entry is NaN
+pub fn max_norm(v: &[f64]) -> Option<f64> {
+    v.iter().try_fold(0.0, |acc: f64, x| {
+        NotNan::new(x.abs()).ok().map(|x| acc.max(*x))
+    })
 }
 
-// Solve Ax = b using naive Gauss elimination
-pub fn solve_linear(a: &Vec<Vec<f64>>, b: &Vec<f64>) -> Vec<f64> {
-    let n = b.len();
-    let mut m: Vec<Vec<f64>> = a
-        .iter()
-        .enumerate()
-        .map(|(i, row)| {
-            let mut r = row.clone();
-            r.push(b[i]);
-            r
-        })
-        .collect();
-
-    for i in 0..n {
-        // Find max row for pivot
-        let mut max_row = i;
-        for k in (i + 1)..n {
-            if m[k][i].abs() > m[max_row][i].abs() {
-                max_row = k;
-            }
-        }
-        m.swap(i, max_row);
-
-        let pivot = m[i][i];
-        if pivot.abs() < 1e-12 {
-            panic!("Singular matrix");
-        }
-
-        for j in i..=n {
-            m[i][j] /= pivot;
-        }
+/// LU factorisation with partial pivoting of a dense matrix,
+/// reusable for several right-hand sides
+#[derive(Clone, Debug)]
+pub struct Lu {
+    /// `L` below the unit diagonal and `U` on and above it
+    lu: Vec<Vec<f64>>,
+    /// Row of the matrix that ended up in each row
+    permutation: Vec<usize>,
+}
 
+impl Lu {
+    /// Factorise `a`, `None` if it is singular
+    pub fn factor(a: &[Vec<f64>]) -> Option<Lu> {
+        let n = a.len();
+        let mut lu = a.to_vec();
+        let mut permutation: Vec<usize> = (0..n).collect();
         for k in 0..n {
-            if k == i {
-                continue;
+            // Find max row for pivot
+            let max_row = (k..n).max_by(|&i, &j| {
+                lu[i][k].abs().total_cmp(&lu[j][k].abs())
+            })?;
+            lu.swap(k, max_row);
+            permutation.swap(k, max_row);
+
+            let pivot = lu[k][k];
+            if pivot.abs() < 1e-12 || pivot.is_nan() {
+                return None;
             }
-            let factor = m[k][i];
-            for j in i..=n {
-                m[k][j] -= factor * m[i][j];
+            let (done, rest) = lu.split_at_mut(k + 1);
+            let pivot_row = &done[k];
+            for row in rest {
+                let factor = row[k] / pivot;
+                row[k] = factor;
+                if factor == 0.0 {
+                    continue;
+                }
+                for (x, p) in
+                    row[k + 1..].iter_mut().zip(&pivot_row[k + 1..])
+                {
+                    *x -= factor * p;
+                }
             }
         }
+        Some(Lu { lu, permutation })
     }
 
-    m.iter().map(|row| row[n]).collect()
+    /// Solution of `A·x = b`
+    pub fn solve(&self, b: &[f64]) -> Vec<f64> {
+        let n = b.len();
+        let mut x: Vec<f64> =
+            self.permutation.iter().map(|&i| b[i]).collect();
+        for i in 0..n {
+            let sum: f64 =
+                (0..i).map(|k| self.lu[i][k] * x[k]).sum();
+            x[i] -= sum;
+        }
+        for i in (0..n).rev() {
+            let sum: f64 =
+                (i + 1..n).map(|j| self.lu[i][j] * x[j]).sum();
+            x[i] = (x[i] - sum) / self.lu[i][i];
+        }
+        x
+    }
 }
 
 // Jacobian approximation
+// All perturbed states are passed to the model in one batch
 pub fn approx_jacobian(
-    model: &Model,
+    model: &dyn Rhs,
     t: f64,
-    y: &Vec<f64>,
-    pars: &Vec<f64>,
+    y: &[f64],
+    pars: &[f64],
     eps: f64,
-) -> Vec<Vec<f64>> {
+    stats: &mut Statistics,
+) -> Result<Vec<Vec<f64>>, SolverError> {
     let n = y.len();
-    let f0 = model(t, y, pars);
+    stats.jacobian_evaluations += 1;
+    stats.rhs_evaluations += n + 1;
+    let f0 = model.eval(t, y, pars)?;
+    let steps: Vec<f64> =
+        y.iter().map(|yj| eps * yj.abs().max(1.0)).collect();
+    let perturbed: Vec<Vec<f64>> = (0..n)
+        .map(|j| {
+            let mut y_perturbed = y.to_vec();
+            y_perturbed[j] += steps[j];
+            y_perturbed
+        })
+        .collect();
+    let f1 = model.eval_batch(t, &perturbed, pars)?;
+    if f1.len() != n {
+        return Err(SolverError::DimensionMismatch {
+            t,
+            quantity: "batch results",
+            expected: n,
+            found: f1.len(),
+        });
+    }
+
     let mut jac = vec![vec![0.0; n]; n];
-    let mut y_perturbed = y.clone();
+    for (j, (f1, h)) in f1.iter().zip(&steps).enumerate() {
+        for (row, (f1i, f0i)) in
+            jac.iter_mut().zip(f1.iter().zip(&f0))
+        {
+            row[j] = (f1i - f0i) / h;
+        }
+    }
 
-    for j in 0..n {
-        let yj = y[j];
-        let h = eps * yj.abs().max(1.0);
-        y_perturbed[j] += h;
-        let f1 = model(t, &y_perturbed, pars);
+    Ok(jac)
+}
 
-        for i in 0..n {
-            jac[i][j] = (f1[i] - f0[i]) / h;
+// Exact Jacobian of the model if it has one, the finite
+// difference approximation otherwise
+pub fn jacobian(
+    model: &dyn Rhs,
+    t: f64,
+    y: &[f64],
+    pars: &[f64],
+    stats: &mut Statistics,
+) -> Result<Vec<Vec<f64>>, SolverError> {
+    match model.jacobian(t, y, pars) {
+        Some(jac) => {
+            stats.jacobian_evaluations += 1;
+            jac
         }
+        None => approx_jacobian(model, t, y, pars, 1e-8, stats),
     }
-
-    jac
 }
 
 // Newton-Raphson solver for IRK stages
+#[allow(clippy::too_many_arguments)]
 pub fn solve_stages(
-    model: &Model,
-    y: &Vec<f64>,
+    model: &dyn Rhs,
+    y: &[f64],
     t: f64,
-    pars: &Vec<f64>,
+    pars: &[f64],
     h: f64,
-    a: &Vec<Vec<f64>>,
-    c: &Vec<f64>,
+    a: &[Vec<f64>],
+    c: &[f64],
     s: usize,
     rtol: f64,
     max_iter: i64,
-) -> Vec<Vec<f64>> {
+    newton: &mut Newton,
+    stats: &mut Statistics,
+) -> Result<Vec<Vec<f64>>, SolverError> {
     let n = y.len();
     let mut k = vec![vec![0.0; n]; s];
+    newton.start(h);
+    // Largest update of the previous iteration
+    let mut previous: Option<f64> = None;
 
-    for iter in 0..max_iter {
+    for _iter in 0..max_iter {
         let mut max_err: f64 = 0.0;
+        let mut max_update: f64 = 0.0;
+        stats.newton_iterations += 1;
 
         for i in 0..s {
             let ti = t + c[i] * h;
-            let mut yi = y.clone();
+            let mut yi = y.to_vec();
             for j in 0..s {
                 let scaled = scale_vec(&k[j], a[i][j] * h);
                 for l in 0..n {
@@ -117,33 +182,53 @@ pub fn solve_stages(
                 }
             }
 
-            let f_eval = model(ti, &yi, pars);
-            let res = sub_vec(&k[i], &f_eval);
-            max_err = max_err.max(max_norm(&res));
-
-            let mut jac =
-                approx_jacobian(model, ti, &yi, pars, 1e-8);
-            let aii = a[i][i];
-            for r in 0..n {
-                for c2 in 0..n {
-                    jac[r][c2] *= -h * aii;
-                }
-                jac[r][r] += 1.0;
+            stats.rhs_evaluations += 1;
+            let f_eval = model.eval(ti, &yi, pars)?;
+            if f_eval.len() != n {
+                return Err(SolverError::DimensionMismatch {
+                    t: ti,
+                    quantity: "derivatives",
+                    expected: n,
+                    found: f_eval.len(),
+                });
             }
-
-            let dk = solve_linear(&jac, &res);
+            let res = sub_vec(&k[i], &f_eval);
+            let err = max_norm(&res)
+                .ok_or(SolverError::NanDetected { t: ti })?;
+            max_err = max_err.max(err);
+
+            let dk = newton.solve(
+                model,
+                ti,
+                &yi,
+                pars,
+                h * a[i][i],
+                &res,
+                stats,
+            )?;
+            max_update = max_update.max(
+                max_norm(&dk)
+                    .ok_or(SolverError::NanDetected { t: ti })?,
+            );
             for l in 0..n {
                 k[i][l] -= dk[l];
             }
         }
 
         if max_err < rtol {
-            break;
+            newton.converged();
+            return Ok(k);
         }
-        if iter == max_iter - 1 {
-            eprintln!("Newton-Raphson did not converge");
+        if let Some(previous) = previous.filter(|&p| p > 0.0)
+            && newton.diverges(max_update / previous)
+        {
+            break;
         }
+        previous = Some(max_update);
     }
 
-    k
+    Err(SolverError::NewtonFailure {
+        t,
+        iterations: max_iter,
+    })
 }
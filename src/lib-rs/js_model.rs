@@ -0,0 +1,107 @@
+use js_sys::{Array, Float64Array, Function};
+use wasm_bindgen::{JsCast, JsValue};
+
+use crate::{Rhs, SolverError};
+
+/// Right hand side written in JavaScript
+///
+/// The function is called as `rhs(t, y, p)` and has to return
+/// the derivatives as an array or `Float64Array`. If `batched`
+/// is set, the states of a finite difference Jacobian are
+/// passed in one call as `rhs(t, ys, p, m)` where `ys` holds
+/// `m` states one after another. The result is expected in the
+/// same layout.
+pub struct JsModel {
+    function: Function,
+    batched: bool,
+}
+
+impl JsModel {
+    pub fn new(function: Function, batched: bool) -> Self {
+        JsModel { function, batched }
+    }
+
+    fn apply(
+        &self,
+        t: f64,
+        args: &Array,
+    ) -> Result<Vec<f64>, SolverError> {
+        let result = self
+            .function
+            .apply(&JsValue::NULL, args)
+            .map_err(|error| SolverError::ModelFailure {
+                t,
+                message: describe(&error),
+            })?;
+        if !(Array::is_array(&result)
+            || result.is_instance_of::<Float64Array>())
+        {
+            return Err(SolverError::ModelFailure {
+                t,
+                message: format!(
+                    "expected an array of derivatives, got {}",
+                    describe(&result)
+                ),
+            });
+        }
+        Ok(Float64Array::new(&result).to_vec())
+    }
+}
+
+impl Rhs for JsModel {
+    fn eval(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        let args = Array::of3(
+            &JsValue::from_f64(time),
+            &Float64Array::from(values),
+            &Float64Array::from(pars),
+        );
+        self.apply(time, &args)
+    }
+
+    fn eval_batch(
+        &self,
+        time: f64,
+        values: &[Vec<f64>],
+        pars: &[f64],
+    ) -> Result<Vec<Vec<f64>>, SolverError> {
+        if !self.batched || values.is_empty() {
+            return values
+                .iter()
+                .map(|y| self.eval(time, y, pars))
+                .collect();
+        }
+        let m = values.len();
+        let n = values[0].len();
+        let args = Array::of4(
+            &JsValue::from_f64(time),
+            &Float64Array::from(&values.concat()[..]),
+            &Float64Array::from(pars),
+            &JsValue::from_f64(m as f64),
+        );
+        let flat = self.apply(time, &args)?;
+        if flat.len() != m * n {
+            return Err(SolverError::DimensionMismatch {
+                t: time,
+                quantity: "batched derivatives",
+                expected: m * n,
+                found: flat.len(),
+            });
+        }
+        Ok(flat.chunks(n.max(1)).map(<[f64]>::to_vec).collect())
+    }
+}
+
+/// Message of a thrown error or a printout of any other value
+fn describe(value: &JsValue) -> String {
+    match value.dyn_ref::<js_sys::Error>() {
+        Some(error) => String::from(error.message()),
+        None => value
+            .as_string()
+            .unwrap_or_else(|| format!("{value:?}")),
+    }
+}
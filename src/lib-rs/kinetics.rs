@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use crate::expr::{Expr, Tape, parse_expr};
+
+/// Rate law template, a function of named arguments
+///
+/// Text models call a law like a built-in function, e.g.
+/// `michaelis_menten(s, vmax, km)`, which inlines its body with
+/// the arguments. [`crate::network::ReactionNetwork`] compiles
+/// a law together with its partial derivatives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KineticLaw {
+    pub name: &'static str,
+    pub arguments: &'static [&'static str],
+    /// Rate in the syntax of [`crate::expr::parse_expr`]
+    pub body: &'static str,
+    pub description: &'static str,
+}
+
+pub const KINETIC_LAWS: &[KineticLaw] = &[
+    KineticLaw {
+        name: "mass_action_1",
+        arguments: &["s", "k"],
+        body: "k * s",
+        description: "irreversible mass action with one substrate",
+    },
+    KineticLaw {
+        name: "mass_action_2",
+        arguments: &["s1", "s2", "k"],
+        body: "k * s1 * s2",
+        description: "irreversible mass action with two substrates",
+    },
+    KineticLaw {
+        name: "reversible_mass_action_1_1",
+        arguments: &["s", "p", "kf", "kr"],
+        body: "kf * s - kr * p",
+        description: "reversible mass action `s <-> p`",
+    },
+    KineticLaw {
+        name: "reversible_mass_action_2_1",
+        arguments: &["s1", "s2", "p", "kf", "kr"],
+        body: "kf * s1 * s2 - kr * p",
+        description: "reversible mass action `s1 + s2 <-> p`",
+    },
+    KineticLaw {
+        name: "michaelis_menten",
+        arguments: &["s", "vmax", "km"],
+        body: "vmax * s / (km + s)",
+        description: "irreversible Michaelis-Menten",
+    },
+    KineticLaw {
+        name: "reversible_michaelis_menten",
+        arguments: &["s", "p", "vmax_f", "vmax_r", "km_s", "km_p"],
+        body: "(vmax_f * s / km_s - vmax_r * p / km_p) \
+               / (1 + s / km_s + p / km_p)",
+        description: "reversible Michaelis-Menten with the maximal \
+                      rates of both directions",
+    },
+    KineticLaw {
+        name: "hill",
+        arguments: &["s", "vmax", "k_half", "n"],
+        body: "vmax * s^n / (k_half^n + s^n)",
+        description: "Hill kinetics with half saturation at `k_half`",
+    },
+    KineticLaw {
+        name: "ordered_bi_bi",
+        arguments: &["a", "b", "vmax", "ki_a", "km_a", "km_b"],
+        body: "vmax * a * b / (ki_a * km_b + km_b * a + km_a * b + a * b)",
+        description: "forward rate of the ordered bi-bi mechanism, \
+                      `a` binds first",
+    },
+    KineticLaw {
+        name: "random_bi_bi",
+        arguments: &["a", "b", "vmax", "k_a", "k_b", "alpha"],
+        body: "vmax * a * b / (alpha * k_a * k_b + alpha * k_b * a \
+               + alpha * k_a * b + a * b)",
+        description: "forward rate of the rapid equilibrium random \
+                      bi-bi mechanism, `alpha` couples both bindings",
+    },
+    KineticLaw {
+        name: "mwc",
+        arguments: &["s", "vmax", "k_r", "k_t", "l", "n"],
+        body: "vmax * (s / k_r * (1 + s / k_r)^(n - 1) \
+               + l * s / k_t * (1 + s / k_t)^(n - 1)) \
+               / ((1 + s / k_r)^n + l * (1 + s / k_t)^n)",
+        description: "generalised Monod-Wyman-Changeux model with \
+                      `n` sites, the dissociation constants of the \
+                      relaxed and tense states and their ratio `l` \
+                      without substrate",
+    },
+    KineticLaw {
+        name: "convenience_1_1",
+        arguments: &[
+            "s", "p", "enzyme", "kcat_f", "kcat_r", "km_s", "km_p",
+        ],
+        body: "enzyme * (kcat_f * s / km_s - kcat_r * p / km_p) \
+               / (1 + s / km_s + p / km_p)",
+        description: "convenience kinetics `s <-> p`",
+    },
+    KineticLaw {
+        name: "convenience_2_2",
+        arguments: &[
+            "a", "b", "p", "q", "enzyme", "kcat_f", "kcat_r",
+            "km_a", "km_b", "km_p", "km_q",
+        ],
+        body: "enzyme * (kcat_f * a / km_a * b / km_b \
+               - kcat_r * p / km_p * q / km_q) \
+               / ((1 + a / km_a) * (1 + b / km_b) \
+               + (1 + p / km_p) * (1 + q / km_q) - 1)",
+        description: "convenience kinetics `a + b <-> p + q`",
+    },
+    KineticLaw {
+        name: "atp_synthase_pmf",
+        arguments: &[
+            "atp",
+            "adp",
+            "ph_lumen",
+            "ph_stroma",
+            "kf",
+            "hpr",
+            "delta_g0_atp",
+            "pi_mol",
+            "rt",
+        ],
+        body: "kf * (adp - atp / (pi_mol * exp((hpr * rt * ln(10) \
+               * (ph_stroma - ph_lumen) - delta_g0_atp) / rt)))",
+        description: "ATP synthase driven by the proton gradient, \
+                      `hpr` protons per ATP, relaxing towards the \
+                      equilibrium given by the pH difference as in \
+                      `models::npq`",
+    },
+];
+
+/// Law of the library with this name
+pub fn kinetic_law(name: &str) -> Option<&'static KineticLaw> {
+    KINETIC_LAWS.iter().find(|law| law.name == name)
+}
+
+impl KineticLaw {
+    /// Rate as a function of the argument names
+    pub fn expr(&self) -> Expr {
+        parse_expr(self.body).expect("library rate laws parse")
+    }
+
+    /// Rate with `args`, in the order of the arguments, in
+    /// place of the argument names
+    pub fn call(&self, args: &[Expr]) -> Expr {
+        self.expr().substitute(&|name| {
+            self.arguments
+                .iter()
+                .position(|&arg| arg == name)
+                .map(|i| args[i].clone())
+        })
+    }
+
+    /// Partial derivative of the rate by each argument
+    pub fn partials(&self) -> Vec<Expr> {
+        let rate = self.expr();
+        self.arguments
+            .iter()
+            .map(|arg| rate.derivative(arg))
+            .collect()
+    }
+
+    /// Rate, and with `gradient` also the partial derivatives
+    /// selected by it, evaluated from argument values
+    pub(crate) fn compile(
+        &self,
+        gradient: &[usize],
+    ) -> CompiledLaw {
+        let slots: HashMap<&str, usize> = self
+            .arguments
+            .iter()
+            .enumerate()
+            .map(|(i, &arg)| (arg, i))
+            .collect();
+        let mut rate = Tape::default();
+        rate.expr(&self.expr(), &slots);
+        let partials = self.partials();
+        let mut derivatives = Tape::default();
+        for &i in gradient {
+            derivatives.expr(&partials[i], &slots);
+        }
+        CompiledLaw { rate, derivatives }
+    }
+}
+
+/// Tapes of a law with the arguments in slots
+pub(crate) struct CompiledLaw {
+    pub rate: Tape,
+    pub derivatives: Tape,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partials_match_finite_differences() {
+        for law in KINETIC_LAWS {
+            let args: Vec<f64> = (0..law.arguments.len())
+                .map(|i| 0.6 + 0.37 * i as f64)
+                .collect();
+            let all: Vec<usize> = (0..args.len()).collect();
+            let compiled = law.compile(&all);
+            let rate = |args: &[f64]| {
+                compiled.rate.run(&mut args.to_vec())[0]
+            };
+            let partials =
+                compiled.derivatives.run(&mut args.clone());
+            for (i, found) in partials.iter().enumerate() {
+                let h = 1e-6 * args[i];
+                let (mut up, mut down) =
+                    (args.clone(), args.clone());
+                up[i] += h;
+                down[i] -= h;
+                let expected =
+                    (rate(&up) - rate(&down)) / (2.0 * h);
+                assert!(
+                    (found - expected).abs()
+                        < 1e-6 * expected.abs().max(1.0),
+                    "{} by {}: {found} != {expected}",
+                    law.name,
+                    law.arguments[i]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn calls_inline_the_arguments() {
+        let law = kinetic_law("michaelis_menten").unwrap();
+        let call = law.call(&[
+            Expr::Name("glucose".to_string()),
+            Expr::Number(2.0),
+            Expr::Name("km".to_string()),
+        ]);
+        assert_eq!(
+            call.to_string(),
+            "2 * glucose / (km + glucose)"
+        );
+        assert!(kinetic_law("unknown").is_none());
+    }
+}
@@ -1,19 +1,162 @@
+pub mod autodiff;
+pub mod conservation;
+pub mod dense;
+pub mod error;
+pub mod events;
 pub mod explicit;
+pub mod expr;
+pub mod flat;
 pub mod implicit;
+pub mod js_model;
+pub mod kinetics;
 pub mod models;
+pub mod mxlpy;
+pub mod network;
+pub mod pam;
+pub mod protocol;
+pub mod sbml;
+pub mod simulate;
+pub mod stochastic;
+pub mod tolerance;
+
+use std::ops::AddAssign;
 
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 
+pub use crate::error::SolverError;
+use crate::events::EventRecord;
 use crate::implicit::Kvaerno45Options;
+use crate::protocol::{ProtocolIntegration, Segment};
+
+type Model = fn(
+    time: f64,
+    values: &[f64],
+    pars: &[f64],
+) -> Result<Vec<f64>, SolverError>;
+
+/// Right hand side of an ODE system as used by the solvers
+///
+/// Implemented for every matching function or closure. Models
+/// with a high cost per call, e.g. ones calling into
+/// JavaScript, can override `eval_batch`.
+pub trait Rhs {
+    fn eval(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<Vec<f64>, SolverError>;
+
+    /// Evaluate several states at the same time point, used for
+    /// the finite difference Jacobian
+    fn eval_batch(
+        &self,
+        time: f64,
+        values: &[Vec<f64>],
+        pars: &[f64],
+    ) -> Result<Vec<Vec<f64>>, SolverError> {
+        values.iter().map(|y| self.eval(time, y, pars)).collect()
+    }
+
+    /// Exact Jacobian `J[i][j] = ∂f_i/∂y_j`, if the model knows
+    /// it; implicit solvers fall back to finite differences on
+    /// `None`
+    fn jacobian(
+        &self,
+        _time: f64,
+        _values: &[f64],
+        _pars: &[f64],
+    ) -> Option<Result<Vec<Vec<f64>>, SolverError>> {
+        None
+    }
+}
+
+impl<F> Rhs for F
+where
+    F: Fn(f64, &[f64], &[f64]) -> Result<Vec<f64>, SolverError>,
+{
+    fn eval(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        self(time, values, pars)
+    }
+}
+
+/// How an integration ended
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum ReturnCode {
+    /// `t_end` was reached
+    #[default]
+    Success,
+    /// Stopped early because `max_steps` was exhausted
+    MaxStepsReached,
+    /// Stopped early because the step size fell below `h_min`
+    StepSizeUnderflow,
+    /// Stopped by an event with `EventAction::Terminate`
+    Terminated,
+}
+
+/// Work counters collected during an integration
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Default, PartialEq,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Statistics {
+    pub accepted_steps: usize,
+    pub rejected_steps: usize,
+    pub rhs_evaluations: usize,
+    pub jacobian_evaluations: usize,
+    pub lu_factorizations: usize,
+    pub newton_iterations: usize,
+    pub newton_failures: usize,
+    /// GMRES iterations of the matrix-free Newton iteration
+    pub linear_iterations: usize,
+}
+
+impl AddAssign for Statistics {
+    fn add_assign(&mut self, other: Self) {
+        self.accepted_steps += other.accepted_steps;
+        self.rejected_steps += other.rejected_steps;
+        self.rhs_evaluations += other.rhs_evaluations;
+        self.jacobian_evaluations += other.jacobian_evaluations;
+        self.lu_factorizations += other.lu_factorizations;
+        self.newton_iterations += other.newton_iterations;
+        self.newton_failures += other.newton_failures;
+        self.linear_iterations += other.linear_iterations;
+    }
+}
 
-type Model =
-    fn(time: f64, values: &[f64], pars: &[f64]) -> Vec<f64>;
+/// Raw integrator steps, kept next to interpolated output
+#[derive(Serialize, Deserialize)]
+pub struct Steps {
+    time: Vec<f64>,
+    values: Vec<Vec<f64>>,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Integration {
     time: Vec<f64>,
     values: Vec<Vec<f64>>,
+    status: ReturnCode,
+    stats: Statistics,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    steps: Option<Steps>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    events: Vec<EventRecord>,
+}
+
+impl Integration {
+    /// `true` if the integration reached `t_end`
+    pub fn is_complete(&self) -> bool {
+        self.status == ReturnCode::Success
+    }
 }
 
 #[wasm_bindgen]
@@ -22,12 +165,14 @@ pub fn wa_lotka_volterra(
     pars: Vec<f64>,
 ) -> Result<JsValue, JsValue> {
     let integration = explicit::euler(
-        models::lotka_volterra,
+        &models::lotka_volterra,
         y0,
         pars,
         0.01,
+        0.0,
         100.0,
-    );
+        &[],
+    )?;
 
     serde_wasm_bindgen::to_value(&integration).map_err(|e| {
         JsValue::from_str(&format!("Serialization error: {}", e))
@@ -39,18 +184,307 @@ pub fn wa_npq(
     pars: Vec<f64>,
 ) -> Result<JsValue, JsValue> {
     let integration = implicit::kvaerno45(
-        models::npq,
+        models::NPQ.autodiff,
         y0,
         pars,
+        0.0,
         50.0,
         Kvaerno45Options {
             rtol: 1e-4,
-            atol: 1e-4,
+            atol: 1e-4.into(),
             ..Default::default()
         }, // implicit::Kvaerno45Options::default(),
-    );
+    )?;
+
+    serde_wasm_bindgen::to_value(&integration).map_err(|e| {
+        JsValue::from_str(&format!("Serialization error: {}", e))
+    })
+}
+
+/// Run the built-in model `model_name` through a list of
+/// `{ tEnd, pars, reset? }` segments
+///
+/// `options` are those of [`wa_simulate`] without `tEnd`, e.g.
+/// `{ solver: "euler", stepSize: 0.01 }`. Empty `y0` uses the
+/// defaults of the model.
+#[wasm_bindgen]
+pub fn wa_simulate_protocol(
+    model_name: &str,
+    y0: Vec<f64>,
+    protocol: JsValue,
+    options: JsValue,
+) -> Result<JsValue, JsValue> {
+    let model = model_from_js(model_name)?;
+    let segments = segments_from_js(protocol)?;
+    let options: simulate::ProtocolOptions =
+        serde_wasm_bindgen::from_value(options).map_err(|e| {
+            JsValue::from_str(&format!("Invalid options: {}", e))
+        })?;
+    let integration =
+        simulate::simulate_protocol(model, y0, &segments, options)?;
+
+    serde_wasm_bindgen::to_value(&integration).map_err(|e| {
+        JsValue::from_str(&format!("Serialization error: {}", e))
+    })
+}
+
+/// Run the NPQ model through a list of `{ tEnd, pars, reset? }`
+/// segments starting at `t = 0`
+#[wasm_bindgen]
+pub fn wa_npq_protocol(
+    y0: Vec<f64>,
+    protocol: JsValue,
+) -> Result<JsValue, JsValue> {
+    let segments = segments_from_js(protocol)?;
+    let integration = npq_protocol(y0, &segments)?;
+
+    serde_wasm_bindgen::to_value(&integration).map_err(|e| {
+        JsValue::from_str(&format!("Serialization error: {}", e))
+    })
+}
+
+/// Simulate a PAM experiment with the NPQ model
+///
+/// Saturating pulses of `pulse_ppfd` are inserted into the
+/// protocol at `pulse_times`, keeping the other parameters of
+/// the segment they fall into. The result additionally holds
+/// the values of `models::NPQ_DERIVED` at every time point and
+/// the Fm', Fs, NPQ and ΦPSII readout of every pulse.
+#[wasm_bindgen]
+pub fn wa_npq_pam(
+    y0: Vec<f64>,
+    protocol: JsValue,
+    pulse_times: Vec<f64>,
+    pulse_duration: f64,
+    pulse_ppfd: f64,
+) -> Result<JsValue, JsValue> {
+    let segments = segments_from_js(protocol)?;
+    let pulsed = pam::insert_pulses(
+        &segments,
+        0.0,
+        &pulse_times,
+        pulse_duration,
+        |pars| models::NpqParameters::with_ppfd(pars, pulse_ppfd),
+    )?;
+    let result = npq_protocol(y0, &pulsed.segments)?;
+    let derived =
+        result.derived(&pulsed.segments, models::npq_derived)?;
+    let fluorescence: Vec<f64> =
+        derived.iter().map(|d| d[0]).collect();
+    let pulses =
+        pam::pulse_readouts(&result, &pulsed, &fluorescence)?;
+    let integration = pam::PamIntegration {
+        protocol: result,
+        derived,
+        pulses,
+    };
+
+    serde_wasm_bindgen::to_value(&integration).map_err(|e| {
+        JsValue::from_str(&format!("Serialization error: {}", e))
+    })
+}
+
+/// Name, default, unit and description of every NPQ parameter
+#[wasm_bindgen]
+pub fn wa_npq_parameters() -> Result<JsValue, JsValue> {
+    serde_wasm_bindgen::to_value(models::NPQ_PARAMETERS).map_err(
+        |e| {
+            JsValue::from_str(&format!(
+                "Serialization error: {}",
+                e
+            ))
+        },
+    )
+}
+
+/// Full NPQ parameter vector from an object of overrides, e.g.
+/// `{ ppfd: 100, kcat_b6f: 3 }`, with defaults for the rest
+#[wasm_bindgen]
+pub fn wa_npq_pars(
+    overrides: JsValue,
+) -> Result<Vec<f64>, JsValue> {
+    let pars: models::NpqParameters =
+        serde_wasm_bindgen::from_value(overrides).map_err(|e| {
+            JsValue::from_str(&format!("Invalid parameters: {}", e))
+        })?;
+    Ok(pars.to_vec())
+}
+
+/// Integrate the built-in model `model_name`
+///
+/// `options` selects the solver and its settings, e.g.
+/// `{ solver: "euler", tEnd: 100, stepSize: 0.01 }` or
+/// `{ solver: "kvaerno45", tEnd: 50, rtol: 1e-4, output: {
+/// uniform: 500 } }`. Empty `y0` or `pars` use the defaults of
+/// the model.
+#[wasm_bindgen]
+pub fn wa_simulate(
+    model_name: &str,
+    y0: Vec<f64>,
+    pars: Vec<f64>,
+    options: JsValue,
+) -> Result<JsValue, JsValue> {
+    let integration =
+        simulate_from_js(model_name, y0, pars, options)?;
+
+    serde_wasm_bindgen::to_value(&integration).map_err(|e| {
+        JsValue::from_str(&format!("Serialization error: {}", e))
+    })
+}
+
+/// Like [`wa_simulate`], but keeps the result in WASM memory
+/// as flat arrays in the given `layout`
+#[wasm_bindgen]
+pub fn wa_simulate_flat(
+    model_name: &str,
+    y0: Vec<f64>,
+    pars: Vec<f64>,
+    options: JsValue,
+    layout: flat::Layout,
+) -> Result<flat::FlatIntegration, JsValue> {
+    let integration =
+        simulate_from_js(model_name, y0, pars, options)?;
+    Ok(flat::FlatIntegration::new(integration, layout))
+}
+
+/// Integrate a model written in JavaScript
+///
+/// `rhs(t, y, p)` returns the derivatives as an array. With
+/// `batched`, the finite difference Jacobian of implicit
+/// solvers calls `rhs(t, ys, p, m)` once with `m` states
+/// concatenated in `ys` and expects the derivatives in the same
+/// layout. Exceptions thrown by `rhs` end the integration with
+/// a `modelFailure` error.
+#[wasm_bindgen]
+pub fn wa_simulate_js(
+    rhs: js_sys::Function,
+    y0: Vec<f64>,
+    pars: Vec<f64>,
+    options: JsValue,
+    batched: bool,
+) -> Result<JsValue, JsValue> {
+    let options: simulate::SimulateOptions =
+        serde_wasm_bindgen::from_value(options).map_err(|e| {
+            JsValue::from_str(&format!("Invalid options: {}", e))
+        })?;
+    let model = js_model::JsModel::new(rhs, batched);
+    let integration =
+        simulate::simulate_rhs(&model, y0, pars, options, vec![])?;
 
     serde_wasm_bindgen::to_value(&integration).map_err(|e| {
         JsValue::from_str(&format!("Serialization error: {}", e))
     })
 }
+
+/// Parse and compile a model written as text, see
+/// [`expr::parse_model`] for the format
+///
+/// The returned model can be simulated repeatedly without
+/// parsing it again.
+#[wasm_bindgen]
+pub fn wa_compile_model(
+    text: &str,
+) -> Result<expr::CompiledModel, JsValue> {
+    let model = expr::parse_model(text)?;
+    Ok(expr::CompiledModel::new(model)?)
+}
+
+/// Read an SBML Level 3 Core document, see
+/// [`sbml::import_sbml`] for what is supported
+#[wasm_bindgen]
+pub fn wa_import_sbml(
+    text: &str,
+) -> Result<expr::CompiledModel, JsValue> {
+    let model = sbml::import_sbml(text)?;
+    Ok(expr::CompiledModel::new(model)?)
+}
+
+/// Read a model in the MxlPy interchange format, see
+/// [`mxlpy::MxlpyModel`]
+#[wasm_bindgen]
+pub fn wa_load_mxlpy(
+    text: &str,
+) -> Result<expr::CompiledModel, JsValue> {
+    let model = mxlpy::read_mxlpy(text)?;
+    Ok(expr::CompiledModel::new(model)?)
+}
+
+/// A built-in model as an SBML document, to reproduce results
+/// in other simulators, see [`sbml::export_sbml`]
+#[wasm_bindgen]
+pub fn wa_export_sbml(name: &str) -> Result<String, JsValue> {
+    let info = models::model_info(name).ok_or_else(|| {
+        JsValue::from_str(&format!("Unknown model: {}", name))
+    })?;
+    Ok(sbml::export_sbml(&info.expr_model(), info.name)?)
+}
+
+/// Names of all built-in models
+#[wasm_bindgen]
+pub fn wa_models() -> Vec<String> {
+    models::MODELS
+        .iter()
+        .map(|model| model.name.to_string())
+        .collect()
+}
+
+/// Variables, parameters and derived values of a built-in model
+/// with their names, defaults and units
+#[wasm_bindgen]
+pub fn wa_model_info(name: &str) -> Result<JsValue, JsValue> {
+    let info = models::model_info(name).ok_or_else(|| {
+        JsValue::from_str(&format!("Unknown model: {}", name))
+    })?;
+    serde_wasm_bindgen::to_value(info).map_err(|e| {
+        JsValue::from_str(&format!("Serialization error: {}", e))
+    })
+}
+
+fn simulate_from_js(
+    model_name: &str,
+    y0: Vec<f64>,
+    pars: Vec<f64>,
+    options: JsValue,
+) -> Result<Integration, JsValue> {
+    let model = model_from_js(model_name)?;
+    let options: simulate::SimulateOptions =
+        serde_wasm_bindgen::from_value(options).map_err(|e| {
+            JsValue::from_str(&format!("Invalid options: {}", e))
+        })?;
+    Ok(simulate::simulate(model, y0, pars, options)?)
+}
+
+fn model_from_js(
+    model_name: &str,
+) -> Result<&'static models::ModelInfo, JsValue> {
+    models::model_info(model_name).ok_or_else(|| {
+        JsValue::from_str(&format!("Unknown model: {}", model_name))
+    })
+}
+
+fn segments_from_js(
+    protocol: JsValue,
+) -> Result<Vec<Segment>, JsValue> {
+    serde_wasm_bindgen::from_value(protocol).map_err(|e| {
+        JsValue::from_str(&format!("Invalid protocol: {}", e))
+    })
+}
+
+fn npq_protocol(
+    y0: Vec<f64>,
+    segments: &[Segment],
+) -> Result<ProtocolIntegration, SolverError> {
+    simulate::simulate_protocol(
+        &models::NPQ,
+        y0,
+        segments,
+        simulate::ProtocolOptions {
+            t_start: 0.0,
+            solver: simulate::Solver::Kvaerno45(Kvaerno45Options {
+                rtol: 1e-4,
+                atol: 1e-4.into(),
+                ..Default::default()
+            }),
+        },
+    )
+}
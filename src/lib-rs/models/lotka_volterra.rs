@@ -1,19 +1,107 @@
+use num_traits::Float;
+
+use super::{ModelInfo, Parameter, Variable};
+use crate::SolverError;
+use crate::autodiff::{AutoDiff, GenericRhs};
+
+pub const LOTKA_VOLTERRA: ModelInfo = ModelInfo {
+    name: "lotka_volterra",
+    variables: &[
+        Variable {
+            name: "prey",
+            initial: 10.0,
+            unit: "1",
+            description: "Prey population",
+        },
+        Variable {
+            name: "predator",
+            initial: 10.0,
+            unit: "1",
+            description: "Predator population",
+        },
+    ],
+    parameters: &[
+        Parameter {
+            name: "alpha",
+            default: 0.1,
+            unit: "1/time",
+            description: "Growth rate of the prey",
+        },
+        Parameter {
+            name: "beta",
+            default: 0.02,
+            unit: "1/time",
+            description: "Rate at which predators eat prey",
+        },
+        Parameter {
+            name: "gamma",
+            default: 0.4,
+            unit: "1/time",
+            description: "Death rate of the predators",
+        },
+        Parameter {
+            name: "delta",
+            default: 0.02,
+            unit: "1/time",
+            description: "Growth of predators per prey eaten",
+        },
+    ],
+    derived: &[],
+    rhs: lotka_volterra,
+    autodiff: &AutoDiff(LotkaVolterra),
+    derived_values: None,
+    leading_parameters: None,
+    equations: "
+derived prey_interaction = predator * prey
+reaction prey_growth: -> prey; alpha * prey
+reaction predation: prey -> ; beta * prey_interaction
+reaction predator_growth: -> predator; delta * prey_interaction
+reaction predator_death: predator -> ; gamma * predator
+",
+};
+
 /// Lotka-Volterra predator-prey model
 pub fn lotka_volterra(
-    _time: f64,
+    time: f64,
     variables: &[f64],
     parameters: &[f64],
-) -> Vec<f64> {
-    let [prey, pred] = variables else {
-        panic!("Expected exactly 2 variables");
-    };
-    let [alpha, beta, gamma, delta] = parameters else {
-        panic!("Expected exactly 4 parameters");
-    };
+) -> Result<Vec<f64>, SolverError> {
+    LotkaVolterra.eval_generic(time, variables, parameters)
+}
+
+/// [`lotka_volterra`] for any float type, e.g. to get its
+/// Jacobian with [`AutoDiff`]
+pub struct LotkaVolterra;
+
+impl GenericRhs for LotkaVolterra {
+    fn eval_generic<T: Float>(
+        &self,
+        time: T,
+        variables: &[T],
+        parameters: &[T],
+    ) -> Result<Vec<T>, SolverError> {
+        let t = time.to_f64().unwrap_or(f64::NAN);
+        let &[prey, pred] = variables else {
+            return Err(SolverError::DimensionMismatch {
+                t,
+                quantity: "variables",
+                expected: 2,
+                found: variables.len(),
+            });
+        };
+        let &[alpha, beta, gamma, delta] = parameters else {
+            return Err(SolverError::DimensionMismatch {
+                t,
+                quantity: "parameters",
+                expected: 4,
+                found: parameters.len(),
+            });
+        };
 
-    let prey_interaction = pred * prey;
-    let dprey_dt = alpha * prey - beta * prey_interaction;
-    let dpred_dt = delta * prey_interaction - gamma * pred;
+        let prey_interaction = pred * prey;
+        let dprey_dt = alpha * prey - beta * prey_interaction;
+        let dpred_dt = delta * prey_interaction - gamma * pred;
 
-    vec![dprey_dt, dpred_dt]
+        Ok(vec![dprey_dt, dpred_dt])
+    }
 }
@@ -1,5 +1,249 @@
 mod lotka_volterra;
 mod npq;
 
-pub use lotka_volterra::lotka_volterra;
-pub use npq::npq;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::expr::{ExprModel, Quantity, State, parse_model};
+use crate::{Model, Rhs, SolverError};
+pub use lotka_volterra::{
+    LOTKA_VOLTERRA, LotkaVolterra, lotka_volterra,
+};
+pub use npq::{
+    NPQ, NPQ_DERIVED, NPQ_PARAMETERS, Npq, NpqParameters, npq,
+    npq_derived,
+};
+
+/// Every built-in model
+pub const MODELS: &[ModelInfo] = &[LOTKA_VOLTERRA, NPQ];
+
+/// Look up a built-in model by its name
+pub fn model_info(name: &str) -> Option<&'static ModelInfo> {
+    MODELS.iter().find(|model| model.name == name)
+}
+
+/// Names, defaults and units of a model, in the order the
+/// model uses for its vectors
+#[derive(Serialize, Clone, Copy)]
+pub struct ModelInfo {
+    pub name: &'static str,
+    pub variables: &'static [Variable],
+    pub parameters: &'static [Parameter],
+    /// Names of the values returned by `derived_values`
+    pub derived: &'static [&'static str],
+    #[serde(skip)]
+    pub rhs: Model,
+    /// `rhs` with the exact Jacobian of
+    /// [`AutoDiff`](crate::autodiff::AutoDiff), which the
+    /// implicit solvers use instead of finite differences
+    #[serde(skip)]
+    pub autodiff: &'static dyn Rhs,
+    #[serde(skip)]
+    pub derived_values: Option<Model>,
+    /// Length of a shorter parameter vector the model also
+    /// takes, the leading parameters with defaults for the
+    /// rest, e.g. `[ppfd]` for NPQ
+    #[serde(skip)]
+    pub leading_parameters: Option<usize>,
+    /// Derived values, reactions and rate rules in the text
+    /// format of [`parse_model`], see [`ModelInfo::expr_model`]
+    #[serde(skip)]
+    pub equations: &'static str,
+}
+
+impl fmt::Debug for ModelInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModelInfo")
+            .field("name", &self.name)
+            .field("variables", &self.variables)
+            .field("parameters", &self.parameters)
+            .field("derived", &self.derived)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ModelInfo {
+    /// Check the lengths of a state and a parameter vector
+    /// before passing them to the model
+    pub fn check_dimensions(
+        &self,
+        values: &[f64],
+        pars: &[f64],
+        t: f64,
+    ) -> Result<(), SolverError> {
+        if values.len() != self.variables.len() {
+            return Err(SolverError::DimensionMismatch {
+                t,
+                quantity: "initial values",
+                expected: self.variables.len(),
+                found: values.len(),
+            });
+        }
+        if pars.len() != self.parameters.len()
+            && Some(pars.len()) != self.leading_parameters
+        {
+            return Err(SolverError::DimensionMismatch {
+                t,
+                quantity: "parameters",
+                expected: self.parameters.len(),
+                found: pars.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The model as expressions with the states and parameters
+    /// of [`variables`](Self::variables) and
+    /// [`parameters`](Self::parameters), e.g. to export it
+    pub fn expr_model(&self) -> ExprModel {
+        let mut model = parse_model(self.equations)
+            .expect("equations of built-in models parse");
+        model.states = self
+            .variables
+            .iter()
+            .map(|v| State {
+                name: v.name.to_string(),
+                value: v.initial,
+                unit: Some(v.unit.to_string()),
+                compartment: None,
+            })
+            .collect();
+        model.parameters = self
+            .parameters
+            .iter()
+            .map(|p| Quantity {
+                name: p.name.to_string(),
+                value: p.default,
+                unit: Some(p.unit.to_string()),
+            })
+            .collect();
+        model
+    }
+}
+
+/// Default initial value and meaning of a state variable
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Variable {
+    pub name: &'static str,
+    pub initial: f64,
+    pub unit: &'static str,
+    pub description: &'static str,
+}
+
+/// Default value and meaning of a model parameter
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Parameter {
+    pub name: &'static str,
+    pub default: f64,
+    pub unit: &'static str,
+    pub description: &'static str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rhs;
+    use crate::expr::CompiledModel;
+    use crate::stochastic::Random;
+
+    #[test]
+    fn metadata_matches_models() {
+        for model in MODELS {
+            let y0: Vec<f64> =
+                model.variables.iter().map(|v| v.initial).collect();
+            let pars: Vec<f64> = model
+                .parameters
+                .iter()
+                .map(|p| p.default)
+                .collect();
+            let dydt = (model.rhs)(0.0, &y0, &pars).unwrap();
+            assert_eq!(dydt.len(), y0.len(), "{}", model.name);
+            if let Some(derived_values) = model.derived_values {
+                let derived =
+                    derived_values(0.0, &y0, &pars).unwrap();
+                assert_eq!(derived.len(), model.derived.len());
+            }
+        }
+        assert_eq!(model_info("npq").unwrap().variables.len(), 8);
+        assert!(model_info("unknown").is_none());
+    }
+
+    #[test]
+    fn equations_match_models() {
+        let mut random = Random(40);
+        for model in MODELS {
+            let expr_model = model.expr_model();
+            let compiled =
+                CompiledModel::new(expr_model.clone()).unwrap();
+            let defaults: Vec<f64> = model
+                .parameters
+                .iter()
+                .map(|p| p.default)
+                .collect();
+            let initial: Vec<f64> =
+                model.variables.iter().map(|v| v.initial).collect();
+            // Away from the initial state and the defaults as
+            // well, so that every term is exercised
+            let mut cases = vec![
+                (initial.clone(), defaults.clone()),
+                (
+                    initial
+                        .iter()
+                        .map(|y| 0.9 * y + 0.05)
+                        .collect(),
+                    defaults.clone(),
+                ),
+            ];
+            for _ in 0..20 {
+                let y = initial
+                    .iter()
+                    .map(|y| {
+                        y * (0.5 + random.next())
+                            + 0.01 * random.next()
+                    })
+                    .collect();
+                let pars = defaults
+                    .iter()
+                    .map(|p| p * (0.8 + 0.4 * random.next()))
+                    .collect();
+                cases.push((y, pars));
+            }
+            for (y, pars) in cases {
+                let expected = (model.rhs)(0.0, &y, &pars).unwrap();
+                let found = compiled.eval(0.0, &y, &pars).unwrap();
+                for (e, f) in expected.iter().zip(&found) {
+                    assert!(e.is_finite(), "{}: {e}", model.name);
+                    assert!(
+                        (e - f).abs() <= 1e-9 * e.abs().max(1.0),
+                        "{}: {e} != {f} at {y:?}, {pars:?}",
+                        model.name
+                    );
+                }
+                let Some(derived_values) = model.derived_values
+                else {
+                    continue;
+                };
+                let expected =
+                    derived_values(0.0, &y, &pars).unwrap();
+                let values = compiled
+                    .derived_values(0.0, &y, &pars)
+                    .unwrap();
+                for (name, e) in model.derived.iter().zip(expected)
+                {
+                    let i = expr_model
+                        .derived
+                        .iter()
+                        .position(|d| d.name == *name)
+                        .unwrap();
+                    let f = values[i];
+                    assert!(
+                        (e - f).abs() <= 1e-9 * e.abs(),
+                        "{}: {name} {e} != {f}",
+                        model.name
+                    );
+                }
+            }
+        }
+    }
+}
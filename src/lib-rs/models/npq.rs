@@ -1,8 +1,354 @@
+use num_traits::Float;
+use serde::{Deserialize, Serialize};
+
+use super::{ModelInfo, Parameter, Variable};
+use crate::SolverError;
+use crate::autodiff::{AutoDiff, GenericRhs};
+
+/// Names of the values returned by [`npq_derived`]
+pub const NPQ_DERIVED: &[&str] = &["fluorescence", "pH_lumen", "Q"];
+
+/// [`npq`] in the text format of [`crate::expr::parse_model`]
+///
+/// The proton balance of the lumen depends on parameters, so
+/// every state has a rate rule and the rates are derived
+/// values. PSII uses the closed form of its quasi steady state.
+const NPQ_EQUATIONS: &str = "
+derived nadp = nadp_tot - nadph
+derived rt = gas_constant * temperature
+derived adp = a_p - atp
+derived d_g_p_h = gas_constant * temperature * ln(10)
+derived pH_lumen = -ln(0.00025 * protons_lumen) / ln(10)
+derived zeaxanthin = carotenoids_tot - violaxanthin
+derived ferredoxine_reduced = fd_tot - ferredoxine_oxidised
+derived plastocyanine_reduced = pc_tot - plastocyanine_oxidised
+derived plastoquinone_reduced = pq_tot - plastoquinone_oxidised
+derived psb_s_protonated = psbs_tot - psb_s_de_protonated
+derived light_harvesting_complex_protonated = lhc_tot - light_harvesting_complex
+derived Q = psb_s_de_protonated * violaxanthin * gamma0 + psb_s_de_protonated * zeaxanthin * gamma3 / (zeaxanthin + k_zsat) + psb_s_protonated * violaxanthin * gamma1 + psb_s_protonated * zeaxanthin * gamma2 / (zeaxanthin + k_zsat)
+derived keq_plastoquinone_reduced = exp((2 * e0_pq * faraday - 2 * e0_qa * faraday - 2 * d_g_p_h * p_h) / rt)
+derived psii_cross_section = light_harvesting_complex * (1 - static_ant_i - static_ant_ii) + static_ant_ii
+derived keq_atp_synthase = pi_mol * exp((hpr * d_g_p_h * (p_h - pH_lumen) - delta_g0_atp) / rt)
+derived keq_b6f = exp((2 * e0_pc * faraday - 2 * e0_pq * faraday + 2 * d_g_p_h * pH_lumen - 2 * d_g_p_h * (p_h - pH_lumen)) / rt)
+derived keq_fnr = exp((2 * e0_nadp * faraday - 2 * e0_fd * faraday - d_g_p_h * p_h) / rt)
+derived keq_pcp700 = exp((e0_p700 * faraday - e0_pc * faraday) / rt)
+derived keq_ferredoxin_reductase = exp((e0_fd * faraday - e0_fa * faraday) / rt)
+
+# Quasi steady state of PSII, B0 and B1 have an open, B2 and
+# B3 a closed reaction centre, B1 and B3 are excited
+derived light = ppfd * psii_cross_section
+derived k_quench = Q * k_h + k_h0
+derived k_decay_open = k_quench + k_f + k2
+derived k_decay_closed = k_quench + k_f
+derived b2_per_b0 = (light + k_pqred * plastoquinone_reduced / keq_plastoquinone_reduced - k_decay_closed * light / k_decay_open) / (k_pqred * plastoquinone_oxidised)
+derived b0 = psii_total / (1 + light / k_decay_open + b2_per_b0 * (1 + light / k_decay_closed))
+derived b1 = light * b0 / k_decay_open
+derived fluorescence = psii_cross_section * k_f * b0 / k_decay_open + psii_cross_section * k_f * b2_per_b0 * b0 / k_decay_closed
+
+derived a1 = psi_total / ((ferredoxine_reduced / (ferredoxine_oxidised * keq_ferredoxin_reductase) + 1) * (ppfd * (1 - psii_cross_section) / (plastocyanine_reduced * k_pcox) + plastocyanine_oxidised / (plastocyanine_reduced * keq_pcp700)) + ppfd * (1 - psii_cross_section) / (ferredoxine_oxidised * k_fdred) + 1)
+derived atp_synthase = atp_synthase_pmf(atp, adp, pH_lumen, p_h, kf_atp_synthase, hpr, delta_g0_atp, pi_mol, rt)
+derived b6f = max(-kcat_b6f, kcat_b6f * (plastocyanine_oxidised^2 * plastoquinone_reduced - plastocyanine_reduced^2 * plastoquinone_oxidised / keq_b6f))
+derived lhc_protonation = psb_s_de_protonated * kf_lhc_protonation * protons_lumen^kh_lhc_protonation / (protons_lumen^kh_lhc_protonation + (4000 * 10^(-ksat_lhc_protonation))^kh_lhc_protonation)
+derived lhc_deprotonation = psb_s_protonated * kf_lhc_deprotonation
+derived cyclic_electron_flow = ferredoxine_reduced^2 * plastoquinone_oxidised * kf_cyclic_electron_flow
+derived violaxanthin_deepoxidase = violaxanthin * kf_violaxanthin_deepoxidase * protons_lumen^kh_violaxanthin_deepoxidase / (protons_lumen^kh_violaxanthin_deepoxidase + (4000 * 10^(-ksat_violaxanthin_deepoxidase))^kh_violaxanthin_deepoxidase)
+derived zeaxanthin_epoxidase = zeaxanthin * kf_zeaxanthin_epoxidase
+derived fnr = e0_fnr * kcat_fnr * (nadp * (ferredoxine_reduced / km_fnr_ferredoxine_reduced)^2 / km_fnr_nadp - nadph * (ferredoxine_oxidised / km_fnr_ferredoxine_reduced)^2 / (keq_fnr * km_fnr_nadp)) / ((nadp / km_fnr_nadp + 1) * (ferredoxine_reduced / km_fnr_ferredoxine_reduced + 1 + (ferredoxine_reduced / km_fnr_ferredoxine_reduced)^2) + (nadph / km_fnr_nadp + 1) * (ferredoxine_oxidised / km_fnr_ferredoxine_reduced + 1 + (ferredoxine_oxidised / km_fnr_ferredoxine_reduced)^2) - 1)
+derived ndh = plastoquinone_oxidised * kf_ndh
+derived psii = 0.5 * b1 * k2
+derived psi = a1 * ppfd * (1 - psii_cross_section)
+derived proton_leak = kf_proton_leak * (protons_lumen - 4000 * 10^(-p_h))
+derived ptox = o2_dissolved_lumen * plastoquinone_reduced * k_ptox
+derived lhc_state_transition_12 = light_harvesting_complex * k_stt7 / (1 + (plastoquinone_oxidised / (pq_tot * km_lhc_state_transition_12))^n_st)
+derived lhc_state_transition_21 = light_harvesting_complex_protonated * k_pph1
+derived ex_atp = atp * kf_ex_atp
+
+rate atp = atp_synthase - ex_atp
+rate plastoquinone_oxidised = ptox + b6f - psii - cyclic_electron_flow - ndh
+rate plastocyanine_oxidised = psi - 2 * b6f
+rate ferredoxine_oxidised = 2 * cyclic_electron_flow + 2 * fnr - psi
+rate protons_lumen = (2 * psii + 4 * b6f - hpr * atp_synthase - proton_leak) / b_h
+rate light_harvesting_complex = lhc_state_transition_21 - lhc_state_transition_12
+rate psb_s_de_protonated = lhc_deprotonation - lhc_protonation
+rate violaxanthin = zeaxanthin_epoxidase - violaxanthin_deepoxidase
+";
+
+pub const NPQ: ModelInfo = ModelInfo {
+    name: "npq",
+    variables: &[
+        Variable {
+            name: "atp",
+            initial: 1.6999999999999997,
+            unit: "mmol/mol Chl",
+            description: "ATP",
+        },
+        Variable {
+            name: "plastoquinone_oxidised",
+            initial: 4.706348349506148,
+            unit: "mmol/mol Chl",
+            description: "Plastoquinone (oxidised)",
+        },
+        Variable {
+            name: "plastocyanine_oxidised",
+            initial: 3.9414515288091567,
+            unit: "mmol/mol Chl",
+            description: "Plastocyanine (oxidised)",
+        },
+        Variable {
+            name: "ferredoxine_oxidised",
+            initial: 3.7761613271207324,
+            unit: "mmol/mol Chl",
+            description: "Ferredoxin (oxidised)",
+        },
+        Variable {
+            name: "protons_lumen",
+            initial: 7.737821100836988,
+            unit: "mmol/mol Chl",
+            description: "Protons in the lumen",
+        },
+        Variable {
+            name: "light_harvesting_complex",
+            initial: 0.5105293511676007,
+            unit: "1",
+            description: "Unphosphorylated LHCII, relative",
+        },
+        Variable {
+            name: "psb_s_de_protonated",
+            initial: 0.5000000001374878,
+            unit: "1",
+            description: "Deprotonated PsbS, relative",
+        },
+        Variable {
+            name: "violaxanthin",
+            initial: 0.09090909090907397,
+            unit: "1",
+            description: "Violaxanthin, relative",
+        },
+    ],
+    parameters: NPQ_PARAMETERS,
+    derived: NPQ_DERIVED,
+    rhs: npq,
+    autodiff: &AutoDiff(Npq),
+    derived_values: Some(npq_derived),
+    leading_parameters: Some(1),
+    equations: NPQ_EQUATIONS,
+};
+
+/// Declares [`NpqParameters`] and [`NPQ_PARAMETERS`] from one
+/// list of `name: default, unit, description` entries
+macro_rules! npq_parameters {
+    ($($name:ident: $default:expr, $unit:literal, $description:literal;)*) => {
+        /// Parameters of the NPQ model
+        ///
+        /// Deserialising from a partial object keeps the defaults
+        /// of the missing fields, so single constants can be
+        /// overridden by name.
+        #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+        #[serde(default, deny_unknown_fields)]
+        pub struct NpqParameters {
+            $(
+                #[doc = $description]
+                pub $name: f64,
+            )*
+        }
+
+        impl Default for NpqParameters {
+            fn default() -> Self {
+                NpqParameters { $($name: $default,)* }
+            }
+        }
+
+        /// Every NPQ parameter in the order of
+        /// [`NpqParameters::to_vec`]
+        pub const NPQ_PARAMETERS: &[Parameter] = &[$(
+            Parameter {
+                name: stringify!($name),
+                default: $default,
+                unit: $unit,
+                description: $description,
+            },
+        )*];
+
+        impl NpqParameters {
+            /// Parameter vector as passed to [`npq`]
+            pub fn to_vec(&self) -> Vec<f64> {
+                vec![$(self.$name,)*]
+            }
+
+            fn from_values(values: &[f64]) -> Option<Self> {
+                let [$($name,)*] = *values else {
+                    return None;
+                };
+                Some(NpqParameters { $($name,)* })
+            }
+        }
+
+        /// [`NpqParameters`] of any float type, as [`Npq`] takes
+        /// them
+        struct Parameters<T> {
+            $($name: T,)*
+        }
+
+        impl<T: Float> Parameters<T> {
+            /// See [`NpqParameters::from_slice`]
+            fn from_slice(values: &[T]) -> Option<Self> {
+                if let [ppfd] = *values {
+                    let defaults = NpqParameters::default();
+                    let defaults = Parameters {
+                        $($name: T::from(defaults.$name)?,)*
+                    };
+                    return Some(Parameters { ppfd, ..defaults });
+                }
+                let [$($name,)*] = *values else {
+                    return None;
+                };
+                Some(Parameters { $($name,)* })
+            }
+        }
+    };
+}
+
+npq_parameters! {
+    ppfd: 100.0, "µmol/(m² s)", "Photosynthetic photon flux density";
+    p_h: 7.9, "1", "Stromal pH";
+    nadph: 0.6, "mmol/mol Chl", "Stromal NADPH concentration";
+    o2_dissolved_lumen: 8.0, "mmol/mol Chl", "Dissolved oxygen in the lumen";
+    b_h: 100.0, "1", "Buffering capacity of the lumen";
+    faraday: 96.485, "kJ/(V mol)", "Faraday constant";
+    e0_pc: 0.38, "V", "Standard potential of plastocyanin";
+    e0_p700: 0.48, "V", "Standard potential of P700";
+    e0_fa: -0.55, "V", "Standard potential of FA";
+    e0_fd: -0.43, "V", "Standard potential of ferredoxin";
+    e0_nadp: -0.113, "V", "Standard potential of NADP";
+    nadp_tot: 0.8, "mmol/mol Chl", "Total NADP pool";
+    gas_constant: 0.0083, "kJ/(K mol)", "Gas constant";
+    temperature: 298.0, "K", "Temperature";
+    a_p: 2.55, "mmol/mol Chl", "Total adenosine phosphate pool";
+    carotenoids_tot: 1.0, "1", "Total xanthophyll pool, relative";
+    fd_tot: 5.0, "mmol/mol Chl", "Total ferredoxin pool";
+    pc_tot: 4.0, "mmol/mol Chl", "Total plastocyanin pool";
+    psbs_tot: 1.0, "1", "Total PsbS, relative";
+    lhc_tot: 1.0, "1", "Total LHCII, relative";
+    gamma0: 0.1, "1", "Quenching by deprotonated PsbS and violaxanthin";
+    gamma1: 0.25, "1", "Quenching by protonated PsbS";
+    gamma2: 0.6, "1", "Quenching by protonated PsbS and zeaxanthin";
+    gamma3: 0.15, "1", "Quenching by zeaxanthin";
+    k_zsat: 0.12, "1", "Half saturation of quenching by zeaxanthin";
+    e0_qa: -0.14, "V", "Standard potential of QA";
+    e0_pq: 0.354, "V", "Standard potential of plastoquinone";
+    pq_tot: 17.5, "mmol/mol Chl", "Total plastoquinone pool";
+    static_ant_ii: 0.1, "1", "Static antenna fraction of PSII";
+    static_ant_i: 0.37, "1", "Static antenna fraction of PSI";
+    kf_atp_synthase: 20.0, "1/s", "Rate constant of the ATP synthase";
+    hpr: 4.666666666666667, "1", "Protons per ATP synthesised";
+    pi_mol: 0.01, "mmol/mol Chl", "Inorganic phosphate";
+    delta_g0_atp: 30.6, "kJ/mol", "Standard Gibbs energy of ATP synthesis";
+    kcat_b6f: 2.5, "1/s", "Turnover of the cytochrome b6f complex";
+    kh_lhc_protonation: 3.0, "1", "Hill coefficient of PsbS protonation";
+    kf_lhc_protonation: 0.0096, "1/s", "Rate constant of PsbS protonation";
+    ksat_lhc_protonation: 5.8, "1", "Half saturation pH of PsbS protonation";
+    kf_lhc_deprotonation: 0.0096, "1/s", "Rate constant of PsbS deprotonation";
+    kf_cyclic_electron_flow: 1.0, "1/s", "Rate constant of cyclic electron flow";
+    kf_violaxanthin_deepoxidase: 0.0024, "1/s", "Rate constant of violaxanthin de-epoxidation";
+    kh_violaxanthin_deepoxidase: 5.0, "1", "Hill coefficient of violaxanthin de-epoxidation";
+    ksat_violaxanthin_deepoxidase: 5.8, "1", "Half saturation pH of violaxanthin de-epoxidation";
+    kf_zeaxanthin_epoxidase: 0.00024, "1/s", "Rate constant of zeaxanthin epoxidation";
+    e0_fnr: 3.0, "mmol/mol Chl", "Total FNR";
+    kcat_fnr: 500.0, "1/s", "Turnover of FNR";
+    km_fnr_ferredoxine_reduced: 1.56, "mmol/mol Chl", "Michaelis constant of FNR for reduced ferredoxin";
+    km_fnr_nadp: 0.22, "mmol/mol Chl", "Michaelis constant of FNR for NADP";
+    kf_ndh: 0.002, "1/s", "Rate constant of the NDH complex";
+    psii_total: 2.5, "mmol/mol Chl", "Total PSII";
+    psi_total: 2.5, "mmol/mol Chl", "Total PSI";
+    k_h0: 500000000.0, "1/s", "Basal rate of heat dissipation in PSII";
+    k_pqred: 250.0, "1/s", "Rate constant of plastoquinone reduction";
+    k_pcox: 2500.0, "1/s", "Rate constant of plastocyanin oxidation";
+    k_fdred: 250000.0, "1/s", "Rate constant of ferredoxin reduction";
+    k2: 5000000000.0, "1/s", "Rate constant of PSII charge separation";
+    k_h: 5000000000.0, "1/s", "Rate constant of quencher dependent heat dissipation";
+    k_f: 625000000.0, "1/s", "Rate constant of fluorescence";
+    kf_proton_leak: 10.0, "1/s", "Rate constant of the proton leak";
+    k_ptox: 0.01, "1/s", "Rate constant of the plastid terminal oxidase";
+    k_stt7: 0.0035, "1/s", "Rate constant of the STT7 kinase";
+    km_lhc_state_transition_12: 0.2, "1", "Half saturation of STT7 by oxidised plastoquinone, relative";
+    n_st: 2.0, "1", "Hill coefficient of the state transitions";
+    k_pph1: 0.0013, "1/s", "Rate constant of the PPH1 phosphatase";
+    kf_ex_atp: 10.0, "1/s", "Rate constant of ATP consumption";
+}
+
+impl NpqParameters {
+    /// Read a parameter vector of [`npq`], either all
+    /// parameters or only `[ppfd]` with defaults for the rest
+    pub fn from_slice(values: &[f64]) -> Option<Self> {
+        match *values {
+            [ppfd] => Some(NpqParameters {
+                ppfd,
+                ..Default::default()
+            }),
+            _ => Self::from_values(values),
+        }
+    }
+
+    /// `values` with only the light intensity changed, e.g.
+    /// for a saturating pulse, keeping every other override
+    pub fn with_ppfd(values: &[f64], ppfd: f64) -> Vec<f64> {
+        let mut values = values.to_vec();
+        match values.first_mut() {
+            Some(first) => *first = ppfd,
+            None => values.push(ppfd),
+        }
+        values
+    }
+}
+
+/// NPQ model by Matuszyńska et al. (2016)
+///
+/// `parameters` is either [`NpqParameters::to_vec`] or only
+/// `[ppfd]`.
 pub fn npq(
-    _time: f64,
+    time: f64,
     variables: &[f64],
     parameters: &[f64],
-) -> Vec<f64> {
+) -> Result<Vec<f64>, SolverError> {
+    Npq.eval_generic(time, variables, parameters)
+}
+
+/// [`npq`] for any float type, e.g. to get its Jacobian with
+/// [`AutoDiff`]
+pub struct Npq;
+
+impl GenericRhs for Npq {
+    fn eval_generic<T: Float>(
+        &self,
+        time: T,
+        variables: &[T],
+        parameters: &[T],
+    ) -> Result<Vec<T>, SolverError> {
+        evaluate(time, variables, parameters)
+            .map(|(derivatives, _)| derivatives)
+    }
+}
+
+/// Fluorescence yield, lumen pH and quencher activity of the
+/// NPQ model, see [`NPQ_DERIVED`]
+pub fn npq_derived(
+    time: f64,
+    variables: &[f64],
+    parameters: &[f64],
+) -> Result<Vec<f64>, SolverError> {
+    evaluate(time, variables, parameters)
+        .map(|(_, derived)| derived)
+}
+
+/// Derivatives and derived values, computed together as they
+/// share most intermediates
+fn evaluate<T: Float>(
+    time: T,
+    variables: &[T],
+    parameters: &[T],
+) -> Result<(Vec<T>, Vec<T>), SolverError> {
+    let t = time.to_f64().unwrap_or(f64::NAN);
+    let c = |x: f64| T::from(x).expect("constants convert to T");
     let [
         atp,
         plastoquinone_oxidised,
@@ -14,123 +360,136 @@ pub fn npq(
         violaxanthin,
     ] = *variables
     else {
-        panic!("Expected exactly 8 parameters");
-    };
-
-    let [ppfd] = parameters else {
-        panic!("Expected exactly 1 parameter");
+        return Err(SolverError::DimensionMismatch {
+            t,
+            quantity: "variables",
+            expected: 8,
+            found: variables.len(),
+        });
     };
 
-    let p_h: f64 = 7.9;
-    let nadph: f64 = 0.6;
-    let o2_dissolved_lumen: f64 = 8.0;
-    let b_h: f64 = 100.0;
-    let f: f64 = 96.485;
-    let e0_pc: f64 = 0.38;
-    let e0_p700: f64 = 0.48;
-    let e0_fa: f64 = -0.55;
-    let e0_fd: f64 = -0.43;
-    let e0_nadp: f64 = -0.113;
-    let nadp_: f64 = 0.8;
-    let r: f64 = 0.0083;
-    let t: f64 = 298.0;
-    let a_p: f64 = 2.55;
-    let carotenoids_tot: f64 = 1.0;
-    let fd: f64 = 5.0;
-    let pc_tot: f64 = 4.0;
-    let psbs_tot: f64 = 1.0;
-    let lhc_tot: f64 = 1.0;
-    let gamma0: f64 = 0.1;
-    let gamma1: f64 = 0.25;
-    let gamma2: f64 = 0.6;
-    let gamma3: f64 = 0.15;
-    let k_zsat: f64 = 0.12;
-    let e0_qa: f64 = -0.14;
-    let e0_pq: f64 = 0.354;
-    let pq_tot: f64 = 17.5;
-    let static_ant_ii: f64 = 0.1;
-    let static_ant_i: f64 = 0.37;
-    let kf_atp_synthase: f64 = 20.0;
-    let hpr: f64 = 4.666666666666667;
-    let pi_mol: f64 = 0.01;
-    let delta_g0_atp: f64 = 30.6;
-    let kcat_b6f: f64 = 2.5;
-    let kh_lhc_protonation: f64 = 3.0;
-    let kf_lhc_protonation: f64 = 0.0096;
-    let ksat_lhc_protonation: f64 = 5.8;
-    let kf_lhc_deprotonation: f64 = 0.0096;
-    let kf_cyclic_electron_flow: f64 = 1.0;
-    let kf_violaxanthin_deepoxidase: f64 = 0.0024;
-    let kh_violaxanthin_deepoxidase: f64 = 5.0;
-    let ksat_violaxanthin_deepoxidase: f64 = 5.8;
-    let kf_zeaxanthin_epoxidase: f64 = 0.00024;
-    let e0_fnr: f64 = 3.0;
-    let kcat_fnr: f64 = 500.0;
-    let km_fnr_ferredoxine_reduced: f64 = 1.56;
-    let km_fnr_nadp: f64 = 0.22;
-    let kf_ndh: f64 = 0.002;
-    let psii_total: f64 = 2.5;
-    let psi_total: f64 = 2.5;
-    let k_h0: f64 = 500000000.0;
-    let k_pqred: f64 = 250.0;
-    let k_pcox: f64 = 2500.0;
-    let k_fdred: f64 = 250000.0;
-    let k2: f64 = 5000000000.0;
-    let k_h: f64 = 5000000000.0;
-    let k_f: f64 = 625000000.0;
-    let kf_proton_leak: f64 = 10.0;
-    let k_ptox: f64 = 0.01;
-    let k_stt7: f64 = 0.0035;
-    let km_lhc_state_transition_12: f64 = 0.2;
-    let n_st: f64 = 2.0;
-    let k_pph1: f64 = 0.0013;
-    let kf_ex_atp: f64 = 10.0;
-    let nadp: f64 = -nadph + nadp_;
-    let rt: f64 = r * t;
-    let adp: f64 = -atp + a_p;
-    let d_g_p_h: f64 = r * t * 10_f64.ln();
-    let p_h_lumen: f64 =
-        -(0.00025 * protons_lumen).ln() / 10_f64.ln();
-    let zeaxanthin: f64 = carotenoids_tot - violaxanthin;
-    let ferredoxine_reduced: f64 = fd - ferredoxine_oxidised;
-    let plastocyanine_reduced: f64 =
-        pc_tot - plastocyanine_oxidised;
-    let psb_s_protonated: f64 = psbs_tot - psb_s_de_protonated;
-    let light_harvesting_complex_protonated: f64 =
+    let Parameters {
+        ppfd,
+        p_h,
+        nadph,
+        o2_dissolved_lumen,
+        b_h,
+        faraday,
+        e0_pc,
+        e0_p700,
+        e0_fa,
+        e0_fd,
+        e0_nadp,
+        nadp_tot,
+        gas_constant,
+        temperature,
+        a_p,
+        carotenoids_tot,
+        fd_tot,
+        pc_tot,
+        psbs_tot,
+        lhc_tot,
+        gamma0,
+        gamma1,
+        gamma2,
+        gamma3,
+        k_zsat,
+        e0_qa,
+        e0_pq,
+        pq_tot,
+        static_ant_ii,
+        static_ant_i,
+        kf_atp_synthase,
+        hpr,
+        pi_mol,
+        delta_g0_atp,
+        kcat_b6f,
+        kh_lhc_protonation,
+        kf_lhc_protonation,
+        ksat_lhc_protonation,
+        kf_lhc_deprotonation,
+        kf_cyclic_electron_flow,
+        kf_violaxanthin_deepoxidase,
+        kh_violaxanthin_deepoxidase,
+        ksat_violaxanthin_deepoxidase,
+        kf_zeaxanthin_epoxidase,
+        e0_fnr,
+        kcat_fnr,
+        km_fnr_ferredoxine_reduced,
+        km_fnr_nadp,
+        kf_ndh,
+        psii_total,
+        psi_total,
+        k_h0,
+        k_pqred,
+        k_pcox,
+        k_fdred,
+        k2,
+        k_h,
+        k_f,
+        kf_proton_leak,
+        k_ptox,
+        k_stt7,
+        km_lhc_state_transition_12,
+        n_st,
+        k_pph1,
+        kf_ex_atp,
+    } = Parameters::from_slice(parameters).ok_or(
+        SolverError::DimensionMismatch {
+            t,
+            quantity: "parameters",
+            expected: NPQ_PARAMETERS.len(),
+            found: parameters.len(),
+        },
+    )?;
+    let nadp: T = -nadph + nadp_tot;
+    let rt: T = gas_constant * temperature;
+    let adp: T = -atp + a_p;
+    let d_g_p_h: T = gas_constant * temperature * c(10.0).ln();
+    let p_h_lumen: T =
+        -(c(0.00025) * protons_lumen).ln() / c(10.0).ln();
+    let zeaxanthin: T = carotenoids_tot - violaxanthin;
+    let ferredoxine_reduced: T = fd_tot - ferredoxine_oxidised;
+    let plastocyanine_reduced: T = pc_tot - plastocyanine_oxidised;
+    let psb_s_protonated: T = psbs_tot - psb_s_de_protonated;
+    let light_harvesting_complex_protonated: T =
         lhc_tot - light_harvesting_complex;
-    let q: f64 = psb_s_de_protonated * violaxanthin * gamma0
+    let q: T = psb_s_de_protonated * violaxanthin * gamma0
         + psb_s_de_protonated * zeaxanthin * gamma3
             / (zeaxanthin + k_zsat)
         + psb_s_protonated * violaxanthin * gamma1
         + psb_s_protonated * zeaxanthin * gamma2
             / (zeaxanthin + k_zsat);
-    let keq_plastoquinone_reduced: f64 =
-        ((2.0 * e0_pq * f - 2.0 * e0_qa * f - 2.0 * d_g_p_h * p_h)
-            / rt)
-            .exp();
-    let plastoquinone_reduced: f64 =
-        pq_tot - plastoquinone_oxidised;
-    let psii_cross_section: f64 = light_harvesting_complex
-        * (-static_ant_i - static_ant_ii + 1.0)
+    let keq_plastoquinone_reduced: T = ((c(2.0) * e0_pq * faraday
+        - c(2.0) * e0_qa * faraday
+        - c(2.0) * d_g_p_h * p_h)
+        / rt)
+        .exp();
+    let plastoquinone_reduced: T = pq_tot - plastoquinone_oxidised;
+    let psii_cross_section: T = light_harvesting_complex
+        * (-static_ant_i - static_ant_ii + c(1.0))
         + static_ant_ii;
-    let keq_atp_synthase: f64 = pi_mol
+    let keq_atp_synthase: T = pi_mol
         * ((-delta_g0_atp + hpr * d_g_p_h * (p_h - p_h_lumen))
             / rt)
             .exp();
-    let keq_b6f: f64 = ((2.0 * e0_pc * f - 2.0 * e0_pq * f
-        + 2.0 * d_g_p_h * p_h_lumen
-        - 2.0 * d_g_p_h * (p_h - p_h_lumen))
+    let keq_b6f: T = ((c(2.0) * e0_pc * faraday
+        - c(2.0) * e0_pq * faraday
+        + c(2.0) * d_g_p_h * p_h_lumen
+        - c(2.0) * d_g_p_h * (p_h - p_h_lumen))
         / rt)
         .exp();
-    let keq_fnr: f64 = ((-2.0 * e0_fd * f + 2.0 * e0_nadp * f
+    let keq_fnr: T = ((-c(2.0) * e0_fd * faraday
+        + c(2.0) * e0_nadp * faraday
         - d_g_p_h * p_h)
         / rt)
         .exp();
-    let vmax_fnr: f64 = e0_fnr * kcat_fnr;
-    let keq_pcp700: f64 = ((e0_p700 * f - e0_pc * f) / rt).exp();
-    let keq_ferredoxin_reductase: f64 =
-        ((-e0_fa * f + e0_fd * f) / rt).exp();
-    let b1: f64 = ppfd
+    let vmax_fnr: T = e0_fnr * kcat_fnr;
+    let keq_pcp700: T =
+        ((e0_p700 * faraday - e0_pc * faraday) / rt).exp();
+    let keq_ferredoxin_reductase: T =
+        ((-e0_fa * faraday + e0_fd * faraday) / rt).exp();
+    let b1: T = ppfd
         * psii_cross_section
         * psii_total
         * plastoquinone_oxidised
@@ -193,8 +552,8 @@ pub fn npq(
                 * k2
                 * k_h0
                 * keq_plastoquinone_reduced
-            + ppfd.powf(2.0)
-                * psii_cross_section.powf(2.0)
+            + ppfd.powf(c(2.0))
+                * psii_cross_section.powf(c(2.0))
                 * k2
                 * keq_plastoquinone_reduced
             + plastoquinone_oxidised
@@ -203,14 +562,14 @@ pub fn npq(
                 * k_h
                 * k_pqred
                 * keq_plastoquinone_reduced
-            + 2.0
+            + c(2.0)
                 * plastoquinone_oxidised
                 * q
                 * k_f
                 * k_h
                 * k_pqred
                 * keq_plastoquinone_reduced
-            + 2.0
+            + c(2.0)
                 * plastoquinone_oxidised
                 * q
                 * k_h
@@ -218,8 +577,8 @@ pub fn npq(
                 * k_pqred
                 * keq_plastoquinone_reduced
             + plastoquinone_oxidised
-                * q.powf(2.0)
-                * k_h.powf(2.0)
+                * q.powf(c(2.0))
+                * k_h.powf(c(2.0))
                 * k_pqred
                 * keq_plastoquinone_reduced
             + plastoquinone_oxidised
@@ -232,56 +591,60 @@ pub fn npq(
                 * k_h0
                 * k_pqred
                 * keq_plastoquinone_reduced
-            + 2.0
+            + c(2.0)
                 * plastoquinone_oxidised
                 * k_f
                 * k_h0
                 * k_pqred
                 * keq_plastoquinone_reduced
             + plastoquinone_oxidised
-                * k_f.powf(2.0)
+                * k_f.powf(c(2.0))
                 * k_pqred
                 * keq_plastoquinone_reduced
             + plastoquinone_oxidised
-                * k_h0.powf(2.0)
+                * k_h0.powf(c(2.0))
                 * k_pqred
                 * keq_plastoquinone_reduced
             + plastoquinone_reduced * q * k2 * k_h * k_pqred
-            + 2.0
+            + c(2.0)
                 * plastoquinone_reduced
                 * q
                 * k_f
                 * k_h
                 * k_pqred
-            + 2.0
+            + c(2.0)
                 * plastoquinone_reduced
                 * q
                 * k_h
                 * k_h0
                 * k_pqred
             + plastoquinone_reduced
-                * q.powf(2.0)
-                * k_h.powf(2.0)
+                * q.powf(c(2.0))
+                * k_h.powf(c(2.0))
                 * k_pqred
             + plastoquinone_reduced * k2 * k_f * k_pqred
             + plastoquinone_reduced * k2 * k_h0 * k_pqred
-            + 2.0 * plastoquinone_reduced * k_f * k_h0 * k_pqred
-            + plastoquinone_reduced * k_f.powf(2.0) * k_pqred
-            + plastoquinone_reduced * k_h0.powf(2.0) * k_pqred);
-    let a1: f64 = psi_total
+            + c(2.0)
+                * plastoquinone_reduced
+                * k_f
+                * k_h0
+                * k_pqred
+            + plastoquinone_reduced * k_f.powf(c(2.0)) * k_pqred
+            + plastoquinone_reduced * k_h0.powf(c(2.0)) * k_pqred);
+    let a1: T = psi_total
         / ((ferredoxine_reduced
             / (ferredoxine_oxidised * keq_ferredoxin_reductase)
-            + 1.0)
-            * (ppfd * (-psii_cross_section + 1.0)
+            + c(1.0))
+            * (ppfd * (-psii_cross_section + c(1.0))
                 / (plastocyanine_reduced * k_pcox)
                 + plastocyanine_oxidised
                     / (plastocyanine_reduced * keq_pcp700))
-            + ppfd * (-psii_cross_section + 1.0)
+            + ppfd * (-psii_cross_section + c(1.0))
                 / (ferredoxine_oxidised * k_fdred)
-            + 1.0);
-    let atp_synthase: f64 =
+            + c(1.0));
+    let atp_synthase: T =
         kf_atp_synthase * (adp - atp / keq_atp_synthase);
-    let b6f: f64 = if kcat_b6f
+    let b6f: T = if kcat_b6f
         <= -kcat_b6f
             * (plastocyanine_oxidised.powi(2)
                 * plastoquinone_reduced
@@ -298,85 +661,107 @@ pub fn npq(
                     * plastoquinone_oxidised
                     / keq_b6f)
     };
-    let lhc_protonation: f64 = psb_s_de_protonated
+    let lhc_protonation: T = psb_s_de_protonated
         * kf_lhc_protonation
         * protons_lumen.powf(kh_lhc_protonation)
         / (protons_lumen.powf(kh_lhc_protonation)
-            + (4000.0 * 10.0_f64.powf(-ksat_lhc_protonation))
+            + (c(4000.0) * c(10.0).powf(-ksat_lhc_protonation))
                 .powf(kh_lhc_protonation));
-    let lhc_deprotonation: f64 =
+    let lhc_deprotonation: T =
         psb_s_protonated * kf_lhc_deprotonation;
-    let cyclic_electron_flow: f64 = ferredoxine_reduced.powf(2.0)
+    let cyclic_electron_flow: T = ferredoxine_reduced.powf(c(2.0))
         * plastoquinone_oxidised
         * kf_cyclic_electron_flow;
-    let violaxanthin_deepoxidase: f64 = violaxanthin
+    let violaxanthin_deepoxidase: T = violaxanthin
         * kf_violaxanthin_deepoxidase
         * protons_lumen.powf(kh_violaxanthin_deepoxidase)
         / (protons_lumen.powf(kh_violaxanthin_deepoxidase)
-            + (4000.0
-                * 10.0_f64.powf(-ksat_violaxanthin_deepoxidase))
+            + (c(4000.0)
+                * c(10.0).powf(-ksat_violaxanthin_deepoxidase))
             .powf(kh_violaxanthin_deepoxidase));
-    let zeaxanthin_epoxidase: f64 =
+    let zeaxanthin_epoxidase: T =
         zeaxanthin * kf_zeaxanthin_epoxidase;
-    let fnr: f64 = vmax_fnr
+    let fnr: T = vmax_fnr
         * (nadp
             * (ferredoxine_reduced / km_fnr_ferredoxine_reduced)
-                .powf(2.0)
+                .powf(c(2.0))
             / km_fnr_nadp
             - nadph
                 * (ferredoxine_oxidised
                     / km_fnr_ferredoxine_reduced)
-                    .powf(2.0)
+                    .powf(c(2.0))
                 / (keq_fnr * km_fnr_nadp))
-        / ((nadp / km_fnr_nadp + 1.0)
+        / ((nadp / km_fnr_nadp + c(1.0))
             * (ferredoxine_reduced / km_fnr_ferredoxine_reduced
-                + 1.0
+                + c(1.0)
                 + (ferredoxine_reduced
                     / km_fnr_ferredoxine_reduced)
-                    .powf(2.0))
-            + (nadph / km_fnr_nadp + 1.0)
+                    .powf(c(2.0)))
+            + (nadph / km_fnr_nadp + c(1.0))
                 * (ferredoxine_oxidised
                     / km_fnr_ferredoxine_reduced
-                    + 1.0
+                    + c(1.0)
                     + (ferredoxine_oxidised
                         / km_fnr_ferredoxine_reduced)
-                        .powf(2.0))
-            - 1.0);
-    let ndh: f64 = plastoquinone_oxidised * kf_ndh;
-    let psii: f64 = 0.5 * b1 * k2;
-    let psi: f64 = a1 * ppfd * (-psii_cross_section + 1.0);
-    let proton_leak: f64 = kf_proton_leak
-        * (protons_lumen - 4000.0 * 10.0_f64.powf(-p_h));
-    let ptox: f64 =
+                        .powf(c(2.0)))
+            - c(1.0));
+    let ndh: T = plastoquinone_oxidised * kf_ndh;
+    let psii: T = c(0.5) * b1 * k2;
+    let psi: T = a1 * ppfd * (-psii_cross_section + c(1.0));
+    let proton_leak: T = kf_proton_leak
+        * (protons_lumen - c(4000.0) * c(10.0).powf(-p_h));
+    let ptox: T =
         o2_dissolved_lumen * plastoquinone_reduced * k_ptox;
-    let lhc_state_transition_12: f64 = 1.0
+    let lhc_state_transition_12: T = c(1.0)
         * light_harvesting_complex
         * k_stt7
-        * (1.0
+        * (c(1.0)
             + (plastoquinone_oxidised
                 / (pq_tot * km_lhc_state_transition_12))
                 .powf(n_st))
         .recip();
-    let lhc_state_transition_21: f64 =
+    let lhc_state_transition_21: T =
         light_harvesting_complex_protonated * k_pph1;
-    let ex_atp: f64 = atp * kf_ex_atp;
-    let d_atpdt: f64 = atp_synthase - ex_atp;
-    let dprotons_lumendt: f64 = -hpr * atp_synthase / b_h
-        + 2.0 * psii * b_h.recip()
-        + 4.0 * b6f * b_h.recip()
+    let ex_atp: T = atp * kf_ex_atp;
+    let d_atpdt: T = atp_synthase - ex_atp;
+    let dprotons_lumendt: T = -hpr * atp_synthase / b_h
+        + c(2.0) * psii * b_h.recip()
+        + c(4.0) * b6f * b_h.recip()
         - proton_leak / b_h;
-    let d_plastocyanine_oxidiseddt: f64 = psi - 2.0 * b6f;
-    let d_plastoquinone_oxidiseddt: f64 =
+    let d_plastocyanine_oxidiseddt: T = psi - c(2.0) * b6f;
+    let d_plastoquinone_oxidiseddt: T =
         -psii + ptox + b6f - cyclic_electron_flow - ndh;
-    let d_psb_s_de_protonateddt: f64 =
+    let d_psb_s_de_protonateddt: T =
         lhc_deprotonation - lhc_protonation;
-    let d_ferredoxine_oxidiseddt: f64 =
-        -psi + 2.0 * cyclic_electron_flow + 2.0 * fnr;
-    let d_violaxanthindt: f64 =
+    let d_ferredoxine_oxidiseddt: T =
+        -psi + c(2.0) * cyclic_electron_flow + c(2.0) * fnr;
+    let d_violaxanthindt: T =
         -violaxanthin_deepoxidase + zeaxanthin_epoxidase;
-    let d_light_harvesting_complexdt: f64 =
+    let d_light_harvesting_complexdt: T =
         -lhc_state_transition_12 + lhc_state_transition_21;
-    return vec![
+
+    // Quasi steady state of the four PSII states, B0 and B1
+    // have an open, B2 and B3 a closed reaction centre, B1
+    // and B3 are excited. `b1` above is the same B1.
+    let light = ppfd * psii_cross_section;
+    let k_quench = q * k_h + k_h0;
+    let k_decay_open = k_quench + k_f + k2;
+    let k_decay_closed = k_quench + k_f;
+    let k_pq_forward = k_pqred * plastoquinone_oxidised;
+    let k_pq_backward =
+        k_pqred * plastoquinone_reduced / keq_plastoquinone_reduced;
+    let b2_per_b0 = (light + k_pq_backward
+        - k_decay_closed * light / k_decay_open)
+        / k_pq_forward;
+    let b0 = psii_total
+        / (c(1.0)
+            + light / k_decay_open
+            + b2_per_b0 * (c(1.0) + light / k_decay_closed));
+    let b2 = b2_per_b0 * b0;
+    let fluorescence = psii_cross_section * k_f * b0 / k_decay_open
+        + psii_cross_section * k_f * b2 / k_decay_closed;
+
+    let derivatives = vec![
         d_atpdt,
         d_plastoquinone_oxidiseddt,
         d_plastocyanine_oxidiseddt,
@@ -386,4 +771,126 @@ pub fn npq(
         d_psb_s_de_protonateddt,
         d_violaxanthindt,
     ];
+    Ok((derivatives, vec![fluorescence, p_h_lumen, q]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pam::insert_pulses;
+    use crate::protocol::Segment;
+
+    const Y0: [f64; 8] =
+        [1.7, 4.706, 3.941, 3.776, 7.738, 0.511, 0.5, 0.091];
+
+    #[test]
+    fn ppfd_alone_uses_default_parameters() {
+        let all = NpqParameters {
+            ppfd: 500.0,
+            ..Default::default()
+        };
+        assert_eq!(all.to_vec().len(), NPQ_PARAMETERS.len());
+        assert_eq!(
+            npq(0.0, &Y0, &[500.0]).unwrap(),
+            npq(0.0, &Y0, &all.to_vec()).unwrap()
+        );
+        assert!(matches!(
+            npq(0.0, &Y0, &[500.0, 1.0]),
+            Err(SolverError::DimensionMismatch { found: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn overrides_parameters_by_name() {
+        let pars: NpqParameters = serde_json::from_value(
+            serde_json::json!({ "kcat_b6f": 5.0, "ppfd": 50.0 }),
+        )
+        .unwrap();
+        assert_eq!(pars.kcat_b6f, 5.0);
+        assert_eq!(pars.ppfd, 50.0);
+        assert_eq!(pars.pq_tot, NpqParameters::default().pq_tot);
+        let kcat = NPQ_PARAMETERS
+            .iter()
+            .position(|p| p.name == "kcat_b6f")
+            .unwrap();
+        assert_eq!(pars.to_vec()[kcat], 5.0);
+
+        let unknown = serde_json::from_value::<NpqParameters>(
+            serde_json::json!({ "kcat_b6": 5.0 }),
+        );
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn differentiates_exactly() {
+        for pars in [vec![500.0], NpqParameters::default().to_vec()]
+        {
+            assert_eq!(
+                NPQ.autodiff.eval(0.0, &Y0, &pars).unwrap(),
+                npq(0.0, &Y0, &pars).unwrap()
+            );
+            let exact = NPQ
+                .autodiff
+                .jacobian(0.0, &Y0, &pars)
+                .unwrap()
+                .unwrap();
+            for j in 0..Y0.len() {
+                let h = 1e-6 * Y0[j];
+                let (mut up, mut down) = (Y0, Y0);
+                up[j] += h;
+                down[j] -= h;
+                let up = npq(0.0, &up, &pars).unwrap();
+                let down = npq(0.0, &down, &pars).unwrap();
+                for i in 0..Y0.len() {
+                    let approx = (up[i] - down[i]) / (2.0 * h);
+                    assert!(
+                        (exact[i][j] - approx).abs()
+                            <= 1e-5 * approx.abs().max(1.0),
+                        "J[{i}][{j}] = {} != {approx}",
+                        exact[i][j]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pulses_keep_parameter_overrides() {
+        let pars = NpqParameters {
+            ppfd: 50.0,
+            kcat_b6f: 5.0,
+            ..Default::default()
+        };
+        let segments = [Segment {
+            t_end: 10.0,
+            pars: pars.to_vec(),
+            reset: vec![],
+        }];
+        let pulsed =
+            insert_pulses(&segments, 0.0, &[4.0], 1.0, |p| {
+                NpqParameters::with_ppfd(p, 5000.0)
+            })
+            .unwrap();
+        let ppfd: Vec<f64> =
+            pulsed.segments.iter().map(|s| s.pars[0]).collect();
+        assert_eq!(ppfd, vec![50.0, 5000.0, 50.0]);
+        for segment in &pulsed.segments {
+            let found =
+                NpqParameters::from_slice(&segment.pars).unwrap();
+            assert_eq!(found.kcat_b6f, 5.0);
+        }
+        // The pulse runs the same model as the rest of the trace
+        let pulse = NpqParameters {
+            ppfd: 5000.0,
+            ..pars
+        };
+        assert_eq!(
+            npq(0.0, &Y0, &pulsed.segments[1].pars).unwrap(),
+            npq(0.0, &Y0, &pulse.to_vec()).unwrap()
+        );
+        assert_eq!(
+            NpqParameters::with_ppfd(&[], 5000.0),
+            vec![5000.0]
+        );
+    }
 }
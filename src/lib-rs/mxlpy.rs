@@ -0,0 +1,469 @@
+use serde::Deserialize;
+use serde::de::{Deserializer, MapAccess, Visitor};
+
+use crate::expr::{
+    Assignment, BinaryOp, Expr, ExprModel, ModelError, Quantity,
+    Reaction, State, parse_expr,
+};
+use crate::kinetics::kinetic_law;
+
+/// Entries of a JSON object in the order of the file
+#[derive(Debug)]
+pub struct Ordered<T>(pub Vec<(String, T)>);
+
+impl<T> Default for Ordered<T> {
+    fn default() -> Self {
+        Ordered(vec![])
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Ordered<T> {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        struct OrderedVisitor<T>(std::marker::PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for OrderedVisitor<T> {
+            type Value = Ordered<T>;
+
+            fn expecting(
+                &self,
+                f: &mut std::fmt::Formatter,
+            ) -> std::fmt::Result {
+                write!(f, "an object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut entries = vec![];
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(Ordered(entries))
+            }
+        }
+
+        deserializer.deserialize_map(OrderedVisitor(
+            std::marker::PhantomData,
+        ))
+    }
+}
+
+/// Value of a variable or parameter, optionally with its unit
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Value {
+    Number(f64),
+    WithUnit { value: f64, unit: Option<String> },
+}
+
+/// Rate function with named arguments, like a Python function
+/// passed to MxlPy
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Function {
+    pub args: Vec<String>,
+    pub body: String,
+}
+
+/// Number, expression or call of a [`Function`]
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Term {
+    Number(f64),
+    Expression(String),
+    Expr {
+        expr: String,
+    },
+    Call {
+        #[serde(rename = "fn")]
+        function: String,
+        args: Vec<String>,
+    },
+}
+
+/// Rate and the change of each variable per unit of rate
+#[derive(Deserialize, Debug)]
+pub struct MxlpyReaction {
+    #[serde(flatten)]
+    pub rate: Term,
+    pub stoichiometry: Ordered<Term>,
+}
+
+/// Model in the interchange format of the MxlPy tool family
+///
+/// The parts of an MxlPy model, each an object keyed by name
+/// whose order is kept:
+///
+/// ```json
+/// {
+///   "functions": { "mass_action": { "args": ["s", "k"], "body": "k * s" } },
+///   "variables": { "prey": 10, "predator": { "value": 10, "unit": "1" } },
+///   "parameters": { "alpha": 0.1, "beta": 0.02, "gamma": 0.4, "delta": 0.02 },
+///   "derived": { "eaten": "beta * prey * predator" },
+///   "reactions": {
+///     "growth": { "fn": "mass_action", "args": ["prey", "alpha"], "stoichiometry": { "prey": 1 } },
+///     "predation": { "expr": "eaten", "stoichiometry": { "prey": -1, "predator": "delta / beta" } },
+///     "death": { "fn": "mass_action", "args": ["predator", "gamma"], "stoichiometry": { "predator": -1 } }
+///   },
+///   "readouts": { "total": "prey + predator" }
+/// }
+/// ```
+///
+/// Rate functions are written like the Python functions of
+/// MxlPy: `functions` declares them with their arguments and
+/// `{ "fn": name, "args": [...] }` calls them with names of the
+/// model, falling back to the rate laws of [`crate::kinetics`].
+/// A plain string or `{ "expr": ... }` is an expression
+/// of the model names directly, see [`crate::expr::parse_expr`].
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MxlpyModel {
+    pub functions: Ordered<Function>,
+    pub variables: Ordered<Value>,
+    pub parameters: Ordered<Value>,
+    pub derived: Ordered<Term>,
+    pub reactions: Ordered<MxlpyReaction>,
+    /// Values only reported, not used by the model
+    pub readouts: Ordered<Term>,
+}
+
+/// Parse `text`, with errors naming `context` instead of a line
+fn expression(
+    context: &str,
+    text: &str,
+) -> Result<Expr, ModelError> {
+    parse_expr(text).map_err(|error| {
+        let position = error
+            .column
+            .map(|column| format!(", column {column}"))
+            .unwrap_or_default();
+        ModelError::new(format!(
+            "{context}{position}: {}",
+            error.message
+        ))
+    })
+}
+
+impl MxlpyModel {
+    /// Expression of `term` with calls inlined
+    fn term(
+        &self,
+        context: &str,
+        term: &Term,
+    ) -> Result<Expr, ModelError> {
+        match term {
+            Term::Number(value) => Ok(Expr::Number(*value)),
+            Term::Expression(text) | Term::Expr { expr: text } => {
+                expression(context, text)
+            }
+            Term::Call { function, args } => {
+                let definition = self
+                    .functions
+                    .0
+                    .iter()
+                    .find(|(name, _)| name == function)
+                    .map(|(_, definition)| definition);
+                let law = kinetic_law(function);
+                let arity = match (definition, law) {
+                    (Some(definition), _) => definition.args.len(),
+                    (None, Some(law)) => law.arguments.len(),
+                    (None, None) => {
+                        return Err(ModelError::new(format!(
+                            "{context}: unknown function `{function}`"
+                        )));
+                    }
+                };
+                if args.len() != arity {
+                    return Err(ModelError::new(format!(
+                        "{context}: `{function}` takes {arity} \
+                         argument(s), got {}",
+                        args.len()
+                    )));
+                }
+                let args: Vec<Expr> =
+                    args.iter().cloned().map(Expr::Name).collect();
+                let Some(definition) = definition else {
+                    // Rate laws of the library need no definition
+                    return Ok(law
+                        .expect("checked above")
+                        .call(&args));
+                };
+                let body = expression(
+                    &format!("function `{function}`"),
+                    &definition.body,
+                )?;
+                Ok(body.substitute(&|name| {
+                    definition
+                        .args
+                        .iter()
+                        .position(|arg| arg == name)
+                        .map(|i| args[i].clone())
+                }))
+            }
+        }
+    }
+
+    /// Convert to a model that can be compiled
+    ///
+    /// Derived values and readouts become derived values.
+    /// Reactions keep their stoichiometry if every coefficient
+    /// is a number. Otherwise, as for the proton balance in
+    /// [`crate::models::npq`], each reaction becomes a derived
+    /// value with its rate and each variable gets a rate rule
+    /// summing its coefficients times the rates.
+    pub fn to_expr_model(&self) -> Result<ExprModel, ModelError> {
+        let value = |value: &Value| match value {
+            Value::Number(value) => (*value, None),
+            Value::WithUnit { value, unit } => {
+                (*value, unit.clone())
+            }
+        };
+        let mut model = ExprModel::default();
+        for (name, initial) in &self.variables.0 {
+            let (value, unit) = value(initial);
+            model.states.push(State {
+                name: name.clone(),
+                value,
+                unit,
+                compartment: None,
+            });
+        }
+        for (name, parameter) in &self.parameters.0 {
+            let (value, unit) = value(parameter);
+            model.parameters.push(Quantity {
+                name: name.clone(),
+                value,
+                unit,
+            });
+        }
+        for (kind, values) in [
+            ("derived", &self.derived),
+            ("readout", &self.readouts),
+        ] {
+            for (name, term) in &values.0 {
+                model.derived.push(Assignment {
+                    name: name.clone(),
+                    expression: self
+                        .term(&format!("{kind} `{name}`"), term)?,
+                });
+            }
+        }
+
+        let mut reactions = vec![];
+        for (name, reaction) in &self.reactions.0 {
+            let rate = self.term(
+                &format!("reaction `{name}`"),
+                &reaction.rate,
+            )?;
+            let mut stoichiometry = vec![];
+            for (variable, coefficient) in &reaction.stoichiometry.0
+            {
+                // Rate rules are only built for the variables, so
+                // any other name would be dropped
+                if !self
+                    .variables
+                    .0
+                    .iter()
+                    .any(|(v, _)| v == variable)
+                {
+                    return Err(ModelError::new(format!(
+                        "reaction `{name}` changes `{variable}`, which \
+                         is not a state"
+                    )));
+                }
+                let context = format!(
+                    "stoichiometry of `{variable}` in reaction `{name}`"
+                );
+                stoichiometry.push((
+                    variable.clone(),
+                    self.term(&context, coefficient)?,
+                ));
+            }
+            reactions.push((name, rate, stoichiometry));
+        }
+        let constant =
+            reactions.iter().all(|(_, _, stoichiometry)| {
+                stoichiometry
+                    .iter()
+                    .all(|(_, c)| c.constant().is_some())
+            });
+        if constant {
+            model.reactions = reactions
+                .into_iter()
+                .map(|(name, rate, stoichiometry)| Reaction {
+                    name: name.clone(),
+                    stoichiometry: stoichiometry
+                        .into_iter()
+                        .map(|(variable, c)| {
+                            (
+                                variable,
+                                c.constant().expect("constant"),
+                            )
+                        })
+                        .collect(),
+                    rate,
+                })
+                .collect();
+            return Ok(model);
+        }
+
+        for (name, rate, _) in &reactions {
+            model.derived.push(Assignment {
+                name: name.to_string(),
+                expression: rate.clone(),
+            });
+        }
+        for state in &model.states {
+            let terms = reactions.iter().flat_map(
+                |(name, _, stoichiometry)| {
+                    stoichiometry
+                        .iter()
+                        .filter(|(variable, _)| {
+                            *variable == state.name
+                        })
+                        .map(|(_, coefficient)| {
+                            let rate = Box::new(Expr::Name(
+                                name.to_string(),
+                            ));
+                            match coefficient.constant() {
+                                Some(1.0) => *rate,
+                                _ => Expr::Binary(
+                                    BinaryOp::Mul,
+                                    Box::new(coefficient.clone()),
+                                    rate,
+                                ),
+                            }
+                        })
+                },
+            );
+            let Some(expression) = terms.reduce(|a, b| {
+                Expr::Binary(
+                    BinaryOp::Add,
+                    Box::new(a),
+                    Box::new(b),
+                )
+            }) else {
+                continue;
+            };
+            model.rate_rules.push(Assignment {
+                name: state.name.clone(),
+                expression,
+            });
+        }
+        Ok(model)
+    }
+}
+
+/// Read a model in the interchange format from JSON text
+pub fn read_mxlpy(text: &str) -> Result<ExprModel, ModelError> {
+    let model: MxlpyModel =
+        serde_json::from_str(text).map_err(|error| {
+            ModelError::at(
+                error.line(),
+                error.column(),
+                error.to_string(),
+            )
+        })?;
+    model.to_expr_model()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rhs;
+    use crate::expr::CompiledModel;
+    use crate::models::LOTKA_VOLTERRA;
+
+    const LOTKA_VOLTERRA_JSON: &str = r#"{
+        "functions": {
+            "mass_action": { "args": ["s", "k"], "body": "k * s" }
+        },
+        "variables": { "prey": 10, "predator": { "value": 10, "unit": "1" } },
+        "parameters": { "alpha": 0.1, "beta": 0.02, "gamma": 0.4, "delta": 0.02 },
+        "derived": { "eaten": "beta * prey * predator" },
+        "reactions": {
+            "growth": { "fn": "mass_action", "args": ["prey", "alpha"], "stoichiometry": { "prey": 1 } },
+            "predation": { "expr": "eaten", "stoichiometry": { "prey": -1, "predator": "delta / beta" } },
+            "death": { "fn": "mass_action", "args": ["predator", "gamma"], "stoichiometry": { "predator": -1 } }
+        },
+        "readouts": { "total": "prey + predator" }
+    }"#;
+
+    #[test]
+    fn loads_a_runnable_model() {
+        let model = read_mxlpy(LOTKA_VOLTERRA_JSON).unwrap();
+        assert_eq!(model.states[1].unit.as_deref(), Some("1"));
+        // `delta / beta` is no number, so the reactions become
+        // rate rules
+        assert!(model.reactions.is_empty());
+        assert_eq!(model.rate_rules.len(), 2);
+        let compiled = CompiledModel::new(model).unwrap();
+        let y = [3.0, 5.0];
+        let pars = compiled.default_parameters();
+        let expected =
+            (LOTKA_VOLTERRA.rhs)(0.0, &y, &pars).unwrap();
+        let found = compiled.eval(0.0, &y, &pars).unwrap();
+        for (e, f) in expected.iter().zip(&found) {
+            assert!((e - f).abs() <= 1e-15 * e.abs());
+        }
+
+        let constant =
+            LOTKA_VOLTERRA_JSON.replace("\"delta / beta\"", "1");
+        let model = read_mxlpy(&constant).unwrap();
+        assert_eq!(model.reactions.len(), 3);
+        assert_eq!(model.reactions[1].stoichiometry[1].1, 1.0);
+        assert_eq!(model.derived[1].name, "total");
+
+        // Rate laws of the library need no definition
+        let model = read_mxlpy(
+            r#"{
+                "variables": { "s": 1 },
+                "parameters": { "k": 2 },
+                "reactions": {
+                    "v": { "fn": "mass_action_1", "args": ["s", "k"], "stoichiometry": { "s": -1 } }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(model.reactions[0].rate.to_string(), "k * s");
+    }
+
+    #[test]
+    fn reports_errors_by_name() {
+        let error = read_mxlpy(r#"{ "derived": { "x": "1 +" } }"#)
+            .unwrap_err();
+        assert_eq!(
+            error.message,
+            "derived `x`, column 4: expected an expression, found end of line"
+        );
+        let error = read_mxlpy(
+            r#"{ "reactions": { "v": { "fn": "f", "args": [], "stoichiometry": {} } } }"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.message,
+            "reaction `v`: unknown function `f`"
+        );
+        // Also with a coefficient that is an expression
+        let error = read_mxlpy(
+            r#"{
+                "functions": { "rate": { "args": ["k"], "body": "k" } },
+                "variables": { "x": 1 },
+                "parameters": { "k": 1 },
+                "reactions": {
+                    "v": { "fn": "rate", "args": ["k"], "stoichiometry": { "x": -1, "k": "2 * k" } }
+                }
+            }"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.message,
+            "reaction `v` changes `k`, which is not a state"
+        );
+        let error = read_mxlpy("{ \"species\": {} }").unwrap_err();
+        assert_eq!(error.line, Some(1));
+    }
+}
@@ -0,0 +1,558 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::expr::ModelError;
+use crate::kinetics::kinetic_law;
+use crate::{Rhs, SolverError};
+
+/// Rate of a reaction from time, species and parameters
+pub type RateLaw = Rc<dyn Fn(f64, &[f64], &[f64]) -> f64>;
+
+/// Partial derivatives of a rate by species index, from time,
+/// species and parameters
+pub type RateGradient =
+    Rc<dyn Fn(f64, &[f64], &[f64]) -> Vec<(usize, f64)>>;
+
+/// Reaction with its stoichiometry by species index
+#[derive(Clone)]
+pub struct NetworkReaction {
+    pub name: String,
+    pub stoichiometry: Vec<(usize, f64)>,
+    pub rate: RateLaw,
+    /// Known for reactions with a law of [`crate::kinetics`]
+    pub gradient: Option<RateGradient>,
+}
+
+impl fmt::Debug for NetworkReaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NetworkReaction")
+            .field("name", &self.name)
+            .field("stoichiometry", &self.stoichiometry)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where an argument of a rate law comes from
+#[derive(Clone, Copy, Debug)]
+enum Source {
+    Species(usize),
+    Parameter(usize),
+}
+
+/// Model assembled from species and reactions
+///
+/// The derivatives are `N · v` with the stoichiometric matrix
+/// `N` and the rates `v` of the reactions. Species and
+/// parameters are declared first, reactions then refer to
+/// species by name:
+///
+/// ```ignore
+/// let network = ReactionNetwork::new()
+///     .species("a", 1.0)?
+///     .species("b", 0.0)?
+///     .parameter("k", 0.5)?
+///     .reaction("convert", &[("a", -1.0), ("b", 1.0)], |_t, y, p| {
+///         p[0] * y[0]
+///     })?;
+/// ```
+///
+/// Rate laws get the species and parameters in the order they
+/// were declared in.
+#[derive(Clone, Debug, Default)]
+pub struct ReactionNetwork {
+    species: Vec<(String, f64)>,
+    parameters: Vec<(String, f64)>,
+    reactions: Vec<NetworkReaction>,
+}
+
+impl ReactionNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a species with its initial value
+    ///
+    /// Fails if the name is already used by a species or a
+    /// parameter.
+    pub fn species(
+        mut self,
+        name: &str,
+        initial: f64,
+    ) -> Result<Self, ModelError> {
+        self.declare(name)?;
+        self.species.push((name.to_string(), initial));
+        Ok(self)
+    }
+
+    /// Add a parameter with its default value
+    ///
+    /// Fails if the name is already used by a species or a
+    /// parameter.
+    pub fn parameter(
+        mut self,
+        name: &str,
+        value: f64,
+    ) -> Result<Self, ModelError> {
+        self.declare(name)?;
+        self.parameters.push((name.to_string(), value));
+        Ok(self)
+    }
+
+    /// Check that `name` is free for a species or parameter,
+    /// as rate laws refer to both by name
+    fn declare(&self, name: &str) -> Result<(), ModelError> {
+        if self.species_index(name).is_some()
+            || self.parameter_index(name).is_some()
+        {
+            return Err(ModelError::new(format!(
+                "`{name}` is declared twice"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Add a reaction changing each species in `stoichiometry`
+    /// by its coefficient times `rate`
+    ///
+    /// Fails if a species was not declared before or a name is
+    /// used twice.
+    pub fn reaction(
+        mut self,
+        name: &str,
+        stoichiometry: &[(&str, f64)],
+        rate: impl Fn(f64, &[f64], &[f64]) -> f64 + 'static,
+    ) -> Result<Self, ModelError> {
+        let stoichiometry = self.resolve(name, stoichiometry)?;
+        self.reactions.push(NetworkReaction {
+            name: name.to_string(),
+            stoichiometry,
+            rate: Rc::new(rate),
+            gradient: None,
+        });
+        Ok(self)
+    }
+
+    /// Add a reaction with a rate law of [`crate::kinetics`]
+    ///
+    /// `arguments` names a species or parameter for each
+    /// argument of the law, e.g. `&["s", "vmax", "km"]` for
+    /// `michaelis_menten`. The partial derivatives of the law
+    /// give the network an exact Jacobian.
+    pub fn kinetic_reaction(
+        mut self,
+        name: &str,
+        stoichiometry: &[(&str, f64)],
+        law: &str,
+        arguments: &[&str],
+    ) -> Result<Self, ModelError> {
+        let stoichiometry = self.resolve(name, stoichiometry)?;
+        let law = kinetic_law(law).ok_or_else(|| {
+            ModelError::new(format!(
+                "reaction `{name}` uses the unknown rate law `{law}`"
+            ))
+        })?;
+        if arguments.len() != law.arguments.len() {
+            return Err(ModelError::new(format!(
+                "rate law `{}` takes {} argument(s), got {}",
+                law.name,
+                law.arguments.len(),
+                arguments.len()
+            )));
+        }
+        let sources = arguments
+            .iter()
+            .map(|&argument| {
+                if let Some(i) = self.species_index(argument) {
+                    Ok(Source::Species(i))
+                } else if let Some(i) =
+                    self.parameter_index(argument)
+                {
+                    Ok(Source::Parameter(i))
+                } else {
+                    Err(ModelError::new(format!(
+                        "reaction `{name}` uses the unknown name \
+                         `{argument}`"
+                    )))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let species: Vec<(usize, usize)> = sources
+            .iter()
+            .enumerate()
+            .filter_map(|(argument, source)| match *source {
+                Source::Species(i) => Some((argument, i)),
+                Source::Parameter(_) => None,
+            })
+            .collect();
+        let compiled = Rc::new(
+            law.compile(
+                &species
+                    .iter()
+                    .map(|&(argument, _)| argument)
+                    .collect::<Vec<_>>(),
+            ),
+        );
+        let slots =
+            move |values: &[f64], pars: &[f64]| -> Vec<f64> {
+                sources
+                    .iter()
+                    .map(|source| match *source {
+                        Source::Species(i) => values[i],
+                        Source::Parameter(i) => pars[i],
+                    })
+                    .collect()
+            };
+        let slots = Rc::new(slots);
+        let rate = {
+            let (compiled, slots) =
+                (compiled.clone(), slots.clone());
+            move |_: f64, values: &[f64], pars: &[f64]| {
+                compiled.rate.run(&mut slots(values, pars))[0]
+            }
+        };
+        let gradient = move |_: f64,
+                             values: &[f64],
+                             pars: &[f64]| {
+            let partials =
+                compiled.derivatives.run(&mut slots(values, pars));
+            species
+                .iter()
+                .zip(partials)
+                .map(|(&(_, i), partial)| (i, partial))
+                .collect()
+        };
+        self.reactions.push(NetworkReaction {
+            name: name.to_string(),
+            stoichiometry,
+            rate: Rc::new(rate),
+            gradient: Some(Rc::new(gradient)),
+        });
+        Ok(self)
+    }
+
+    /// Stoichiometry by species index of a new reaction
+    fn resolve(
+        &self,
+        name: &str,
+        stoichiometry: &[(&str, f64)],
+    ) -> Result<Vec<(usize, f64)>, ModelError> {
+        if self.reactions.iter().any(|r| r.name == name) {
+            return Err(ModelError::new(format!(
+                "reaction `{name}` is defined twice"
+            )));
+        }
+        stoichiometry
+            .iter()
+            .map(|&(species, coefficient)| {
+                let index = self
+                    .species_index(species)
+                    .ok_or_else(|| {
+                        ModelError::new(format!(
+                            "reaction `{name}` uses the unknown \
+                             species `{species}`"
+                        ))
+                    })?;
+                Ok((index, coefficient))
+            })
+            .collect()
+    }
+
+    /// Position of a species in the state vector
+    pub fn species_index(&self, name: &str) -> Option<usize> {
+        self.species.iter().position(|(species, _)| species == name)
+    }
+
+    /// Position of a parameter in the parameter vector
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        self.parameters.iter().position(|(p, _)| p == name)
+    }
+
+    pub fn species_names(&self) -> Vec<&str> {
+        self.species.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    pub fn reaction_names(&self) -> Vec<&str> {
+        self.reactions.iter().map(|r| r.name.as_str()).collect()
+    }
+
+    pub fn reactions(&self) -> &[NetworkReaction] {
+        &self.reactions
+    }
+
+    pub fn initial_values(&self) -> Vec<f64> {
+        self.species.iter().map(|&(_, value)| value).collect()
+    }
+
+    pub fn default_parameters(&self) -> Vec<f64> {
+        self.parameters.iter().map(|&(_, value)| value).collect()
+    }
+
+    /// `N[i][j]`, the change of species `i` per unit of rate of
+    /// reaction `j`
+    pub fn stoichiometric_matrix(&self) -> Vec<Vec<f64>> {
+        let mut matrix = vec![
+            vec![0.0; self.reactions.len()];
+            self.species.len()
+        ];
+        for (j, reaction) in self.reactions.iter().enumerate() {
+            for &(i, coefficient) in &reaction.stoichiometry {
+                matrix[i][j] += coefficient;
+            }
+        }
+        matrix
+    }
+
+    fn check(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<(), SolverError> {
+        for (quantity, expected, found) in [
+            ("variables", self.species.len(), values.len()),
+            ("parameters", self.parameters.len(), pars.len()),
+        ] {
+            if expected != found {
+                return Err(SolverError::DimensionMismatch {
+                    t: time,
+                    quantity,
+                    expected,
+                    found,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Rate of every reaction, in the order of declaration
+    pub fn fluxes(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        self.check(time, values, pars)?;
+        Ok(self
+            .reactions
+            .iter()
+            .map(|reaction| (reaction.rate)(time, values, pars))
+            .collect())
+    }
+}
+
+impl Rhs for ReactionNetwork {
+    fn eval(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        let fluxes = self.fluxes(time, values, pars)?;
+        let mut derivatives = vec![0.0; self.species.len()];
+        for (reaction, flux) in self.reactions.iter().zip(fluxes) {
+            for &(i, coefficient) in &reaction.stoichiometry {
+                derivatives[i] += coefficient * flux;
+            }
+        }
+        Ok(derivatives)
+    }
+
+    /// `N · ∂v/∂y`, known if every reaction has a rate law of
+    /// [`crate::kinetics`]
+    fn jacobian(
+        &self,
+        time: f64,
+        values: &[f64],
+        pars: &[f64],
+    ) -> Option<Result<Vec<Vec<f64>>, SolverError>> {
+        if self.reactions.iter().any(|r| r.gradient.is_none()) {
+            return None;
+        }
+        if let Err(error) = self.check(time, values, pars) {
+            return Some(Err(error));
+        }
+        let n = self.species.len();
+        let mut jac = vec![vec![0.0; n]; n];
+        for reaction in &self.reactions {
+            let gradient = reaction.gradient.as_ref()?;
+            for (j, partial) in gradient(time, values, pars) {
+                for &(i, coefficient) in &reaction.stoichiometry {
+                    jac[i][j] += coefficient * partial;
+                }
+            }
+        }
+        Some(Ok(jac))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::lotka_volterra;
+
+    fn lotka_volterra_network()
+    -> Result<ReactionNetwork, ModelError> {
+        ReactionNetwork::new()
+            .species("prey", 10.0)?
+            .species("predator", 10.0)?
+            .parameter("alpha", 0.1)?
+            .parameter("beta", 0.02)?
+            .parameter("gamma", 0.4)?
+            .parameter("delta", 0.02)?
+            .reaction("growth", &[("prey", 1.0)], |_, y, p| {
+                p[0] * y[0]
+            })
+            .and_then(|n| {
+                n.reaction(
+                    "predation",
+                    &[("prey", -1.0)],
+                    |_, y, p| p[1] * y[0] * y[1],
+                )
+            })
+            .and_then(|n| {
+                n.reaction(
+                    "birth",
+                    &[("predator", 1.0)],
+                    |_, y, p| p[3] * y[0] * y[1],
+                )
+            })
+            .and_then(|n| {
+                n.reaction(
+                    "death",
+                    &[("predator", -1.0)],
+                    |_, y, p| p[2] * y[1],
+                )
+            })
+    }
+
+    #[test]
+    fn assembles_the_right_hand_side() {
+        let network = lotka_volterra_network().unwrap();
+        let y = [3.0, 5.0];
+        let pars = network.default_parameters();
+        assert_eq!(
+            network.eval(0.0, &y, &pars).unwrap(),
+            lotka_volterra(0.0, &y, &pars).unwrap()
+        );
+        assert_eq!(
+            network.stoichiometric_matrix(),
+            vec![
+                vec![1.0, -1.0, 0.0, 0.0],
+                vec![0.0, 0.0, 1.0, -1.0]
+            ]
+        );
+        assert_eq!(network.fluxes(0.0, &y, &pars).unwrap()[3], 2.0);
+        assert!(matches!(
+            network.eval(0.0, &y, &[]),
+            Err(SolverError::DimensionMismatch { expected: 4, .. })
+        ));
+    }
+
+    /// `a + b <-> c -> d`, with an ordinary rate last
+    fn kinetic_network() -> Result<ReactionNetwork, ModelError> {
+        ReactionNetwork::new()
+            .species("a", 2.0)?
+            .species("b", 1.5)?
+            .species("c", 0.5)?
+            .species("d", 0.0)?
+            .parameter("kf", 1.2)?
+            .parameter("kr", 0.3)?
+            .parameter("vmax", 2.0)?
+            .parameter("km", 0.4)?
+            .kinetic_reaction(
+                "binding",
+                &[("a", -1.0), ("b", -1.0), ("c", 1.0)],
+                "reversible_mass_action_2_1",
+                &["a", "b", "c", "kf", "kr"],
+            )
+            .and_then(|n| {
+                n.kinetic_reaction(
+                    "conversion",
+                    &[("c", -1.0), ("d", 1.0)],
+                    "michaelis_menten",
+                    &["c", "vmax", "km"],
+                )
+            })
+    }
+
+    #[test]
+    fn differentiates_kinetic_laws() {
+        let network = kinetic_network().unwrap();
+        let y = network.initial_values();
+        let pars = network.default_parameters();
+        assert_eq!(
+            network.fluxes(0.0, &y, &pars).unwrap(),
+            vec![1.2 * 2.0 * 1.5 - 0.3 * 0.5, 2.0 * 0.5 / 0.9]
+        );
+        let exact =
+            network.jacobian(0.0, &y, &pars).unwrap().unwrap();
+        for j in 0..y.len() {
+            let (mut up, mut down) = (y.clone(), y.clone());
+            up[j] += 1e-6;
+            down[j] -= 1e-6;
+            let up = network.eval(0.0, &up, &pars).unwrap();
+            let down = network.eval(0.0, &down, &pars).unwrap();
+            for i in 0..y.len() {
+                let approx = (up[i] - down[i]) / 2e-6;
+                assert!(
+                    (exact[i][j] - approx).abs() < 1e-6,
+                    "J[{i}][{j}] = {} != {approx}",
+                    exact[i][j]
+                );
+            }
+        }
+
+        let mixed = network
+            .reaction("outflow", &[("d", -1.0)], |_, y, _| y[3])
+            .unwrap();
+        assert!(mixed.jacobian(0.0, &y, &pars).is_none());
+
+        let error = ReactionNetwork::new()
+            .species("s", 1.0)
+            .and_then(|n| {
+                n.kinetic_reaction(
+                    "r",
+                    &[("s", -1.0)],
+                    "hill",
+                    &["s"],
+                )
+            })
+            .unwrap_err();
+        assert_eq!(
+            error.message,
+            "rate law `hill` takes 4 argument(s), got 1"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_species() {
+        let error = ReactionNetwork::new()
+            .species("a", 1.0)
+            .and_then(|n| {
+                n.reaction("r", &[("b", 1.0)], |_, _, _| 1.0)
+            })
+            .unwrap_err();
+        assert_eq!(
+            error.message,
+            "reaction `r` uses the unknown species `b`"
+        );
+    }
+
+    #[test]
+    fn rejects_repeated_names() {
+        let a = ReactionNetwork::new().species("a", 1.0).unwrap();
+        let k = ReactionNetwork::new().parameter("k", 1.0).unwrap();
+        for (network, name) in [
+            (a.clone().species("a", 2.0), "a"),
+            (k.clone().parameter("k", 2.0), "k"),
+            // Rate laws could not tell which one is meant
+            (a.parameter("a", 2.0), "a"),
+            (k.species("k", 2.0), "k"),
+        ] {
+            assert_eq!(
+                network.unwrap_err().message,
+                format!("`{name}` is declared twice")
+            );
+        }
+    }
+}
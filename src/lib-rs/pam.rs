@@ -0,0 +1,358 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::SolverError;
+use crate::protocol::{ProtocolIntegration, Segment};
+
+/// Protocol with saturating pulses cut into its segments
+pub struct PulsedProtocol {
+    pub segments: Vec<Segment>,
+    /// Start of every pulse and the segments it covers
+    pub pulses: Vec<(f64, Range<usize>)>,
+}
+
+/// Insert pulses of length `duration` starting at `pulse_times`
+///
+/// Segments are split at the start and end of every pulse.
+/// During a pulse the parameters of the underlying segment are
+/// replaced by `pulse(pars)`, e.g. a saturating light
+/// intensity. State resets stay on the first part of their
+/// segment. Pulses outside the protocol are dropped, a
+/// `duration` that is not positive is an error.
+pub fn insert_pulses(
+    segments: &[Segment],
+    t_start: f64,
+    pulse_times: &[f64],
+    duration: f64,
+    pulse: impl Fn(&[f64]) -> Vec<f64>,
+) -> Result<PulsedProtocol, SolverError> {
+    if !(duration.is_finite() && duration > 0.0) {
+        return Err(SolverError::InvalidOption {
+            t: t_start,
+            option: "pulse duration",
+            message: "must be positive and finite".to_string(),
+        });
+    }
+    let t_final = segments.last().map_or(t_start, |s| s.t_end);
+    let mut starts: Vec<f64> = pulse_times
+        .iter()
+        .copied()
+        .filter(|t| (t_start..t_final).contains(t))
+        .collect();
+    starts.sort_by(f64::total_cmp);
+
+    let mut cuts: Vec<f64> = segments
+        .iter()
+        .map(|s| s.t_end)
+        .chain(starts.iter().copied())
+        .chain(starts.iter().map(|t| (t + duration).min(t_final)))
+        .filter(|&t| t > t_start)
+        .collect();
+    cuts.sort_by(f64::total_cmp);
+    cuts.dedup();
+
+    let mut pieces = Vec::with_capacity(cuts.len());
+    let mut pulses: Vec<(f64, Range<usize>)> =
+        starts.iter().map(|&t| (t, 0..0)).collect();
+    let mut a = t_start;
+    let mut base = 0;
+    let mut reset_applied = None;
+    for b in cuts {
+        while segments[base].t_end < b {
+            base += 1;
+        }
+        let segment = &segments[base];
+        let active = starts
+            .iter()
+            .position(|&t| t <= a && b <= t + duration);
+        let pars = match active {
+            Some(i) => {
+                let covered = &mut pulses[i].1;
+                if covered.start == covered.end {
+                    *covered = pieces.len()..pieces.len();
+                }
+                covered.end = pieces.len() + 1;
+                pulse(&segment.pars)
+            }
+            None => segment.pars.clone(),
+        };
+        let reset = if reset_applied == Some(base) {
+            vec![]
+        } else {
+            reset_applied = Some(base);
+            segment.reset.clone()
+        };
+        pieces.push(Segment {
+            t_end: b,
+            pars,
+            reset,
+        });
+        a = b;
+    }
+
+    Ok(PulsedProtocol {
+        segments: pieces,
+        pulses,
+    })
+}
+
+/// Fluorescence readout of one saturating pulse
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PulseReadout {
+    /// Start of the pulse
+    pub time: f64,
+    /// Highest fluorescence during the pulse
+    pub fm_prime: f64,
+    /// Fluorescence right before the pulse, missing for a pulse
+    /// at the start of the protocol
+    pub fs: Option<f64>,
+    /// `(Fm - Fm') / Fm'` with the first pulse taken as the
+    /// dark adapted `Fm`
+    pub npq: f64,
+    /// Operating efficiency of PSII, `(Fm' - Fs) / Fm'`
+    pub phi_psii: Option<f64>,
+}
+
+/// Extract Fm', Fs and the quantities based on them for every
+/// pulse that was simulated
+///
+/// A pulse without output points of its own, i.e. one starting
+/// during an earlier pulse, is an error.
+pub fn pulse_readouts(
+    result: &ProtocolIntegration,
+    pulsed: &PulsedProtocol,
+    fluorescence: &[f64],
+) -> Result<Vec<PulseReadout>, SolverError> {
+    let starts = &result.segment_starts;
+    let mut readouts: Vec<PulseReadout> = vec![];
+    for &(time, ref covered) in &pulsed.pulses {
+        let empty = || SolverError::InvalidOption {
+            t: time,
+            option: "pulse times",
+            message: format!(
+                "the pulse at {time} overlaps an earlier one"
+            ),
+        };
+        if covered.is_empty() {
+            return Err(empty());
+        }
+        let Some(&first) = starts.get(covered.start) else {
+            break;
+        };
+        // A pulse starting inside another one only covers the
+        // pieces after it
+        if result.integration.time.get(first) != Some(&time) {
+            return Err(empty());
+        }
+        let last = starts
+            .get(covered.end)
+            .copied()
+            .unwrap_or(fluorescence.len());
+        let fm_prime = fluorescence[first..last.max(first)]
+            .iter()
+            .copied()
+            .reduce(f64::max)
+            .ok_or_else(empty)?;
+        // The last point before the pulse, which is evaluated
+        // with the parameters of the preceding segment
+        let fs = first.checked_sub(1).map(|i| fluorescence[i]);
+        let fm = readouts.first().map_or(fm_prime, |r| r.fm_prime);
+        readouts.push(PulseReadout {
+            time,
+            fm_prime,
+            fs,
+            npq: (fm - fm_prime) / fm_prime,
+            phi_psii: fs.map(|fs| (fm_prime - fs) / fm_prime),
+        });
+    }
+    Ok(readouts)
+}
+
+/// Protocol result with derived values and pulse readouts
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PamIntegration {
+    #[serde(flatten)]
+    pub protocol: ProtocolIntegration,
+    /// Derived values at every output point
+    pub derived: Vec<Vec<f64>>,
+    pub pulses: Vec<PulseReadout>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::run_protocol;
+    use crate::{Integration, ReturnCode, SolverError};
+
+    fn light(t_end: f64, ppfd: f64) -> Segment {
+        Segment {
+            t_end,
+            pars: vec![ppfd],
+            reset: vec![],
+        }
+    }
+
+    #[test]
+    fn splits_segments_at_pulses() {
+        let segments = vec![
+            Segment {
+                reset: vec![(0, 1.0)],
+                ..light(4.0, 100.0)
+            },
+            light(10.0, 0.0),
+        ];
+        let pulsed = insert_pulses(
+            &segments,
+            0.0,
+            &[3.5, 1.0, 20.0],
+            1.0,
+            |_| vec![1500.0],
+        )
+        .unwrap();
+        let ends: Vec<_> =
+            pulsed.segments.iter().map(|s| s.t_end).collect();
+        assert_eq!(ends, vec![1.0, 2.0, 3.5, 4.0, 4.5, 10.0]);
+        let pars: Vec<_> =
+            pulsed.segments.iter().map(|s| s.pars[0]).collect();
+        assert_eq!(
+            pars,
+            vec![100.0, 1500.0, 100.0, 1500.0, 1500.0, 0.0]
+        );
+        assert_eq!(pulsed.segments[0].reset, vec![(0, 1.0)]);
+        assert!(pulsed.segments[1].reset.is_empty());
+        assert_eq!(pulsed.pulses, vec![(1.0, 1..2), (3.5, 3..5)]);
+    }
+
+    #[test]
+    fn rejects_invalid_pulse_durations() {
+        for duration in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let pulsed = insert_pulses(
+                &[light(4.0, 1.0)],
+                0.0,
+                &[1.0],
+                duration,
+                |_| vec![4.0],
+            );
+            assert!(matches!(
+                pulsed,
+                Err(SolverError::InvalidOption {
+                    option: "pulse duration",
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn reads_out_pulses() {
+        // Fluorescence follows the light intensity directly
+        let pulsed = insert_pulses(
+            &[light(4.0, 1.0)],
+            0.0,
+            &[1.0, 3.0],
+            0.5,
+            |_| vec![4.0],
+        )
+        .unwrap();
+        let fluorescence =
+            |t: f64,
+             _y: &[f64],
+             p: &[f64]|
+             -> Result<Vec<f64>, SolverError> {
+                Ok(vec![p[0] - 0.1 * t])
+            };
+        let result = run_protocol(
+            |y0, _pars, t_start, t_end| {
+                Ok(Integration {
+                    time: vec![t_start, t_end],
+                    values: vec![y0.clone(), y0],
+                    status: ReturnCode::Success,
+                    stats: Default::default(),
+                    steps: None,
+                    events: vec![],
+                })
+            },
+            vec![],
+            0.0,
+            &pulsed.segments,
+        )
+        .unwrap();
+        let derived: Vec<f64> = result
+            .derived(&pulsed.segments, fluorescence)
+            .unwrap()
+            .into_iter()
+            .map(|d| d[0])
+            .collect();
+        let readouts =
+            pulse_readouts(&result, &pulsed, &derived).unwrap();
+        assert_eq!(readouts.len(), 2);
+        let second = &readouts[1];
+        assert!((second.fm_prime - 3.7).abs() < 1e-12);
+        assert!((second.fs.unwrap() - 0.7).abs() < 1e-12);
+        assert!((second.npq - (3.9 - 3.7) / 3.7).abs() < 1e-12);
+        assert!(
+            (second.phi_psii.unwrap() - 3.0 / 3.7).abs() < 1e-12
+        );
+    }
+
+    /// Protocol with two output points per segment and the
+    /// light intensity as fluorescence
+    fn read_out(
+        pulse_times: &[f64],
+    ) -> Result<Vec<PulseReadout>, SolverError> {
+        let pulsed = insert_pulses(
+            &[light(4.0, 1.0)],
+            0.0,
+            pulse_times,
+            0.5,
+            |_| vec![4.0],
+        )?;
+        let result = run_protocol(
+            |y0, _pars, t_start, t_end| {
+                Ok(Integration {
+                    time: vec![t_start, t_end],
+                    values: vec![y0.clone(), y0],
+                    status: ReturnCode::Success,
+                    stats: Default::default(),
+                    steps: None,
+                    events: vec![],
+                })
+            },
+            vec![],
+            0.0,
+            &pulsed.segments,
+        )?;
+        let fluorescence: Vec<f64> = result
+            .derived(&pulsed.segments, |_t, _y, p| Ok(p.to_vec()))?
+            .into_iter()
+            .map(|d| d[0])
+            .collect();
+        pulse_readouts(&result, &pulsed, &fluorescence)
+    }
+
+    #[test]
+    fn reports_missing_fs_at_the_start() {
+        let readouts = read_out(&[0.0, 2.0]).unwrap();
+        assert_eq!(readouts[0].fm_prime, 4.0);
+        assert_eq!(readouts[0].fs, None);
+        assert_eq!(readouts[0].phi_psii, None);
+        assert_eq!(readouts[1].fs, Some(1.0));
+        assert_eq!(readouts[1].phi_psii, Some(0.75));
+    }
+
+    #[test]
+    fn rejects_overlapping_pulses() {
+        for times in [[1.0, 1.0], [1.0, 1.25]] {
+            assert!(matches!(
+                read_out(&times),
+                Err(SolverError::InvalidOption {
+                    option: "pulse times",
+                    ..
+                })
+            ));
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Integration, Model, ReturnCode, SolverError, Steps};
+
+/// Part of a protocol with constant parameters
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Segment {
+    pub t_end: f64,
+    pub pars: Vec<f64>,
+    /// `(index, value)` pairs set on the state right before
+    /// the segment starts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reset: Vec<(usize, f64)>,
+}
+
+/// Result of all segments stitched into one integration
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtocolIntegration {
+    #[serde(flatten)]
+    pub integration: Integration,
+    /// Index into `time` at which each segment starts
+    pub segment_starts: Vec<usize>,
+}
+
+impl ProtocolIntegration {
+    /// Evaluate `derived(t, y, pars)` at every output point with
+    /// the parameters of the segment the point belongs to
+    pub fn derived(
+        &self,
+        segments: &[Segment],
+        derived: Model,
+    ) -> Result<Vec<Vec<f64>>, SolverError> {
+        let Integration { time, values, .. } = &self.integration;
+        let mut result = Vec::with_capacity(time.len());
+        for (i, segment) in segments.iter().enumerate() {
+            let start = match self.segment_starts.get(i) {
+                Some(&start) => start,
+                None => break,
+            };
+            let end = self
+                .segment_starts
+                .get(i + 1)
+                .copied()
+                .unwrap_or(time.len());
+            for j in start..end {
+                result.push(derived(
+                    time[j],
+                    &values[j],
+                    &segment.pars,
+                )?);
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Run `segments` one after another, each starting where the
+/// previous one ended
+///
+/// `solve(y0, pars, t_start, t_end)` integrates a single
+/// segment, so any solver can be plugged in. Every segment is
+/// a fresh integration, which restarts the step size control
+/// at the discontinuities. The boundary time appears twice in
+/// the output, once for each side. The last reported point of
+/// a segment is where the next one starts, so the solver has
+/// to report `t_end`. A segment that does not reach its
+/// `t_end` ends the protocol with its return code.
+pub fn run_protocol(
+    mut solve: impl FnMut(
+        Vec<f64>,
+        Vec<f64>,
+        f64,
+        f64,
+    ) -> Result<Integration, SolverError>,
+    y0: Vec<f64>,
+    t_start: f64,
+    segments: &[Segment],
+) -> Result<ProtocolIntegration, SolverError> {
+    let mut t = t_start;
+    let mut y = y0;
+    let mut result = Integration {
+        time: vec![],
+        values: vec![],
+        status: ReturnCode::Success,
+        stats: Default::default(),
+        steps: None,
+        events: vec![],
+    };
+    let mut segment_starts = Vec::with_capacity(segments.len());
+
+    for (i, segment) in segments.iter().enumerate() {
+        for &(index, value) in &segment.reset {
+            let found = y.len();
+            let Some(entry) = y.get_mut(index) else {
+                return Err(SolverError::DimensionMismatch {
+                    t,
+                    quantity: "variables to reset",
+                    expected: index + 1,
+                    found,
+                });
+            };
+            *entry = value;
+        }
+
+        let part = solve(
+            y.clone(),
+            segment.pars.clone(),
+            t,
+            segment.t_end,
+        )?;
+        if part.status == ReturnCode::Success
+            && part.time.last() != Some(&segment.t_end)
+        {
+            return Err(SolverError::InvalidOption {
+                t,
+                option: "output times",
+                message: format!(
+                    "segment {i} has to report its end point {}",
+                    segment.t_end
+                ),
+            });
+        }
+        if let (Some(&t_last), Some(y_last)) =
+            (part.time.last(), part.values.last())
+        {
+            t = t_last;
+            y = y_last.clone();
+        }
+
+        segment_starts.push(result.time.len());
+        result.time.extend(part.time);
+        result.values.extend(part.values);
+        result.stats += part.stats;
+        result.events.extend(part.events);
+        if let Some(steps) = part.steps {
+            let all = result.steps.get_or_insert(Steps {
+                time: vec![],
+                values: vec![],
+            });
+            all.time.extend(steps.time);
+            all.values.extend(steps.values);
+        }
+        if part.status != ReturnCode::Success {
+            result.status = part.status;
+            break;
+        }
+    }
+
+    Ok(ProtocolIntegration {
+        integration: result,
+        segment_starts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explicit::euler;
+
+    fn decay(
+        _t: f64,
+        y: &[f64],
+        p: &[f64],
+    ) -> Result<Vec<f64>, SolverError> {
+        Ok(vec![-p[0] * y[0]])
+    }
+
+    #[test]
+    fn chains_segments() {
+        let segments = vec![
+            Segment {
+                t_end: 1.0,
+                pars: vec![0.0],
+                reset: vec![],
+            },
+            Segment {
+                t_end: 1.5,
+                pars: vec![1.0],
+                reset: vec![(0, 2.0)],
+            },
+        ];
+        let result = run_protocol(
+            |y0, pars, t_start, t_end| {
+                euler(&decay, y0, pars, 0.25, t_start, t_end, &[])
+            },
+            vec![1.0],
+            0.0,
+            &segments,
+        )
+        .unwrap();
+        let integration = &result.integration;
+        assert_eq!(result.segment_starts, vec![0, 5]);
+        assert_eq!(integration.status, ReturnCode::Success);
+        assert_eq!(integration.stats.accepted_steps, 6);
+        assert_eq!(
+            integration.time,
+            vec![0.0, 0.25, 0.5, 0.75, 1.0, 1.0, 1.25, 1.5]
+        );
+        assert_eq!(integration.values[4], vec![1.0]);
+        assert_eq!(integration.values[5], vec![2.0]);
+        assert_eq!(integration.values[7], vec![1.125]);
+    }
+
+    #[test]
+    fn rejects_reset_outside_the_state() {
+        let segments = vec![Segment {
+            t_end: 1.0,
+            pars: vec![1.0],
+            reset: vec![(3, 0.0)],
+        }];
+        let error = run_protocol(
+            |y0, pars, t_start, t_end| {
+                euler(&decay, y0, pars, 0.25, t_start, t_end, &[])
+            },
+            vec![1.0],
+            0.0,
+            &segments,
+        );
+        assert!(matches!(
+            error.err(),
+            Some(SolverError::DimensionMismatch {
+                expected: 4,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,645 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use super::units::parse_unit;
+use crate::events::Crossing;
+use crate::expr::{
+    BinaryOp, CompiledModel, Expr, ExprModel, Function, ModelError,
+    PI, TIME,
+};
+
+/// Text with the characters that are special in XML escaped
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn number(out: &mut String, value: f64) {
+    if value.is_nan() {
+        out.push_str("<notanumber/>");
+    } else if value == f64::INFINITY {
+        out.push_str("<infinity/>");
+    } else if value == f64::NEG_INFINITY {
+        out.push_str("<apply><minus/><infinity/></apply>");
+    } else {
+        write!(out, "<cn> {value} </cn>").unwrap();
+    }
+}
+
+/// Content MathML of `expr`, without the `<math>` element
+fn mathml(out: &mut String, expr: &Expr) {
+    match expr {
+        Expr::Number(value) => number(out, *value),
+        Expr::Name(name) if name == TIME => out.push_str(
+            "<csymbol encoding=\"text\" \
+             definitionURL=\"http://www.sbml.org/sbml/symbols/time\"> \
+             time </csymbol>",
+        ),
+        Expr::Name(name) if name == PI => out.push_str("<pi/>"),
+        Expr::Name(name) => write!(out, "<ci> {name} </ci>").unwrap(),
+        Expr::Neg(x) => {
+            out.push_str("<apply><minus/>");
+            mathml(out, x);
+            out.push_str("</apply>");
+        }
+        Expr::Binary(op, a, b) => {
+            let operator = match op {
+                BinaryOp::Add => "plus",
+                BinaryOp::Sub => "minus",
+                BinaryOp::Mul => "times",
+                BinaryOp::Div => "divide",
+                BinaryOp::Pow => "power",
+            };
+            write!(out, "<apply><{operator}/>").unwrap();
+            mathml(out, a);
+            mathml(out, b);
+            out.push_str("</apply>");
+        }
+        Expr::Call(function, args) => {
+            // `<log/>` without `<logbase>` is the decadic
+            // logarithm, `<root/>` without `<degree>` the square
+            // root
+            let operator = match function {
+                Function::Exp => "exp",
+                Function::Ln => "ln",
+                Function::Log10 => "log",
+                Function::Sqrt => "root",
+                Function::Abs => "abs",
+                Function::Sin => "sin",
+                Function::Cos => "cos",
+                Function::Tan => "tan",
+                Function::Min => "min",
+                Function::Max => "max",
+            };
+            write!(out, "<apply><{operator}/>").unwrap();
+            for arg in args {
+                mathml(out, arg);
+            }
+            out.push_str("</apply>");
+        }
+    }
+}
+
+/// `<math>` element with `expr`, on its own line
+fn math(out: &mut String, indent: &str, expr: &Expr) {
+    write!(
+        out,
+        "{indent}<math xmlns=\"http://www.w3.org/1998/Math/MathML\">"
+    )
+    .unwrap();
+    mathml(out, expr);
+    out.push_str("</math>\n");
+}
+
+/// Unit definitions of every unit used in `model`, by unit text
+///
+/// Units that cannot be written with SBML base units are left
+/// out and only kept in the notes of their symbol.
+struct Units {
+    ids: Vec<(String, String)>,
+    definitions: String,
+}
+
+impl Units {
+    fn new<'a>(units: impl Iterator<Item = &'a str>) -> Self {
+        let mut ids: Vec<(String, String)> = vec![];
+        let mut definitions = String::new();
+        for unit in units {
+            if ids.iter().any(|(text, _)| text == unit) {
+                continue;
+            }
+            let Some(factors) = parse_unit(unit) else {
+                continue;
+            };
+            if factors.is_empty() {
+                ids.push((
+                    unit.to_string(),
+                    "dimensionless".into(),
+                ));
+                continue;
+            }
+            let id = format!("unit_{}", ids.len());
+            writeln!(
+                definitions,
+                "      <unitDefinition id=\"{id}\" name=\"{}\">\n        \
+                 <listOfUnits>",
+                escape(unit)
+            )
+            .unwrap();
+            for factor in factors {
+                writeln!(
+                    definitions,
+                    "          <unit kind=\"{}\" exponent=\"{}\" \
+                     scale=\"{}\" multiplier=\"{}\"/>",
+                    factor.kind,
+                    factor.exponent,
+                    factor.scale,
+                    factor.multiplier
+                )
+                .unwrap();
+            }
+            definitions.push_str(
+                "        </listOfUnits>\n      </unitDefinition>\n",
+            );
+            ids.push((unit.to_string(), id));
+        }
+        Units { ids, definitions }
+    }
+
+    /// ` attribute="id"` for `unit`, or nothing
+    fn attribute(
+        &self,
+        attribute: &str,
+        unit: Option<&str>,
+    ) -> String {
+        unit.and_then(|unit| {
+            self.ids.iter().find(|(text, _)| text == unit)
+        })
+        .map(|(_, id)| format!(" {attribute}=\"{id}\""))
+        .unwrap_or_default()
+    }
+
+    /// Element body with a note on a unit without definition,
+    /// or `/>` to close the element
+    fn notes(
+        &self,
+        unit: Option<&str>,
+        indent: &str,
+        tag: &str,
+    ) -> String {
+        match unit {
+            Some(unit)
+                if !self
+                    .ids
+                    .iter()
+                    .any(|(text, _)| text == unit) =>
+            {
+                format!(
+                    ">\n{indent}  <notes><p \
+                     xmlns=\"http://www.w3.org/1999/xhtml\">Unit: \
+                     {}</p></notes>\n{indent}</{tag}>",
+                    escape(unit)
+                )
+            }
+            _ => "/>".to_string(),
+        }
+    }
+}
+
+/// Write a model as an SBML Level 3 Version 2 Core document
+///
+/// States become species, in a compartment of size one unless
+/// they are a concentration in a compartment of their own.
+/// Parameters used as compartments become compartments,
+/// derived values parameters with an assignment rule. Events
+/// fire on the comparison becoming true, with the values at the
+/// time of the event. Units are written as unit definitions
+/// where they consist of SBML base units, e.g. `µmol/(m² s)`,
+/// and as notes otherwise. Events on `!=` have no counterpart
+/// in SBML and are rejected, as are events that change the
+/// compartment of a concentration.
+pub fn export_sbml(
+    model: &ExprModel,
+    id: &str,
+) -> Result<String, ModelError> {
+    // Only write models that can be simulated
+    CompiledModel::new(model.clone())?;
+    if let Some(event) = model
+        .events
+        .iter()
+        .find(|event| event.crossing == Crossing::Any)
+    {
+        return Err(ModelError::new(format!(
+            "event `{}` fires on any crossing, which SBML cannot \
+             express",
+            event.name
+        )));
+    }
+
+    let compartments: HashSet<&str> = model
+        .states
+        .iter()
+        .filter_map(|state| state.compartment.as_deref())
+        .collect();
+    let assigned: HashSet<&str> = model
+        .events
+        .iter()
+        .flat_map(|event| &event.assignments)
+        .map(|assignment| assignment.name.as_str())
+        .collect();
+    // SBML keeps the amounts of species when an event resizes
+    // their compartment, while concentration states keep their
+    // value here
+    if let Some(state) = model.states.iter().find(|state| {
+        state
+            .compartment
+            .as_deref()
+            .is_some_and(|c| assigned.contains(c))
+    }) {
+        return Err(ModelError::new(format!(
+            "event changes `{}`, the compartment of the \
+             concentration `{}`, which SBML would apply to its \
+             amount instead",
+            state.compartment.as_deref().unwrap_or_default(),
+            state.name
+        )));
+    }
+    let constant = |name: &str| !assigned.contains(name);
+    // Compartment of the states that are amounts, with a name
+    // no symbol of the model uses
+    let mut default_compartment = "default_compartment".to_string();
+    while model.states.iter().any(|s| s.name == default_compartment)
+        || model
+            .parameters
+            .iter()
+            .any(|p| p.name == default_compartment)
+        || model
+            .derived
+            .iter()
+            .any(|d| d.name == default_compartment)
+    {
+        default_compartment.push('_');
+    }
+    let units =
+        Units::new(
+            model
+                .states
+                .iter()
+                .filter(|state| state.compartment.is_none())
+                .filter_map(|state| state.unit.as_deref())
+                .chain(model.parameters.iter().filter_map(
+                    |parameter| parameter.unit.as_deref(),
+                )),
+        );
+
+    let mut out = String::new();
+    out.push_str(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <sbml xmlns=\"http://www.sbml.org/sbml/level3/version2/core\" \
+         level=\"3\" version=\"2\">\n",
+    );
+    writeln!(out, "  <model id=\"{}\">", escape(id)).unwrap();
+    if !units.definitions.is_empty() {
+        out.push_str("    <listOfUnitDefinitions>\n");
+        out.push_str(&units.definitions);
+        out.push_str("    </listOfUnitDefinitions>\n");
+    }
+
+    out.push_str("    <listOfCompartments>\n");
+    if model.states.iter().any(|state| state.compartment.is_none())
+    {
+        writeln!(
+            out,
+            "      <compartment id=\"{default_compartment}\" size=\"1\" \
+             spatialDimensions=\"3\" constant=\"true\"/>"
+        )
+        .unwrap();
+    }
+    for parameter in &model.parameters {
+        if !compartments.contains(parameter.name.as_str()) {
+            continue;
+        }
+        let unit = parameter.unit.as_deref();
+        writeln!(
+            out,
+            "      <compartment id=\"{}\" size=\"{}\"{} \
+             spatialDimensions=\"3\" constant=\"{}\"{}",
+            parameter.name,
+            parameter.value,
+            units.attribute("units", unit),
+            constant(&parameter.name),
+            units.notes(unit, "      ", "compartment"),
+        )
+        .unwrap();
+    }
+    out.push_str("    </listOfCompartments>\n");
+
+    if !model.states.is_empty() {
+        out.push_str("    <listOfSpecies>\n");
+    }
+    for state in &model.states {
+        let (compartment, initial, amounts, unit) = match &state
+            .compartment
+        {
+            Some(compartment) => {
+                (compartment, "initialConcentration", false, None)
+            }
+            None => (
+                &default_compartment,
+                "initialAmount",
+                true,
+                state.unit.as_deref(),
+            ),
+        };
+        writeln!(
+            out,
+            "      <species id=\"{}\" compartment=\"{compartment}\" \
+             {initial}=\"{}\"{} hasOnlySubstanceUnits=\"{amounts}\" \
+             boundaryCondition=\"false\" constant=\"false\"{}",
+            state.name,
+            state.value,
+            units.attribute("substanceUnits", unit),
+            units.notes(unit, "      ", "species"),
+        )
+        .unwrap();
+    }
+    if !model.states.is_empty() {
+        out.push_str("    </listOfSpecies>\n");
+    }
+
+    let parameters: Vec<_> = model
+        .parameters
+        .iter()
+        .filter(|p| !compartments.contains(p.name.as_str()))
+        .collect();
+    if !parameters.is_empty() || !model.derived.is_empty() {
+        out.push_str("    <listOfParameters>\n");
+    }
+    for parameter in parameters.iter() {
+        let unit = parameter.unit.as_deref();
+        writeln!(
+            out,
+            "      <parameter id=\"{}\" value=\"{}\"{} constant=\"{}\"{}",
+            parameter.name,
+            parameter.value,
+            units.attribute("units", unit),
+            constant(&parameter.name),
+            units.notes(unit, "      ", "parameter"),
+        )
+        .unwrap();
+    }
+    for derived in &model.derived {
+        writeln!(
+            out,
+            "      <parameter id=\"{}\" constant=\"false\"/>",
+            derived.name
+        )
+        .unwrap();
+    }
+    if !parameters.is_empty() || !model.derived.is_empty() {
+        out.push_str("    </listOfParameters>\n");
+    }
+
+    if !model.derived.is_empty() || !model.rate_rules.is_empty() {
+        out.push_str("    <listOfRules>\n");
+    }
+    for (tag, rule) in model
+        .derived
+        .iter()
+        .map(|rule| ("assignmentRule", rule))
+        .chain(
+            model.rate_rules.iter().map(|rule| ("rateRule", rule)),
+        )
+    {
+        writeln!(out, "      <{tag} variable=\"{}\">", rule.name)
+            .unwrap();
+        math(&mut out, "        ", &rule.expression);
+        writeln!(out, "      </{tag}>").unwrap();
+    }
+    if !model.derived.is_empty() || !model.rate_rules.is_empty() {
+        out.push_str("    </listOfRules>\n");
+    }
+
+    if !model.reactions.is_empty() {
+        out.push_str("    <listOfReactions>\n");
+    }
+    for reaction in &model.reactions {
+        // Rates may be negative
+        writeln!(
+            out,
+            "      <reaction id=\"{}\" reversible=\"true\">",
+            reaction.name
+        )
+        .unwrap();
+        for (list, sign) in
+            [("listOfReactants", -1.0), ("listOfProducts", 1.0)]
+        {
+            let references: Vec<_> = reaction
+                .stoichiometry
+                .iter()
+                .filter(|(_, coefficient)| sign * coefficient > 0.0)
+                .collect();
+            if references.is_empty() {
+                continue;
+            }
+            writeln!(out, "        <{list}>").unwrap();
+            for (species, coefficient) in references {
+                writeln!(
+                    out,
+                    "          <speciesReference species=\"{species}\" \
+                     stoichiometry=\"{}\" constant=\"true\"/>",
+                    coefficient.abs()
+                )
+                .unwrap();
+            }
+            writeln!(out, "        </{list}>").unwrap();
+        }
+        // States the rate depends on without being changed
+        let mut modifiers: Vec<&str> = vec![];
+        for name in reaction.rate.names() {
+            if model.states.iter().any(|s| s.name == name)
+                && !reaction
+                    .stoichiometry
+                    .iter()
+                    .any(|(s, _)| s == name)
+                && !modifiers.contains(&name)
+            {
+                modifiers.push(name);
+            }
+        }
+        if !modifiers.is_empty() {
+            out.push_str("        <listOfModifiers>\n");
+            for species in modifiers {
+                writeln!(
+                    out,
+                    "          <modifierSpeciesReference \
+                     species=\"{species}\"/>"
+                )
+                .unwrap();
+            }
+            out.push_str("        </listOfModifiers>\n");
+        }
+        out.push_str("        <kineticLaw>\n");
+        math(&mut out, "          ", &reaction.rate);
+        out.push_str("        </kineticLaw>\n      </reaction>\n");
+    }
+    if !model.reactions.is_empty() {
+        out.push_str("    </listOfReactions>\n");
+    }
+
+    if !model.events.is_empty() {
+        out.push_str("    <listOfEvents>\n");
+    }
+    for event in &model.events {
+        let comparison = match event.crossing {
+            Crossing::Rising => "gt",
+            _ => "lt",
+        };
+        writeln!(
+            out,
+            "      <event id=\"{}\" useValuesFromTriggerTime=\"true\">\n        \
+             <trigger initialValue=\"true\" persistent=\"true\">",
+            event.name
+        )
+        .unwrap();
+        write!(
+            out,
+            "          <math xmlns=\"http://www.w3.org/1998/Math/MathML\">\
+             <apply><{comparison}/>"
+        )
+        .unwrap();
+        mathml(&mut out, &event.trigger);
+        number(&mut out, 0.0);
+        out.push_str("</apply></math>\n        </trigger>\n");
+        if !event.assignments.is_empty() {
+            out.push_str("        <listOfEventAssignments>\n");
+            for assignment in &event.assignments {
+                writeln!(
+                    out,
+                    "          <eventAssignment variable=\"{}\">",
+                    assignment.name
+                )
+                .unwrap();
+                math(
+                    &mut out,
+                    "            ",
+                    &assignment.expression,
+                );
+                out.push_str("          </eventAssignment>\n");
+            }
+            out.push_str("        </listOfEventAssignments>\n");
+        }
+        out.push_str("      </event>\n");
+    }
+    if !model.events.is_empty() {
+        out.push_str("    </listOfEvents>\n");
+    }
+    out.push_str("  </model>\n</sbml>\n");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rhs;
+    use crate::expr::parse_model;
+    use crate::models::MODELS;
+    use crate::sbml::import_sbml;
+
+    /// Derivatives of `model` and of `model` written to SBML and
+    /// read back
+    fn round_trip(
+        model: &ExprModel,
+    ) -> (ExprModel, Vec<f64>, Vec<f64>) {
+        let text = export_sbml(model, "test").unwrap();
+        let imported = import_sbml(&text).unwrap();
+        let original = CompiledModel::new(model.clone()).unwrap();
+        let y: Vec<f64> =
+            model.states.iter().map(|s| s.value).collect();
+        let pars: Vec<f64> =
+            model.parameters.iter().map(|p| p.value).collect();
+        let expected = original.eval(1.0, &y, &pars).unwrap();
+        let pars: Vec<f64> =
+            imported.parameters.iter().map(|p| p.value).collect();
+        let found = CompiledModel::new(imported.clone())
+            .unwrap()
+            .eval(1.0, &y, &pars)
+            .unwrap();
+        (imported, expected, found)
+    }
+
+    #[test]
+    fn round_trips_built_in_models() {
+        for info in MODELS {
+            let model = info.expr_model();
+            let (imported, expected, found) = round_trip(&model);
+            assert_eq!(expected, found, "{}", info.name);
+            assert_eq!(imported.derived.len(), model.derived.len());
+            let names = |model: &ExprModel| -> Vec<String> {
+                model
+                    .parameters
+                    .iter()
+                    .map(|p| p.name.clone())
+                    .collect()
+            };
+            // The compartment of the states comes first
+            assert_eq!(names(&imported)[0], "default_compartment");
+            assert_eq!(names(&imported)[1..], names(&model));
+        }
+        let npq = MODELS[1].expr_model();
+        let text = export_sbml(&npq, "npq").unwrap();
+        assert!(text.contains(
+            "<assignmentRule variable=\"fluorescence\">"
+        ));
+        let (imported, _, _) = round_trip(&npq);
+        let ppfd = &imported.parameters[1];
+        assert_eq!(
+            (ppfd.name.as_str(), ppfd.unit.as_deref()),
+            ("ppfd", Some("µmol/(m² s)"))
+        );
+    }
+
+    #[test]
+    fn round_trips_compartments_rules_and_events() {
+        let model = parse_model(
+            "state x = 1 in cell\n\
+             state y = 2 [mmol]\n\
+             state v = 0\n\
+             parameter cell = 2 [l]\n\
+             parameter k = 0.5 [1/time]\n\
+             derived flux = k * x * sqrt(y) / (1 + log10(y))\n\
+             reaction convert: x -> 2 y; flux\n\
+             reaction decay: y -> ; k * y * exp(-time) * x\n\
+             rate v = -min(x, y) + pi\n\
+             event refill: x < 0.5; x = 1, y = 2 * y\n",
+        )
+        .unwrap();
+        let (imported, expected, found) = round_trip(&model);
+        for (e, f) in expected.iter().zip(&found) {
+            assert!((e - f).abs() <= 1e-15 * e.abs().max(1.0));
+        }
+        assert_eq!(
+            imported.states[0].compartment.as_deref(),
+            Some("cell")
+        );
+        assert_eq!(
+            imported.states[1].unit.as_deref(),
+            Some("mmol")
+        );
+        let refill = &imported.events[0];
+        assert_eq!(refill.crossing, Crossing::Falling);
+        assert_eq!(refill.assignments.len(), 2);
+
+        let text = export_sbml(&model, "test").unwrap();
+        assert!(
+            text.contains(
+                "<modifierSpeciesReference species=\"x\"/>"
+            )
+        );
+        assert!(text.contains("Unit: 1/time"));
+
+        let model = parse_model(
+            "state x = 1\nrate x = 1\nevent e: x != 2;",
+        )
+        .unwrap();
+        let error = export_sbml(&model, "test").unwrap_err();
+        assert!(error.message.contains("any crossing"));
+
+        let model = parse_model(
+            "state x = 1 in cell\n\
+             parameter cell = 2\n\
+             rate x = -x\n\
+             event grow: x < 0.5; cell = 2 * cell\n",
+        )
+        .unwrap();
+        let error = export_sbml(&model, "test").unwrap_err();
+        assert_eq!(
+            error.message,
+            "event changes `cell`, the compartment of the \
+             concentration `x`, which SBML would apply to its \
+             amount instead"
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+
+use roxmltree::Node;
+
+use super::error_at;
+use crate::events::Crossing;
+use crate::expr::{BinaryOp, Expr, Function, ModelError, PI, TIME};
+
+/// Function definition, inlined wherever it is called
+pub struct Lambda {
+    pub arguments: Vec<String>,
+    pub body: Expr,
+}
+
+pub type Functions = HashMap<String, Lambda>;
+
+/// Child elements, skipping text and comments
+pub fn elements<'a, 'input>(
+    node: Node<'a, 'input>,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(Node::is_element)
+}
+
+/// The single expression inside a `<math>` element
+pub fn math(
+    node: Node,
+    functions: &Functions,
+) -> Result<Expr, ModelError> {
+    let mut children = elements(node);
+    match (children.next(), children.next()) {
+        (Some(child), None) => expr(child, functions),
+        _ => Err(error_at(
+            node,
+            "expected exactly one expression in <math>".to_string(),
+        )),
+    }
+}
+
+/// `<lambda>` with `<bvar>` arguments and a body
+pub fn lambda(
+    node: Node,
+    functions: &Functions,
+) -> Result<Lambda, ModelError> {
+    let mut arguments = vec![];
+    let mut body = None;
+    for child in elements(node) {
+        match child.tag_name().name() {
+            "bvar" => {
+                let name = elements(child)
+                    .next()
+                    .filter(|ci| ci.tag_name().name() == "ci")
+                    .ok_or_else(|| {
+                        error_at(
+                            child,
+                            "expected <ci> in <bvar>".to_string(),
+                        )
+                    })?;
+                arguments.push(text(name).to_string());
+            }
+            _ if body.is_none() => {
+                body = Some(expr(child, functions)?)
+            }
+            _ => {
+                return Err(error_at(
+                    child,
+                    "expected a single body in <lambda>"
+                        .to_string(),
+                ));
+            }
+        }
+    }
+    let body = body.ok_or_else(|| {
+        error_at(node, "<lambda> has no body".to_string())
+    })?;
+    Ok(Lambda { arguments, body })
+}
+
+/// Event trigger as function and direction in which it has to
+/// cross zero, e.g. `a > b` becomes `a - b` rising
+pub fn trigger(
+    node: Node,
+    functions: &Functions,
+) -> Result<(Expr, Crossing), ModelError> {
+    let unsupported = || {
+        error_at(
+            node,
+            "only triggers comparing two values with <gt>, <geq>, <lt> \
+             or <leq> are supported"
+                .to_string(),
+        )
+    };
+    if node.tag_name().name() != "apply" {
+        return Err(unsupported());
+    }
+    let mut children = elements(node);
+    let operator = children.next().ok_or_else(unsupported)?;
+    let crossing = match operator.tag_name().name() {
+        "gt" | "geq" => Crossing::Rising,
+        "lt" | "leq" => Crossing::Falling,
+        _ => return Err(unsupported()),
+    };
+    let (Some(a), Some(b), None) =
+        (children.next(), children.next(), children.next())
+    else {
+        return Err(unsupported());
+    };
+    let g = Expr::Binary(
+        BinaryOp::Sub,
+        Box::new(expr(a, functions)?),
+        Box::new(expr(b, functions)?),
+    );
+    Ok((g, crossing))
+}
+
+fn text<'a>(node: Node<'a, '_>) -> &'a str {
+    node.text().unwrap_or_default().trim()
+}
+
+/// Parse a `<cn>` including the `e-notation` and `rational`
+/// types, whose parts are separated by `<sep/>`
+fn number(node: Node) -> Result<f64, ModelError> {
+    let parts: Vec<&str> = node
+        .children()
+        .filter(Node::is_text)
+        .map(|part| part.text().unwrap_or_default().trim())
+        .collect();
+    let parse = |part: &str| {
+        part.parse::<f64>().map_err(|_| {
+            error_at(node, format!("invalid number `{part}`"))
+        })
+    };
+    match (node.attribute("type"), parts.as_slice()) {
+        (Some("e-notation"), [mantissa, exponent]) => {
+            Ok(parse(mantissa)? * 10f64.powf(parse(exponent)?))
+        }
+        (Some("rational"), [numerator, denominator]) => {
+            Ok(parse(numerator)? / parse(denominator)?)
+        }
+        (_, [value]) => parse(value),
+        _ => Err(error_at(node, "malformed <cn>".to_string())),
+    }
+}
+
+fn fold(args: Vec<Expr>, op: BinaryOp, empty: f64) -> Expr {
+    args.into_iter()
+        .reduce(|a, b| Expr::Binary(op, Box::new(a), Box::new(b)))
+        .unwrap_or(Expr::Number(empty))
+}
+
+pub fn expr(
+    node: Node,
+    functions: &Functions,
+) -> Result<Expr, ModelError> {
+    let name = node.tag_name().name();
+    let unsupported = || {
+        error_at(
+            node,
+            format!("MathML element <{name}> is not supported"),
+        )
+    };
+    match name {
+        "cn" => Ok(Expr::Number(number(node)?)),
+        "ci" => Ok(Expr::Name(text(node).to_string())),
+        "csymbol" => {
+            let url =
+                node.attribute("definitionURL").unwrap_or_default();
+            if url.ends_with("/time") {
+                Ok(Expr::Name(TIME.to_string()))
+            } else if url.ends_with("/avogadro") {
+                Ok(Expr::Number(6.02214076e23))
+            } else {
+                Err(error_at(
+                    node,
+                    format!("csymbol `{url}` is not supported"),
+                ))
+            }
+        }
+        "pi" => Ok(Expr::Name(PI.to_string())),
+        "exponentiale" => {
+            Ok(Expr::Call(Function::Exp, vec![Expr::Number(1.0)]))
+        }
+        "infinity" => Ok(Expr::Number(f64::INFINITY)),
+        "notanumber" => Ok(Expr::Number(f64::NAN)),
+        "apply" => apply(node, functions),
+        _ => Err(unsupported()),
+    }
+}
+
+fn apply(
+    node: Node,
+    functions: &Functions,
+) -> Result<Expr, ModelError> {
+    let mut children = elements(node);
+    let operator = children.next().ok_or_else(|| {
+        error_at(node, "empty <apply>".to_string())
+    })?;
+    // Qualifiers of `root` and `log`
+    let mut qualifier = None;
+    let mut args = vec![];
+    for child in children {
+        match child.tag_name().name() {
+            "degree" | "logbase" => {
+                let value =
+                    elements(child).next().ok_or_else(|| {
+                        error_at(
+                            child,
+                            "empty qualifier".to_string(),
+                        )
+                    })?;
+                qualifier = Some(expr(value, functions)?);
+            }
+            _ => args.push(expr(child, functions)?),
+        }
+    }
+
+    let name = operator.tag_name().name();
+    let arity = |args: &[Expr], n: usize| {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(error_at(
+                node,
+                format!(
+                    "<{name}> takes {n} argument(s), got {}",
+                    args.len()
+                ),
+            ))
+        }
+    };
+    let call = |function: Function, args: Vec<Expr>| {
+        arity(&args, function.arity())?;
+        Ok(Expr::Call(function, args))
+    };
+    let binary = |op: BinaryOp, args: Vec<Expr>| {
+        arity(&args, 2)?;
+        let [a, b] =
+            <[Expr; 2]>::try_from(args).expect("two arguments");
+        Ok(Expr::Binary(op, Box::new(a), Box::new(b)))
+    };
+    match name {
+        "plus" => Ok(fold(args, BinaryOp::Add, 0.0)),
+        "times" => Ok(fold(args, BinaryOp::Mul, 1.0)),
+        "minus" if args.len() == 1 => {
+            Ok(Expr::Neg(Box::new(args.remove(0))))
+        }
+        "minus" => binary(BinaryOp::Sub, args),
+        "divide" => binary(BinaryOp::Div, args),
+        "power" => binary(BinaryOp::Pow, args),
+        "root" => match qualifier {
+            Some(degree) if degree.constant() != Some(2.0) => {
+                arity(&args, 1)?;
+                let inverse = Expr::Binary(
+                    BinaryOp::Div,
+                    Box::new(Expr::Number(1.0)),
+                    Box::new(degree),
+                );
+                binary(BinaryOp::Pow, vec![args.remove(0), inverse])
+            }
+            _ => call(Function::Sqrt, args),
+        },
+        "log" => match qualifier {
+            None => call(Function::Log10, args),
+            Some(base) => {
+                arity(&args, 1)?;
+                let ln = |x| Expr::Call(Function::Ln, vec![x]);
+                binary(
+                    BinaryOp::Div,
+                    vec![ln(args.remove(0)), ln(base)],
+                )
+            }
+        },
+        "ln" => call(Function::Ln, args),
+        "exp" => call(Function::Exp, args),
+        "abs" => call(Function::Abs, args),
+        "sin" => call(Function::Sin, args),
+        "cos" => call(Function::Cos, args),
+        "tan" => call(Function::Tan, args),
+        "min" | "max" => {
+            let function = if name == "min" {
+                Function::Min
+            } else {
+                Function::Max
+            };
+            args.into_iter()
+                .reduce(|a, b| Expr::Call(function, vec![a, b]))
+                .ok_or_else(|| {
+                    error_at(
+                        node,
+                        format!("<{name}> without arguments"),
+                    )
+                })
+        }
+        "ci" => {
+            let id = text(operator);
+            let lambda = functions.get(id).ok_or_else(|| {
+                error_at(
+                    operator,
+                    format!("unknown function `{id}`"),
+                )
+            })?;
+            arity(&args, lambda.arguments.len())?;
+            let values: HashMap<&str, &Expr> = lambda
+                .arguments
+                .iter()
+                .map(String::as_str)
+                .zip(&args)
+                .collect();
+            Ok(lambda.body.substitute(&|name| {
+                values.get(name).map(|&x| x.clone())
+            }))
+        }
+        _ => Err(error_at(
+            operator,
+            format!("MathML element <{name}> is not supported"),
+        )),
+    }
+}
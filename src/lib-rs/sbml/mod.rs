@@ -0,0 +1,690 @@
+mod export;
+mod mathml;
+mod units;
+
+use std::collections::HashMap;
+
+use roxmltree::{Document, Node};
+
+use crate::expr::{
+    Assignment, BinaryOp, Expr, ExprModel, ModelError, ModelEvent,
+    Quantity, Reaction, State, TIME,
+};
+pub use export::export_sbml;
+use mathml::{Functions, elements};
+
+/// Error pointing at the start of `node` in the document
+fn error_at(node: Node, message: String) -> ModelError {
+    let position = node.document().text_pos_at(node.range().start);
+    ModelError::at(
+        position.row as usize,
+        position.col as usize,
+        message,
+    )
+}
+
+/// Elements `<item>` inside `<list>` below `node`
+fn list<'a, 'input>(
+    node: Node<'a, 'input>,
+    list: &'static str,
+    item: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    elements(node)
+        .filter(move |child| child.tag_name().name() == list)
+        .flat_map(elements)
+        .filter(move |child| child.tag_name().name() == item)
+}
+
+fn child<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &str,
+) -> Option<Node<'a, 'input>> {
+    elements(node).find(|child| child.tag_name().name() == name)
+}
+
+fn required<'a>(
+    node: Node<'a, '_>,
+    attribute: &str,
+) -> Result<&'a str, ModelError> {
+    node.attribute(attribute).ok_or_else(|| {
+        error_at(
+            node,
+            format!(
+                "<{}> is missing the attribute `{attribute}`",
+                node.tag_name().name()
+            ),
+        )
+    })
+}
+
+fn number(
+    node: Node,
+    attribute: &str,
+) -> Result<Option<f64>, ModelError> {
+    node.attribute(attribute)
+        .map(|value| {
+            value.parse().map_err(|_| {
+                error_at(node, format!("invalid number `{value}`"))
+            })
+        })
+        .transpose()
+}
+
+fn flag(node: Node, attribute: &str, default: bool) -> bool {
+    node.attribute(attribute)
+        .map_or(default, |value| value == "true")
+}
+
+/// `<math>` below `node`
+fn math_of(
+    node: Node,
+    functions: &Functions,
+) -> Result<Expr, ModelError> {
+    let math = child(node, "math").ok_or_else(|| {
+        error_at(
+            node,
+            format!("<{}> has no <math>", node.tag_name().name()),
+        )
+    })?;
+    mathml::math(math, functions)
+}
+
+/// Role of an SBML symbol in the imported model
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    State,
+    Parameter,
+    Derived,
+}
+
+/// Read an SBML Level 3 Core model
+///
+/// Species become states, or parameters if they are constant.
+/// Species measured in concentration keep their compartment,
+/// so reactions change their amount. Compartments and
+/// parameters become states when they have a rate rule and
+/// derived values when they have an assignment rule. Function
+/// definitions are inlined and local parameters of kinetic
+/// laws are renamed to `reaction_parameter`. Initial
+/// assignments are evaluated once while reading, with the
+/// values given in the file. Units are kept as the names of
+/// their unit definitions.
+///
+/// Algebraic rules, delays, conversion factors, events with
+/// other triggers than a single comparison, compartments of
+/// variable size holding concentrations and fast reactions are
+/// rejected with an error. Constraints only check a simulation
+/// and are ignored.
+pub fn import_sbml(text: &str) -> Result<ExprModel, ModelError> {
+    let document = Document::parse(text).map_err(|error| {
+        let position = error.pos();
+        ModelError::at(
+            position.row as usize,
+            position.col as usize,
+            error.to_string(),
+        )
+    })?;
+    let sbml = document.root_element();
+    if sbml.tag_name().name() != "sbml" {
+        return Err(error_at(
+            sbml,
+            "expected an <sbml> document".into(),
+        ));
+    }
+    if sbml.attribute("level") != Some("3") {
+        return Err(error_at(
+            sbml,
+            "only SBML Level 3 is supported".to_string(),
+        ));
+    }
+    let model = child(sbml, "model").ok_or_else(|| {
+        error_at(sbml, "the document has no <model>".to_string())
+    })?;
+    if let Some(node) = std::iter::once(model)
+        .chain(list(model, "listOfSpecies", "species"))
+        .find(|node| node.has_attribute("conversionFactor"))
+    {
+        return Err(error_at(
+            node,
+            "conversion factors are not supported".to_string(),
+        ));
+    }
+
+    let mut functions = Functions::new();
+    for definition in list(
+        model,
+        "listOfFunctionDefinitions",
+        "functionDefinition",
+    ) {
+        let id = required(definition, "id")?;
+        let lambda = child(definition, "math")
+            .and_then(|math| elements(math).next())
+            .filter(|node| node.tag_name().name() == "lambda")
+            .ok_or_else(|| {
+                error_at(
+                    definition,
+                    "expected <math><lambda>".to_string(),
+                )
+            })?;
+        let lambda = mathml::lambda(lambda, &functions)?;
+        functions.insert(id.to_string(), lambda);
+    }
+
+    // Units by id, written as the name of their definition if
+    // it has one
+    let mut unit_names = HashMap::new();
+    for definition in
+        list(model, "listOfUnitDefinitions", "unitDefinition")
+    {
+        let id = required(definition, "id")?;
+        unit_names
+            .insert(id, definition.attribute("name").unwrap_or(id));
+    }
+    let unit_of = |node: Node| {
+        let attribute = match node.tag_name().name() {
+            "species" => "substanceUnits",
+            _ => "units",
+        };
+        node.attribute(attribute).map(|id| {
+            unit_names.get(id).copied().unwrap_or(id).to_string()
+        })
+    };
+
+    let mut assignment_rules = HashMap::new();
+    let mut rate_rules = vec![];
+    for rule in elements(model)
+        .filter(|c| c.tag_name().name() == "listOfRules")
+        .flat_map(elements)
+    {
+        let variable = required(rule, "variable")?;
+        let expression = math_of(rule, &functions)?;
+        match rule.tag_name().name() {
+            "assignmentRule" => {
+                assignment_rules.insert(variable, expression);
+            }
+            "rateRule" => rate_rules.push(Assignment {
+                name: variable.to_string(),
+                expression,
+            }),
+            other => {
+                return Err(error_at(
+                    rule,
+                    format!("<{other}> is not supported"),
+                ));
+            }
+        }
+    }
+    let kind = |id: &str, constant: bool| {
+        if assignment_rules.contains_key(id) {
+            Kind::Derived
+        } else if rate_rules.iter().any(|rule| rule.name == id) {
+            Kind::State
+        } else if constant {
+            Kind::Parameter
+        } else {
+            Kind::State
+        }
+    };
+
+    // Initial value of every symbol as an expression, solved
+    // once everything is known
+    let mut initial: Vec<(&str, Expr, Node)> = vec![];
+    let mut kinds: Vec<(&str, Kind, Node)> = vec![];
+    let mut compartments = HashMap::new();
+    for compartment in
+        list(model, "listOfCompartments", "compartment")
+    {
+        let id = required(compartment, "id")?;
+        // Without rules, a compartment can only change through
+        // events, which are applied to parameters as well. Its
+        // species keep their amount then, so only a constant
+        // one may hold concentrations.
+        let kind = kind(id, true);
+        let constant = flag(compartment, "constant", true);
+        kinds.push((id, kind, compartment));
+        compartments.insert(id, (kind, constant));
+        if let Some(size) = number(compartment, "size")? {
+            initial.push((id, Expr::Number(size), compartment));
+        }
+    }
+
+    let mut species_compartment = HashMap::new();
+    let mut fixed = vec![];
+    for species in list(model, "listOfSpecies", "species") {
+        let id = required(species, "id")?;
+        let compartment = required(species, "compartment")?;
+        let amounts = flag(species, "hasOnlySubstanceUnits", false);
+        let kind = kind(id, flag(species, "constant", false));
+        kinds.push((id, kind, species));
+        if flag(species, "boundaryCondition", false)
+            || kind != Kind::State
+        {
+            fixed.push(id);
+        }
+        let size = || Box::new(Expr::Name(compartment.to_string()));
+        let value = match (
+            number(species, "initialAmount")?,
+            number(species, "initialConcentration")?,
+        ) {
+            (Some(amount), _) if !amounts => Some(Expr::Binary(
+                BinaryOp::Div,
+                Box::new(Expr::Number(amount)),
+                size(),
+            )),
+            (None, Some(concentration)) if amounts => {
+                Some(Expr::Binary(
+                    BinaryOp::Mul,
+                    Box::new(Expr::Number(concentration)),
+                    size(),
+                ))
+            }
+            (Some(value), _) | (None, Some(value)) => {
+                Some(Expr::Number(value))
+            }
+            (None, None) => None,
+        };
+        if let Some(value) = value {
+            initial.push((id, value, species));
+        }
+        match compartments.get(compartment) {
+            None => {
+                return Err(error_at(
+                    species,
+                    format!("unknown compartment `{compartment}`"),
+                ));
+            }
+            Some((Kind::Parameter, true)) => {}
+            Some(_) if amounts => {}
+            Some(_) => {
+                return Err(error_at(
+                    species,
+                    format!(
+                        "species `{id}` is a concentration in \
+                         `{compartment}`, whose size changes, which \
+                         is not supported"
+                    ),
+                ));
+            }
+        }
+        if !amounts {
+            species_compartment.insert(id, compartment);
+        }
+    }
+
+    for parameter in list(model, "listOfParameters", "parameter") {
+        let id = required(parameter, "id")?;
+        let kind = kind(id, flag(parameter, "constant", true));
+        kinds.push((id, kind, parameter));
+        if let Some(value) = number(parameter, "value")? {
+            initial.push((id, Expr::Number(value), parameter));
+        }
+    }
+
+    for assignment in
+        list(model, "listOfInitialAssignments", "initialAssignment")
+    {
+        let symbol = required(assignment, "symbol")?;
+        let expression = math_of(assignment, &functions)?;
+        initial.retain(|(id, _, _)| *id != symbol);
+        initial.push((symbol, expression, assignment));
+    }
+    for (&id, expression) in &assignment_rules {
+        initial.retain(|(other, _, _)| *other != id);
+        initial.push((id, expression.clone(), model));
+    }
+    let values = solve_initial_values(initial)?;
+    let value_of = |id: &str, node: Node| {
+        values.get(id).copied().ok_or_else(|| {
+            error_at(node, format!("`{id}` has no initial value"))
+        })
+    };
+
+    let mut result = ExprModel::default();
+    let mut local_parameters = vec![];
+    for reaction in list(model, "listOfReactions", "reaction") {
+        let id = required(reaction, "id")?;
+        if flag(reaction, "fast", false) {
+            return Err(error_at(
+                reaction,
+                format!("fast reaction `{id}` is not supported"),
+            ));
+        }
+        let mut stoichiometry = vec![];
+        for (side, sign) in
+            [("listOfReactants", -1.0), ("listOfProducts", 1.0)]
+        {
+            for reference in
+                list(reaction, side, "speciesReference")
+            {
+                let species = required(reference, "species")?;
+                let coefficient =
+                    number(reference, "stoichiometry")?
+                        .unwrap_or(1.0);
+                if !fixed.contains(&species) {
+                    stoichiometry.push((
+                        species.to_string(),
+                        sign * coefficient,
+                    ));
+                }
+            }
+        }
+        let law =
+            child(reaction, "kineticLaw").ok_or_else(|| {
+                error_at(
+                    reaction,
+                    format!("reaction `{id}` has no <kineticLaw>"),
+                )
+            })?;
+        let mut renamed = HashMap::new();
+        for local in
+            list(law, "listOfLocalParameters", "localParameter")
+        {
+            let name = required(local, "id")?;
+            let global = format!("{id}_{name}");
+            let value =
+                number(local, "value")?.ok_or_else(|| {
+                    error_at(
+                        local,
+                        format!(
+                            "local parameter `{name}` has no value"
+                        ),
+                    )
+                })?;
+            local_parameters.push(Quantity {
+                name: global.clone(),
+                value,
+                unit: unit_of(local),
+            });
+            renamed.insert(name, Expr::Name(global));
+        }
+        let rate = math_of(law, &functions)?
+            .substitute(&|name| renamed.get(name).cloned());
+        result.reactions.push(Reaction {
+            name: id.to_string(),
+            stoichiometry,
+            rate,
+        });
+    }
+
+    for &(id, kind, node) in &kinds {
+        match kind {
+            Kind::State => result.states.push(State {
+                name: id.to_string(),
+                value: value_of(id, node)?,
+                // The substance units of a concentration are not
+                // the unit of the state
+                unit: match species_compartment.get(id) {
+                    Some(_) => None,
+                    None => unit_of(node),
+                },
+                compartment: species_compartment
+                    .get(id)
+                    .map(|c| c.to_string()),
+            }),
+            Kind::Parameter => result.parameters.push(Quantity {
+                name: id.to_string(),
+                value: value_of(id, node)?,
+                unit: unit_of(node),
+            }),
+            Kind::Derived => result.derived.push(Assignment {
+                name: id.to_string(),
+                expression: assignment_rules[id].clone(),
+            }),
+        }
+    }
+    result.parameters.extend(local_parameters);
+    result.rate_rules = rate_rules;
+
+    for event in list(model, "listOfEvents", "event") {
+        let name = event.attribute("id").map_or_else(
+            || format!("event{}", result.events.len()),
+            str::to_string,
+        );
+        if let Some(delay) = child(event, "delay") {
+            return Err(error_at(
+                delay,
+                "event delays are not supported".to_string(),
+            ));
+        }
+        let trigger = child(event, "trigger")
+            .and_then(|trigger| child(trigger, "math"))
+            .and_then(|math| elements(math).next())
+            .ok_or_else(|| {
+                error_at(
+                    event,
+                    format!("event `{name}` has no trigger"),
+                )
+            })?;
+        let (trigger, crossing) =
+            mathml::trigger(trigger, &functions)?;
+        let assignments = list(
+            event,
+            "listOfEventAssignments",
+            "eventAssignment",
+        )
+        .map(|assignment| {
+            Ok(Assignment {
+                name: required(assignment, "variable")?.to_string(),
+                expression: math_of(assignment, &functions)?,
+            })
+        })
+        .collect::<Result<_, ModelError>>()?;
+        result.events.push(ModelEvent {
+            name,
+            trigger,
+            crossing,
+            assignments,
+        });
+    }
+
+    Ok(result)
+}
+
+/// Evaluate the initial value expressions, each of which may
+/// use the others, starting at time zero
+fn solve_initial_values(
+    mut pending: Vec<(&str, Expr, Node)>,
+) -> Result<HashMap<String, f64>, ModelError> {
+    let mut values = HashMap::from([(TIME.to_string(), 0.0)]);
+    while !pending.is_empty() {
+        let before = pending.len();
+        pending.retain(|(id, expression, _)| {
+            let known = expression
+                .substitute(&|name| {
+                    values.get(name).map(|&v| Expr::Number(v))
+                })
+                .constant();
+            match known {
+                Some(value) => {
+                    values.insert(id.to_string(), value);
+                    false
+                }
+                None => true,
+            }
+        });
+        if pending.len() == before {
+            let (id, _, node) = &pending[0];
+            return Err(error_at(
+                *node,
+                format!(
+                    "the initial value of `{id}` depends on unknown or circular values"
+                ),
+            ));
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rhs;
+    use crate::events::Crossing;
+    use crate::expr::CompiledModel;
+
+    fn document(model: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<sbml xmlns="http://www.sbml.org/sbml/level3/version2/core" level="3" version="2">
+  <model id="test">
+{model}
+  </model>
+</sbml>"#
+        )
+    }
+
+    const MATH: &str =
+        r#"xmlns="http://www.w3.org/1998/Math/MathML""#;
+
+    #[test]
+    fn imports_a_complete_model() {
+        let text = document(&format!(
+            r#"
+    <listOfFunctionDefinitions>
+      <functionDefinition id="mm">
+        <math {MATH}><lambda>
+          <bvar><ci>s</ci></bvar><bvar><ci>km</ci></bvar>
+          <apply><divide/><ci>s</ci>
+            <apply><plus/><ci>km</ci><ci>s</ci></apply></apply>
+        </lambda></math>
+      </functionDefinition>
+    </listOfFunctionDefinitions>
+    <listOfCompartments>
+      <compartment id="cell" size="2" constant="true"/>
+    </listOfCompartments>
+    <listOfSpecies>
+      <species id="S" compartment="cell" initialAmount="4"
+        hasOnlySubstanceUnits="false" boundaryCondition="false" constant="false"/>
+      <species id="P" compartment="cell" initialConcentration="0"
+        hasOnlySubstanceUnits="false" boundaryCondition="false" constant="false"/>
+      <species id="E" compartment="cell" initialConcentration="1"
+        hasOnlySubstanceUnits="false" boundaryCondition="true" constant="true"/>
+    </listOfSpecies>
+    <listOfParameters>
+      <parameter id="vmax" constant="true"/>
+      <parameter id="total" constant="false"/>
+      <parameter id="clock" value="0" constant="false"/>
+    </listOfParameters>
+    <listOfInitialAssignments>
+      <initialAssignment symbol="vmax">
+        <math {MATH}><apply><times/><cn>3</cn><ci>E</ci></apply></math>
+      </initialAssignment>
+    </listOfInitialAssignments>
+    <listOfRules>
+      <assignmentRule variable="total">
+        <math {MATH}><apply><plus/><ci>S</ci><ci>P</ci></apply></math>
+      </assignmentRule>
+      <rateRule variable="clock">
+        <math {MATH}><cn type="e-notation">1<sep/>0</cn></math>
+      </rateRule>
+    </listOfRules>
+    <listOfReactions>
+      <reaction id="convert" reversible="false">
+        <listOfReactants><speciesReference species="S" stoichiometry="1" constant="true"/></listOfReactants>
+        <listOfProducts><speciesReference species="P" stoichiometry="2" constant="true"/></listOfProducts>
+        <listOfModifiers><modifierSpeciesReference species="E"/></listOfModifiers>
+        <kineticLaw>
+          <math {MATH}><apply><times/><ci>cell</ci><ci>vmax</ci>
+            <apply><ci>mm</ci><ci>S</ci><ci>km</ci></apply></apply></math>
+          <listOfLocalParameters><localParameter id="km" value="2"/></listOfLocalParameters>
+        </kineticLaw>
+      </reaction>
+    </listOfReactions>
+    <listOfEvents>
+      <event id="wash" useValuesFromTriggerTime="true">
+        <trigger initialValue="false" persistent="true">
+          <math {MATH}><apply><gt/><csymbol encoding="text"
+            definitionURL="http://www.sbml.org/sbml/symbols/time">t</csymbol>
+            <cn>5</cn></apply></math>
+        </trigger>
+        <listOfEventAssignments>
+          <eventAssignment variable="P"><math {MATH}><cn>0</cn></math></eventAssignment>
+        </listOfEventAssignments>
+      </event>
+    </listOfEvents>"#
+        ));
+        let model = import_sbml(&text).unwrap();
+        let names = |quantities: Vec<&String>| {
+            quantities.into_iter().cloned().collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(model.states.iter().map(|s| &s.name).collect()),
+            ["S", "P", "clock"]
+        );
+        assert_eq!(
+            names(
+                model.parameters.iter().map(|p| &p.name).collect()
+            ),
+            ["cell", "E", "vmax", "convert_km"]
+        );
+        assert_eq!(model.states[0].value, 2.0);
+        assert_eq!(model.parameters[2].value, 3.0);
+        assert_eq!(
+            model.reactions[0].rate.to_string(),
+            "cell * vmax * (S / (convert_km + S))"
+        );
+        assert_eq!(model.events[0].crossing, Crossing::Rising);
+
+        let compiled = CompiledModel::new(model).unwrap();
+        let pars = compiled.default_parameters();
+        // Rate in amount per time is 2 * 3 * 2 / 4 = 3, spread
+        // over a compartment of size 2
+        let derivatives = compiled
+            .eval(0.0, &compiled.initial_values(), &pars)
+            .unwrap();
+        assert_eq!(derivatives, vec![-1.5, 3.0, 1.0]);
+        assert_eq!(
+            compiled
+                .derived_values(0.0, &[2.0, 0.5, 0.0], &pars)
+                .unwrap(),
+            vec![2.5]
+        );
+        assert_eq!(compiled.events().len(), 1);
+    }
+
+    #[test]
+    fn reports_unsupported_constructs() {
+        let error = import_sbml(&document(&format!(
+            r#"
+    <listOfParameters><parameter id="p" constant="false"/></listOfParameters>
+    <listOfRules>
+      <assignmentRule variable="p">
+        <math {MATH}><piecewise><piece><cn>1</cn><true/></piece></piecewise></math>
+      </assignmentRule>
+    </listOfRules>"#
+        )))
+        .unwrap_err();
+        assert_eq!(
+            error.message,
+            "MathML element <piecewise> is not supported"
+        );
+        assert_eq!(error.line, Some(8));
+
+        // Events resizing `cell` would keep the amount of `S`
+        let error = import_sbml(&document(
+            r#"
+    <listOfCompartments>
+      <compartment id="cell" size="2" constant="false"/>
+    </listOfCompartments>
+    <listOfSpecies>
+      <species id="S" compartment="cell" initialConcentration="1"
+        hasOnlySubstanceUnits="false" boundaryCondition="false" constant="false"/>
+    </listOfSpecies>"#,
+        ))
+        .unwrap_err();
+        assert_eq!(
+            error.message,
+            "species `S` is a concentration in `cell`, whose size \
+             changes, which is not supported"
+        );
+
+        let error = import_sbml(
+            r#"<sbml level="2" version="4"><model/></sbml>"#,
+        )
+        .unwrap_err();
+        assert_eq!(error.message, "only SBML Level 3 is supported");
+
+        let error = import_sbml("<sbml level=\"3\">").unwrap_err();
+        assert_eq!(error.line, Some(1));
+    }
+}
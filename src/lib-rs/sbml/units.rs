@@ -0,0 +1,120 @@
+/// Factor of an SBML unit definition, the base unit `kind`
+/// times `multiplier * 10^scale` to the power `exponent`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unit {
+    pub kind: &'static str,
+    pub exponent: i32,
+    pub scale: i32,
+    pub multiplier: f64,
+}
+
+/// Base unit, scale and multiplier of a unit symbol, `Some(None)`
+/// for symbols that do not change the unit
+fn symbol(name: &str) -> Option<Option<(&'static str, i32, f64)>> {
+    let unit = match name {
+        "s" => ("second", 0, 1.0),
+        "min" => ("second", 0, 60.0),
+        "h" => ("second", 0, 3600.0),
+        "mol" => ("mole", 0, 1.0),
+        "mmol" => ("mole", -3, 1.0),
+        "µmol" | "μmol" | "umol" => ("mole", -6, 1.0),
+        "nmol" => ("mole", -9, 1.0),
+        "m" => ("metre", 0, 1.0),
+        "l" | "L" => ("litre", 0, 1.0),
+        "ml" | "mL" => ("litre", -3, 1.0),
+        "g" => ("gram", 0, 1.0),
+        "kg" => ("kilogram", 0, 1.0),
+        "K" => ("kelvin", 0, 1.0),
+        "V" => ("volt", 0, 1.0),
+        "J" => ("joule", 0, 1.0),
+        "kJ" => ("joule", 3, 1.0),
+        // Labels what is counted, as in `mmol/mol Chl`
+        "Chl" | "1" => return Some(None),
+        _ => return None,
+    };
+    Some(Some(unit))
+}
+
+/// Factors like `m²` or `s^-1` separated by spaces or `*`
+fn product(text: &str, sign: i32) -> Option<Vec<Unit>> {
+    let mut units = vec![];
+    for factor in text
+        .split(|c: char| c.is_whitespace() || c == '*' || c == '·')
+        .filter(|factor| !factor.is_empty())
+    {
+        let (name, exponent) =
+            if let Some(name) = factor.strip_suffix('²') {
+                (name, 2)
+            } else if let Some(name) = factor.strip_suffix('³') {
+                (name, 3)
+            } else if let Some((name, exponent)) =
+                factor.split_once('^')
+            {
+                (name, exponent.parse().ok()?)
+            } else {
+                (factor, 1)
+            };
+        if let Some((kind, scale, multiplier)) = symbol(name)? {
+            units.push(Unit {
+                kind,
+                exponent: sign * exponent,
+                scale,
+                multiplier,
+            });
+        }
+    }
+    Some(units)
+}
+
+/// Read a unit like `µmol/(m² s)` as SBML units, `None` if it
+/// uses symbols without an SBML base unit
+///
+/// An empty result is dimensionless.
+pub fn parse_unit(text: &str) -> Option<Vec<Unit>> {
+    let (numerator, denominator) = match text.split_once('/') {
+        Some((numerator, denominator)) => {
+            let denominator = denominator.trim();
+            let denominator = denominator
+                .strip_prefix('(')
+                .and_then(|inner| inner.strip_suffix(')'))
+                .unwrap_or(denominator);
+            (numerator, denominator)
+        }
+        None => (text, ""),
+    };
+    if denominator.contains(['/', '(', ')'])
+        || numerator.contains(['(', ')'])
+    {
+        return None;
+    }
+    let mut units = product(numerator, 1)?;
+    units.extend(product(denominator, -1)?);
+    Some(units)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_model_units() {
+        let units = parse_unit("µmol/(m² s)").unwrap();
+        let kinds: Vec<_> = units
+            .iter()
+            .map(|u| (u.kind, u.exponent, u.scale))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("mole", 1, -6),
+                ("metre", -2, 0),
+                ("second", -1, 0)
+            ]
+        );
+        assert_eq!(parse_unit("1").unwrap(), vec![]);
+        assert_eq!(parse_unit("mmol/mol Chl").unwrap().len(), 2);
+        assert_eq!(parse_unit("kJ/(K mol)").unwrap()[0].scale, 3);
+        assert_eq!(parse_unit("1/time"), None);
+        assert_eq!(parse_unit("a/b/c"), None);
+    }
+}
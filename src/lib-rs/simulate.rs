@@ -0,0 +1,477 @@
+use serde::Deserialize;
+
+use crate::dense::OutputTimes;
+use crate::events::Event;
+use crate::implicit::Kvaerno45Options;
+use crate::models::ModelInfo;
+use crate::protocol::{ProtocolIntegration, Segment, run_protocol};
+use crate::{Integration, Rhs, SolverError, explicit, implicit};
+
+/// Solver and its settings, tagged by `solver`
+#[derive(Deserialize, Clone)]
+#[serde(tag = "solver", rename_all = "camelCase")]
+pub enum Solver {
+    #[serde(rename_all = "camelCase")]
+    Euler {
+        step_size: f64,
+    },
+    Kvaerno45(Kvaerno45Options),
+}
+
+/// Settings of [`simulate`], e.g.
+/// `{ solver: "kvaerno45", tEnd: 50, rtol: 1e-4 }`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulateOptions {
+    #[serde(default)]
+    pub t_start: f64,
+    pub t_end: f64,
+    #[serde(flatten)]
+    pub solver: Solver,
+}
+
+impl SimulateOptions {
+    /// Reject settings the solvers cannot work with, e.g. ones
+    /// passed in from JavaScript, before integrating
+    pub fn validate(&self) -> Result<(), SolverError> {
+        let t = self.t_start;
+        if !self.t_start.is_finite() || !self.t_end.is_finite() {
+            return Err(SolverError::InvalidOption {
+                t,
+                option: "time span",
+                message: format!(
+                    "{} to {} is not finite",
+                    self.t_start, self.t_end
+                ),
+            });
+        }
+        match &self.solver {
+            &Solver::Euler { step_size } => {
+                if !(step_size.is_finite() && step_size > 0.0) {
+                    return Err(SolverError::InvalidOption {
+                        t,
+                        option: "step size",
+                        message: format!(
+                            "{step_size} is not positive"
+                        ),
+                    });
+                }
+                Ok(())
+            }
+            Solver::Kvaerno45(options) => options.validate(t),
+        }
+    }
+}
+
+/// Settings of [`simulate_protocol`], those of
+/// [`SimulateOptions`] without `tEnd`, which the segments give
+///
+/// Output times of kvaerno45 refer to the whole protocol, e.g.
+/// `uniform: 11` gives 11 points in total, not per segment.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtocolOptions {
+    #[serde(default)]
+    pub t_start: f64,
+    #[serde(flatten)]
+    pub solver: Solver,
+}
+
+/// Integrate a built-in model
+///
+/// Empty `y0` or `pars` fall back to the defaults of the model.
+pub fn simulate(
+    model: &ModelInfo,
+    y0: Vec<f64>,
+    pars: Vec<f64>,
+    options: SimulateOptions,
+) -> Result<Integration, SolverError> {
+    let y0 = initial_values(model, y0);
+    let pars = if pars.is_empty() {
+        model.parameters.iter().map(|p| p.default).collect()
+    } else {
+        pars
+    };
+    model.check_dimensions(&y0, &pars, options.t_start)?;
+    simulate_rhs(model.autodiff, y0, pars, options, vec![])
+}
+
+/// Run a built-in model through `segments` with any solver,
+/// see [`run_protocol`]
+///
+/// Empty `y0` falls back to the defaults of the model.
+pub fn simulate_protocol(
+    model: &ModelInfo,
+    y0: Vec<f64>,
+    segments: &[Segment],
+    options: ProtocolOptions,
+) -> Result<ProtocolIntegration, SolverError> {
+    let y0 = initial_values(model, y0);
+    let mut t = options.t_start;
+    for segment in segments {
+        model.check_dimensions(&y0, &segment.pars, t)?;
+        t = segment.t_end;
+    }
+    let t_final =
+        segments.last().map_or(options.t_start, |s| s.t_end);
+    let requested = match &options.solver {
+        Solver::Kvaerno45(kvaerno45) => {
+            kvaerno45.output.resolve(options.t_start, t_final)?
+        }
+        Solver::Euler { .. } => None,
+    };
+    run_protocol(
+        |y0, pars, t_start, t_end| {
+            let mut solver = options.solver.clone();
+            if let (Solver::Kvaerno45(kvaerno45), Some(requested)) =
+                (&mut solver, &requested)
+            {
+                // Every segment reports both of its ends, which
+                // `run_protocol` and the pulse readouts rely on
+                let inner = requested
+                    .iter()
+                    .copied()
+                    .filter(|&t| t_start < t && t < t_end);
+                kvaerno45.output = OutputTimes::Times(
+                    [t_start]
+                        .into_iter()
+                        .chain(inner)
+                        .chain([t_end])
+                        .collect(),
+                );
+            }
+            let options = SimulateOptions {
+                t_start,
+                t_end,
+                solver,
+            };
+            simulate_rhs(model.autodiff, y0, pars, options, vec![])
+        },
+        y0,
+        options.t_start,
+        segments,
+    )
+}
+
+fn initial_values(model: &ModelInfo, y0: Vec<f64>) -> Vec<f64> {
+    if y0.is_empty() {
+        model.variables.iter().map(|v| v.initial).collect()
+    } else {
+        y0
+    }
+}
+
+/// Integrate any right hand side, e.g. a model defined in
+/// JavaScript, with the events of the model
+pub fn simulate_rhs(
+    rhs: &dyn Rhs,
+    y0: Vec<f64>,
+    pars: Vec<f64>,
+    options: SimulateOptions,
+    events: Vec<Event>,
+) -> Result<Integration, SolverError> {
+    options.validate()?;
+    let SimulateOptions {
+        t_start,
+        t_end,
+        solver,
+    } = options;
+
+    match solver {
+        Solver::Euler { step_size } => explicit::euler(
+            rhs, y0, pars, step_size, t_start, t_end, &events,
+        ),
+        Solver::Kvaerno45(mut options) => {
+            options.events.extend(events);
+            implicit::kvaerno45(
+                rhs, y0, pars, t_start, t_end, options,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReturnCode;
+    use crate::implicit::JacobianOption;
+    use crate::implicit::krylov::KrylovOptions;
+    use crate::models::{LOTKA_VOLTERRA, NPQ};
+    use crate::protocol::Segment;
+    use crate::tolerance::ErrorNorm;
+
+    fn options(value: serde_json::Value) -> SimulateOptions {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn reads_solver_settings() {
+        let SimulateOptions {
+            t_start,
+            t_end,
+            solver: Solver::Kvaerno45(kvaerno45),
+        } = options(serde_json::json!({
+            "solver": "kvaerno45",
+            "tEnd": 50.0,
+            "rtol": 1e-4,
+            "errorNorm": "max",
+            "excluded": [1],
+            "output": { "uniform": 11 },
+            "jacobian": { "sparse": { "entries": [[0, 1]] } },
+        }))
+        else {
+            panic!("expected kvaerno45");
+        };
+        assert_eq!((t_start, t_end), (0.0, 50.0));
+        assert_eq!(kvaerno45.rtol, 1e-4);
+        assert_eq!(
+            kvaerno45.atol,
+            Kvaerno45Options::default().atol
+        );
+        assert_eq!(kvaerno45.error_norm, ErrorNorm::Max);
+        assert_eq!(kvaerno45.excluded, vec![1]);
+        assert_eq!(kvaerno45.output, OutputTimes::Uniform(11));
+        assert_eq!(
+            kvaerno45.jacobian,
+            JacobianOption::Sparse {
+                entries: Some(vec![(0, 1)])
+            }
+        );
+        assert_eq!(
+            serde_json::from_value::<JacobianOption>(
+                serde_json::json!({
+                    "banded": { "lower": 0, "upper": 1 }
+                })
+            )
+            .unwrap(),
+            JacobianOption::Banded { lower: 0, upper: 1 }
+        );
+        assert_eq!(
+            serde_json::from_value::<JacobianOption>(
+                serde_json::json!({ "krylov": { "maxDimension": 5 } })
+            )
+            .unwrap(),
+            JacobianOption::Krylov(KrylovOptions {
+                max_dimension: 5,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn simulates_with_model_defaults() {
+        let result = simulate(
+            &LOTKA_VOLTERRA,
+            vec![],
+            vec![],
+            options(serde_json::json!({
+                "solver": "euler",
+                "tStart": 1.0,
+                "tEnd": 2.0,
+                "stepSize": 0.25,
+            })),
+        )
+        .unwrap();
+        assert_eq!(result.status, ReturnCode::Success);
+        assert_eq!(result.time, vec![1.0, 1.25, 1.5, 1.75, 2.0]);
+        assert_eq!(result.values[0], vec![10.0, 10.0]);
+    }
+
+    #[test]
+    fn runs_protocols_with_any_solver() {
+        let segments = [
+            Segment {
+                t_end: 1.0,
+                pars: vec![1.0, 0.1, 0.1, 1.0],
+                reset: vec![],
+            },
+            Segment {
+                t_end: 2.0,
+                pars: vec![1.0, 0.1, 0.1, 1.0],
+                reset: vec![(0, 5.0)],
+            },
+        ];
+        for solver in [
+            serde_json::json!({ "solver": "euler", "stepSize": 0.25 }),
+            serde_json::json!({ "solver": "kvaerno45", "rtol": 1e-2 }),
+        ] {
+            let options: ProtocolOptions =
+                serde_json::from_value(solver).unwrap();
+            let result = simulate_protocol(
+                &LOTKA_VOLTERRA,
+                vec![],
+                &segments,
+                options,
+            )
+            .unwrap();
+            let time = &result.integration.time;
+            assert_eq!(result.segment_starts.len(), 2);
+            assert_eq!(time[0], 0.0);
+            assert_eq!(time.last(), Some(&2.0));
+            let start = result.segment_starts[1];
+            assert_eq!(time[start], 1.0);
+            assert_eq!(result.integration.values[start][0], 5.0);
+        }
+    }
+
+    #[test]
+    fn spreads_output_times_over_segments() {
+        let segments = [
+            Segment {
+                t_end: 1.0,
+                pars: vec![1.0, 0.1, 0.1, 1.0],
+                reset: vec![],
+            },
+            Segment {
+                t_end: 2.0,
+                pars: vec![1.0, 0.1, 0.1, 1.0],
+                reset: vec![(0, 5.0)],
+            },
+        ];
+        for (output, expected) in [
+            (
+                serde_json::json!({ "uniform": 5 }),
+                vec![0.0, 0.5, 1.0, 1.0, 1.5, 2.0],
+            ),
+            (
+                serde_json::json!({ "times": [0.25, 1.0, 2.0] }),
+                vec![0.0, 0.25, 1.0, 1.0, 2.0],
+            ),
+        ] {
+            let options: ProtocolOptions =
+                serde_json::from_value(serde_json::json!({
+                    "solver": "kvaerno45",
+                    "rtol": 1e-2,
+                    "output": output,
+                }))
+                .unwrap();
+            let result = simulate_protocol(
+                &LOTKA_VOLTERRA,
+                vec![],
+                &segments,
+                options,
+            )
+            .unwrap();
+            assert_eq!(result.integration.time, expected);
+            assert_eq!(result.segment_starts, vec![0, 3]);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let simulate_json = |value| {
+            simulate(
+                &LOTKA_VOLTERRA,
+                vec![],
+                vec![],
+                options(value),
+            )
+        };
+        for (value, expected) in [
+            (
+                serde_json::json!({
+                    "solver": "kvaerno45",
+                    "tEnd": 1.0,
+                    "hMin": 1.0,
+                    "hMax": 0.1,
+                }),
+                "h_min",
+            ),
+            (
+                serde_json::json!({
+                    "solver": "euler",
+                    "tEnd": 1.0,
+                    "stepSize": -0.1,
+                }),
+                "step size",
+            ),
+        ] {
+            let error = simulate_json(value).err();
+            assert!(
+                matches!(
+                    error,
+                    Some(SolverError::InvalidOption { option, .. })
+                        if option == expected
+                ),
+                "{expected}: {error:?}"
+            );
+        }
+        let infinite = SimulateOptions {
+            t_start: 0.0,
+            t_end: f64::INFINITY,
+            solver: Solver::Euler { step_size: 0.1 },
+        };
+        assert!(matches!(
+            infinite.validate(),
+            Err(SolverError::InvalidOption {
+                option: "time span",
+                ..
+            })
+        ));
+        let error = simulate(
+            &LOTKA_VOLTERRA,
+            vec![1.0],
+            vec![],
+            options(serde_json::json!({
+                "solver": "euler",
+                "tEnd": 1.0,
+                "stepSize": 0.1,
+            })),
+        );
+        assert!(matches!(
+            error,
+            Err(SolverError::DimensionMismatch { found: 1, .. })
+        ));
+        let error = simulate(
+            &LOTKA_VOLTERRA,
+            vec![],
+            vec![0.1],
+            options(serde_json::json!({
+                "solver": "euler",
+                "tEnd": 1.0,
+                "stepSize": 0.1,
+            })),
+        );
+        assert!(matches!(
+            error,
+            Err(SolverError::DimensionMismatch {
+                quantity: "parameters",
+                expected: 4,
+                found: 1,
+                ..
+            })
+        ));
+
+        // Each segment of a protocol, while NPQ also takes
+        // `[ppfd]` alone
+        let protocol = |model, y0, pars: Vec<f64>| {
+            let segments = [Segment {
+                t_end: 1.0,
+                pars,
+                reset: vec![],
+            }];
+            let options: ProtocolOptions =
+                serde_json::from_value(serde_json::json!({
+                    "solver": "euler",
+                    "stepSize": 0.5,
+                }))
+                .unwrap();
+            simulate_protocol(model, y0, &segments, options)
+        };
+        assert!(matches!(
+            protocol(&LOTKA_VOLTERRA, vec![1.0], vec![0.1; 4]),
+            Err(SolverError::DimensionMismatch {
+                quantity: "initial values",
+                ..
+            })
+        ));
+        assert!(matches!(
+            protocol(&LOTKA_VOLTERRA, vec![], vec![0.1]),
+            Err(SolverError::DimensionMismatch {
+                quantity: "parameters",
+                ..
+            })
+        ));
+        assert!(protocol(&NPQ, vec![], vec![100.0]).is_ok());
+    }
+}
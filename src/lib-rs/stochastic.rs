@@ -0,0 +1,256 @@
+use serde::Deserialize;
+
+use crate::dense::OutputTimes;
+use crate::network::ReactionNetwork;
+use crate::{Integration, ReturnCode, SolverError, Statistics};
+
+#[derive(Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GillespieOptions {
+    /// Seed of the random numbers, equal seeds give equal
+    /// trajectories
+    pub seed: u64,
+    pub max_steps: i64,
+    /// `Steps` reports every reaction event, other time points
+    /// get the state at that time
+    pub output: OutputTimes,
+}
+
+impl Default for GillespieOptions {
+    fn default() -> Self {
+        GillespieOptions {
+            seed: 0,
+            max_steps: 1_000_000,
+            output: OutputTimes::Steps,
+        }
+    }
+}
+
+/// SplitMix64 generator, small and good enough for sampling
+/// waiting times
+pub(crate) struct Random(pub(crate) u64);
+
+impl Random {
+    /// Uniform number in `(0, 1]`
+    pub(crate) fn next(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        ((z >> 11) as f64 + 1.0) / (1u64 << 53) as f64
+    }
+}
+
+/// Stochastic simulation of a reaction network with
+/// Gillespie's direct method
+///
+/// Species are molecule counts and reaction rates are the
+/// propensities, the probability per time of a reaction to
+/// happen once. Rates are taken as constant between two
+/// events, also if they depend on time.
+pub fn gillespie(
+    network: &ReactionNetwork,
+    y0: Vec<f64>,
+    pars: Vec<f64>,
+    t_start: f64,
+    t_end: f64,
+    options: GillespieOptions,
+) -> Result<Integration, SolverError> {
+    if !t_start.is_finite() || !t_end.is_finite() {
+        return Err(SolverError::InvalidOption {
+            t: t_start,
+            option: "time span",
+            message: format!("{t_start} to {t_end} is not finite"),
+        });
+    }
+    if t_end < t_start {
+        return Err(SolverError::InvalidOption {
+            t: t_start,
+            option: "t_end",
+            message: "stochastic simulations only run forward in \
+                      time"
+                .to_string(),
+        });
+    }
+    let output = options.output.resolve(t_start, t_end)?;
+    let mut next_output = 0;
+    let mut random = Random(options.seed);
+    let mut stats = Statistics::default();
+    let mut status = ReturnCode::Success;
+
+    let mut t = t_start;
+    let mut y = y0;
+    let mut time = vec![];
+    let mut values = vec![];
+    if output.is_none() {
+        time.push(t);
+        values.push(y.clone());
+    }
+    loop {
+        let propensities = network.fluxes(t, &y, &pars)?;
+        stats.rhs_evaluations += 1;
+        if let Some((j, _)) = propensities
+            .iter()
+            .enumerate()
+            .find(|&(_, &a)| !(a >= 0.0 && a.is_finite()))
+        {
+            return Err(SolverError::ModelFailure {
+                t,
+                message: format!(
+                    "reaction `{}` has the propensity {}",
+                    network.reactions()[j].name,
+                    propensities[j]
+                ),
+            });
+        }
+        let total: f64 = propensities.iter().sum();
+        let t_next = if total > 0.0 {
+            t - random.next().ln() / total
+        } else {
+            f64::INFINITY
+        };
+
+        // The state holds until the next event
+        if let Some(times) = &output {
+            while next_output < times.len()
+                && times[next_output] < t_next
+            {
+                time.push(times[next_output]);
+                values.push(y.clone());
+                next_output += 1;
+            }
+        }
+        if t_next > t_end {
+            if output.is_none() {
+                time.push(t_end);
+                values.push(y.clone());
+            }
+            break;
+        }
+        if stats.accepted_steps as i64 >= options.max_steps {
+            status = ReturnCode::MaxStepsReached;
+            break;
+        }
+
+        let threshold = random.next() * total;
+        let mut sum = 0.0;
+        // Nothing can happen once every propensity is zero
+        let Some(reaction) = network
+            .reactions()
+            .iter()
+            .zip(&propensities)
+            .find(|&(_, &a)| {
+                sum += a;
+                sum >= threshold && a > 0.0
+            })
+            .map(|(reaction, _)| reaction)
+        else {
+            break;
+        };
+        for &(i, coefficient) in &reaction.stoichiometry {
+            y[i] += coefficient;
+        }
+        t = t_next;
+        stats.accepted_steps += 1;
+        if output.is_none() {
+            time.push(t);
+            values.push(y.clone());
+        }
+    }
+
+    Ok(Integration {
+        time,
+        values,
+        status,
+        stats,
+        steps: None,
+        events: vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::ModelError;
+
+    fn decay() -> Result<ReactionNetwork, ModelError> {
+        ReactionNetwork::new()
+            .species("a", 1000.0)?
+            .parameter("k", 1.0)?
+            .reaction("decay", &[("a", -1.0)], |_, y, p| {
+                p[0] * y[0]
+            })
+    }
+
+    #[test]
+    fn samples_a_decay() {
+        let network = decay().unwrap();
+        let run = |seed| {
+            gillespie(
+                &network,
+                network.initial_values(),
+                network.default_parameters(),
+                0.0,
+                1.0,
+                GillespieOptions {
+                    seed,
+                    output: OutputTimes::Uniform(3),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let result = run(1);
+        assert_eq!(result.time, vec![0.0, 0.5, 1.0]);
+        assert_eq!(result.values[0], vec![1000.0]);
+        // 1000 / e = 368 with a standard deviation of 15
+        let last = result.values[2][0];
+        assert!((last - 368.0).abs() < 60.0, "{last}");
+        assert_eq!(result.values, run(1).values);
+        assert_ne!(result.values, run(2).values);
+    }
+
+    #[test]
+    fn reports_every_event_until_exhausted() {
+        let network = decay().unwrap();
+        let result = gillespie(
+            &network,
+            vec![5.0],
+            vec![1.0],
+            0.0,
+            1000.0,
+            GillespieOptions::default(),
+        )
+        .unwrap();
+        let counts: Vec<f64> =
+            result.values.iter().map(|y| y[0]).collect();
+        assert_eq!(counts, vec![5.0, 4.0, 3.0, 2.0, 1.0, 0.0, 0.0]);
+        assert_eq!(result.time.last(), Some(&1000.0));
+        assert_eq!(result.stats.accepted_steps, 5);
+    }
+
+    #[test]
+    fn rejects_infinite_time_spans() {
+        let network = decay().unwrap();
+        for (t_start, t_end) in
+            [(0.0, f64::INFINITY), (f64::NAN, 1.0)]
+        {
+            let result = gillespie(
+                &network,
+                vec![5.0],
+                vec![1.0],
+                t_start,
+                t_end,
+                GillespieOptions::default(),
+            );
+            assert!(matches!(
+                result,
+                Err(SolverError::InvalidOption {
+                    option: "time span",
+                    ..
+                })
+            ));
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use serde::Deserialize;
+
+use crate::SolverError;
+
+/// Absolute tolerance, either shared by all components or one
+/// per component for states of very different magnitude
+///
+/// A number or an array in JSON.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Tolerance {
+    Scalar(f64),
+    Vector(Vec<f64>),
+}
+
+impl From<f64> for Tolerance {
+    fn from(atol: f64) -> Self {
+        Tolerance::Scalar(atol)
+    }
+}
+
+impl From<Vec<f64>> for Tolerance {
+    fn from(atol: Vec<f64>) -> Self {
+        Tolerance::Vector(atol)
+    }
+}
+
+/// How the scaled local errors of the components are combined
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorNorm {
+    /// Root mean square, as in Hairer and Wanner's codes
+    #[default]
+    Rms,
+    /// Largest component, stricter for single components
+    Max,
+}
+
+/// Error control of an adaptive integrator, checked against
+/// the dimension of the state
+pub struct ErrorControl {
+    rtol: f64,
+    atol: Vec<f64>,
+    norm: ErrorNorm,
+    /// Components taking part in the error estimate
+    controlled: Vec<usize>,
+}
+
+impl ErrorControl {
+    pub fn new(
+        rtol: f64,
+        atol: &Tolerance,
+        norm: ErrorNorm,
+        excluded: &[usize],
+        n: usize,
+        t: f64,
+    ) -> Result<Self, SolverError> {
+        let atol = match atol {
+            &Tolerance::Scalar(atol) => vec![atol; n],
+            Tolerance::Vector(atol) if atol.len() != n => {
+                return Err(SolverError::DimensionMismatch {
+                    t,
+                    quantity: "absolute tolerances",
+                    expected: n,
+                    found: atol.len(),
+                });
+            }
+            Tolerance::Vector(atol) => atol.clone(),
+        };
+        // Also rejects NaN
+        if atol
+            .iter()
+            .chain([&rtol])
+            .any(|x| x.is_nan() || *x < 0.0)
+        {
+            return Err(SolverError::InvalidOption {
+                t,
+                option: "tolerances",
+                message: "must not be negative".to_string(),
+            });
+        }
+        if let Some(i) = excluded.iter().find(|&&i| i >= n) {
+            return Err(SolverError::InvalidOption {
+                t,
+                option: "excluded",
+                message: format!(
+                    "component {i} of a state with {n} components"
+                ),
+            });
+        }
+        let controlled: Vec<usize> =
+            (0..n).filter(|i| !excluded.contains(i)).collect();
+        // The scaled error of a component that reaches zero would
+        // be infinite
+        if let Some(i) = controlled
+            .iter()
+            .find(|&&i| rtol == 0.0 && atol[i] == 0.0)
+        {
+            return Err(SolverError::InvalidOption {
+                t,
+                option: "tolerances",
+                message: format!(
+                    "component {i} has neither a relative nor an \
+                     absolute tolerance"
+                ),
+            });
+        }
+        Ok(ErrorControl {
+            rtol,
+            atol,
+            norm,
+            controlled,
+        })
+    }
+
+    /// Norm of the local `error` of a step from `y` to `y_new`,
+    /// relative to the tolerances; the step is accepted below
+    /// one and NaN propagates
+    pub fn error(
+        &self,
+        error: &[f64],
+        y: &[f64],
+        y_new: &[f64],
+    ) -> f64 {
+        let scaled = self.controlled.iter().map(|&i| {
+            let sc = self.atol[i]
+                + self.rtol * y[i].abs().max(y_new[i].abs());
+            (error[i] / sc).abs()
+        });
+        match self.norm {
+            ErrorNorm::Rms if self.controlled.is_empty() => 0.0,
+            ErrorNorm::Rms => {
+                let sum: f64 = scaled.map(|x| x * x).sum();
+                (sum / self.controlled.len() as f64).sqrt()
+            }
+            // Keeps the first NaN, which `f64::max` would drop
+            ErrorNorm::Max => scaled.fold(0.0, |acc, x| {
+                if x > acc || x.is_nan() { x } else { acc }
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combines_scaled_errors() {
+        let y = [0.01, 1000.0, 1.0];
+        let error = [1e-4, 1e-2, 0.5];
+        let atol = Tolerance::from(vec![1e-4, 1e-2, 1.0]);
+        let control = |norm, excluded: &[usize]| {
+            ErrorControl::new(0.0, &atol, norm, excluded, 3, 0.0)
+                .unwrap()
+        };
+        let rms =
+            control(ErrorNorm::Rms, &[]).error(&error, &y, &y);
+        assert!((rms - (2.25f64 / 3.0).sqrt()).abs() < 1e-12);
+        let max =
+            control(ErrorNorm::Max, &[]).error(&error, &y, &y);
+        assert_eq!(max, 1.0);
+        let max =
+            control(ErrorNorm::Max, &[0, 1]).error(&error, &y, &y);
+        assert_eq!(max, 0.5);
+        assert_eq!(
+            control(ErrorNorm::Rms, &[0, 1, 2])
+                .error(&error, &y, &y),
+            0.0
+        );
+        assert!(
+            control(ErrorNorm::Max, &[])
+                .error(&[f64::NAN, 0.0, 0.0], &y, &y)
+                .is_nan()
+        );
+
+        // Relative tolerance against the larger of both states
+        let control = ErrorControl::new(
+            1e-3,
+            &0.0.into(),
+            ErrorNorm::Max,
+            &[],
+            1,
+            0.0,
+        )
+        .unwrap();
+        assert_eq!(control.error(&[1e-3], &[0.5], &[-2.0]), 0.5);
+    }
+
+    #[test]
+    fn rejects_mismatched_tolerances() {
+        let new = |atol: Tolerance, excluded: &[usize]| {
+            ErrorControl::new(
+                1e-6,
+                &atol,
+                ErrorNorm::Rms,
+                excluded,
+                2,
+                1.0,
+            )
+            .err()
+        };
+        assert!(matches!(
+            new(vec![1e-8].into(), &[]),
+            Some(SolverError::DimensionMismatch {
+                expected: 2,
+                found: 1,
+                ..
+            })
+        ));
+        assert!(matches!(
+            new((-1.0).into(), &[]),
+            Some(SolverError::InvalidOption { .. })
+        ));
+        assert!(matches!(
+            new(1e-8.into(), &[2]),
+            Some(SolverError::InvalidOption {
+                option: "excluded",
+                ..
+            })
+        ));
+        assert!(new(1e-8.into(), &[1]).is_none());
+        // Zero tolerances only for components left out
+        let zero = |excluded: &[usize]| {
+            ErrorControl::new(
+                0.0,
+                &vec![1e-8, 0.0].into(),
+                ErrorNorm::Rms,
+                excluded,
+                2,
+                1.0,
+            )
+            .err()
+        };
+        assert!(matches!(
+            zero(&[]),
+            Some(SolverError::InvalidOption {
+                option: "tolerances",
+                ..
+            })
+        ));
+        assert!(zero(&[1]).is_none());
+        assert_eq!(
+            serde_json::from_str::<Tolerance>("[1e-8, 0.1]")
+                .unwrap(),
+            Tolerance::Vector(vec![1e-8, 0.1])
+        );
+    }
+}